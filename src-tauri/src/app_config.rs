@@ -0,0 +1,280 @@
+//! App-wide defaults read from a hand-edited `config.toml`.
+//!
+//! The file sits in the app config directory and holds the model new sessions
+//! start with, the temperature responses are sampled at, the quick-ask hotkey,
+//! hints for the frontend's theme and the backend the store is saved with. It is read at startup and watched for
+//! changes, which are applied without restarting the app and announced with
+//! `CONFIG_CHANGED_EVENT`. Unlike the other config files it is never written
+//! by the app.
+
+use crate::commands::{StoreState, DEFAULT_MODEL};
+use crate::events::CONFIG_CHANGED_EVENT;
+use crate::hotkey::{HotkeyConfig, HotkeyState};
+use crate::persistence::StorageKind;
+use crate::ChatError;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+
+/// Name of the config file inside the app config directory
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Whether the frontend is drawn light or dark
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Appearance {
+    /// Follows the OS setting
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+/// Hints the frontend uses to style itself. The backend only passes them on.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThemeHints {
+    pub appearance: Appearance,
+
+    /// CSS color of highlighted elements, such as `"#7c3aed"`
+    pub accent_color: Option<String>,
+
+    /// Font size of messages in pixels
+    pub font_size: Option<u16>,
+
+    /// Opacity of the overlay window, from 0 to 1
+    pub opacity: Option<f32>,
+}
+
+/// The defaults in `config.toml`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    /// Model of sessions started without one
+    pub default_model: String,
+
+    /// Sampling temperature of responses, or None for the provider's default
+    pub temperature: Option<f32>,
+
+    /// Accelerator of the quick-ask hotkey, replacing the one set from the
+    /// frontend. None leaves the hotkey as it is.
+    pub hotkey: Option<String>,
+
+    pub theme: ThemeHints,
+
+    /// Backend the store is saved with. Only read at startup.
+    pub storage: StorageKind,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            default_model: DEFAULT_MODEL.to_string(),
+            temperature: None,
+            hotkey: None,
+            theme: ThemeHints::default(),
+            storage: StorageKind::Json,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Parses the config at `path`, or returns the default config if there
+    /// is no file there
+    pub fn load(path: &Path) -> Result<AppConfig, ChatError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => AppConfig::parse(&contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AppConfig::default()),
+            Err(e) => Err(ChatError::Persistence(e.to_string())),
+        }
+    }
+
+    /// Parses `contents` as TOML. Missing keys are given their defaults.
+    pub fn parse(contents: &str) -> Result<AppConfig, ChatError> {
+        let config: AppConfig =
+            toml::from_str(contents).map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        match config.temperature {
+            Some(temperature) if !(0.0..=2.0).contains(&temperature) => {
+                Err(ChatError::Persistence(format!(
+                    "temperature must be between 0 and 2, not {}",
+                    temperature
+                )))
+            }
+            _ => Ok(config),
+        }
+    }
+}
+
+/// The Tauri managed state holding the config in effect and the watcher of
+/// its file
+#[derive(Debug)]
+pub struct AppConfigState {
+    config: Mutex<AppConfig>,
+    path: PathBuf,
+    watcher: Mutex<Option<RecommendedWatcher>>,
+}
+
+impl AppConfigState {
+    /// Loads the config inside `app_config_dir`. A config that can not be
+    /// parsed is reported and replaced by the default one, so a typo does not
+    /// keep the app from starting.
+    pub fn in_app_config_dir(app_config_dir: &Path) -> AppConfigState {
+        let path = app_config_dir.join(CONFIG_FILE_NAME);
+        let config = AppConfig::load(&path).unwrap_or_else(|e| {
+            eprintln!("Could not read {}: {}", CONFIG_FILE_NAME, e);
+            AppConfig::default()
+        });
+
+        AppConfigState {
+            config: Mutex::new(config),
+            path,
+            watcher: Mutex::new(None),
+        }
+    }
+
+    /// Returns a copy of the config in effect
+    pub fn get_config(&self) -> AppConfig {
+        self.lock().clone()
+    }
+
+    /// Applies the config in effect to the store and the hotkey
+    pub fn apply(&self, app: &AppHandle) {
+        apply(app, &self.get_config());
+    }
+
+    /// Starts watching the config file, re-applying it whenever it changes.
+    /// The state must be managed by `app`.
+    pub fn watch(&self, app: &AppHandle) -> Result<(), ChatError> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        // Editors often replace the file rather than writing to it, so the
+        // directory holding it is watched instead
+        fs::create_dir_all(dir).map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        let handle = app.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) if touches_config(&event) => reload(&handle),
+                Ok(_) => {}
+                Err(e) => eprintln!("Could not watch {}: {}", CONFIG_FILE_NAME, e),
+            })
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        *self.watcher.lock().unwrap_or_else(|e| e.into_inner()) = Some(watcher);
+
+        Ok(())
+    }
+
+    /// Replaces the config in effect with `config`, returning false if they
+    /// are the same
+    fn replace(&self, config: AppConfig) -> bool {
+        let mut current = self.lock();
+        if *current == config {
+            return false;
+        }
+        *current = config;
+
+        true
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AppConfig> {
+        // The config is only ever replaced whole, so a poisoned lock is still usable
+        self.config.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Whether `event` may have changed the config file
+fn touches_config(event: &Event) -> bool {
+    !event.kind.is_access()
+        && event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == Some(CONFIG_FILE_NAME.as_ref()))
+}
+
+/// Re-reads the config file, applying it and emitting `CONFIG_CHANGED_EVENT`
+/// if it changed. A file that can not be parsed is reported, keeping the
+/// config in effect.
+fn reload(app: &AppHandle) {
+    let state = app.state::<AppConfigState>();
+    let config = match AppConfig::load(&state.path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not reload {}: {}", CONFIG_FILE_NAME, e);
+            return;
+        }
+    };
+
+    // A single save is often reported as several events
+    if !state.replace(config.clone()) {
+        return;
+    }
+
+    apply(app, &config);
+    if let Err(e) = app.emit_all(CONFIG_CHANGED_EVENT, config) {
+        eprintln!("Could not announce the new config: {}", e);
+    }
+}
+
+/// Applies `config` to the store and the hotkey managed by `app`
+fn apply(app: &AppHandle, config: &AppConfig) {
+    if let Some(store) = app.try_state::<StoreState>() {
+        tauri::async_runtime::block_on(store.write()).set_temperature(config.temperature);
+    }
+
+    let Some(accelerator) = &config.hotkey else {
+        return;
+    };
+    if let Some(hotkey) = app.try_state::<HotkeyState>() {
+        let current = hotkey.get_config();
+        if current.accelerator != *accelerator {
+            let config = HotkeyConfig {
+                accelerator: accelerator.clone(),
+                ..current
+            };
+            if let Err(e) = hotkey.set_config(app, config) {
+                eprintln!("Could not register the quick-ask hotkey: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_app_config_parse() {
+        assert_eq!(AppConfig::default(), AppConfig::parse("").unwrap());
+
+        let config = AppConfig::parse(
+            r##"
+            default_model = "gpt-4"
+            temperature = 0.2
+            hotkey = "Alt+Space"
+            storage = "sqlite"
+
+            [theme]
+            appearance = "dark"
+            accent_color = "#7c3aed"
+            "##,
+        )
+        .unwrap();
+        assert_eq!("gpt-4", config.default_model);
+        assert_eq!(Some(0.2), config.temperature);
+        assert_eq!(Some("Alt+Space"), config.hotkey.as_deref());
+        assert_eq!(StorageKind::Sqlite, config.storage);
+        assert_eq!(Appearance::Dark, config.theme.appearance);
+        assert_eq!(Some("#7c3aed"), config.theme.accent_color.as_deref());
+        assert_eq!(None, config.theme.font_size);
+
+        assert!(AppConfig::parse("temperature = 3.0").is_err());
+        assert!(AppConfig::parse("default_model = ").is_err());
+    }
+}
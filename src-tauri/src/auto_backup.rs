@@ -0,0 +1,385 @@
+//! Backing up the store file on a schedule, to recover from a damaged store.
+//!
+//! While the app runs, the store file is copied into `AUTO_BACKUP_DIR_NAME`
+//! inside the app data directory once the newest copy there is older than
+//! the configured interval. Copies are taken as they are saved, so backups of
+//! an encrypted store stay encrypted with the passphrase of the time. Only the
+//! newest few copies are kept, and a copy identical to the newest is not
+//! taken at all. The schedule is saved to its own file in the app config
+//! directory.
+//!
+//! Restoring a backup copies it back over the store file, after backing up
+//! the store file it replaces. The journal of changes to a store file that
+//! could still be read is discarded with it, while that of a damaged one is
+//! replayed on top of the backup to recover what it can.
+
+use crate::commands::StoreState;
+use crate::persistence::{
+    write_atomically, JsonConfig, JsonFileBackend, StorageBackend, STORE_FILE_NAME,
+};
+use crate::{current_timestamp, ChatError};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+/// Name of the file the backup schedule is saved to inside the app config
+/// directory
+pub const AUTO_BACKUP_FILE_NAME: &str = "auto_backup.json";
+
+/// Name of the directory backups are kept in inside the app data directory
+pub const AUTO_BACKUP_DIR_NAME: &str = "backups";
+
+/// How often the schedule is checked for a backup that is due
+pub const BACKUP_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Start and end of the names of backup files, around the Unix timestamp of
+/// when they were taken
+const BACKUP_PREFIX: &str = "store-";
+const BACKUP_SUFFIX: &str = ".json";
+
+/// When the store is backed up, and how many backups are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoBackupConfig {
+    pub enabled: bool,
+
+    /// Hours between backups
+    pub interval_hours: u64,
+
+    /// The number of backups kept. Older ones are deleted.
+    pub keep: usize,
+}
+
+impl Default for AutoBackupConfig {
+    fn default() -> Self {
+        AutoBackupConfig {
+            enabled: true,
+            interval_hours: 24,
+            keep: 7,
+        }
+    }
+}
+
+impl JsonConfig for AutoBackupConfig {}
+
+impl AutoBackupConfig {
+    /// Returns whether a backup is due at `now` when the newest was taken at
+    /// `newest`, or there is none if None
+    pub fn is_due(&self, newest: Option<u64>, now: u64) -> bool {
+        self.enabled
+            && newest.is_none_or(|x| {
+                now.saturating_sub(x) >= self.interval_hours.saturating_mul(60 * 60)
+            })
+    }
+}
+
+/// A backup of the store file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackupInfo {
+    pub file_name: String,
+    /// Unix timestamp of when the backup was taken
+    pub created_at: u64,
+    /// Size of the backup in bytes
+    pub size: u64,
+    /// Whether the backup needs a passphrase to be restored
+    pub encrypted: bool,
+}
+
+/// Returns the backups in `dir`, newest first
+pub fn list_backups(dir: &Path) -> Result<Vec<BackupInfo>, ChatError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(ChatError::Persistence(e.to_string())),
+    };
+
+    let mut backups = vec![];
+    for entry in entries {
+        let entry = entry.map_err(|e| ChatError::Persistence(e.to_string()))?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let Some(created_at) = file_name
+            .strip_prefix(BACKUP_PREFIX)
+            .and_then(|x| x.strip_suffix(BACKUP_SUFFIX))
+            .and_then(|x| x.parse().ok())
+        else {
+            continue;
+        };
+
+        backups.push(BackupInfo {
+            size: entry.metadata().map_or(0, |x| x.len()),
+            encrypted: JsonFileBackend::new(entry.path()).is_encrypted()?,
+            file_name,
+            created_at,
+        });
+    }
+    backups.sort_by_key(|x| std::cmp::Reverse(x.created_at));
+
+    Ok(backups)
+}
+
+/// Copies the store file at `store_path` into `dir` as taken at `now`, unless
+/// it is identical to the newest backup there or does not exist. Backups
+/// taken within a second of the newest are dated just after it, so none is
+/// overwritten. Returns the backup taken, if any.
+pub fn back_up(store_path: &Path, dir: &Path, now: u64) -> Result<Option<BackupInfo>, ChatError> {
+    let contents = match fs::read(store_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ChatError::Persistence(e.to_string())),
+    };
+    let mut now = now;
+    if let Some(newest) = list_backups(dir)?.first() {
+        if fs::read(dir.join(&newest.file_name)).is_ok_and(|x| x == contents) {
+            return Ok(None);
+        }
+        now = now.max(newest.created_at + 1);
+    }
+
+    let file_name = format!("{}{}{}", BACKUP_PREFIX, now, BACKUP_SUFFIX);
+    write_atomically(&dir.join(&file_name), &contents)?;
+
+    Ok(Some(BackupInfo {
+        file_name,
+        created_at: now,
+        size: contents.len() as u64,
+        encrypted: crate::encryption::is_encrypted(&contents),
+    }))
+}
+
+/// Deletes all but the newest `keep` backups in `dir`, returning the number
+/// deleted
+pub fn rotate(dir: &Path, keep: usize) -> Result<usize, ChatError> {
+    let backups = list_backups(dir)?;
+    let mut deleted = 0;
+    for backup in backups.iter().skip(keep) {
+        fs::remove_file(dir.join(&backup.file_name))
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+        deleted += 1;
+    }
+
+    Ok(deleted)
+}
+
+/// The Tauri managed state holding the backup schedule
+#[derive(Debug)]
+pub struct AutoBackupState {
+    config: Mutex<AutoBackupConfig>,
+    path: PathBuf,
+    /// The directory backups are kept in
+    dir: PathBuf,
+    /// The store file that is backed up
+    store_path: PathBuf,
+}
+
+impl AutoBackupState {
+    /// Loads the schedule saved inside `app_config_dir`, keeping the backups
+    /// of the store inside `app_data_dir`
+    pub fn in_app_dirs(
+        app_config_dir: &Path,
+        app_data_dir: &Path,
+    ) -> Result<AutoBackupState, ChatError> {
+        let path = app_config_dir.join(AUTO_BACKUP_FILE_NAME);
+
+        Ok(AutoBackupState {
+            config: Mutex::new(AutoBackupConfig::load(&path)?),
+            path,
+            dir: app_data_dir.join(AUTO_BACKUP_DIR_NAME),
+            store_path: app_data_dir.join(STORE_FILE_NAME),
+        })
+    }
+
+    /// Returns a copy of the current schedule
+    pub fn get_config(&self) -> AutoBackupConfig {
+        *self.lock()
+    }
+
+    /// Replaces the schedule with `config` and saves it
+    pub fn set_config(&self, config: AutoBackupConfig) -> Result<(), ChatError> {
+        let mut current = self.lock();
+
+        config.save(&self.path)?;
+        *current = config;
+
+        Ok(())
+    }
+
+    /// Returns the backups kept, newest first
+    pub fn list_backups(&self) -> Result<Vec<BackupInfo>, ChatError> {
+        list_backups(&self.dir)
+    }
+
+    /// Backs up the store if a backup is due, deleting the oldest backups
+    /// beyond those kept. Returns the backup taken, if any.
+    pub fn back_up_if_due(&self) -> Result<Option<BackupInfo>, ChatError> {
+        let config = self.get_config();
+        let now = current_timestamp();
+        let newest = self.list_backups()?.first().map(|x| x.created_at);
+        if !config.is_due(newest, now) {
+            return Ok(None);
+        }
+
+        let backup = back_up(&self.store_path, &self.dir, now)?;
+        rotate(&self.dir, config.keep.max(1))?;
+
+        Ok(backup)
+    }
+
+    /// Copies the backup named `file_name` over the store file, unlocking it
+    /// with `passphrase` if it is encrypted, and returns the backend to load
+    /// the restored store through. The store file replaced is backed up
+    /// first, and its journal discarded unless it is damaged. Returns
+    /// `ChatError::StoreLocked` if the backup is encrypted but no passphrase
+    /// was given.
+    pub fn restore(
+        &self,
+        file_name: &str,
+        passphrase: Option<&str>,
+    ) -> Result<JsonFileBackend, ChatError> {
+        if !self
+            .list_backups()?
+            .iter()
+            .any(|x| x.file_name == file_name)
+        {
+            return Err(ChatError::Backup(format!(
+                "There is no backup named {}",
+                file_name
+            )));
+        }
+        let path = self.dir.join(file_name);
+        let backend = unlocked(JsonFileBackend::new(&path), passphrase)?;
+        // Loading checks the backup can be read, and migrated, before the
+        // store file is replaced
+        backend
+            .load()?
+            .ok_or_else(|| ChatError::Backup(String::from("The backup is empty")))?;
+        let contents = fs::read(&path).map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        // Loading a plain store file that can be read compacts its journal
+        // into it, so the backup of it is complete
+        let current = JsonFileBackend::new(&self.store_path);
+        let damaged = matches!(current.load(), Err(ChatError::Persistence(_)));
+        back_up(&self.store_path, &self.dir, current_timestamp())?;
+        write_atomically(&self.store_path, &contents)?;
+        if !damaged {
+            current.discard_journal()?;
+        }
+
+        unlocked(JsonFileBackend::new(&self.store_path), passphrase)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AutoBackupConfig> {
+        // The config is only ever replaced whole, so a poisoned lock is still usable
+        self.config.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Backs up the store whenever a backup is due, checking every
+/// `BACKUP_CHECK_INTERVAL` for as long as the app runs. A store that was not
+/// loaded, as it is locked or damaged, is not backed up, so its backups are
+/// not replaced by copies that may be damaged.
+pub fn spawn_auto_backups(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(BACKUP_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !app.state::<StoreState>().read().await.is_persisted() {
+                continue;
+            }
+            if let Err(e) = app.state::<AutoBackupState>().back_up_if_due() {
+                eprintln!("Could not back up the store: {}", e);
+            }
+        }
+    });
+}
+
+/// Unlocks `backend` with `passphrase` if the file it saves to is encrypted
+fn unlocked(
+    backend: JsonFileBackend,
+    passphrase: Option<&str>,
+) -> Result<JsonFileBackend, ChatError> {
+    match (backend.is_encrypted()?, passphrase) {
+        (false, _) => Ok(backend),
+        (true, Some(passphrase)) => backend.unlock(passphrase),
+        (true, None) => Err(ChatError::StoreLocked),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::StoreSnapshot;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_auto_backup_schedule() {
+        let config = AutoBackupConfig::default();
+        assert!(config.is_due(None, 1_000));
+        assert!(!config.is_due(Some(1_000), 1_000 + 60 * 60));
+        assert!(config.is_due(Some(1_000), 1_000 + 24 * 60 * 60));
+
+        let disabled = AutoBackupConfig {
+            enabled: false,
+            ..config
+        };
+        assert!(!disabled.is_due(None, 1_000));
+    }
+
+    #[test]
+    fn test_back_up_rotate_and_restore() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let data_dir = std::env::temp_dir().join(format!("chat-overlay-auto-backup-{}", nanos));
+        let state = AutoBackupState::in_app_dirs(&data_dir.join("config"), &data_dir).unwrap();
+        let dir = data_dir.join(AUTO_BACKUP_DIR_NAME);
+        let store_path = data_dir.join(STORE_FILE_NAME);
+
+        // Nothing is backed up before the store is first saved
+        assert_eq!(None, state.back_up_if_due().unwrap());
+        let store = JsonFileBackend::new(&store_path);
+        store.save(&StoreSnapshot::default()).unwrap();
+        let first = state.back_up_if_due().unwrap().unwrap();
+        assert!(!first.encrypted);
+        assert_eq!(vec![first.clone()], state.list_backups().unwrap());
+
+        // A store that has not changed is not backed up again
+        assert_eq!(None, back_up(&store_path, &dir, 2_000_000_000).unwrap());
+
+        // Backups taken in the same second are dated after one another
+        for damage in 1..=2 {
+            fs::write(&store_path, format!("damaged {}", damage)).unwrap();
+            back_up(&store_path, &dir, first.created_at).unwrap();
+        }
+        let damaged = list_backups(&dir).unwrap()[0].clone();
+        assert_eq!(first.created_at + 2, damaged.created_at);
+
+        // Damaged backups are refused, and the store file left as it is
+        assert!(state.restore(&damaged.file_name, None).is_err());
+        assert!(matches!(
+            state.restore("../store.json", None),
+            Err(ChatError::Backup(_))
+        ));
+        fs::write(&store_path, b"damaged 3").unwrap();
+        assert!(state.restore(&damaged.file_name, None).is_err());
+        assert_eq!("damaged 3", fs::read_to_string(&store_path).unwrap());
+
+        let backend = state.restore(&first.file_name, None).unwrap();
+        assert!(backend.load().unwrap().unwrap().sessions.is_empty());
+        // The damaged store file was backed up before being replaced
+        assert_eq!(4, list_backups(&dir).unwrap().len());
+
+        assert_eq!(2, rotate(&dir, 2).unwrap());
+        let kept: Vec<u64> = list_backups(&dir)
+            .unwrap()
+            .iter()
+            .map(|x| x.created_at)
+            .collect();
+        assert_eq!(vec![first.created_at + 3, first.created_at + 2], kept);
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+//! Backing up the whole store, along with the settings, to a single file.
+//!
+//! A backup is a gzip compressed JSON document. It holds the store's
+//! sessions, prompt templates, memories and trash as a versioned snapshot, so
+//! backups made by older versions are migrated like store files, and the
+//! contents of every settings file in the app config directory. A SHA-256
+//! checksum of the contents is kept alongside them, so a damaged backup is
+//! refused instead of restored.
+//!
+//! Backups of an encrypted store are encrypted with the store's key, so
+//! restoring one takes the passphrase the store had when it was backed up.
+//! Restored settings take effect the next time the app starts.
+
+use crate::app_config::CONFIG_FILE_NAME;
+use crate::auto_backup::AUTO_BACKUP_FILE_NAME;
+use crate::budget::BUDGET_FILE_NAME;
+use crate::cache::CACHE_FILE_NAME;
+use crate::encryption::{self, EncryptionKey};
+use crate::hotkey::HOTKEY_FILE_NAME;
+use crate::memory::MEMORY_FILE_NAME;
+use crate::moderation::MODERATION_FILE_NAME;
+use crate::notifications::NOTIFICATIONS_FILE_NAME;
+use crate::persistence::{self, write_atomically, StoreSnapshot, VersionedSnapshot};
+use crate::profiles::PROFILES_FILE_NAME;
+use crate::providers::azure::AZURE_FILE_NAME;
+use crate::providers::compatible::ENDPOINTS_FILE_NAME;
+use crate::rate_limit::RATE_LIMIT_FILE_NAME;
+use crate::speech::SPEECH_FILE_NAME;
+use crate::tools::file_reader::FILE_READER_FILE_NAME;
+use crate::tools::web_search::WEB_SEARCH_FILE_NAME;
+use crate::trash::TRASH_FILE_NAME;
+use crate::window::WINDOW_FILE_NAME;
+use crate::{current_timestamp, ChatError};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// Version of the backup format written by this build
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// Name suggested for new backups
+pub const BACKUP_FILE_NAME: &str = "chat-overlay-backup.json.gz";
+
+/// The settings files inside the app config directory that are backed up.
+/// Only these are restored, whatever else a backup holds.
+pub const SETTINGS_FILES: &[&str] = &[
+    CONFIG_FILE_NAME,
+    AUTO_BACKUP_FILE_NAME,
+    AZURE_FILE_NAME,
+    BUDGET_FILE_NAME,
+    CACHE_FILE_NAME,
+    ENDPOINTS_FILE_NAME,
+    FILE_READER_FILE_NAME,
+    HOTKEY_FILE_NAME,
+    MEMORY_FILE_NAME,
+    MODERATION_FILE_NAME,
+    NOTIFICATIONS_FILE_NAME,
+    PROFILES_FILE_NAME,
+    RATE_LIMIT_FILE_NAME,
+    SPEECH_FILE_NAME,
+    TRASH_FILE_NAME,
+    WEB_SEARCH_FILE_NAME,
+    WINDOW_FILE_NAME,
+];
+
+/// The layout of a backup file once decompressed
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    version: u32,
+    created_at: u64,
+    /// Hex encoded SHA-256 checksum of `contents`
+    sha256: String,
+    /// The serialized `BackupContents`, kept as written so its checksum can
+    /// be checked
+    contents: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupContents {
+    /// The store as a versioned snapshot
+    store: Value,
+    /// The contents of each settings file, keyed by file name
+    settings: BTreeMap<String, String>,
+}
+
+/// The contents of a backup
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// Unix timestamp of when the backup was made
+    pub created_at: u64,
+    pub snapshot: StoreSnapshot,
+    /// The contents of each settings file, keyed by file name
+    pub settings: BTreeMap<String, String>,
+}
+
+/// What restoring a backup brings back, and what it replaces
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RestoreReport {
+    /// Unix timestamp of when the backup was made
+    pub created_at: u64,
+    pub sessions: usize,
+    pub messages: usize,
+    pub prompts: usize,
+    pub memories: usize,
+    pub trashed_items: usize,
+    /// Names of the settings files restored
+    pub settings: Vec<String>,
+    /// The number of sessions in the store that are replaced
+    pub replaced_sessions: usize,
+    /// Whether nothing was restored, only reported
+    pub dry_run: bool,
+}
+
+impl Backup {
+    /// Backs up `snapshot` along with `settings`
+    pub fn new(snapshot: StoreSnapshot, settings: BTreeMap<String, String>) -> Backup {
+        Backup {
+            created_at: current_timestamp(),
+            snapshot,
+            settings,
+        }
+    }
+
+    /// Reads the settings files saved inside `app_config_dir`, leaving out
+    /// those never saved
+    pub fn read_settings(app_config_dir: &Path) -> Result<BTreeMap<String, String>, ChatError> {
+        let mut settings = BTreeMap::new();
+        for &name in SETTINGS_FILES {
+            match fs::read_to_string(app_config_dir.join(name)) {
+                Ok(contents) => {
+                    settings.insert(name.to_string(), contents);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(ChatError::Persistence(e.to_string())),
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// Writes this backup to `path`, replacing any file there, encrypted with
+    /// `key` if given
+    pub fn write(&self, path: &Path, key: Option<&EncryptionKey>) -> Result<(), ChatError> {
+        let contents = BackupContents {
+            store: serde_json::to_value(VersionedSnapshot::new(&self.snapshot))
+                .map_err(|e| ChatError::Persistence(e.to_string()))?,
+            settings: self.settings.clone(),
+        };
+        let contents =
+            serde_json::to_string(&contents).map_err(|e| ChatError::Persistence(e.to_string()))?;
+        let file = BackupFile {
+            version: BACKUP_FORMAT_VERSION,
+            created_at: self.created_at,
+            sha256: sha256_hex(&contents),
+            contents,
+        };
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, &file)
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+        let compressed = encoder
+            .finish()
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        match key {
+            Some(key) => write_atomically(path, &key.encrypt(&compressed)?),
+            None => write_atomically(path, &compressed),
+        }
+    }
+
+    /// Reads the backup at `path`, unlocking it with `passphrase` if it is
+    /// encrypted. Returns `ChatError::StoreLocked` if it is encrypted but no
+    /// passphrase is given, and `ChatError::Backup` if it is not a backup, was
+    /// made by a newer version, or has been damaged.
+    pub fn read(path: &Path, passphrase: Option<&str>) -> Result<Backup, ChatError> {
+        let contents = fs::read(path).map_err(|e| ChatError::Persistence(e.to_string()))?;
+        let compressed = match passphrase {
+            _ if !encryption::is_encrypted(&contents) => contents,
+            Some(passphrase) => EncryptionKey::unlock(passphrase, &contents)?.decrypt(&contents)?,
+            None => return Err(ChatError::StoreLocked),
+        };
+        let mut json = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut json)
+            .map_err(|e| ChatError::Backup(e.to_string()))?;
+        let file: BackupFile =
+            serde_json::from_slice(&json).map_err(|e| ChatError::Backup(e.to_string()))?;
+
+        if file.version > BACKUP_FORMAT_VERSION {
+            return Err(ChatError::Backup(format!(
+                "It has format version {}, but only versions up to {} are supported",
+                file.version, BACKUP_FORMAT_VERSION
+            )));
+        }
+        if sha256_hex(&file.contents) != file.sha256 {
+            return Err(ChatError::Backup(String::from(
+                "Its contents do not match their checksum",
+            )));
+        }
+
+        let contents: BackupContents =
+            serde_json::from_str(&file.contents).map_err(|e| ChatError::Backup(e.to_string()))?;
+
+        Ok(Backup {
+            created_at: file.created_at,
+            snapshot: persistence::migrate(contents.store)?,
+            settings: contents.settings,
+        })
+    }
+
+    /// Writes the settings of this backup into `app_config_dir`, replacing
+    /// the saved ones
+    pub fn restore_settings(&self, app_config_dir: &Path) -> Result<(), ChatError> {
+        for (name, contents) in self.restored_settings() {
+            write_atomically(&app_config_dir.join(name), contents.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the settings files of this backup that are restored
+    fn restored_settings(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.settings
+            .iter()
+            .filter(|(name, _)| SETTINGS_FILES.contains(&name.as_str()))
+    }
+
+    /// Reports what restoring this backup over a store of `current_sessions`
+    /// sessions brings back
+    pub fn report(&self, current_sessions: usize, dry_run: bool) -> RestoreReport {
+        RestoreReport {
+            created_at: self.created_at,
+            sessions: self.snapshot.sessions.len(),
+            messages: self
+                .snapshot
+                .sessions
+                .iter()
+                .map(|x| x.message_count())
+                .sum(),
+            prompts: self.snapshot.prompts.len(),
+            memories: self.snapshot.memories.len(),
+            trashed_items: self.snapshot.trash.get_items().len(),
+            settings: self.restored_settings().map(|(x, _)| x.clone()).collect(),
+            replaced_sessions: current_sessions,
+            dry_run,
+        }
+    }
+}
+
+/// Returns the hex encoded SHA-256 checksum of `contents`
+fn sha256_hex(contents: &str) -> String {
+    Sha256::digest(contents.as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::{ChatRole, Store};
+    use std::io::Write;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_backup_round_trip() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-backup-{}", nanos));
+        let config_dir = dir.join("config");
+        let path = dir.join(BACKUP_FILE_NAME);
+
+        let mut store = Store::new(MockProvider::default());
+        let id = store.add_empty_session(String::from("Backed up"), "gpt-3.5-turbo");
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("Hi")),
+                (ChatRole::Assistant, String::from("Hello")),
+            ]);
+        write_atomically(
+            &config_dir.join(HOTKEY_FILE_NAME),
+            b"{\"accelerator\": \"Alt+Q\"}",
+        )
+        .unwrap();
+        write_atomically(&config_dir.join("unrelated.json"), b"{}").unwrap();
+
+        let settings = Backup::read_settings(&config_dir).unwrap();
+        assert_eq!(vec![HOTKEY_FILE_NAME], settings.keys().collect::<Vec<_>>());
+        Backup::new(store.snapshot(), settings)
+            .write(&path, None)
+            .unwrap();
+
+        let backup = Backup::read(&path, None).unwrap();
+        let report = backup.report(3, true);
+        assert_eq!(1, report.sessions);
+        assert_eq!(2, report.messages);
+        assert_eq!(vec![HOTKEY_FILE_NAME.to_string()], report.settings);
+        assert_eq!(3, report.replaced_sessions);
+        assert_eq!(
+            "Hello",
+            backup.snapshot.sessions[0].get_messages()[1].get_content()
+        );
+
+        fs::remove_file(config_dir.join(HOTKEY_FILE_NAME)).unwrap();
+        backup.restore_settings(&config_dir).unwrap();
+        assert_eq!(
+            "{\"accelerator\": \"Alt+Q\"}",
+            fs::read_to_string(config_dir.join(HOTKEY_FILE_NAME)).unwrap()
+        );
+
+        // A backup whose contents were changed is refused
+        let mut file: BackupFile = {
+            let mut json = Vec::new();
+            GzDecoder::new(fs::read(&path).unwrap().as_slice())
+                .read_to_end(&mut json)
+                .unwrap();
+            serde_json::from_slice(&json).unwrap()
+        };
+        file.contents = file.contents.replace("Hello", "Hijacked");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&serde_json::to_vec(&file).unwrap())
+            .unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+        assert!(matches!(
+            Backup::read(&path, None),
+            Err(ChatError::Backup(_))
+        ));
+
+        fs::write(&path, b"not a backup").unwrap();
+        assert!(matches!(
+            Backup::read(&path, None),
+            Err(ChatError::Backup(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_backup_encrypted() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-backup-encrypted-{}", nanos));
+        let path = dir.join(BACKUP_FILE_NAME);
+
+        let mut store = Store::new(MockProvider::default());
+        store.add_empty_session(String::from("Secret"), "gpt-3.5-turbo");
+        let key = EncryptionKey::new("hunter2").unwrap();
+        Backup::new(store.snapshot(), BTreeMap::new())
+            .write(&path, Some(&key))
+            .unwrap();
+
+        let contents = fs::read(&path).unwrap();
+        assert!(encryption::is_encrypted(&contents));
+        assert!(!String::from_utf8_lossy(&contents).contains("Secret"));
+
+        assert_eq!(
+            Some(ChatError::StoreLocked),
+            Backup::read(&path, None).err()
+        );
+        assert_eq!(
+            Some(ChatError::WrongPassphrase),
+            Backup::read(&path, Some("wrong")).err()
+        );
+        let backup = Backup::read(&path, Some("hunter2")).unwrap();
+        assert_eq!("Secret", backup.snapshot.sessions[0].get_title());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
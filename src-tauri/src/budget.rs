@@ -0,0 +1,330 @@
+//! Keeping spending on the chat models within a monthly budget.
+//!
+//! The user can cap what is spent each calendar month, in USD. What has been
+//! spent is kept in a `SpendingLedger`, added to as responses are committed.
+//! It is saved apart from the messages, so deleting them, or emptying the
+//! trash, does not hand the money back to the budget. The store's listener is
+//! warned once a month when spending reaches `WARNING_FRACTION` of the cap,
+//! and again when it reaches the cap. If the budget is enforced, paid requests, be they chats, images,
+//! titles, summaries or speech, are refused from then on until the user
+//! overrides it for the rest of the month. The budget is saved to its own
+//! file in the app config directory.
+
+use crate::persistence::{load_json_or_default, save_json, JsonConfig};
+use crate::usage::DateRange;
+use crate::ChatError;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the file the budget is saved to inside the app config directory
+pub const BUDGET_FILE_NAME: &str = "budget.json";
+
+/// Name of the file the spending ledger is saved to inside the app data
+/// directory
+pub const LEDGER_FILE_NAME: &str = "spending.json";
+
+/// Share of the monthly limit spent at which the user is first warned
+pub const WARNING_FRACTION: f64 = 0.8;
+
+/// How much may be spent each month, and what happens once it has been
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// Most that may be spent each month in USD, or None for no budget
+    pub monthly_limit: Option<f64>,
+
+    /// Whether chat requests are refused once the limit has been spent
+    pub enforce: bool,
+
+    /// The month, written `YYYY-MM`, in which the user chose to keep sending
+    /// requests past the limit
+    pub override_month: Option<String>,
+}
+
+/// How much of the monthly limit has been spent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetLevel {
+    #[default]
+    Under,
+    /// At least `WARNING_FRACTION` of the limit has been spent
+    Warning,
+    /// The whole limit has been spent
+    Exceeded,
+}
+
+/// What has been spent in a month, measured against the budget
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetStatus {
+    /// The month, in UTC, written `YYYY-MM`
+    pub month: String,
+    /// Cost in USD of the month's requests, leaving out models whose price
+    /// is unknown
+    pub spent: f64,
+    pub limit: Option<f64>,
+    pub level: BudgetLevel,
+    /// Whether chat requests are refused
+    pub blocked: bool,
+}
+
+impl JsonConfig for BudgetConfig {}
+
+impl BudgetConfig {
+    /// Returns `spent` in `month` measured against this budget
+    pub fn status(&self, month: &str, spent: f64) -> BudgetStatus {
+        let level = match self.monthly_limit {
+            Some(limit) if spent >= limit => BudgetLevel::Exceeded,
+            Some(limit) if spent >= limit * WARNING_FRACTION => BudgetLevel::Warning,
+            _ => BudgetLevel::Under,
+        };
+        let overridden = self.override_month.as_deref() == Some(month);
+
+        BudgetStatus {
+            month: month.to_string(),
+            spent,
+            limit: self.monthly_limit,
+            level,
+            blocked: self.enforce && level == BudgetLevel::Exceeded && !overridden,
+        }
+    }
+}
+
+/// What has been spent each month, only ever added to
+#[derive(Debug, Clone, Default)]
+pub struct SpendingLedger {
+    /// USD spent each month, keyed by the month written `YYYY-MM`
+    months: BTreeMap<String, f64>,
+    /// Where the ledger is saved, if anywhere
+    path: Option<PathBuf>,
+}
+
+impl SpendingLedger {
+    /// Create an empty ledger that is not saved to disk
+    pub fn new() -> SpendingLedger {
+        SpendingLedger::default()
+    }
+
+    /// Loads the ledger saved at `path`, or creates an empty one if nothing
+    /// has been saved there yet. The ledger is saved back to `path` whenever
+    /// it changes.
+    pub fn load(path: impl Into<PathBuf>) -> Result<SpendingLedger, ChatError> {
+        let path = path.into();
+
+        Ok(SpendingLedger {
+            months: load_json_or_default(&path)?,
+            path: Some(path),
+        })
+    }
+
+    /// Loads the ledger saved inside `app_data_dir`
+    pub fn in_app_data_dir(app_data_dir: &Path) -> Result<SpendingLedger, ChatError> {
+        SpendingLedger::load(app_data_dir.join(LEDGER_FILE_NAME))
+    }
+
+    /// Returns true if nothing has been spent
+    pub fn is_empty(&self) -> bool {
+        self.months.is_empty()
+    }
+
+    /// Returns what has been spent in `month`, written `YYYY-MM`
+    pub fn spent(&self, month: &str) -> f64 {
+        self.months.get(month).copied().unwrap_or_default()
+    }
+
+    /// Adds `cost` in USD to what has been spent in the month of `timestamp`,
+    /// saving the ledger
+    pub fn record(&mut self, timestamp: u64, cost: f64) -> Result<(), ChatError> {
+        if cost <= 0.0 {
+            return Ok(());
+        }
+
+        let (month, _) = month_of(timestamp);
+        *self.months.entry(month).or_default() += cost;
+
+        match &self.path {
+            Some(path) => save_json(path, &self.months),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the calendar month of `timestamp` in UTC, written `YYYY-MM`, along
+/// with the timestamps it spans
+pub fn month_of(timestamp: u64) -> (String, DateRange) {
+    let date = NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+        .unwrap_or_default()
+        .date();
+    let (year, month) = (date.year(), date.month());
+    let (next_year, next_month) = match month {
+        12 => (year + 1, 1),
+        _ => (year, month + 1),
+    };
+
+    let start_of = |year, month| {
+        NaiveDate::from_ymd_opt(year, month, 1)
+            .and_then(|x| x.and_hms_opt(0, 0, 0))
+            .map_or(0, |x| x.timestamp().max(0) as u64)
+    };
+    let range = DateRange {
+        start: Some(start_of(year, month)),
+        end: Some(start_of(next_year, next_month).saturating_sub(1)),
+    };
+
+    (format!("{:04}-{:02}", year, month), range)
+}
+
+/// A store's budget, along with the warnings already given about it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Budget {
+    pub(crate) config: BudgetConfig,
+    pub(crate) ledger: SpendingLedger,
+    /// The month and level of the last status checked
+    warned: Option<(String, BudgetLevel)>,
+}
+
+impl Budget {
+    /// Returns whether the listener should be warned about `status`, which it
+    /// is the first time each level is reached in a month
+    pub(crate) fn should_warn(&mut self, status: &BudgetStatus) -> bool {
+        let warned = match &self.warned {
+            Some((month, level)) if *month == status.month => *level,
+            _ => BudgetLevel::Under,
+        };
+        // Raising the limit lowers the level, so reaching it again warns again
+        self.warned = Some((status.month.clone(), status.level));
+
+        status.level > warned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::events::{StoreEvent, StoreListener};
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the budget warnings it is told about
+    #[derive(Debug, Clone, Default)]
+    struct Warnings(Arc<Mutex<Vec<BudgetLevel>>>);
+
+    impl StoreListener for Warnings {
+        fn on_event(&self, event: &StoreEvent) {
+            if let StoreEvent::Budget(status) = event {
+                self.0.lock().unwrap().push(status.level);
+            }
+        }
+    }
+
+    #[test]
+    fn test_month_of() {
+        let (month, range) = month_of(1_700_000_000);
+        assert_eq!("2023-11", month);
+        assert_eq!(Some(1_698_796_800), range.start);
+        assert_eq!(Some(1_701_388_799), range.end);
+
+        let (month, range) = month_of(1_703_980_800);
+        assert_eq!("2023-12", month);
+        assert_eq!(Some(1_704_067_199), range.end);
+    }
+
+    #[tokio::test]
+    async fn test_store_budget() {
+        let warnings = Warnings::default();
+        let mut store = Store::new(MockProvider::canned("Hello"));
+        store.set_listener(warnings.clone());
+        let id = store.add_empty_session(String::from("Spending"), "gpt-4");
+        let cancel = CancellationToken::new();
+
+        store
+            .send_message(id, String::from("Hi"), &cancel)
+            .await
+            .unwrap();
+        let spent = store.budget_status().spent;
+        assert!(spent > 0.0);
+        assert_eq!(None, store.budget_status().limit);
+
+        store.set_budget_config(BudgetConfig {
+            monthly_limit: Some(spent / 0.9),
+            enforce: true,
+            override_month: None,
+        });
+        store.notify_budget();
+        store.notify_budget();
+        assert_eq!(vec![BudgetLevel::Warning], *warnings.0.lock().unwrap());
+
+        // Still under the limit, so the message is sent, and spends the rest
+        store
+            .send_message(id, String::from("Hi"), &cancel)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![BudgetLevel::Warning, BudgetLevel::Exceeded],
+            *warnings.0.lock().unwrap()
+        );
+
+        let status = store.budget_status();
+        assert!(status.blocked);
+        assert!(matches!(
+            store.prepare_message(id, String::from("Hi")),
+            Err(ChatError::BudgetExceeded(_))
+        ));
+        assert!(store
+            .send_message(id, String::from("Hi"), &cancel)
+            .await
+            .is_err());
+        // As are the requests made for images and titles
+        assert!(matches!(
+            store.prepare_image(id, String::from("A cat"), Default::default()),
+            Err(ChatError::BudgetExceeded(_))
+        ));
+        assert!(matches!(
+            store.prepare_title(id),
+            Err(ChatError::BudgetExceeded(_))
+        ));
+
+        store.override_budget();
+        assert!(!store.budget_status().blocked);
+        store
+            .send_message(id, String::from("Hi"), &cancel)
+            .await
+            .unwrap();
+        assert_eq!(2, warnings.0.lock().unwrap().len());
+
+        // Deleting what was spent on does not give the budget back
+        let spent = store.budget_status().spent;
+        store.delete_session(id);
+        store.empty_trash();
+        assert_eq!(spent, store.budget_status().spent);
+    }
+
+    #[test]
+    fn test_ledger_saved() {
+        let path = std::env::temp_dir().join(format!(
+            "chat-overlay-ledger-{}.json",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let mut ledger = SpendingLedger::load(&path).unwrap();
+        assert!(ledger.is_empty());
+
+        ledger.record(1_700_000_000, 0.25).unwrap();
+        ledger.record(1_700_000_000, 0.5).unwrap();
+        ledger.record(1_703_980_800, 1.0).unwrap();
+        ledger.record(1_703_980_800, 0.0).unwrap();
+
+        let loaded = SpendingLedger::load(&path).unwrap();
+        assert_eq!(0.75, loaded.spent("2023-11"));
+        assert_eq!(1.0, loaded.spent("2023-12"));
+        assert_eq!(0.0, loaded.spent("2024-01"));
+
+        fs::remove_file(path).unwrap();
+    }
+}
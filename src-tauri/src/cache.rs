@@ -0,0 +1,324 @@
+//! Reusing the answers to questions asked before.
+//!
+//! With the cache turned on, each response is kept under a hash of the
+//! provider, model, sampling temperature and message history of its request,
+//! with the whitespace of the messages normalized. Asking the exact same
+//! question again, as templates often do, returns the kept answer instantly
+//! instead of paying for another request. Answers are kept for a configurable
+//! time, and regenerated responses always make a new request. Requests with
+//! images or the results of tools are never cached, as the same history may
+//! call for a different answer. The cache lives in memory, while its config is
+//! saved to its own file in the app config directory.
+
+use crate::images::{GeneratedImage, ImageOptions};
+use crate::moderation::ModerationResult;
+use crate::persistence::JsonConfig;
+use crate::providers::{Completion, CompletionRequest, LlmProvider, OnToken};
+use crate::speech::SpeechOptions;
+use crate::ChatError;
+use async_openai::types::Role;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Name of the file the cache config is saved to inside the app config
+/// directory
+pub const CACHE_FILE_NAME: &str = "cache.json";
+
+/// Whether responses are cached, and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+
+    /// Seconds an answer is reused for after it was received
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            ttl_secs: 60 * 60,
+        }
+    }
+}
+
+impl JsonConfig for CacheConfig {}
+
+impl CacheConfig {
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+/// A kept answer, along with when it was received
+#[derive(Debug)]
+struct CachedCompletion {
+    completion: Completion,
+    received: Instant,
+}
+
+type Entries = Arc<Mutex<HashMap<u64, CachedCompletion>>>;
+
+/// The answers kept by the store
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ResponseCache {
+    config: CacheConfig,
+    entries: Entries,
+}
+
+impl ResponseCache {
+    pub fn get_config(&self) -> CacheConfig {
+        self.config
+    }
+
+    /// Replaces whether responses are cached, and for how long, with
+    /// `config`. Turning the cache off drops every kept answer.
+    pub fn set_config(&mut self, config: CacheConfig) {
+        if !config.enabled {
+            self.clear();
+        }
+        self.config = config;
+    }
+
+    /// Drops every kept answer, returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut entries = lock(&self.entries);
+        let count = entries.len();
+        entries.clear();
+
+        count
+    }
+
+    /// Returns `provider`, registered under `name`, with its answers kept and
+    /// reused if the cache is on
+    pub fn wrap(&self, name: Option<&str>, provider: Arc<dyn LlmProvider>) -> Arc<dyn LlmProvider> {
+        if !self.config.enabled {
+            return provider;
+        }
+
+        Arc::new(Cached {
+            inner: provider,
+            name: name.map(String::from),
+            ttl: self.config.ttl(),
+            entries: self.entries.clone(),
+        })
+    }
+}
+
+fn lock(entries: &Entries) -> MutexGuard<'_, HashMap<u64, CachedCompletion>> {
+    // Entries are only ever inserted or removed whole, so a poisoned lock is
+    // still usable
+    entries.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Collapses every run of whitespace in `text` to a single space, so answers
+/// are reused regardless of how the question was spaced
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the key of `request` sent to the provider registered under
+/// `provider`, or None if its answer should not be cached
+fn cache_key(provider: Option<&str>, request: &CompletionRequest) -> Option<u64> {
+    if !request.images.is_empty() || request.messages.iter().any(|x| x.role == Role::Function) {
+        return None;
+    }
+
+    let messages: Vec<_> = request
+        .messages
+        .iter()
+        .map(|msg| {
+            let mut msg = msg.clone();
+            msg.content = msg.content.as_deref().map(normalize);
+            msg
+        })
+        .collect();
+
+    let mut hasher = DefaultHasher::new();
+    provider.hash(&mut hasher);
+    request.model.hash(&mut hasher);
+    request.temperature.map(f32::to_bits).hash(&mut hasher);
+    serde_json::to_string(&messages).ok()?.hash(&mut hasher);
+    serde_json::to_string(&request.functions)
+        .ok()?
+        .hash(&mut hasher);
+
+    Some(hasher.finish())
+}
+
+/// A provider whose answers are kept and reused
+#[derive(Debug)]
+struct Cached {
+    inner: Arc<dyn LlmProvider>,
+    name: Option<String>,
+    ttl: Duration,
+    entries: Entries,
+}
+
+impl Cached {
+    /// Returns the answer kept under `key`, if it has not expired. Reused
+    /// answers cost nothing, so they have no usage.
+    fn get(&self, key: Option<u64>) -> Option<Completion> {
+        let key = key?;
+        let mut entries = lock(&self.entries);
+        match entries.get(&key) {
+            Some(cached) if cached.received.elapsed() < self.ttl => Some(Completion {
+                usage: None,
+                ..cached.completion.clone()
+            }),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Keeps `completion` under `key`, dropping any expired answers. Calls to
+    /// functions are not kept, as they lead to tools being run.
+    fn insert(&self, key: Option<u64>, completion: &Completion) {
+        let Some(key) = key else {
+            return;
+        };
+        if completion.message.function_call.is_some() {
+            return;
+        }
+
+        let mut entries = lock(&self.entries);
+        entries.retain(|_, x| x.received.elapsed() < self.ttl);
+        entries.insert(
+            key,
+            CachedCompletion {
+                completion: completion.clone(),
+                received: Instant::now(),
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl LlmProvider for Cached {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        let key = cache_key(self.name.as_deref(), &request);
+        if let Some(completion) = self.get(key) {
+            return Ok(completion);
+        }
+
+        let completion = self.inner.complete(request).await?;
+        self.insert(key, &completion);
+
+        Ok(completion)
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        let key = cache_key(self.name.as_deref(), &request);
+        if let Some(completion) = self.get(key) {
+            on_token(completion.message.content.as_deref().unwrap_or_default());
+            return Ok(completion);
+        }
+
+        let completion = self.inner.stream(request, on_token).await?;
+        self.insert(key, &completion);
+
+        Ok(completion)
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        self.inner.list_models().await
+    }
+
+    async fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> Result<GeneratedImage, ChatError> {
+        self.inner.generate_image(prompt, options).await
+    }
+
+    async fn synthesize_speech(
+        &self,
+        text: &str,
+        options: &SpeechOptions,
+    ) -> Result<Vec<u8>, ChatError> {
+        self.inner.synthesize_speech(text, options).await
+    }
+
+    async fn moderate(&self, text: &str) -> Result<ModerationResult, ChatError> {
+        self.inner.moderate(text).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+
+    /// Asks `question` in a new session, returning its id and the answer
+    async fn ask(store: &mut Store, question: &str) -> (crate::ids::SessionId, String) {
+        let id = store.add_empty_session(String::from("Cached"), "counter");
+        let request = store.prepare_message(id, question.to_string()).unwrap();
+        let completed = request.complete(&CancellationToken::new()).await.unwrap();
+        let (_, reply) = store.commit(completed).unwrap();
+
+        (id, reply.get_content())
+    }
+
+    #[tokio::test]
+    async fn test_store_caches_responses() {
+        // Cached answers take no response from the script, so the numbers
+        // count the requests sent
+        let provider = MockProvider::default();
+        for count in 1..=7 {
+            provider.push_text(&format!("Answer {}", count));
+        }
+        let mut store = Store::new(provider.clone());
+
+        // Nothing is kept while the cache is off
+        assert_eq!("Answer 1", ask(&mut store, "Hello").await.1);
+        assert_eq!("Answer 2", ask(&mut store, "Hello").await.1);
+
+        store.set_cache_config(CacheConfig {
+            enabled: true,
+            ttl_secs: 60,
+        });
+        assert_eq!("Answer 3", ask(&mut store, "What is Rust?").await.1);
+        let (id, answer) = ask(&mut store, "  What is\n Rust? ").await;
+        assert_eq!("Answer 3", answer);
+        assert_eq!(3, provider.get_requests().len());
+        let messages = store.get_session(id).unwrap().get_messages();
+        assert_eq!(None, messages[1].get_usage());
+        assert_eq!(
+            Some("stop"),
+            messages[1].get_metadata().unwrap().finish_reason.as_deref()
+        );
+
+        // Regenerating always asks again
+        let message_id = messages[1].get_id();
+        let request = store.prepare_regenerate(id, message_id).unwrap();
+        let completed = request.complete(&CancellationToken::new()).await.unwrap();
+        let (_, reply) = store.commit(completed).unwrap();
+        assert_eq!("Answer 4", reply.get_content());
+
+        assert_eq!(1, store.clear_response_cache());
+        assert_eq!("Answer 5", ask(&mut store, "What is Rust?").await.1);
+
+        store.set_cache_config(CacheConfig {
+            enabled: true,
+            ttl_secs: 0,
+        });
+        assert_eq!("Answer 6", ask(&mut store, "What is Rust?").await.1);
+        assert_eq!("Answer 7", ask(&mut store, "What is Rust?").await.1);
+    }
+}
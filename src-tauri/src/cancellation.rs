@@ -0,0 +1,204 @@
+//! Cancelling chat requests that are still in flight.
+//!
+//! Requests run outside the store's lock, so the tokens used to cancel them
+//! are kept in a registry of their own. A session can have several requests
+//! running at once, such as a reply and a title, so each request is
+//! registered under an id of its own.
+
+use crate::ids::SessionId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub use tokio_util::sync::CancellationToken;
+
+/// Identifies a request registered with a `CancellationRegistry`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(u64);
+
+type Tokens = HashMap<SessionId, Vec<(RequestId, CancellationToken)>>;
+
+/// Tracks the cancellation tokens of the requests running in each session
+#[derive(Debug, Default)]
+pub struct CancellationRegistry {
+    tokens: Mutex<Tokens>,
+    next_id: AtomicU64,
+}
+
+impl CancellationRegistry {
+    /// Create a registry with no running requests
+    pub fn new() -> CancellationRegistry {
+        CancellationRegistry::default()
+    }
+
+    /// Returns a new token for a request starting in the session with matching
+    /// id, along with the id the request is registered under. Requests
+    /// already running in the session keep their tokens.
+    pub fn register(&self, session_id: SessionId) -> (RequestId, CancellationToken) {
+        let id = RequestId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let token = CancellationToken::new();
+        let mut tokens = self.lock();
+        tokens
+            .entry(session_id)
+            .or_default()
+            .push((id, token.clone()));
+
+        (id, token)
+    }
+
+    /// Cancels every request running in the session with matching id. Returns
+    /// false if no request is running in the session.
+    pub fn cancel(&self, session_id: SessionId) -> bool {
+        match self.lock().remove(&session_id) {
+            Some(tokens) => {
+                tokens.iter().for_each(|(_, token)| token.cancel());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets the token of the request with matching id, running in the
+    /// session with matching id, once it has finished. Other requests in the
+    /// session can still be cancelled.
+    pub fn finish(&self, session_id: SessionId, request_id: RequestId) {
+        let mut tokens = self.lock();
+        if let Some(running) = tokens.get_mut(&session_id) {
+            running.retain(|(id, _)| *id != request_id);
+            if running.is_empty() {
+                tokens.remove(&session_id);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tokens> {
+        // The map is never left half updated, so a poisoned lock is still usable
+        self.tokens.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::{MockProvider, MockResponse};
+    use crate::ChatRole;
+    use crate::{ChatError, ChatSession, Store};
+
+    /// Streams a single token, then never finishes, for each of the first
+    /// `requests` requests
+    fn stalling(requests: usize) -> MockProvider {
+        let provider = MockProvider::default();
+        for _ in 0..requests {
+            provider.push(MockResponse::Stall(String::from("Partial")));
+        }
+        provider
+    }
+
+    #[test]
+    fn test_registry_cancel() {
+        let registry = CancellationRegistry::new();
+        let id = SessionId::generate();
+        let (_, token) = registry.register(id);
+
+        assert!(!registry.cancel(SessionId::generate()));
+        assert!(!token.is_cancelled());
+
+        assert!(registry.cancel(id));
+        assert!(token.is_cancelled());
+        assert!(!registry.cancel(id));
+
+        let (request, token) = registry.register(id);
+        registry.finish(id, request);
+        assert!(!registry.cancel(id));
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn test_registry_overlapping_requests() {
+        let registry = CancellationRegistry::new();
+        let id = SessionId::generate();
+        let (first, first_token) = registry.register(id);
+        let (second, second_token) = registry.register(id);
+        assert_ne!(first, second);
+
+        // The first request finishing leaves the second one cancellable
+        registry.finish(id, first);
+        assert!(registry.cancel(id));
+        assert!(second_token.is_cancelled());
+        assert!(!first_token.is_cancelled());
+
+        // Cancelling stops every request running in the session
+        let (_, first_token) = registry.register(id);
+        let (_, second_token) = registry.register(id);
+        assert!(registry.cancel(id));
+        assert!(first_token.is_cancelled() && second_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_requests_keep_partial_content() {
+        let mut store = Store::new(stalling(2));
+        let id = store.add_empty_session(String::from("Stalling"), "gpt-3.5-turbo");
+
+        let token = CancellationToken::new();
+        token.cancel();
+        assert_eq!(
+            Err(ChatError::Cancelled),
+            store
+                .send_message(id, String::from("Hello"), &token)
+                .await
+                .map(|_| ())
+        );
+        assert_eq!(0, store.get_session(id).unwrap().message_count());
+
+        let token = CancellationToken::new();
+        let response = store
+            .send_message_streaming(id, String::from("Hello"), |_| token.cancel(), &token)
+            .await
+            .unwrap();
+
+        assert_eq!(ChatRole::Assistant, response.get_role());
+        assert_eq!("Partial", response.get_content());
+        assert!(response.is_truncated());
+        assert_eq!(2, store.get_session(id).unwrap().message_count());
+    }
+
+    #[tokio::test]
+    async fn test_continue_truncated_response() {
+        let mut session = ChatSession::new(String::from("Stalling"), "gpt-3.5-turbo");
+        let token = CancellationToken::new();
+        session
+            .add_message_streaming(
+                String::from("Hello"),
+                &stalling(1),
+                |_| token.cancel(),
+                &token,
+            )
+            .await
+            .unwrap();
+        let id = session.get_messages()[1].get_id();
+
+        let provider = MockProvider::canned(" response");
+        session
+            .continue_message(id, &provider, &CancellationToken::new())
+            .await
+            .unwrap();
+
+        let messages = session.get_messages();
+        assert_eq!(2, messages.len());
+        assert_eq!("Partial response", messages[1].get_content());
+        assert!(!messages[1].is_truncated());
+
+        // The partial response is sent for the chat model to pick up from
+        let sent = &provider.get_requests()[0].messages;
+        assert_eq!(Some(String::from("Partial")), sent[1].content);
+        assert_eq!(3, sent.len());
+
+        let user = session.get_messages()[0].get_id();
+        assert_eq!(
+            Err(ChatError::NotAResponse(user)),
+            session
+                .continue_message(user, &provider, &CancellationToken::new())
+                .await
+        );
+    }
+}
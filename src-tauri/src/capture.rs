@@ -0,0 +1,128 @@
+//! Capturing text from the clipboard, or the current selection where the
+//! platform has one, to ask the chat model about.
+//!
+//! Captured text is wrapped in a prompt template such as "Explain this:"
+//! before it is sent. Templates saved in the store take precedence over the
+//! built-in ones with the same name.
+
+use crate::prompts::PromptTemplate;
+use crate::{ChatError, Store};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Placeholder capture templates put the captured text in
+pub const CAPTURED_TEXT_VAR: &str = "text";
+
+/// Where text is captured from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureSource {
+    /// The system clipboard
+    Clipboard,
+
+    /// The currently selected text. Only Linux exposes the selection, other
+    /// platforms read the clipboard instead.
+    Selection,
+}
+
+/// Returns the templates available for captured text without saving any
+pub fn builtin_templates() -> Vec<PromptTemplate> {
+    vec![
+        PromptTemplate::new(
+            "explain",
+            "Explain the captured text",
+            "Explain this:\n\n{{text}}",
+        ),
+        PromptTemplate::new(
+            "translate",
+            "Translate the captured text into English",
+            "Translate this into English:\n\n{{text}}",
+        ),
+        PromptTemplate::new(
+            "summarize",
+            "Summarize the captured text",
+            "Summarize this:\n\n{{text}}",
+        ),
+    ]
+}
+
+/// Reads the text currently held by `source`. Returns
+/// `ChatError::NothingCaptured` if it holds no text.
+pub fn read_text(source: CaptureSource) -> Result<String, ChatError> {
+    let mut clipboard =
+        arboard::Clipboard::new().map_err(|e| ChatError::Clipboard(e.to_string()))?;
+
+    let text = match source {
+        #[cfg(target_os = "linux")]
+        CaptureSource::Selection => {
+            use arboard::{GetExtLinux, LinuxClipboardKind};
+
+            clipboard
+                .get()
+                .clipboard(LinuxClipboardKind::Primary)
+                .text()
+        }
+        _ => clipboard.get_text(),
+    };
+
+    match text {
+        Ok(text) if !text.trim().is_empty() => Ok(text),
+        Ok(_) | Err(arboard::Error::ContentNotAvailable) => Err(ChatError::NothingCaptured),
+        Err(e) => Err(ChatError::Clipboard(e.to_string())),
+    }
+}
+
+/// Wraps `text` in the template named `template`, looking in `store` before
+/// the built-in templates. The text is used as is if `template` is None.
+pub fn wrap_text(store: &Store, template: Option<&str>, text: String) -> Result<String, ChatError> {
+    let Some(name) = template else {
+        return Ok(text);
+    };
+
+    let vars = HashMap::from([(CAPTURED_TEXT_VAR.to_string(), text)]);
+
+    match store.get_prompt(name) {
+        Some(prompt) => prompt.render(&vars),
+        None => builtin_templates()
+            .into_iter()
+            .find(|x| x.name == name)
+            .ok_or_else(|| ChatError::PromptNotFound(name.to_string()))?
+            .render(&vars),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+
+    #[test]
+    fn test_wrap_captured_text() {
+        let mut store = Store::new(MockProvider::default());
+        let text = String::from("fn main() {}");
+
+        assert_eq!(
+            "fn main() {}",
+            wrap_text(&store, None, text.clone()).unwrap()
+        );
+        assert_eq!(
+            "Explain this:\n\nfn main() {}",
+            wrap_text(&store, Some("explain"), text.clone()).unwrap()
+        );
+
+        store.save_prompt(PromptTemplate::new(
+            "explain",
+            "Explain like I'm five",
+            "Explain this like I'm five: {{text}}",
+        ));
+        assert_eq!(
+            "Explain this like I'm five: fn main() {}",
+            wrap_text(&store, Some("explain"), text.clone()).unwrap()
+        );
+
+        assert_eq!(
+            Err(ChatError::PromptNotFound(String::from("nope"))),
+            wrap_text(&store, Some("nope"), text)
+        );
+    }
+}
@@ -0,0 +1,2327 @@
+//! Tauri commands exposing the `Store` to the frontend.
+//!
+//! The store is managed by Tauri as a `StoreState`. Errors are returned to the
+//! frontend as their display message.
+//!
+//! Commands that wait on the chat model prepare their request under a read
+//! lock and only take the write lock to commit the response, so other
+//! commands are not blocked while a response is on its way.
+
+use crate::app_config::{AppConfig, AppConfigState};
+use crate::auto_backup::{AutoBackupConfig, AutoBackupState, BackupInfo};
+use crate::backup::{Backup, RestoreReport, BACKUP_FILE_NAME};
+use crate::budget::{BudgetConfig, BudgetStatus, BUDGET_FILE_NAME};
+use crate::cache::{CacheConfig, CACHE_FILE_NAME};
+use crate::cancellation::{CancellationRegistry, CancellationToken};
+use crate::capture::{self, CaptureSource};
+use crate::embeddings::SemanticMatch;
+use crate::encryption::{EncryptionKey, EncryptionStatus};
+use crate::events::{
+    ImportProgressPayload, SpeechPayload, TokenPayload, IMPORT_PROGRESS_EVENT, SPEECH_EVENT,
+    STREAM_END_EVENT, TOKEN_EVENT,
+};
+use crate::hotkey::{HotkeyConfig, HotkeyState};
+use crate::ids::{MemoryId, MessageId, SessionId};
+use crate::images::{ImageOptions, ImageRequest, SessionMode, GENERATED_IMAGES_DIR_NAME};
+use crate::import::CHATGPT_CONVERSATIONS_FILE;
+use crate::memory::{Memory, MemoryConfig, MEMORY_FILE_NAME};
+use crate::models::ModelInfo;
+use crate::moderation::{ModerationConfig, MODERATION_FILE_NAME};
+use crate::notifications::{self, NotificationConfig, NotificationState};
+use crate::offline;
+use crate::pending::{CompletedRequest, QueuedMessage};
+use crate::persistence::{JsonConfig, JsonFileBackend, StorageKind};
+use crate::profiles::{self, Profile, ProfilesConfig, PROFILES_FILE_NAME};
+use crate::prompts::PromptTemplate;
+use crate::providers::anthropic::{AnthropicProvider, ANTHROPIC_PROVIDER};
+use crate::providers::azure::{self, AzureSettings, AZURE_FILE_NAME};
+use crate::providers::compatible::{self, Endpoint, EndpointsConfig, ENDPOINTS_FILE_NAME};
+use crate::providers::LlmProvider;
+use crate::rate_limit::{RateLimitConfig, RATE_LIMIT_FILE_NAME};
+use crate::search::SearchResult;
+use crate::secrets;
+use crate::speech::{SpeechConfig, SpeechState, SPEECH_CACHE_DIR_NAME};
+use crate::stats::{AggregateStats, SessionStats};
+use crate::timeouts::Timeouts;
+use crate::tools::file_reader::{self, FileReaderConfig, FILE_READER_FILE_NAME};
+use crate::tools::web_search::{self, WebSearchConfig, WEB_SEARCH_FILE_NAME};
+use crate::trash::{TrashConfig, TrashedItem, TRASH_FILE_NAME};
+use crate::tray::{self, OverlayStatus};
+use crate::usage::{self, DateRange, UsageReport};
+use crate::vision::{self, ScreenRegion, SCREENSHOTS_DIR_NAME};
+use crate::window::{Corner, WindowSettings, WindowState};
+use crate::{
+    Bookmark, ChatError, ChatRole, ChatSession, ExportFormat, Message, MessagePage, MessageStatus,
+    SessionPage, SessionSummary, SortBy, Store,
+};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tauri::api::dialog::blocking::FileDialogBuilder;
+use tauri::{AppHandle, Manager, State, Window};
+use tokio::sync::RwLock;
+
+/// The Tauri managed state holding the store
+pub type StoreState = RwLock<Store>;
+
+/// Model used for new sessions when the frontend does not pick one
+pub(crate) const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+
+/// Commits the response of a finished request to the store, returning the
+/// message holding the response. The response is read aloud if that is
+/// turned on, the session's older messages are summarized if it is getting
+/// long, and facts worth remembering are picked out of it if memory is on.
+pub(crate) async fn commit(
+    app: &AppHandle,
+    state: &StoreState,
+    completed: Result<CompletedRequest, ChatError>,
+) -> Result<Message, String> {
+    let completed = completed.map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    let (session_id, message) = store.commit(completed).map_err(|e| e.to_string())?;
+    let title = store
+        .get_session(session_id)
+        .map(|x| x.get_title().to_string())
+        .unwrap_or_default();
+    drop(store);
+
+    notifications::notify_response(app, session_id, &title, &message);
+    read_aloud(app, session_id, &message);
+    summarize(app, session_id);
+    remember(app, session_id);
+
+    Ok(message)
+}
+
+/// Like `commit`, but queues `queued` to be sent later if the request failed
+/// because the chat model could not be reached, returning the queued User
+/// message instead of a response
+async fn commit_or_queue(
+    app: &AppHandle,
+    state: &StoreState,
+    completed: Result<CompletedRequest, ChatError>,
+    queued: Option<QueuedMessage>,
+) -> Result<Message, String> {
+    match (completed, queued) {
+        (Err(e), Some(queued)) if e.is_offline() => state
+            .write()
+            .await
+            .queue_offline(queued)
+            .map(|(_, message)| message)
+            .map_err(|e| e.to_string()),
+        (completed, _) => commit(app, state, completed).await,
+    }
+}
+
+/// Summarizes the older messages of the session with matching id in the
+/// background, if they take up most of its chat model's context window
+fn summarize(app: &AppHandle, session_id: SessionId) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<StoreState>();
+
+        let request = match state.read().await.prepare_summary(session_id) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => return eprintln!("Could not summarize the session: {}", e),
+        };
+        let committed = match request.complete(&CancellationToken::new()).await {
+            Ok(completed) => state.write().await.commit_summary(completed),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = committed {
+            eprintln!("Could not summarize the session: {}", e);
+        }
+    });
+}
+
+/// Picks facts worth remembering out of the latest exchange of the session
+/// with matching id in the background, if memory is on
+fn remember(app: &AppHandle, session_id: SessionId) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<StoreState>();
+
+        let request = match state.read().await.prepare_memory_extraction(session_id) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => return eprintln!("Could not remember facts: {}", e),
+        };
+        match request.complete(&CancellationToken::new()).await {
+            Ok(extracted) => {
+                state.write().await.commit_memories(extracted);
+            }
+            Err(e) => eprintln!("Could not remember facts: {}", e),
+        }
+    });
+}
+
+/// Creates a new session titled `title`, sending `content` as its first
+/// message. The session uses `model`, or the default model of the app config
+/// if None, and the provider registered under `provider`, or the default
+/// provider if None. Returns the summary of the created session.
+///
+/// If the chat model can not be reached, the session is created with the
+/// message queued, to be sent once it can.
+#[tauri::command]
+pub async fn create_session(
+    state: State<'_, StoreState>,
+    app_config: State<'_, AppConfigState>,
+    title: String,
+    content: String,
+    model: Option<String>,
+    provider: Option<String>,
+) -> Result<SessionSummary, String> {
+    let msg = ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(content),
+        name: None,
+        function_call: None,
+    };
+
+    let pending = state
+        .read()
+        .await
+        .prepare_session(
+            vec![msg],
+            title,
+            &model.unwrap_or_else(|| app_config.get_config().default_model),
+            provider.as_deref(),
+        )
+        .map_err(|e| e.to_string())?;
+    let queued = pending.to_queued();
+    let completed = pending.complete(&CancellationToken::new()).await;
+
+    let mut store = state.write().await;
+    let committed = match (completed, queued) {
+        (Err(e), Some(queued)) if e.is_offline() => store.queue_offline(queued),
+        (completed, _) => completed.and_then(|x| store.commit(x)),
+    };
+    let (id, _) = committed.map_err(|e| e.to_string())?;
+
+    store.get_session_summary(id).map_err(|e| e.to_string())
+}
+
+/// Sends `content` in the session with matching id, returning the response.
+/// Sessions in image mode generate an image from `content` instead. The
+/// request can be stopped with `cancel_request`.
+///
+/// If the chat model can not be reached, the User message is queued and
+/// returned with a Pending status. Its response is added once it can be.
+#[tauri::command]
+pub async fn send_message(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    content: String,
+) -> Result<Message, String> {
+    let store = state.read().await;
+    let mode = store
+        .get_session(session_id)
+        .map(ChatSession::get_mode)
+        .ok_or_else(|| ChatError::SessionNotFound(session_id).to_string())?;
+
+    if mode == SessionMode::Image {
+        let request = store
+            .prepare_image(session_id, content, ImageOptions::default())
+            .map_err(|e| e.to_string())?;
+        drop(store);
+
+        return generate(&app, &state, &cancellations, request).await;
+    }
+
+    let pending = store
+        .prepare_message(session_id, content)
+        .map_err(|e| e.to_string())?;
+    drop(store);
+
+    let queued = pending.to_queued();
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = pending.complete(&cancel).await;
+    cancellations.finish(session_id, request_id);
+
+    commit_or_queue(&app, &state, completed, queued).await
+}
+
+/// Sends the text held by the clipboard, or the current selection, in the
+/// session with matching id, wrapped in the prompt template named `template`
+/// if any. Returns the response. The request can be stopped with
+/// `cancel_request`.
+#[tauri::command]
+pub async fn send_captured_text(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    source: CaptureSource,
+    template: Option<String>,
+) -> Result<Message, String> {
+    let text = capture::read_text(source).map_err(|e| e.to_string())?;
+
+    let pending = {
+        let store = state.read().await;
+        capture::wrap_text(&store, template.as_deref(), text)
+            .and_then(|content| store.prepare_message(session_id, content))
+            .map_err(|e| e.to_string())?
+    };
+
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = pending.complete(&cancel).await;
+    cancellations.finish(session_id, request_id);
+
+    commit(&app, &state, completed).await
+}
+
+/// Captures `region` of the screen and asks `question` about it in the
+/// session with matching id, returning the response. The screenshot is kept
+/// in the app data directory. The request can be stopped with
+/// `cancel_request`.
+#[tauri::command]
+pub async fn ask_about_screen(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    region: ScreenRegion,
+    question: String,
+) -> Result<Message, String> {
+    let dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or("Could not resolve the app data directory")?
+        .join(SCREENSHOTS_DIR_NAME);
+    let image = vision::capture_region(region, &dir).map_err(|e| e.to_string())?;
+
+    let pending = state
+        .read()
+        .await
+        .prepare_image_message(session_id, question, image)
+        .map_err(|e| e.to_string())?;
+
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = pending.complete(&cancel).await;
+    cancellations.finish(session_id, request_id);
+
+    commit(&app, &state, completed).await
+}
+
+/// Returns the built-in prompt templates for captured text. Templates saved
+/// with the same name replace them.
+#[tauri::command]
+pub fn list_capture_templates() -> Vec<PromptTemplate> {
+    capture::builtin_templates()
+}
+
+/// Like `send_message`, but emits each piece of the response to `window` as
+/// it arrives, followed by the complete response once the stream ends. If the
+/// request is stopped with `cancel_request`, the content received so far is
+/// kept as the response. Sessions in image mode generate an image, emitting
+/// only the end of the stream once it is added.
+#[tauri::command]
+pub async fn send_message_streaming(
+    window: Window,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    content: String,
+) -> Result<Message, String> {
+    let store = state.read().await;
+    let mode = store
+        .get_session(session_id)
+        .map(ChatSession::get_mode)
+        .ok_or_else(|| ChatError::SessionNotFound(session_id).to_string())?;
+
+    if mode == SessionMode::Image {
+        let request = store
+            .prepare_image(session_id, content, ImageOptions::default())
+            .map_err(|e| e.to_string())?;
+        drop(store);
+
+        let image = generate(&window.app_handle(), &state, &cancellations, request).await?;
+        let payload = TokenPayload {
+            session_id,
+            token: image.get_content(),
+        };
+        window
+            .emit(STREAM_END_EVENT, payload)
+            .map_err(|e| e.to_string())?;
+
+        return Ok(image);
+    }
+
+    let pending = store
+        .prepare_message(session_id, content)
+        .map_err(|e| e.to_string())?;
+    drop(store);
+
+    let queued = pending.to_queued();
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = pending
+        .stream(
+            |token| {
+                let payload = TokenPayload {
+                    session_id,
+                    token: token.to_string(),
+                };
+
+                if let Err(e) = window.emit(TOKEN_EVENT, payload) {
+                    eprintln!("Could not emit token: {}", e);
+                }
+            },
+            &cancel,
+        )
+        .await;
+    cancellations.finish(session_id, request_id);
+    let response = commit_or_queue(&window.app_handle(), &state, completed, queued).await?;
+    if response.get_status() == MessageStatus::Pending {
+        return Ok(response);
+    }
+
+    let payload = TokenPayload {
+        session_id,
+        token: response.get_content(),
+    };
+    window
+        .emit(STREAM_END_EVENT, payload)
+        .map_err(|e| e.to_string())?;
+
+    Ok(response)
+}
+
+/// Requests a new response in place of the chat model's message with matching
+/// id, returning the message with the new response active. The request can be
+/// stopped with `cancel_request`.
+#[tauri::command]
+pub async fn regenerate_message(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    message_id: MessageId,
+) -> Result<Message, String> {
+    let pending = state
+        .read()
+        .await
+        .prepare_regenerate(session_id, message_id)
+        .map_err(|e| e.to_string())?;
+
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = pending.complete(&cancel).await;
+    cancellations.finish(session_id, request_id);
+
+    commit(&app, &state, completed).await
+}
+
+/// Asks the chat model to finish the response with matching id where it
+/// stopped, such as one cut short by `cancel_request`, returning the message
+/// with the rest appended. The request can be stopped with `cancel_request`.
+#[tauri::command]
+pub async fn continue_message(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    message_id: MessageId,
+) -> Result<Message, String> {
+    let pending = state
+        .read()
+        .await
+        .prepare_continue(session_id, message_id)
+        .map_err(|e| e.to_string())?;
+
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = pending.complete(&cancel).await;
+    cancellations.finish(session_id, request_id);
+
+    commit(&app, &state, completed).await
+}
+
+/// Replaces the content of the User message with matching id and re-runs the
+/// conversation from there, returning the new response. Messages after the
+/// edited one are removed. The request can be stopped with `cancel_request`.
+#[tauri::command]
+pub async fn edit_message(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    message_id: MessageId,
+    content: String,
+) -> Result<Message, String> {
+    let pending = state
+        .read()
+        .await
+        .prepare_edit(session_id, message_id, content)
+        .map_err(|e| e.to_string())?;
+
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = pending.complete(&cancel).await;
+    cancellations.finish(session_id, request_id);
+
+    commit(&app, &state, completed).await
+}
+
+/// Makes the response at `index` the content of the message with matching id
+#[tauri::command]
+pub async fn select_message_variant(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    message_id: MessageId,
+    index: usize,
+) -> Result<Message, String> {
+    let mut store = state.write().await;
+
+    store
+        .select_message_variant(session_id, message_id, index)
+        .map_err(|e| e.to_string())
+}
+
+/// Creates a new session with the messages of the session with matching id, up
+/// to and including the message with id `message_id`. Returns the summary of
+/// the new session.
+#[tauri::command]
+pub async fn fork_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    message_id: MessageId,
+) -> Result<SessionSummary, String> {
+    let mut store = state.write().await;
+
+    let id = store
+        .fork_session(session_id, message_id)
+        .map_err(|e| e.to_string())?;
+
+    store.get_session_summary(id).map_err(|e| e.to_string())
+}
+
+/// Creates a copy of the session with matching id, with messages of its own,
+/// to take the conversation in another direction. Returns the summary of the
+/// copy.
+#[tauri::command]
+pub async fn duplicate_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<SessionSummary, String> {
+    let mut store = state.write().await;
+
+    let id = store
+        .duplicate_session(session_id)
+        .map_err(|e| e.to_string())?;
+
+    store.get_session_summary(id).map_err(|e| e.to_string())
+}
+
+/// Appends the messages of the session with id `source_id` to the session
+/// with id `target_id`, then moves the source to the trash. Returns the
+/// summary of the target.
+#[tauri::command]
+pub async fn merge_sessions(
+    state: State<'_, StoreState>,
+    target_id: SessionId,
+    source_id: SessionId,
+) -> Result<SessionSummary, String> {
+    let mut store = state.write().await;
+
+    store
+        .merge_sessions(target_id, source_id)
+        .map_err(|e| e.to_string())?;
+
+    store
+        .get_session_summary(target_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the summaries of every session in the store, or only of those
+/// tagged `tag` if given. If `archived` is given, only the archived sessions,
+/// or only the others with pinned sessions first, are returned.
+#[tauri::command]
+pub async fn list_sessions(
+    state: State<'_, StoreState>,
+    tag: Option<String>,
+    archived: Option<bool>,
+) -> Result<Vec<SessionSummary>, String> {
+    let store = state.read().await;
+
+    let sessions = match archived {
+        Some(true) => store.get_archived(),
+        Some(false) => store.get_active(),
+        None => store.get_all_sessions(),
+    };
+
+    Ok(sessions
+        .into_iter()
+        .filter(|session| match &tag {
+            Some(tag) => session.has_tag(tag),
+            None => true,
+        })
+        .map(|session| session.summary())
+        .collect())
+}
+
+/// Bookmarks the message with id `message_id` in the session with matching
+/// id, or removes its bookmark if it has one. Returns whether the message is
+/// now bookmarked.
+#[tauri::command]
+pub async fn toggle_bookmark(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    message_id: MessageId,
+) -> Result<bool, String> {
+    let mut store = state.write().await;
+
+    store
+        .toggle_bookmark(session_id, message_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the bookmarked messages across every session, newest first
+#[tauri::command]
+pub async fn list_bookmarks(state: State<'_, StoreState>) -> Result<Vec<Bookmark>, String> {
+    Ok(state.read().await.get_bookmarked_messages())
+}
+
+/// Keeps `draft` as the unsent prompt of the session with matching id, so it
+/// can be restored after the overlay is hidden. Empty drafts clear it.
+#[tauri::command]
+pub async fn set_draft(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    draft: String,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .set_session_draft(session_id, draft)
+        .map_err(|e| e.to_string())
+}
+
+/// Removes and returns the unsent prompt of the session with matching id, if
+/// one was kept
+#[tauri::command]
+pub async fn take_draft(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<Option<String>, String> {
+    let mut store = state.write().await;
+
+    store
+        .take_session_draft(session_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Pins or unpins the session with matching id
+#[tauri::command]
+pub async fn set_session_pinned(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    pinned: bool,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .set_session_pinned(session_id, pinned)
+        .map_err(|e| e.to_string())
+}
+
+/// Keeps the ephemeral session with matching id as a saved session
+#[tauri::command]
+pub async fn promote_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store.promote_session(session_id).map_err(|e| e.to_string())
+}
+
+/// Archives or unarchives the session with matching id
+#[tauri::command]
+pub async fn set_session_archived(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    archived: bool,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .set_session_archived(session_id, archived)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the summaries of up to `limit` sessions sorted by `sort`, or by
+/// last activity if None, skipping the first `offset`
+#[tauri::command]
+pub async fn list_sessions_page(
+    state: State<'_, StoreState>,
+    offset: usize,
+    limit: usize,
+    sort: Option<SortBy>,
+) -> Result<SessionPage, String> {
+    let store = state.read().await;
+
+    Ok(SessionPage {
+        sessions: store
+            .get_sessions_page(offset, limit, sort.unwrap_or_default())
+            .into_iter()
+            .map(|session| session.summary())
+            .collect(),
+        total: store.get_all_sessions().len(),
+    })
+}
+
+/// Returns every tag used by a session, sorted
+#[tauri::command]
+pub async fn list_tags(state: State<'_, StoreState>) -> Result<Vec<String>, String> {
+    let store = state.read().await;
+
+    Ok(store.get_tags().into_iter().map(str::to_string).collect())
+}
+
+/// Tags the session with matching id with `tag`. Returns false if the
+/// session already had it.
+#[tauri::command]
+pub async fn add_session_tag(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    tag: String,
+) -> Result<bool, String> {
+    let mut store = state.write().await;
+
+    store
+        .add_session_tag(session_id, &tag)
+        .map_err(|e| e.to_string())
+}
+
+/// Removes `tag` from the session with matching id. Returns false if the
+/// session did not have it.
+#[tauri::command]
+pub async fn remove_session_tag(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    tag: String,
+) -> Result<bool, String> {
+    let mut store = state.write().await;
+
+    store
+        .remove_session_tag(session_id, &tag)
+        .map_err(|e| e.to_string())
+}
+
+/// Searches session titles and message contents for `query`, returning the
+/// matches ranked from best to worst
+#[tauri::command]
+pub async fn search(
+    state: State<'_, StoreState>,
+    query: String,
+) -> Result<Vec<SearchResult>, String> {
+    let store = state.read().await;
+
+    Ok(store.search(&query))
+}
+
+/// Returns the `limit` matches of `query` after skipping `offset`, ranked
+/// from best to worst
+#[tauri::command]
+pub async fn search_page(
+    state: State<'_, StoreState>,
+    query: String,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<SearchResult>, String> {
+    let store = state.read().await;
+
+    Ok(store.search_page(&query, offset, limit))
+}
+
+/// Returns the `k` past messages most similar in meaning to `query`, best first
+#[tauri::command]
+pub async fn semantic_search(
+    state: State<'_, StoreState>,
+    query: String,
+    k: usize,
+) -> Result<Vec<SemanticMatch>, String> {
+    // The store is only locked between embedding requests, so other commands
+    // are not held up by them
+    loop {
+        let request = state
+            .read()
+            .await
+            .prepare_embeddings()
+            .map_err(|e| e.to_string())?;
+        let Some(request) = request else {
+            break;
+        };
+
+        let completed = request.complete().await.map_err(|e| e.to_string())?;
+        let mut store = state.write().await;
+        store
+            .commit_embeddings(completed)
+            .map_err(|e| e.to_string())?;
+    }
+
+    let query = state
+        .read()
+        .await
+        .prepare_semantic_query(&query, k)
+        .map_err(|e| e.to_string())?;
+    let query = query.complete().await.map_err(|e| e.to_string())?;
+
+    Ok(state.read().await.semantic_matches(query))
+}
+
+/// Returns the session with matching id, including its messages
+#[tauri::command]
+pub async fn get_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<ChatSession, String> {
+    let store = state.read().await;
+
+    store
+        .get_session(session_id)
+        .cloned()
+        .ok_or_else(|| ChatError::SessionNotFound(session_id).to_string())
+}
+
+/// Returns up to `limit` messages of the session with matching id, in order,
+/// skipping the first `offset`, so long sessions can be shown a page at a
+/// time
+#[tauri::command]
+pub async fn get_messages_range(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    offset: usize,
+    limit: usize,
+) -> Result<MessagePage, String> {
+    let store = state.read().await;
+    let session = store
+        .get_session(session_id)
+        .ok_or_else(|| ChatError::SessionNotFound(session_id).to_string())?;
+
+    Ok(MessagePage {
+        messages: session
+            .get_messages_range(offset, limit)
+            .into_iter()
+            .cloned()
+            .collect(),
+        total: session.message_count(),
+        revision: store.session_revision(session_id),
+    })
+}
+
+/// Renames the session with matching id to `title`
+#[tauri::command]
+pub async fn rename_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    title: String,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .rename_session(session_id, title)
+        .map_err(|e| e.to_string())
+}
+
+/// Retitles the session with matching id after what it is about now, asking
+/// the chat model for a short title. Sessions without messages keep their
+/// title. Returns the summary of the session.
+#[tauri::command]
+pub async fn regenerate_title(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<SessionSummary, String> {
+    let request = state
+        .read()
+        .await
+        .prepare_title(session_id)
+        .map_err(|e| e.to_string())?;
+
+    if let Some(request) = request {
+        let completed = request
+            .complete(&CancellationToken::new())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut store = state.write().await;
+        store.commit_title(completed).map_err(|e| e.to_string())?;
+    }
+
+    state
+        .read()
+        .await
+        .get_session_summary(session_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Sets the system prompt sent before the messages of the session with
+/// matching id. The prompt is removed if None.
+#[tauri::command]
+pub async fn set_system_prompt(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    prompt: Option<String>,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .set_system_prompt(session_id, prompt)
+        .map_err(|e| e.to_string())
+}
+
+/// Deletes the session with matching id, returning it if it existed
+#[tauri::command]
+pub async fn delete_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<Option<ChatSession>, String> {
+    let mut store = state.write().await;
+
+    Ok(store.delete_session(session_id))
+}
+
+/// Deletes a message from the session with matching id, returning the
+/// message if it existed
+#[tauri::command]
+pub async fn delete_message(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    message_id: MessageId,
+) -> Result<Option<Message>, String> {
+    let mut store = state.write().await;
+
+    store
+        .delete_message(session_id, message_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Puts the session with matching id back from the trash
+#[tauri::command]
+pub async fn restore_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store.restore_session(session_id).map_err(|e| e.to_string())
+}
+
+/// Puts a message deleted from the session with matching id back from the
+/// trash
+#[tauri::command]
+pub async fn restore_message(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    message_id: MessageId,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .restore_message(session_id, message_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the deleted sessions and messages that can still be restored,
+/// oldest first
+#[tauri::command]
+pub async fn list_trash(state: State<'_, StoreState>) -> Result<Vec<TrashedItem>, String> {
+    let store = state.read().await;
+
+    Ok(store.get_trash().to_vec())
+}
+
+/// Deletes everything in the trash for good, returning how many items were
+/// deleted
+#[tauri::command]
+pub async fn empty_trash(state: State<'_, StoreState>) -> Result<usize, String> {
+    let mut store = state.write().await;
+
+    Ok(store.empty_trash())
+}
+
+/// Returns how long deleted items are kept in the trash
+#[tauri::command]
+pub fn get_trash_config(app: AppHandle) -> Result<TrashConfig, String> {
+    TrashConfig::load(&config_path(&app, TRASH_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces how long deleted items are kept in the trash with `config`,
+/// purging those already older
+#[tauri::command]
+pub async fn set_trash_config(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: TrashConfig,
+) -> Result<(), String> {
+    config
+        .save(&config_path(&app, TRASH_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_trash_retention(config.retention_secs());
+
+    Ok(())
+}
+
+/// Makes the session with matching id send its messages to the provider
+/// registered under `provider`, or the default provider if None.
+#[tauri::command]
+pub async fn set_session_provider(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    provider: Option<String>,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .set_session_provider(session_id, provider)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the names of the providers sessions can pick besides the default
+#[tauri::command]
+pub async fn list_providers(state: State<'_, StoreState>) -> Result<Vec<String>, String> {
+    let store = state.read().await;
+
+    Ok(store
+        .get_provider_names()
+        .into_iter()
+        .map(String::from)
+        .collect())
+}
+
+/// Returns the chat models available from the provider registered under
+/// `provider`, or from the default provider if None, with what each can do.
+/// The models are fetched again if `refresh` is set, or cached ones reused.
+#[tauri::command]
+pub async fn list_models(
+    state: State<'_, StoreState>,
+    provider: Option<String>,
+    refresh: Option<bool>,
+) -> Result<Vec<ModelInfo>, String> {
+    let store = state.read().await;
+
+    store
+        .model_catalog(provider.as_deref(), refresh.unwrap_or_default())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Returns every prompt template, in the order they were added
+#[tauri::command]
+pub async fn list_prompts(state: State<'_, StoreState>) -> Result<Vec<PromptTemplate>, String> {
+    let store = state.read().await;
+
+    Ok(store.get_prompts().clone())
+}
+
+/// Saves `prompt`, replacing any prompt template with the same name
+#[tauri::command]
+pub async fn save_prompt(
+    state: State<'_, StoreState>,
+    prompt: PromptTemplate,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store.save_prompt(prompt);
+
+    Ok(())
+}
+
+/// Deletes the prompt template with matching name, returning it if it existed
+#[tauri::command]
+pub async fn delete_prompt(
+    state: State<'_, StoreState>,
+    name: String,
+) -> Result<Option<PromptTemplate>, String> {
+    let mut store = state.write().await;
+
+    Ok(store.delete_prompt(&name))
+}
+
+/// Renders the prompt template with matching name, filling its placeholders
+/// with `vars`
+#[tauri::command]
+pub async fn render_prompt(
+    state: State<'_, StoreState>,
+    name: String,
+    vars: HashMap<String, String>,
+) -> Result<String, String> {
+    let store = state.read().await;
+
+    store.render_prompt(&name, &vars).map_err(|e| e.to_string())
+}
+
+/// Returns the facts remembered about the user, oldest first
+#[tauri::command]
+pub async fn list_memories(state: State<'_, StoreState>) -> Result<Vec<Memory>, String> {
+    let store = state.read().await;
+
+    Ok(store.get_memories().to_vec())
+}
+
+/// Remembers `text` as a fact about the user, returning the memory
+#[tauri::command]
+pub async fn add_memory(state: State<'_, StoreState>, text: String) -> Result<Memory, String> {
+    let mut store = state.write().await;
+
+    Ok(store.add_memory(text))
+}
+
+/// Replaces the text of the memory with matching id with `text`
+#[tauri::command]
+pub async fn update_memory(
+    state: State<'_, StoreState>,
+    memory_id: MemoryId,
+    text: String,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .update_memory(memory_id, text)
+        .map_err(|e| e.to_string())
+}
+
+/// Forgets the memory with matching id, returning it if it existed
+#[tauri::command]
+pub async fn delete_memory(
+    state: State<'_, StoreState>,
+    memory_id: MemoryId,
+) -> Result<Option<Memory>, String> {
+    let mut store = state.write().await;
+
+    Ok(store.delete_memory(memory_id))
+}
+
+/// Returns whether facts are remembered, and how many new sessions are given
+#[tauri::command]
+pub fn get_memory_config(app: AppHandle) -> Result<MemoryConfig, String> {
+    MemoryConfig::load(&config_path(&app, MEMORY_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces whether facts are remembered, and how many new sessions are
+/// given, with `config`
+#[tauri::command]
+pub async fn set_memory_config(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: MemoryConfig,
+) -> Result<(), String> {
+    config
+        .save(&config_path(&app, MEMORY_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_memory_config(config);
+
+    Ok(())
+}
+
+/// Returns how many requests and tokens may be sent to each provider every
+/// minute
+#[tauri::command]
+pub fn get_rate_limit_config(app: AppHandle) -> Result<RateLimitConfig, String> {
+    RateLimitConfig::load(&config_path(&app, RATE_LIMIT_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces how many requests and tokens may be sent to each provider every
+/// minute with `config`
+#[tauri::command]
+pub async fn set_rate_limit_config(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: RateLimitConfig,
+) -> Result<(), String> {
+    config
+        .save(&config_path(&app, RATE_LIMIT_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_rate_limit_config(config);
+
+    Ok(())
+}
+
+/// Returns how much may be spent each month, and what happens once it has
+/// been
+#[tauri::command]
+pub fn get_budget_config(app: AppHandle) -> Result<BudgetConfig, String> {
+    BudgetConfig::load(&config_path(&app, BUDGET_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces how much may be spent each month, and what happens once it has
+/// been, with `config`
+#[tauri::command]
+pub async fn set_budget_config(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: BudgetConfig,
+) -> Result<(), String> {
+    config
+        .save(&config_path(&app, BUDGET_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_budget_config(config);
+
+    Ok(())
+}
+
+/// Returns what has been spent this month, measured against the budget
+#[tauri::command]
+pub async fn budget_status(state: State<'_, StoreState>) -> Result<BudgetStatus, String> {
+    Ok(state.read().await.budget_status())
+}
+
+/// Lets requests through for the rest of the month even though its budget
+/// has been spent
+#[tauri::command]
+pub async fn override_budget(app: AppHandle, state: State<'_, StoreState>) -> Result<(), String> {
+    let config = state.write().await.override_budget();
+
+    config
+        .save(&config_path(&app, BUDGET_FILE_NAME)?)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns whether User messages are moderated, and what happens to flagged
+/// ones
+#[tauri::command]
+pub fn get_moderation_config(app: AppHandle) -> Result<ModerationConfig, String> {
+    ModerationConfig::load(&config_path(&app, MODERATION_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces whether User messages are moderated, and what happens to flagged
+/// ones, with `config`
+#[tauri::command]
+pub async fn set_moderation_config(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: ModerationConfig,
+) -> Result<(), String> {
+    config
+        .save(&config_path(&app, MODERATION_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_moderation_config(config);
+
+    Ok(())
+}
+
+/// Sends the messages queued while offline right away, instead of waiting for
+/// the next retry
+#[tauri::command]
+pub async fn send_queued_messages(app: AppHandle) {
+    offline::flush(&app).await
+}
+
+/// Returns the defaults in effect from the app's `config.toml`
+#[tauri::command]
+pub fn get_app_config(app_config: State<'_, AppConfigState>) -> AppConfig {
+    app_config.get_config()
+}
+
+/// Returns whether responses are cached, and for how long
+#[tauri::command]
+pub fn get_cache_config(app: AppHandle) -> Result<CacheConfig, String> {
+    CacheConfig::load(&config_path(&app, CACHE_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces whether responses are cached, and for how long, with `config`
+#[tauri::command]
+pub async fn set_cache_config(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: CacheConfig,
+) -> Result<(), String> {
+    config
+        .save(&config_path(&app, CACHE_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_cache_config(config);
+
+    Ok(())
+}
+
+/// Drops every cached answer, returning how many there were
+#[tauri::command]
+pub async fn clear_cache(state: State<'_, StoreState>) -> Result<usize, String> {
+    Ok(state.read().await.clear_response_cache())
+}
+
+/// Returns the tokens used and their cost across every session
+#[tauri::command]
+pub async fn usage_report(state: State<'_, StoreState>) -> Result<UsageReport, String> {
+    let store = state.read().await;
+
+    Ok(store.usage_report())
+}
+
+/// Writes the tokens used and their cost within `date_range`, one row per
+/// day and chat model, as CSV to `path`, or to a path picked in a save dialog
+/// if None. Returns the path written to, or None if the dialog was closed
+/// without picking one.
+#[tauri::command]
+pub async fn export_usage_csv(
+    state: State<'_, StoreState>,
+    path: Option<PathBuf>,
+    date_range: DateRange,
+) -> Result<Option<PathBuf>, String> {
+    let csv = usage::to_csv(&state.read().await.daily_usage(date_range));
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let dialog = FileDialogBuilder::new()
+                .set_title("Export usage")
+                .set_file_name("usage.csv")
+                .add_filter("csv", &["csv"]);
+
+            match dialog.save_file() {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    std::fs::write(&path, csv).map_err(|e| e.to_string())?;
+
+    Ok(Some(path))
+}
+
+/// Returns statistics about the session with matching id
+#[tauri::command]
+pub async fn session_stats(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+) -> Result<SessionStats, String> {
+    let store = state.read().await;
+    let session = store
+        .get_session(session_id)
+        .ok_or_else(|| ChatError::SessionNotFound(session_id).to_string())?;
+
+    Ok(session.stats())
+}
+
+/// Returns the statistics of every session added up, for the usage dashboard
+#[tauri::command]
+pub async fn aggregate_stats(state: State<'_, StoreState>) -> Result<AggregateStats, String> {
+    Ok(state.read().await.aggregate_stats())
+}
+
+/// Stores `api_key` in the platform keyring and makes the store send new
+/// requests with it.
+#[tauri::command]
+pub async fn set_api_key(state: State<'_, StoreState>, api_key: String) -> Result<(), String> {
+    secrets::set_api_key(&api_key).map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_default_provider(secrets::openai_client(Some(&api_key)));
+    store.set_embedder(Timeouts::default().wrap(secrets::openai_client(Some(&api_key))));
+
+    Ok(())
+}
+
+/// Checks `api_key` is accepted by OpenAI by listing the available models. The
+/// stored key is checked if None.
+#[tauri::command]
+pub async fn validate_api_key(api_key: Option<String>) -> Result<(), String> {
+    let client = match api_key {
+        Some(key) => secrets::openai_client(Some(&key)),
+        None => secrets::stored_openai_client(),
+    };
+
+    client
+        .list_models()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Removes the stored API key. The store falls back to the `OPENAI_API_KEY`
+/// environment variable.
+#[tauri::command]
+pub async fn clear_api_key(state: State<'_, StoreState>) -> Result<(), String> {
+    secrets::clear_api_key().map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.set_default_provider(secrets::openai_client(None));
+    store.set_embedder(Timeouts::default().wrap(secrets::openai_client(None)));
+
+    Ok(())
+}
+
+/// Returns the quick-ask hotkey and how it behaves
+#[tauri::command]
+pub fn get_hotkey(hotkey: State<'_, HotkeyState>) -> HotkeyConfig {
+    hotkey.get_config()
+}
+
+/// Replaces the quick-ask hotkey with `config`. The current hotkey is kept if
+/// `config` can not be registered, such as when another app already uses it.
+#[tauri::command]
+pub fn set_hotkey(
+    app: AppHandle,
+    hotkey: State<'_, HotkeyState>,
+    config: HotkeyConfig,
+) -> Result<(), String> {
+    hotkey.set_config(&app, config)
+}
+
+/// Pauses the quick-ask hotkey, leaving its key combination to other apps, or
+/// resumes it if not `paused`
+#[tauri::command]
+pub fn set_hotkey_paused(
+    app: AppHandle,
+    hotkey: State<'_, HotkeyState>,
+    paused: bool,
+) -> Result<(), String> {
+    tray::set_hotkey_paused(&app, &hotkey, paused)
+}
+
+/// Returns whether the overlay is visible and the quick-ask hotkey paused
+#[tauri::command]
+pub fn get_overlay_status(app: AppHandle) -> OverlayStatus {
+    tray::status(&app)
+}
+
+/// Shows the overlay, or hides it if not `visible`, keeping the tray in step
+/// with the frontend
+#[tauri::command]
+pub fn set_overlay_visible(app: AppHandle, visible: bool) {
+    match visible {
+        true => tray::show_overlay(&app),
+        false => tray::hide_overlay(&app),
+    }
+}
+
+/// Returns how the overlay window is placed and drawn
+#[tauri::command]
+pub fn get_window_settings(window: State<'_, WindowState>) -> WindowSettings {
+    window.get_settings()
+}
+
+/// Keeps the overlay above other windows, or lets them cover it if not
+/// `always_on_top`
+#[tauri::command]
+pub fn set_always_on_top(
+    app: AppHandle,
+    window: State<'_, WindowState>,
+    always_on_top: bool,
+) -> Result<(), String> {
+    window.set_always_on_top(&app, always_on_top)
+}
+
+/// Draws the overlay with `opacity`, from `window::MIN_OPACITY` to 1
+#[tauri::command]
+pub fn set_window_opacity(
+    app: AppHandle,
+    window: State<'_, WindowState>,
+    opacity: f32,
+) -> Result<(), String> {
+    window.set_opacity(&app, opacity)
+}
+
+/// Lets clicks pass through the overlay to the windows under it, or has the
+/// overlay take them again if not `click_through`
+#[tauri::command]
+pub fn set_click_through(
+    app: AppHandle,
+    window: State<'_, WindowState>,
+    click_through: bool,
+) -> Result<(), String> {
+    window.set_click_through(&app, click_through)
+}
+
+/// Moves the overlay to `corner` of the monitor it is on
+#[tauri::command]
+pub fn snap_overlay(
+    app: AppHandle,
+    window: State<'_, WindowState>,
+    corner: Corner,
+) -> Result<(), String> {
+    window.snap_to_corner(&app, corner)
+}
+
+/// Keeps the overlay visible when another window takes focus, or lets it hide
+/// again if not `pinned`
+#[tauri::command]
+pub fn set_overlay_pinned(window: State<'_, WindowState>, pinned: bool) -> Result<(), String> {
+    window.set_pinned(pinned)
+}
+
+/// Has the quick-ask hotkey bring the overlay up on the monitor the user is
+/// working on, or leave it where it was
+#[tauri::command]
+pub fn set_follow_active_monitor(
+    window: State<'_, WindowState>,
+    follow: bool,
+) -> Result<(), String> {
+    window.set_follow_active_monitor(follow)
+}
+
+/// Stops every request running in the session with matching id. Returns
+/// false if no request is running in the session.
+#[tauri::command]
+pub fn cancel_request(
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+) -> bool {
+    cancellations.cancel(session_id)
+}
+
+/// Returns the path of the app config directory
+fn config_dir(app: &AppHandle) -> Result<PathBuf, String> {
+    app.path_resolver()
+        .app_config_dir()
+        .ok_or_else(|| String::from("Could not resolve the app config directory"))
+}
+
+/// Returns the path of the file named `file_name` inside the app config
+/// directory
+fn config_path(app: &AppHandle, file_name: &str) -> Result<PathBuf, String> {
+    Ok(config_dir(app)?.join(file_name))
+}
+
+/// Stores `api_key` in the platform keyring and registers the `anthropic`
+/// provider with it, so sessions can be pointed at Claude models
+#[tauri::command]
+pub async fn set_anthropic_api_key(
+    state: State<'_, StoreState>,
+    api_key: String,
+) -> Result<(), String> {
+    secrets::set_anthropic_api_key(&api_key).map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.register_provider(ANTHROPIC_PROVIDER, AnthropicProvider::new(api_key));
+
+    Ok(())
+}
+
+/// Removes the stored Anthropic API key and the `anthropic` provider along
+/// with it
+#[tauri::command]
+pub async fn clear_anthropic_api_key(state: State<'_, StoreState>) -> Result<(), String> {
+    secrets::clear_anthropic_api_key().map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.unregister_provider(ANTHROPIC_PROVIDER);
+
+    Ok(())
+}
+
+/// Returns where the Azure OpenAI deployment is hosted
+#[tauri::command]
+pub fn get_azure(app: AppHandle) -> Result<AzureSettings, String> {
+    AzureSettings::load(&config_path(&app, AZURE_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces the Azure settings with `settings`, storing `api_key` in the
+/// platform keyring if given. Sessions can use the deployment through the
+/// `azure` provider once `settings` is complete.
+#[tauri::command]
+pub async fn set_azure(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    settings: AzureSettings,
+    api_key: Option<String>,
+) -> Result<(), String> {
+    if let Some(key) = api_key {
+        secrets::set_azure_api_key(&key).map_err(|e| e.to_string())?;
+    }
+    settings
+        .save(&config_path(&app, AZURE_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    azure::configure(&mut store, &settings).map_err(|e| e.to_string())
+}
+
+/// Removes the stored Azure OpenAI key and the `azure` provider along with it
+#[tauri::command]
+pub async fn clear_azure_api_key(state: State<'_, StoreState>) -> Result<(), String> {
+    secrets::clear_azure_api_key().map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.unregister_provider(azure::AZURE_PROVIDER);
+
+    Ok(())
+}
+
+/// Returns the OpenAI-compatible endpoints the user added
+#[tauri::command]
+pub fn get_endpoints(app: AppHandle) -> Result<EndpointsConfig, String> {
+    EndpointsConfig::load(&config_path(&app, ENDPOINTS_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Adds `endpoint`, replacing any endpoint with the same name, and storing
+/// `api_key` for it in the platform keyring if given. Sessions can use it as
+/// the provider with the endpoint's name.
+#[tauri::command]
+pub async fn set_endpoint(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    endpoint: Endpoint,
+    api_key: Option<String>,
+) -> Result<(), String> {
+    // Checked before anything is saved
+    compatible::endpoint_client(&endpoint, api_key.as_deref()).map_err(|e| e.to_string())?;
+
+    if let Some(key) = api_key {
+        secrets::set_endpoint_api_key(&endpoint.name, &key).map_err(|e| e.to_string())?;
+    }
+    let path = config_path(&app, ENDPOINTS_FILE_NAME)?;
+    let mut config = EndpointsConfig::load(&path).map_err(|e| e.to_string())?;
+    config.upsert(endpoint.clone());
+    config.save(&path).map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    compatible::register(&mut store, &endpoint).map_err(|e| e.to_string())
+}
+
+/// Removes the endpoint named `name`, along with its key and provider
+#[tauri::command]
+pub async fn delete_endpoint(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    name: String,
+) -> Result<(), String> {
+    let path = config_path(&app, ENDPOINTS_FILE_NAME)?;
+    let mut config = EndpointsConfig::load(&path).map_err(|e| e.to_string())?;
+    if config.remove(&name).is_none() {
+        return Ok(());
+    }
+    config.save(&path).map_err(|e| e.to_string())?;
+    secrets::clear_endpoint_api_key(&name).map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.unregister_provider(&name);
+
+    Ok(())
+}
+
+/// Returns the profiles the user added
+#[tauri::command]
+pub fn get_profiles(app: AppHandle) -> Result<ProfilesConfig, String> {
+    ProfilesConfig::load(&config_path(&app, PROFILES_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Adds `profile`, replacing any profile with the same name, and storing
+/// `api_key` as the profile's own key and `proxy_password` as the password of
+/// its proxy in the platform keyring if given.
+/// Sessions already using the profile pick up the changes with their next
+/// message.
+#[tauri::command]
+pub async fn set_profile(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    profile: Profile,
+    api_key: Option<String>,
+    proxy_password: Option<String>,
+) -> Result<(), String> {
+    if let Some(key) = api_key {
+        secrets::set_profile_api_key(&profile.name, &key).map_err(|e| e.to_string())?;
+    }
+    if let Some(password) = proxy_password {
+        secrets::set_profile_proxy_password(&profile.name, &password).map_err(|e| e.to_string())?;
+    }
+    let path = config_path(&app, PROFILES_FILE_NAME)?;
+    let mut config = ProfilesConfig::load(&path).map_err(|e| e.to_string())?;
+    config.upsert(profile.clone());
+    config.save(&path).map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    profiles::register(&mut store, &profile).map_err(|e| e.to_string())
+}
+
+/// Removes the profile named `name`, along with its own key, proxy password
+/// and provider.
+/// Sessions using it fail to send messages until they switch to another
+/// profile or provider.
+#[tauri::command]
+pub async fn delete_profile(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    name: String,
+) -> Result<(), String> {
+    let path = config_path(&app, PROFILES_FILE_NAME)?;
+    let mut config = ProfilesConfig::load(&path).map_err(|e| e.to_string())?;
+    if config.remove(&name).is_none() {
+        return Ok(());
+    }
+    config.save(&path).map_err(|e| e.to_string())?;
+    secrets::clear_profile_api_key(&name).map_err(|e| e.to_string())?;
+    secrets::clear_profile_proxy_password(&name).map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    profiles::unregister(&mut store, &name);
+
+    Ok(())
+}
+
+/// Switches the session with matching id to the profile named `profile`, or
+/// back to the default provider if None
+#[tauri::command]
+pub async fn set_session_profile(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    profile: Option<String>,
+) -> Result<(), String> {
+    let config =
+        ProfilesConfig::load(&config_path(&app, PROFILES_FILE_NAME)?).map_err(|e| e.to_string())?;
+    let profile = match profile {
+        Some(name) => Some(
+            config
+                .get(&name)
+                .ok_or(ChatError::ProfileNotFound(name))
+                .map_err(|e| e.to_string())?,
+        ),
+        None => None,
+    };
+
+    let mut store = state.write().await;
+    store
+        .set_session_profile(session_id, profile)
+        .map_err(|e| e.to_string())
+}
+
+/// Checks `endpoint` can be reached with `api_key` by listing the models it
+/// serves, without adding it. The key stored for an endpoint with the same
+/// name is used if `api_key` is None.
+#[tauri::command]
+pub async fn ping_provider(
+    endpoint: Endpoint,
+    api_key: Option<String>,
+) -> Result<Vec<String>, String> {
+    let api_key = match api_key {
+        Some(key) => Some(key),
+        None => secrets::get_endpoint_api_key(&endpoint.name).map_err(|e| e.to_string())?,
+    };
+    let client =
+        compatible::endpoint_client(&endpoint, api_key.as_deref()).map_err(|e| e.to_string())?;
+
+    client.list_models().await.map_err(|e| e.to_string())
+}
+
+/// Returns whether, and how, the chat model can search the web
+#[tauri::command]
+pub fn get_web_search(app: AppHandle) -> Result<WebSearchConfig, String> {
+    WebSearchConfig::load(&config_path(&app, WEB_SEARCH_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces the web search config with `config`, storing `api_key` in the
+/// platform keyring if given. The chat model is offered the web search tool
+/// from the next request on if `config` enables it.
+#[tauri::command]
+pub async fn set_web_search(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: WebSearchConfig,
+    api_key: Option<String>,
+) -> Result<(), String> {
+    if let Some(key) = api_key {
+        secrets::set_search_api_key(&key).map_err(|e| e.to_string())?;
+    }
+    config
+        .save(&config_path(&app, WEB_SEARCH_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    web_search::configure(&mut store, &config).map_err(|e| e.to_string())
+}
+
+/// Returns which files the chat model can read
+#[tauri::command]
+pub fn get_file_reader(app: AppHandle) -> Result<FileReaderConfig, String> {
+    FileReaderConfig::load(&config_path(&app, FILE_READER_FILE_NAME)?).map_err(|e| e.to_string())
+}
+
+/// Replaces the directories, and size of the files, the chat model can read
+/// with `config`. The chat model is offered the file reader tool from the
+/// next request on if `config` approves any directories.
+#[tauri::command]
+pub async fn set_file_reader(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    config: FileReaderConfig,
+) -> Result<(), String> {
+    config
+        .save(&config_path(&app, FILE_READER_FILE_NAME)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    file_reader::configure(&mut store, &config);
+
+    Ok(())
+}
+
+/// Generates the image `request` asks for, saving it inside the app data
+/// directory, then commits it to the store. The request can be stopped with
+/// `cancel_request`.
+async fn generate(
+    app: &AppHandle,
+    state: &StoreState,
+    cancellations: &CancellationRegistry,
+    request: ImageRequest,
+) -> Result<Message, String> {
+    let dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or("Could not resolve the app data directory")?
+        .join(GENERATED_IMAGES_DIR_NAME);
+
+    let session_id = request.get_session_id();
+    let (request_id, cancel) = cancellations.register(session_id);
+    let completed = request.generate(&dir, &cancel).await;
+    cancellations.finish(session_id, request_id);
+
+    let completed = completed.map_err(|e| e.to_string())?;
+    state
+        .write()
+        .await
+        .commit_image(completed)
+        .map_err(|e| e.to_string())
+}
+
+/// Sends new messages in the session with matching id to `model`, once it is
+/// checked against the models offered by the session's provider. Messages
+/// already in the session keep the model that produced them.
+#[tauri::command]
+pub async fn set_session_model(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    model: String,
+) -> Result<(), String> {
+    let catalog = {
+        let store = state.read().await;
+        let provider = store
+            .get_session(session_id)
+            .ok_or_else(|| ChatError::SessionNotFound(session_id).to_string())?
+            .get_provider()
+            .map(str::to_string);
+
+        store
+            .model_catalog(provider.as_deref(), false)
+            .await
+            .map_err(|e| e.to_string())?
+    };
+
+    let mut store = state.write().await;
+    store
+        .set_session_model(session_id, &model, &catalog)
+        .map_err(|e| e.to_string())
+}
+
+/// Sets whether prompts in the session with matching id are sent to the
+/// chat model or generate images
+#[tauri::command]
+pub async fn set_session_mode(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    mode: SessionMode,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+
+    store
+        .set_session_mode(session_id, mode)
+        .map_err(|e| e.to_string())
+}
+
+/// Generates an image from `prompt` in the session with matching id,
+/// whatever its mode. Returns the message holding the image.
+#[tauri::command]
+pub async fn generate_image(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    prompt: String,
+    options: Option<ImageOptions>,
+) -> Result<Message, String> {
+    let request = state
+        .read()
+        .await
+        .prepare_image(session_id, prompt, options.unwrap_or_default())
+        .map_err(|e| e.to_string())?;
+
+    generate(&app, &state, &cancellations, request).await
+}
+
+/// Generates the image with id `message_id` again with `options`, such as a
+/// different size or quality. The new image replaces the old one.
+#[tauri::command]
+pub async fn regenerate_image(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    cancellations: State<'_, CancellationRegistry>,
+    session_id: SessionId,
+    message_id: MessageId,
+    options: ImageOptions,
+) -> Result<Message, String> {
+    let request = state
+        .read()
+        .await
+        .prepare_image_regenerate(session_id, message_id, options)
+        .map_err(|e| e.to_string())?;
+
+    generate(&app, &state, &cancellations, request).await
+}
+
+/// Returns the audio of the response with id `message_id`, synthesizing it
+/// first unless it is already cached in the app data directory
+async fn speak(
+    app: &AppHandle,
+    state: &StoreState,
+    session_id: SessionId,
+    message_id: MessageId,
+    config: SpeechConfig,
+) -> Result<PathBuf, String> {
+    let dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or("Could not resolve the app data directory")?
+        .join(SPEECH_CACHE_DIR_NAME);
+
+    let request = state
+        .read()
+        .await
+        .prepare_speech(session_id, message_id, config.options)
+        .map_err(|e| e.to_string())?;
+
+    request
+        .synthesize(&dir, &CancellationToken::new())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Reads `message` aloud in the background if it is a response and reading
+/// responses aloud is turned on, emitting `SPEECH_EVENT` once its audio is
+/// ready
+fn read_aloud(app: &AppHandle, session_id: SessionId, message: &Message) {
+    let config = app.state::<SpeechState>().get_config();
+    if !config.read_aloud || message.get_role() != ChatRole::Assistant {
+        return;
+    }
+
+    let app = app.clone();
+    let message_id = message.get_id();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<StoreState>();
+
+        match speak(&app, &state, session_id, message_id, config).await {
+            Ok(path) => {
+                let payload = SpeechPayload {
+                    session_id,
+                    message_id,
+                    path,
+                };
+
+                if let Err(e) = app.emit_all(SPEECH_EVENT, payload) {
+                    eprintln!("Could not emit speech: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Could not read the response aloud: {}", e),
+        }
+    });
+}
+
+/// Reads the response with id `message_id` aloud, returning the path of its
+/// audio file
+#[tauri::command]
+pub async fn speak_message(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    speech: State<'_, SpeechState>,
+    session_id: SessionId,
+    message_id: MessageId,
+) -> Result<PathBuf, String> {
+    let config = speech.get_config();
+
+    speak(&app, &state, session_id, message_id, config).await
+}
+
+/// Returns the current notification settings
+#[tauri::command]
+pub fn get_notification_config(notifications: State<'_, NotificationState>) -> NotificationConfig {
+    notifications.get_config()
+}
+
+/// Replaces the notification settings, such as whether any are shown, and
+/// saves them
+#[tauri::command]
+pub fn set_notification_config(
+    notifications: State<'_, NotificationState>,
+    config: NotificationConfig,
+) -> Result<(), String> {
+    notifications.set_config(config).map_err(|e| e.to_string())
+}
+
+/// Mutes the notifications of the session with matching id, or unmutes them
+/// if not `muted`
+#[tauri::command]
+pub fn set_session_muted(
+    notifications: State<'_, NotificationState>,
+    session_id: SessionId,
+    muted: bool,
+) -> Result<(), String> {
+    notifications
+        .set_session_muted(session_id, muted)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the current speech settings
+#[tauri::command]
+pub fn get_speech(speech: State<'_, SpeechState>) -> SpeechConfig {
+    speech.get_config()
+}
+
+/// Replaces the speech settings, such as whether responses are read aloud,
+/// and saves them
+#[tauri::command]
+pub fn set_speech(speech: State<'_, SpeechState>, config: SpeechConfig) -> Result<(), String> {
+    speech.set_config(config).map_err(|e| e.to_string())
+}
+
+/// Returns the backend saving to the store file inside the app data
+/// directory, without a key
+fn store_backend(app: &AppHandle) -> Result<JsonFileBackend, String> {
+    let app_data_dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or("Could not resolve the app data directory")?;
+
+    Ok(JsonFileBackend::in_app_data_dir(&app_data_dir))
+}
+
+/// Returns whether the store is encrypted, and whether it still has to be
+/// unlocked with `unlock_store`
+#[tauri::command]
+pub async fn encryption_status(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+) -> Result<EncryptionStatus, String> {
+    let encrypted = store_backend(&app)?
+        .is_encrypted()
+        .map_err(|e| e.to_string())?;
+    let locked = encrypted && !state.read().await.is_persisted();
+
+    Ok(EncryptionStatus { encrypted, locked })
+}
+
+/// Decrypts the store with `passphrase` and loads its sessions
+#[tauri::command]
+pub async fn unlock_store(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    passphrase: String,
+) -> Result<(), String> {
+    let backend = store_backend(&app)?
+        .unlock(&passphrase)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    store.restore(backend).map_err(|e| e.to_string())
+}
+
+/// Encrypts the store with a key derived from `passphrase`, replacing any
+/// passphrase it was encrypted with before
+#[tauri::command]
+pub async fn enable_encryption(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    passphrase: String,
+) -> Result<(), String> {
+    if passphrase.is_empty() {
+        return Err(String::from("The passphrase can not be empty"));
+    }
+    let key = EncryptionKey::new(&passphrase).map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    match store.storage_kind() {
+        None => return Err(ChatError::StoreLocked.to_string()),
+        Some(StorageKind::Sqlite) => {
+            return Err(String::from("A store saved to SQLite can not be encrypted"))
+        }
+        Some(StorageKind::Json) => {}
+    }
+    store
+        .set_backend(store_backend(&app)?.with_key(Some(key)))
+        .map_err(|e| e.to_string())
+}
+
+/// Saves the store as plain JSON again, once `passphrase` is checked
+#[tauri::command]
+pub async fn disable_encryption(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    passphrase: String,
+) -> Result<(), String> {
+    let backend = store_backend(&app)?;
+    backend
+        .clone()
+        .unlock(&passphrase)
+        .map_err(|e| e.to_string())?;
+
+    let mut store = state.write().await;
+    if !store.is_persisted() {
+        return Err(ChatError::StoreLocked.to_string());
+    }
+    store.set_backend(backend).map_err(|e| e.to_string())
+}
+
+/// Exports the session with matching id in `format` to `path`, or to a path
+/// picked in a save dialog if None. Returns the path written to, or None if
+/// the dialog was closed without picking one.
+#[tauri::command]
+pub async fn export_session(
+    state: State<'_, StoreState>,
+    session_id: SessionId,
+    format: ExportFormat,
+    path: Option<PathBuf>,
+) -> Result<Option<PathBuf>, String> {
+    let (title, contents) = {
+        let store = state.read().await;
+        let session = store
+            .get_session(session_id)
+            .ok_or_else(|| ChatError::SessionNotFound(session_id).to_string())?;
+
+        (
+            session.get_title(),
+            session.export(format).map_err(|e| e.to_string())?,
+        )
+    };
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            // Characters file systems reject are left out of the suggested name
+            let title: String = title
+                .chars()
+                .filter(|c| !matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+                .collect();
+            let file_name = format!("{}.{}", title.trim(), format.extension());
+            let dialog = FileDialogBuilder::new()
+                .set_title("Export session")
+                .set_file_name(&file_name)
+                .add_filter(format.extension(), &[format.extension()]);
+
+            match dialog.save_file() {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    std::fs::write(&path, contents).map_err(|e| e.to_string())?;
+
+    Ok(Some(path))
+}
+
+/// Writes the sessions with ids `session_ids` as a fine-tuning dataset in
+/// OpenAI's JSONL format to `path`, or to a path picked in a save dialog if
+/// None. Returns the path written to, or None if the dialog was closed without
+/// picking one.
+#[tauri::command]
+pub async fn export_training_jsonl(
+    state: State<'_, StoreState>,
+    session_ids: Vec<SessionId>,
+    path: Option<PathBuf>,
+) -> Result<Option<PathBuf>, String> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let dialog = FileDialogBuilder::new()
+                .set_title("Export fine-tuning dataset")
+                .set_file_name("training.jsonl")
+                .add_filter("jsonl", &["jsonl"]);
+
+            match dialog.save_file() {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    state
+        .read()
+        .await
+        .export_training_jsonl(&session_ids, &path)
+        .map_err(|e| e.to_string())?;
+
+    Ok(Some(path))
+}
+
+/// Backs up the whole store, along with the settings, to `path`, or to a path
+/// picked in a save dialog if None. The backup of an encrypted store is
+/// encrypted with the same key. Returns the path written to, or None if the
+/// dialog was closed without picking one.
+#[tauri::command]
+pub async fn backup_store(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    path: Option<PathBuf>,
+) -> Result<Option<PathBuf>, String> {
+    let (snapshot, key) = {
+        let store = state.read().await;
+        if !store.is_persisted() {
+            return Err(ChatError::StoreLocked.to_string());
+        }
+        (store.snapshot(), store.encryption_key())
+    };
+    let app_config_dir = config_dir(&app)?;
+    let settings = Backup::read_settings(&app_config_dir).map_err(|e| e.to_string())?;
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let dialog = FileDialogBuilder::new()
+                .set_title("Back up")
+                .set_file_name(BACKUP_FILE_NAME)
+                .add_filter("gz", &["gz"]);
+
+            match dialog.save_file() {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    Backup::new(snapshot, settings)
+        .write(&path, key.as_ref())
+        .map_err(|e| e.to_string())?;
+
+    Ok(Some(path))
+}
+
+/// Restores the backup at `path`, or at a path picked in a file dialog if
+/// None, replacing the whole store and the saved settings. An encrypted backup
+/// is unlocked with `passphrase`. With `dry_run` nothing is restored, only
+/// reported. Returns what was restored, or None if
+/// the dialog was closed without picking a backup.
+#[tauri::command]
+pub async fn restore_store(
+    app: AppHandle,
+    state: State<'_, StoreState>,
+    path: Option<PathBuf>,
+    passphrase: Option<String>,
+    dry_run: bool,
+) -> Result<Option<RestoreReport>, String> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let dialog = FileDialogBuilder::new()
+                .set_title("Restore a backup")
+                .add_filter("gz", &["gz"]);
+
+            match dialog.pick_file() {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    let backup = Backup::read(&path, passphrase.as_deref()).map_err(|e| e.to_string())?;
+    let mut store = state.write().await;
+    let report = backup.report(store.get_all_sessions().len(), dry_run);
+    if dry_run {
+        return Ok(Some(report));
+    }
+
+    store
+        .replace_snapshot(backup.snapshot.clone())
+        .map_err(|e| e.to_string())?;
+    backup
+        .restore_settings(&config_dir(&app)?)
+        .map_err(|e| e.to_string())?;
+
+    Ok(Some(report))
+}
+
+/// Returns the automatic backups of the store, newest first
+#[tauri::command]
+pub fn list_backups(backups: State<'_, AutoBackupState>) -> Result<Vec<BackupInfo>, String> {
+    backups.list_backups().map_err(|e| e.to_string())
+}
+
+/// Replaces the store with the automatic backup named `file_name`, such as
+/// after the store file was damaged, and loads its sessions. An encrypted
+/// backup is unlocked with `passphrase`.
+#[tauri::command]
+pub async fn restore_backup(
+    state: State<'_, StoreState>,
+    backups: State<'_, AutoBackupState>,
+    file_name: String,
+    passphrase: Option<String>,
+) -> Result<(), String> {
+    let mut store = state.write().await;
+    let backend = backups
+        .restore(&file_name, passphrase.as_deref())
+        .map_err(|e| e.to_string())?;
+
+    store.restore(backend).map_err(|e| e.to_string())
+}
+
+/// Returns when the store is backed up automatically
+#[tauri::command]
+pub fn get_auto_backup_config(backups: State<'_, AutoBackupState>) -> AutoBackupConfig {
+    backups.get_config()
+}
+
+/// Replaces when the store is backed up automatically, and how many backups
+/// are kept, and saves it
+#[tauri::command]
+pub fn set_auto_backup_config(
+    backups: State<'_, AutoBackupState>,
+    config: AutoBackupConfig,
+) -> Result<(), String> {
+    backups.set_config(config).map_err(|e| e.to_string())
+}
+
+/// Imports the conversations in the ChatGPT data export at `path`, or at a
+/// `conversations.json` picked in an open dialog if None, as new sessions.
+/// `IMPORT_PROGRESS_EVENT` is emitted after each conversation. Returns the
+/// summaries of the new sessions, or None if the dialog was closed without
+/// picking a file.
+#[tauri::command]
+pub async fn import_chatgpt_export(
+    window: Window,
+    state: State<'_, StoreState>,
+    app_config: State<'_, AppConfigState>,
+    path: Option<PathBuf>,
+) -> Result<Option<Vec<SessionSummary>>, String> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let dialog = FileDialogBuilder::new()
+                .set_title("Import ChatGPT conversations")
+                .set_file_name(CHATGPT_CONVERSATIONS_FILE)
+                .add_filter("json", &["json"]);
+
+            match dialog.pick_file() {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    let model = app_config.get_config().default_model;
+    let mut store = state.write().await;
+    let ids = store
+        .import_chatgpt_export(&path, &model, |imported, total| {
+            let payload = ImportProgressPayload { imported, total };
+            if let Err(e) = window.emit(IMPORT_PROGRESS_EVENT, payload) {
+                eprintln!("Could not emit import progress: {}", e);
+            }
+        })
+        .map_err(|e| e.to_string())?;
+
+    ids.into_iter()
+        .map(|id| store.get_session_summary(id).map_err(|e| e.to_string()))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
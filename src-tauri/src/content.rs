@@ -0,0 +1,248 @@
+//! The parts a message's content is made of.
+//!
+//! Besides text, a message can hold images, calls the chat model made to a
+//! function, and the results of those calls. Messages saved before content
+//! had parts hold a single string, which is read back as one text part.
+
+use crate::role::ChatRole;
+use crate::vision;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionResponseMessage, FunctionCall, Role,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A single part of a message's content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageContent {
+    /// Plain text
+    Text { text: String },
+
+    /// An image saved on disk
+    Image { path: PathBuf, mime: String },
+
+    /// A call the chat model made to the function named `name`, with its
+    /// arguments as a JSON string
+    ToolCall { name: String, arguments: String },
+
+    /// The result of calling the function named `name`, along with the
+    /// sources it came from, if any
+    ToolResult {
+        name: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        sources: Vec<Source>,
+    },
+}
+
+/// A web page, or other document, a tool result came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub title: String,
+    pub url: String,
+}
+
+impl MessageContent {
+    /// Create a text part
+    pub fn text(text: impl Into<String>) -> MessageContent {
+        MessageContent::Text { text: text.into() }
+    }
+
+    /// Create an image part for the image at `path`, guessing its media type
+    /// from the file extension
+    pub fn image(path: impl Into<PathBuf>) -> MessageContent {
+        let path = path.into();
+        let mime = vision::mime_type(&path).to_string();
+
+        MessageContent::Image { path, mime }
+    }
+}
+
+/// Returns the text of `parts`, joined by new lines. Tool results count as
+/// text, while images and tool calls are left out.
+pub fn plain_text(parts: &[MessageContent]) -> String {
+    parts
+        .iter()
+        .filter_map(|part| match part {
+            MessageContent::Text { text } => Some(text.as_str()),
+            MessageContent::ToolResult { content, .. } => Some(content.as_str()),
+            MessageContent::Image { .. } | MessageContent::ToolCall { .. } => None,
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Returns the paths of the images in `parts`, in order
+pub fn images(parts: &[MessageContent]) -> impl Iterator<Item = &Path> {
+    parts.iter().filter_map(|part| match part {
+        MessageContent::Image { path, .. } => Some(path.as_path()),
+        _ => None,
+    })
+}
+
+/// Returns the sources of the tool results in `parts`, in order
+pub fn sources(parts: &[MessageContent]) -> impl Iterator<Item = &Source> {
+    parts.iter().flat_map(|part| match part {
+        MessageContent::ToolResult { sources, .. } => sources.as_slice(),
+        _ => &[],
+    })
+}
+
+/// Returns the function call in `parts`, if any
+fn tool_call(parts: &[MessageContent]) -> Option<FunctionCall> {
+    parts.iter().find_map(|part| match part {
+        MessageContent::ToolCall { name, arguments } => Some(FunctionCall {
+            name: name.clone(),
+            arguments: arguments.clone(),
+        }),
+        _ => None,
+    })
+}
+
+/// Converts a message with `role` and `parts` into a request message.
+///
+/// Tool results are sent as Function messages named after the function, and
+/// tool calls as the message's function call. Images can not be part of
+/// request messages, so they are left out.
+pub fn to_request_message(
+    role: ChatRole,
+    parts: &[MessageContent],
+) -> ChatCompletionRequestMessage {
+    let tool_result = parts.iter().find_map(|part| match part {
+        MessageContent::ToolResult { name, .. } => Some(name.clone()),
+        _ => None,
+    });
+    let function_call = tool_call(parts);
+    let text = plain_text(parts);
+
+    ChatCompletionRequestMessage {
+        role: if tool_result.is_some() {
+            Role::Function
+        } else {
+            role.into()
+        },
+        // Function calls may come without any content
+        content: if text.is_empty() && function_call.is_some() {
+            None
+        } else {
+            Some(text)
+        },
+        name: tool_result,
+        function_call,
+    }
+}
+
+/// Converts a message with `role` and `parts` into a response message
+pub fn to_response_message(
+    role: ChatRole,
+    parts: &[MessageContent],
+) -> ChatCompletionResponseMessage {
+    ChatCompletionResponseMessage {
+        role: role.into(),
+        content: Some(plain_text(parts)),
+        function_call: tool_call(parts),
+    }
+}
+
+/// Returns the parts of a response from the chat model
+pub fn from_response(message: &ChatCompletionResponseMessage) -> Vec<MessageContent> {
+    let mut parts = Vec::new();
+
+    if let Some(text) = message.content.as_ref().filter(|x| !x.is_empty()) {
+        parts.push(MessageContent::text(text.clone()));
+    }
+
+    if let Some(call) = &message.function_call {
+        parts.push(MessageContent::ToolCall {
+            name: call.name.clone(),
+            arguments: call.arguments.clone(),
+        });
+    }
+
+    if parts.is_empty() {
+        parts.push(MessageContent::text(""));
+    }
+
+    parts
+}
+
+/// Reads a message's content, either as a list of parts or as the single
+/// string older versions saved
+pub(crate) fn deserialize_parts<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<MessageContent>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Text(String),
+        Parts(Vec<MessageContent>),
+    }
+
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Text(text) => vec![MessageContent::text(text)],
+        Stored::Parts(parts) => parts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text() {
+        let parts = vec![
+            MessageContent::text("What is this?"),
+            MessageContent::image("shot.png"),
+            MessageContent::text("Be brief."),
+        ];
+
+        assert_eq!("What is this?\nBe brief.", plain_text(&parts));
+        assert_eq!(
+            vec![Path::new("shot.png")],
+            images(&parts).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_request_message_conversion() {
+        let call = vec![MessageContent::ToolCall {
+            name: String::from("get_time"),
+            arguments: String::from("{}"),
+        }];
+        let msg = to_request_message(ChatRole::Assistant, &call);
+        assert_eq!(None, msg.content);
+        assert_eq!(
+            Some(String::from("get_time")),
+            msg.function_call.map(|x| x.name)
+        );
+
+        let result = vec![MessageContent::ToolResult {
+            name: String::from("get_time"),
+            content: String::from("12:00"),
+            sources: vec![],
+        }];
+        let msg = to_request_message(ChatRole::User, &result);
+        assert_eq!(Role::Function, msg.role);
+        assert_eq!(Some(String::from("get_time")), msg.name);
+        assert_eq!(Some(String::from("12:00")), msg.content);
+    }
+
+    #[test]
+    fn test_deserialize_old_content() {
+        #[derive(Deserialize)]
+        struct Stored {
+            #[serde(deserialize_with = "deserialize_parts")]
+            content: Vec<MessageContent>,
+        }
+
+        let old: Stored = serde_json::from_str(r#"{"content": "Hi"}"#).unwrap();
+        assert_eq!(vec![MessageContent::text("Hi")], old.content);
+
+        let new: Stored = serde_json::from_str(
+            r#"{"content": [{"type": "image", "path": "a.png", "mime": "image/png"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(vec![MessageContent::image("a.png")], new.content);
+    }
+}
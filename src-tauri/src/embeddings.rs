@@ -0,0 +1,626 @@
+//! Semantic search over past messages using embedding vectors.
+//!
+//! Each message is embedded once and its vector kept in an `EmbeddingIndex`,
+//! saved as a JSON file next to the store. Searches embed the query and rank
+//! messages by cosine similarity.
+
+use crate::ids::{MessageId, SessionId};
+use crate::persistence::write_atomically;
+use crate::providers::ollama::OllamaProvider;
+use crate::tokens::count_tokens;
+use crate::{ChatError, ChatSession};
+use async_openai::{config::Config, types::CreateEmbeddingRequestArgs, Client};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Name of the file the index is saved to inside the app data directory
+pub const EMBEDDINGS_FILE_NAME: &str = "embeddings.json";
+
+/// OpenAI model used to embed messages
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// Ollama model used to embed messages
+const OLLAMA_EMBEDDING_MODEL: &str = "nomic-embed-text";
+
+/// The most messages embedded in a single request
+pub const EMBEDDING_BATCH_SIZE: usize = 100;
+
+/// The most tokens embedded in a single request, well within what the
+/// embeddings API accepts
+pub const EMBEDDING_BATCH_TOKENS: usize = 100_000;
+
+/// A backend able to turn text into embedding vectors
+#[async_trait]
+pub trait Embedder: fmt::Debug + Send + Sync {
+    /// Returns the embedding of each of `texts`, in order
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChatError>;
+}
+
+#[async_trait]
+impl<C: Config + fmt::Debug + Send + Sync> Embedder for Client<C> {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChatError> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(OPENAI_EMBEDDING_MODEL)
+            .input(texts)
+            .build()?;
+
+        let mut data = self.embeddings().create(request).await?.data;
+        data.sort_by_key(|x| x.index);
+
+        Ok(data.into_iter().map(|x| x.embedding).collect())
+    }
+}
+
+#[derive(Serialize)]
+struct OllamaEmbeddingRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f32>,
+}
+
+#[async_trait]
+impl Embedder for OllamaProvider {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChatError> {
+        let http = self.get_http_client();
+        let mut embeddings = Vec::with_capacity(texts.len());
+
+        // Ollama embeds a single prompt per request
+        for text in texts.iter() {
+            let response: OllamaEmbeddingResponse = http
+                .post(format!("{}/api/embeddings", self.get_base_url()))
+                .json(&OllamaEmbeddingRequest {
+                    model: OLLAMA_EMBEDDING_MODEL,
+                    prompt: text,
+                })
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            embeddings.push(response.embedding);
+        }
+
+        Ok(embeddings)
+    }
+}
+
+/// The embedding of a single message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct IndexedMessage {
+    session_id: SessionId,
+    message_id: MessageId,
+    vector: Vec<f32>,
+}
+
+/// Messages to embed together, by id, and their text
+#[derive(Debug)]
+struct Batch {
+    ids: Vec<(SessionId, MessageId)>,
+    texts: Vec<String>,
+}
+
+impl Batch {
+    async fn embed(self, embedder: &dyn Embedder) -> Result<Vec<IndexedMessage>, ChatError> {
+        let vectors = embedder.embed(self.texts).await?;
+        if vectors.len() != self.ids.len() {
+            return Err(ChatError::Request(format!(
+                "{} embeddings were returned for {} messages",
+                vectors.len(),
+                self.ids.len()
+            )));
+        }
+
+        Ok(self
+            .ids
+            .into_iter()
+            .zip(vectors)
+            .map(|((session_id, message_id), vector)| IndexedMessage {
+                session_id,
+                message_id,
+                vector,
+            })
+            .collect())
+    }
+}
+
+/// A batch of messages to embed, prepared by `Store::prepare_embeddings`.
+/// Embed it with `complete` without holding on to the store, then hand the
+/// result to `Store::commit_embeddings`.
+#[derive(Debug)]
+pub struct EmbeddingRequest {
+    embedder: Arc<dyn Embedder>,
+    batch: Batch,
+}
+
+/// Embeddings of a batch of messages waiting to be committed to the index
+#[derive(Debug)]
+pub struct CompletedEmbeddings {
+    entries: Vec<IndexedMessage>,
+}
+
+impl EmbeddingRequest {
+    /// Sends the request, returning the embeddings to commit
+    pub async fn complete(self) -> Result<CompletedEmbeddings, ChatError> {
+        Ok(CompletedEmbeddings {
+            entries: self.batch.embed(self.embedder.as_ref()).await?,
+        })
+    }
+}
+
+/// A semantic search query, prepared by `Store::prepare_semantic_query`.
+/// Embed it with `complete` without holding on to the store, then hand the
+/// result to `Store::semantic_matches`.
+#[derive(Debug)]
+pub struct SemanticQuery {
+    embedder: Arc<dyn Embedder>,
+    query: String,
+    k: usize,
+}
+
+/// The embedding of a semantic search query
+#[derive(Debug)]
+pub struct EmbeddedQuery {
+    vector: Vec<f32>,
+    k: usize,
+}
+
+impl SemanticQuery {
+    /// Creates a query for the `k` messages closest to `query`, embedded
+    /// with `embedder`
+    pub fn new(embedder: Arc<dyn Embedder>, query: &str, k: usize) -> SemanticQuery {
+        SemanticQuery {
+            embedder,
+            query: query.to_string(),
+            k,
+        }
+    }
+
+    /// Embeds the query
+    pub async fn complete(self) -> Result<EmbeddedQuery, ChatError> {
+        let vector = self
+            .embedder
+            .embed(vec![self.query])
+            .await?
+            .pop()
+            .ok_or_else(|| ChatError::Request("No embedding was returned".to_string()))?;
+
+        Ok(EmbeddedQuery { vector, k: self.k })
+    }
+}
+
+impl EmbeddedQuery {
+    /// Returns the messages in `sessions` indexed in `index` most similar to
+    /// the query, best first
+    pub fn nearest<'a>(
+        &self,
+        index: &EmbeddingIndex,
+        sessions: impl IntoIterator<Item = &'a ChatSession>,
+    ) -> Vec<SemanticMatch> {
+        index.nearest(sessions, &self.vector, self.k)
+    }
+}
+
+/// Returns the ids of the messages in `sessions` that may be indexed
+fn indexable<'a>(
+    sessions: impl IntoIterator<Item = &'a ChatSession>,
+) -> HashSet<(SessionId, MessageId)> {
+    sessions
+        .into_iter()
+        .filter(|x| !x.is_ephemeral())
+        .flat_map(|session| session.messages.values().map(|msg| (session.id, msg.id)))
+        .collect()
+}
+
+/// A past message similar to a semantic search query
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SemanticMatch {
+    pub session_id: SessionId,
+    pub message_id: MessageId,
+    /// Cosine similarity to the query, from -1 to 1
+    pub score: f32,
+}
+
+/// The embeddings of every indexed message
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingIndex {
+    entries: Vec<IndexedMessage>,
+    /// Where the index is saved, if anywhere
+    path: Option<PathBuf>,
+}
+
+/// Returns the cosine similarity of `a` and `b`, or 0 if either has no length
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+impl EmbeddingIndex {
+    /// Create an empty index that is not saved to disk
+    pub fn new() -> EmbeddingIndex {
+        EmbeddingIndex::default()
+    }
+
+    /// Loads the index saved at `path`, or creates an empty one if nothing
+    /// has been saved there yet. The index is saved back to `path` whenever
+    /// it changes.
+    ///
+    /// The index only caches embeddings of the store's messages, so an index
+    /// that can not be read, such as one saved before ids were UUIDs, is
+    /// started over and rebuilt on the next search.
+    pub fn load(path: impl Into<PathBuf>) -> Result<EmbeddingIndex, ChatError> {
+        let path = path.into();
+
+        let entries = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(ChatError::Persistence(e.to_string())),
+        };
+
+        Ok(EmbeddingIndex {
+            entries,
+            path: Some(path),
+        })
+    }
+
+    /// Loads the index saved inside `app_data_dir`
+    pub fn in_app_data_dir(app_data_dir: &Path) -> Result<EmbeddingIndex, ChatError> {
+        EmbeddingIndex::load(app_data_dir.join(EMBEDDINGS_FILE_NAME))
+    }
+
+    /// Returns the number of indexed messages
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no messages are indexed
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn save(&self) -> Result<(), ChatError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let contents =
+            serde_json::to_vec(&self.entries).map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        write_atomically(path, &contents)
+    }
+
+    /// Brings the index up to date with `sessions`, embedding new messages
+    /// with `embedder` and forgetting deleted ones. Ephemeral sessions are
+    /// never indexed, so their messages are not saved with the index.
+    ///
+    /// New messages are embedded in batches, and the index saved after each,
+    /// so a failed batch only loses its own embeddings.
+    pub async fn update<'a>(
+        &mut self,
+        sessions: impl IntoIterator<Item = &'a ChatSession>,
+        embedder: &dyn Embedder,
+    ) -> Result<(), ChatError> {
+        let sessions: Vec<&ChatSession> = sessions.into_iter().collect();
+        self.commit(sessions.iter().copied(), vec![])?;
+
+        while let Some(batch) = self.next_batch(sessions.iter().copied()) {
+            let entries = batch.embed(embedder).await?;
+            self.commit(sessions.iter().copied(), entries)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next batch of messages in `sessions` to embed with
+    /// `embedder`, or None if every message is indexed
+    pub fn prepare<'a>(
+        &self,
+        sessions: impl IntoIterator<Item = &'a ChatSession>,
+        embedder: Arc<dyn Embedder>,
+    ) -> Option<EmbeddingRequest> {
+        self.next_batch(sessions)
+            .map(|batch| EmbeddingRequest { embedder, batch })
+    }
+
+    /// Adds the embeddings of `completed` to the index and forgets deleted
+    /// messages, saving the index if it changed. Embeddings of messages
+    /// deleted from `sessions` while they were embedded are left out.
+    pub fn commit_embeddings<'a>(
+        &mut self,
+        sessions: impl IntoIterator<Item = &'a ChatSession>,
+        completed: CompletedEmbeddings,
+    ) -> Result<(), ChatError> {
+        self.commit(sessions, completed.entries)
+    }
+
+    fn commit<'a>(
+        &mut self,
+        sessions: impl IntoIterator<Item = &'a ChatSession>,
+        entries: Vec<IndexedMessage>,
+    ) -> Result<(), ChatError> {
+        let existing = indexable(sessions);
+
+        let before = self.entries.len();
+        self.entries
+            .retain(|x| existing.contains(&(x.session_id, x.message_id)));
+        let mut changed = self.entries.len() != before;
+
+        let indexed: HashSet<(SessionId, MessageId)> = self
+            .entries
+            .iter()
+            .map(|x| (x.session_id, x.message_id))
+            .collect();
+        for entry in entries {
+            let id = (entry.session_id, entry.message_id);
+            if existing.contains(&id) && !indexed.contains(&id) {
+                self.entries.push(entry);
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the ids and text of the next messages in `sessions` to embed,
+    /// at most `EMBEDDING_BATCH_SIZE` of them and `EMBEDDING_BATCH_TOKENS`
+    /// tokens together, or None if every message is indexed
+    fn next_batch<'a>(&self, sessions: impl IntoIterator<Item = &'a ChatSession>) -> Option<Batch> {
+        let indexed: HashSet<(SessionId, MessageId)> = self
+            .entries
+            .iter()
+            .map(|x| (x.session_id, x.message_id))
+            .collect();
+
+        let mut ids = Vec::new();
+        let mut texts = Vec::new();
+        let mut tokens = 0;
+        for session in sessions.into_iter().filter(|x| !x.is_ephemeral()) {
+            for msg in session.messages.values() {
+                let text = msg.plain_text();
+                if indexed.contains(&(session.id, msg.id)) || text.trim().is_empty() {
+                    continue;
+                }
+
+                // A batch always takes its first message, however long
+                tokens += count_tokens(&text);
+                if !texts.is_empty() && tokens > EMBEDDING_BATCH_TOKENS {
+                    return Some(Batch { ids, texts });
+                }
+                ids.push((session.id, msg.id));
+                texts.push(text);
+                if texts.len() == EMBEDDING_BATCH_SIZE {
+                    return Some(Batch { ids, texts });
+                }
+            }
+        }
+
+        (!texts.is_empty()).then_some(Batch { ids, texts })
+    }
+
+    /// Forgets the messages of the sessions with ids in `session_ids`
+    pub fn remove_sessions(&mut self, session_ids: &[SessionId]) -> Result<(), ChatError> {
+        let before = self.entries.len();
+        self.entries
+            .retain(|x| !session_ids.contains(&x.session_id));
+
+        if self.entries.len() != before {
+            self.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the `k` indexed messages in `sessions` most similar to `query`,
+    /// best first
+    pub fn nearest<'a>(
+        &self,
+        sessions: impl IntoIterator<Item = &'a ChatSession>,
+        query: &[f32],
+        k: usize,
+    ) -> Vec<SemanticMatch> {
+        let existing = indexable(sessions);
+        let mut matches: Vec<SemanticMatch> = self
+            .entries
+            .iter()
+            .filter(|x| existing.contains(&(x.session_id, x.message_id)))
+            .map(|x| SemanticMatch {
+                session_id: x.session_id,
+                message_id: x.message_id,
+                score: cosine_similarity(query, &x.vector),
+            })
+            .collect();
+
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        matches.truncate(k);
+
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatRole;
+    use std::sync::Mutex;
+
+    /// Embeds text as the counts of the letters a, b and c
+    #[derive(Debug)]
+    struct LetterEmbedder;
+
+    #[async_trait]
+    impl Embedder for LetterEmbedder {
+        async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChatError> {
+            Ok(texts
+                .iter()
+                .map(|text| {
+                    ['a', 'b', 'c']
+                        .iter()
+                        .map(|letter| text.matches(*letter).count() as f32)
+                        .collect()
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(1.0, cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]));
+        assert_eq!(0.0, cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]));
+        assert_eq!(0.0, cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]));
+    }
+
+    #[tokio::test]
+    async fn test_index_update_and_nearest() {
+        let mut chs = ChatSession::new(String::from("Letters"), "gpt-3.5-turbo");
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("aaa")),
+            (ChatRole::Assistant, String::from("bbb")),
+            (ChatRole::User, String::from("aab")),
+        ]);
+        let mut sessions = vec![chs];
+
+        let mut index = EmbeddingIndex::new();
+        index.update(&sessions, &LetterEmbedder).await.unwrap();
+        assert_eq!(3, index.len());
+
+        let query = LetterEmbedder.embed(vec![String::from("a")]).await.unwrap();
+        let ids: Vec<MessageId> = sessions[0]
+            .get_messages()
+            .iter()
+            .map(|x| x.get_id())
+            .collect();
+        let nearest: Vec<MessageId> = index
+            .nearest(&sessions, &query[0], 2)
+            .iter()
+            .map(|x| x.message_id)
+            .collect();
+        assert_eq!(vec![ids[0], ids[2]], nearest);
+
+        sessions[0].delete_message(ids[0]);
+        index.update(&sessions, &LetterEmbedder).await.unwrap();
+        assert_eq!(2, index.len());
+        assert_eq!(ids[2], index.nearest(&sessions, &query[0], 5)[0].message_id);
+
+        index.remove_sessions(&[sessions[0].id]).unwrap();
+        assert!(index.is_empty());
+    }
+
+    /// Embeds like `LetterEmbedder`, failing once it has embedded `limit`
+    /// texts in total
+    #[derive(Debug)]
+    struct LimitedEmbedder {
+        batches: Mutex<Vec<usize>>,
+        limit: usize,
+    }
+
+    #[async_trait]
+    impl Embedder for LimitedEmbedder {
+        async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChatError> {
+            {
+                let mut batches = self.batches.lock().unwrap();
+                if batches.iter().sum::<usize>() + texts.len() > self.limit {
+                    return Err(ChatError::Request(String::from("quota exceeded")));
+                }
+                batches.push(texts.len());
+            }
+
+            LetterEmbedder.embed(texts).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_index_update_in_batches() {
+        let mut chs = ChatSession::new(String::from("Many"), "gpt-3.5-turbo");
+        chs.add_message_batch_without_api(
+            (0..EMBEDDING_BATCH_SIZE * 2 + 10)
+                .map(|_| (ChatRole::User, String::from("abc")))
+                .collect(),
+        );
+        let sessions = vec![chs];
+        let path = std::env::temp_dir().join(format!(
+            "chat-overlay-embeddings-{}.json",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+
+        // Batches embedded before one fails are kept
+        let failing = LimitedEmbedder {
+            batches: Mutex::new(vec![]),
+            limit: EMBEDDING_BATCH_SIZE * 2,
+        };
+        let mut index = EmbeddingIndex::load(&path).unwrap();
+        assert!(index.update(&sessions, &failing).await.is_err());
+        assert_eq!(
+            vec![EMBEDDING_BATCH_SIZE, EMBEDDING_BATCH_SIZE],
+            *failing.batches.lock().unwrap()
+        );
+        assert_eq!(
+            EMBEDDING_BATCH_SIZE * 2,
+            EmbeddingIndex::load(&path).unwrap().len()
+        );
+
+        let embedder = LimitedEmbedder {
+            batches: Mutex::new(vec![]),
+            limit: usize::MAX,
+        };
+        index.update(&sessions, &embedder).await.unwrap();
+        assert_eq!(vec![10], *embedder.batches.lock().unwrap());
+        assert_eq!(EMBEDDING_BATCH_SIZE * 2 + 10, index.len());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_index_prepare_and_commit() {
+        let mut chs = ChatSession::new(String::from("Letters"), "gpt-3.5-turbo");
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("aaa")),
+            (ChatRole::Assistant, String::from("bbb")),
+        ]);
+        let mut sessions = vec![chs];
+        let mut index = EmbeddingIndex::new();
+
+        let request = index.prepare(&sessions, Arc::new(LetterEmbedder)).unwrap();
+        let completed = request.complete().await.unwrap();
+
+        // Messages deleted while they were embedded are left out
+        let deleted = sessions[0].get_messages()[0].get_id();
+        sessions[0].delete_message(deleted);
+        index.commit_embeddings(&sessions, completed).unwrap();
+        assert_eq!(1, index.len());
+        assert!(index.prepare(&sessions, Arc::new(LetterEmbedder)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_index_skips_ephemeral_sessions() {
+        let mut chs = ChatSession::new(String::from("Scratch"), "gpt-3.5-turbo");
+        chs.add_message_batch_without_api(vec![(ChatRole::User, String::from("aaa"))]);
+        chs.ephemeral = true;
+
+        let mut index = EmbeddingIndex::new();
+        index.update(&[chs], &LetterEmbedder).await.unwrap();
+        assert!(index.is_empty());
+    }
+}
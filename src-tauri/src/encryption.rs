@@ -0,0 +1,191 @@
+//! Encrypting the persisted store with a passphrase.
+//!
+//! The key is derived from the passphrase with Argon2id and a random salt,
+//! and the store encrypted with AES-256-GCM under a fresh nonce on every
+//! save. The salt and nonce are written next to the ciphertext, so only the
+//! passphrase is needed to decrypt the file again. The passphrase itself is
+//! never saved.
+
+use crate::ChatError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Length of the salt the key is derived with, in bytes
+const SALT_LEN: usize = 16;
+
+/// Length of the derived key, in bytes
+const KEY_LEN: usize = 32;
+
+/// Name of the cipher recorded in encrypted files
+const CIPHER: &str = "aes-256-gcm";
+
+/// Name of the key derivation function recorded in encrypted files
+const KDF: &str = "argon2id";
+
+/// Whether the store is encrypted, as shown in the settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EncryptionStatus {
+    pub encrypted: bool,
+    /// Whether the store still has to be unlocked with its passphrase before
+    /// its sessions can be loaded
+    pub locked: bool,
+}
+
+/// A key derived from a passphrase, along with the salt it was derived with
+#[derive(Clone)]
+pub struct EncryptionKey {
+    key: [u8; KEY_LEN],
+    salt: [u8; SALT_LEN],
+}
+
+// Written by hand so the key is never printed
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").finish_non_exhaustive()
+    }
+}
+
+/// The on-disk layout of encrypted data
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    cipher: String,
+    kdf: String,
+    /// The salt the key was derived with, base64 encoded
+    salt: String,
+    /// The nonce the data was encrypted with, base64 encoded
+    nonce: String,
+    /// The encrypted data, base64 encoded
+    ciphertext: String,
+}
+
+impl EncryptionKey {
+    /// Derives a key from `passphrase` with a new random salt
+    pub fn new(passphrase: &str) -> Result<EncryptionKey, ChatError> {
+        let mut salt = [0; SALT_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+
+        EncryptionKey::derive(passphrase, salt)
+    }
+
+    /// Derives the key `contents`, encrypted data, was encrypted with from
+    /// `passphrase`. Returns `ChatError::WrongPassphrase` if the key can not
+    /// decrypt `contents`.
+    pub fn unlock(passphrase: &str, contents: &[u8]) -> Result<EncryptionKey, ChatError> {
+        let file = parse(contents)?;
+        let salt = decode(&file.salt)?
+            .try_into()
+            .map_err(|_| ChatError::Encryption(String::from("The salt has the wrong length")))?;
+
+        let key = EncryptionKey::derive(passphrase, salt)?;
+        key.decrypt(contents)?;
+
+        Ok(key)
+    }
+
+    fn derive(passphrase: &str, salt: [u8; SALT_LEN]) -> Result<EncryptionKey, ChatError> {
+        let mut key = [0; KEY_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| ChatError::Encryption(e.to_string()))?;
+
+        Ok(EncryptionKey { key, salt })
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+
+    /// Encrypts `plaintext`, returning the encrypted file's contents
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, ChatError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, plaintext)
+            .map_err(|e| ChatError::Encryption(e.to_string()))?;
+
+        let file = EncryptedFile {
+            cipher: CIPHER.to_string(),
+            kdf: KDF.to_string(),
+            salt: STANDARD.encode(self.salt),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        };
+
+        serde_json::to_vec_pretty(&file).map_err(|e| ChatError::Encryption(e.to_string()))
+    }
+
+    /// Decrypts `contents`, the contents of an encrypted file. Returns
+    /// `ChatError::WrongPassphrase` if they were encrypted with another key.
+    pub fn decrypt(&self, contents: &[u8]) -> Result<Vec<u8>, ChatError> {
+        let file = parse(contents)?;
+        let nonce = decode(&file.nonce)?;
+        if nonce.len() != 12 {
+            return Err(ChatError::Encryption(String::from(
+                "The nonce has the wrong length",
+            )));
+        }
+        let ciphertext = decode(&file.ciphertext)?;
+
+        self.cipher()
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| ChatError::WrongPassphrase)
+    }
+}
+
+/// Returns whether `contents` is encrypted data rather than plain JSON
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(contents)
+        .is_ok_and(|value| value.get("ciphertext").is_some() && value.get("cipher").is_some())
+}
+
+fn parse(contents: &[u8]) -> Result<EncryptedFile, ChatError> {
+    let file: EncryptedFile =
+        serde_json::from_slice(contents).map_err(|e| ChatError::Encryption(e.to_string()))?;
+
+    if file.cipher != CIPHER || file.kdf != KDF {
+        return Err(ChatError::Encryption(format!(
+            "Unsupported encryption {} with {}",
+            file.cipher, file.kdf
+        )));
+    }
+
+    Ok(file)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, ChatError> {
+    STANDARD
+        .decode(data)
+        .map_err(|e| ChatError::Encryption(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_round_trip() {
+        let key = EncryptionKey::new("correct horse").unwrap();
+        let contents = key.encrypt(b"{\"secret\":true}").unwrap();
+
+        assert!(is_encrypted(&contents));
+        assert!(!is_encrypted(b"{\"version\":1}"));
+        assert_ne!(contents, key.encrypt(b"{\"secret\":true}").unwrap());
+
+        let unlocked = EncryptionKey::unlock("correct horse", &contents).unwrap();
+        assert_eq!(
+            b"{\"secret\":true}".to_vec(),
+            unlocked.decrypt(&contents).unwrap()
+        );
+
+        assert_eq!(
+            Some(ChatError::WrongPassphrase),
+            EncryptionKey::unlock("battery staple", &contents).err()
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! The error type returned by every public API of the crate.
+//!
+//! Errors from the libraries used to reach the chat model are sorted into
+//! variants the frontend can act on, such as asking for an API key or
+//! offering to retry.
+
+use crate::ids::{MemoryId, MessageId, SessionId};
+use crate::role::ChatRole;
+use async_openai::error::{ApiError, OpenAIError};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors returned by the chat store
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ChatError {
+    /// No session with the given id exists in the store
+    #[error("No session with id {0} exists")]
+    SessionNotFound(SessionId),
+    /// The store could not be saved or loaded
+    #[error("Could not persist the store: {0}")]
+    Persistence(String),
+    /// The request to the chat model failed
+    #[error("The chat request failed: {0}")]
+    Request(String),
+    /// The chat model could not be reached
+    #[error("Could not reach the chat model: {0}")]
+    Network(String),
+    /// The chat model responded without any message
+    #[error("The chat model returned an empty response")]
+    EmptyResponse,
+    /// The requested chat model does not exist or can not be used
+    #[error("The chat model can not be used: {0}")]
+    InvalidModel(String),
+    /// No API key, or an invalid one, was sent with the request
+    #[error("No valid API key is set. Add one in the settings")]
+    MissingApiKey,
+    /// No provider with the given name is registered with the store
+    #[error("No provider named {0} is registered")]
+    ProviderNotFound(String),
+    /// The platform keyring could not be accessed
+    #[error("Could not access the keyring: {0}")]
+    Keyring(String),
+    /// The request was cancelled before any response was received
+    #[error("The chat request was cancelled")]
+    Cancelled,
+    /// No message with the given id exists in the session
+    #[error("No message with id {0} exists")]
+    MessageNotFound(MessageId),
+    /// The message with the given id is not a response from the chat model
+    #[error("Message {0} is not a response from the chat model")]
+    NotAResponse(MessageId),
+    /// The message has no variant at the given index
+    #[error("The message has no variant at index {0}")]
+    VariantNotFound(usize),
+    /// The message with the given id was not written by the User
+    #[error("Message {0} is not a User message")]
+    NotAUserMessage(MessageId),
+    /// No prompt template with the given name exists in the store
+    #[error("No prompt named {0} exists")]
+    PromptNotFound(String),
+    /// A prompt template was rendered without a value for the given placeholder
+    #[error("No value was given for the prompt placeholder {0}")]
+    MissingPromptVariable(String),
+    /// Semantic search was used on a store without an embedder
+    #[error("Semantic search is not enabled")]
+    SemanticSearchDisabled,
+    /// The request to the chat model failed for a reason that may go away,
+    /// such as rate limiting or a server error. Holds how long the server
+    /// asked to wait before retrying, if it did.
+    #[error("The chat request failed, try again later: {0}")]
+    Transient(String, Option<Duration>),
+    /// The clipboard could not be accessed
+    #[error("Could not access the clipboard: {0}")]
+    Clipboard(String),
+    /// There was no text to capture
+    #[error("There is no text to capture")]
+    NothingCaptured,
+    /// An image could not be captured, saved, or read
+    #[error("Could not handle the image: {0}")]
+    Image(String),
+    /// A tool could not read a file
+    #[error("Could not read the file: {0}")]
+    FileRead(String),
+    /// A tool was asked to read a file outside the approved directories
+    #[error("{0} is outside the directories files can be read from")]
+    FileNotAllowed(String),
+    /// The provider can not do what was asked of it
+    #[error("The provider does not support {0}")]
+    Unsupported(String),
+    /// The message with the given id does not hold a generated image
+    #[error("Message {0} is not a generated image")]
+    NotAnImage(MessageId),
+    /// The message with the given id is no longer waiting to be sent, such as
+    /// when it was already sent from the queue
+    #[error("Message {0} is not waiting to be sent")]
+    NotQueued(MessageId),
+    /// An OpenAI-compatible endpoint can not be used as configured
+    #[error("Invalid endpoint: {0}")]
+    InvalidEndpoint(String),
+    /// The store is encrypted and has not been unlocked yet
+    #[error("The store is encrypted. Unlock it with its passphrase")]
+    StoreLocked,
+    /// The passphrase does not decrypt the store
+    #[error("The passphrase is wrong")]
+    WrongPassphrase,
+    /// The store could not be encrypted or decrypted
+    #[error("Could not encrypt the store: {0}")]
+    Encryption(String),
+    /// Conversations exported from another app could not be read
+    #[error("Could not import the conversations: {0}")]
+    Import(String),
+    /// No memory with the given id exists in the store
+    #[error("No memory with id {0} exists")]
+    MemoryNotFound(MemoryId),
+    /// The message was flagged by moderation and not sent. Holds the
+    /// categories it was flagged for.
+    #[error("The message was flagged for {}", .0.join(", "))]
+    Flagged(Vec<String>),
+    /// No profile with the given name exists
+    #[error("No profile named {0} exists")]
+    ProfileNotFound(String),
+    /// The provider took longer to connect or respond than its timeouts allow
+    #[error("The chat model did not respond in time: {0}")]
+    Timeout(String),
+    /// The monthly budget, holding its limit in USD, has been spent and
+    /// requests are refused until the user overrides it
+    #[error("This month's budget of ${0:.2} has been spent. Override it in the settings to keep sending messages")]
+    BudgetExceeded(f64),
+    /// A session was started with messages of the given roles, rather than a
+    /// User message, or a System message then a User message
+    #[error("Sessions start with a User message, or a System message then a User message, not {}", .0.iter().map(|x| crate::role_name(*x)).collect::<Vec<_>>().join(", "))]
+    InvalidOpeningRoles(Vec<ChatRole>),
+    /// The backup could not be restored, as it is not a backup, was made by a
+    /// newer version or has been damaged
+    #[error("Could not restore the backup: {0}")]
+    Backup(String),
+}
+
+impl ChatError {
+    /// Returns true if the request that failed with this error may succeed
+    /// when sent again
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ChatError::Transient(..) | ChatError::Network(_))
+    }
+
+    /// Returns true if the chat model could not be reached at all, such as
+    /// when the network is down
+    pub fn is_offline(&self) -> bool {
+        matches!(self, ChatError::Network(_))
+    }
+
+    /// Returns how long the server asked to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ChatError::Transient(_, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl From<OpenAIError> for ChatError {
+    fn from(e: OpenAIError) -> Self {
+        match e {
+            OpenAIError::Reqwest(e) => ChatError::from(e),
+            OpenAIError::ApiError(e) => ChatError::from(e),
+            e => ChatError::Request(e.to_string()),
+        }
+    }
+}
+
+impl From<ApiError> for ChatError {
+    fn from(e: ApiError) -> Self {
+        let code = e.code.as_ref().and_then(|code| code.as_str());
+
+        match (e.r#type.as_deref(), code) {
+            // Running out of quota is reported as rate limiting, but does not
+            // go away by retrying
+            (Some("insufficient_quota"), _) | (_, Some("insufficient_quota")) => {
+                ChatError::Request(e.message)
+            }
+            (_, Some("rate_limit_exceeded"))
+            | (Some("server_error" | "requests" | "tokens"), _) => {
+                ChatError::Transient(e.message, None)
+            }
+            (_, Some("model_not_found")) => ChatError::InvalidModel(e.message),
+            (_, Some("invalid_api_key")) => ChatError::MissingApiKey,
+            _ if e.message.starts_with("You didn't provide an API key") => ChatError::MissingApiKey,
+            _ => ChatError::Request(e.message),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ChatError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) if crate::retry::is_transient_status(status) => {
+                ChatError::Transient(e.to_string(), None)
+            }
+            Some(_) => ChatError::Request(e.to_string()),
+            None if e.is_timeout() => ChatError::Timeout(e.to_string()),
+            None if e.is_connect() || e.is_request() => ChatError::Network(e.to_string()),
+            None => ChatError::Request(e.to_string()),
+        }
+    }
+}
+
+impl From<keyring::Error> for ChatError {
+    fn from(e: keyring::Error) -> Self {
+        ChatError::Keyring(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(kind: Option<&str>, code: Option<&str>, message: &str) -> ApiError {
+        ApiError {
+            message: message.to_string(),
+            r#type: kind.map(String::from),
+            param: None,
+            code: code.map(|code| serde_json::Value::String(code.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_api_errors() {
+        let error = api_error(
+            Some("invalid_request_error"),
+            Some("model_not_found"),
+            "nope",
+        );
+        assert_eq!(
+            ChatError::InvalidModel(String::from("nope")),
+            ChatError::from(error)
+        );
+
+        let error = api_error(
+            Some("invalid_request_error"),
+            Some("invalid_api_key"),
+            "bad",
+        );
+        assert_eq!(ChatError::MissingApiKey, ChatError::from(error));
+
+        let error = api_error(Some("requests"), Some("rate_limit_exceeded"), "slow down");
+        assert!(ChatError::from(error).is_transient());
+
+        let error = api_error(Some("insufficient_quota"), None, "pay up");
+        assert_eq!(
+            ChatError::Request(String::from("pay up")),
+            ChatError::from(error)
+        );
+    }
+}
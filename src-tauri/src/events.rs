@@ -0,0 +1,409 @@
+//! Names and payloads of the Tauri events emitted to the frontend.
+
+use crate::budget::BudgetStatus;
+use crate::ids::{MessageId, SessionId};
+use crate::{Message, MessageStatus, SessionSummary};
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager};
+
+/// Emitted with a `TokenPayload` for each piece of a streamed response
+pub const TOKEN_EVENT: &str = "chat://token";
+
+/// Emitted with a `TokenPayload` holding the full response once a stream ends
+pub const STREAM_END_EVENT: &str = "chat://stream-end";
+
+/// Payload of the streaming events. `token` is the newly received content,
+/// or the complete response for `STREAM_END_EVENT`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenPayload {
+    pub session_id: SessionId,
+    pub token: String,
+}
+
+/// Emitted with a `SpeechPayload` once a response read aloud automatically
+/// has been synthesized
+pub const SPEECH_EVENT: &str = "chat://speech";
+
+/// Payload of `SPEECH_EVENT`. `path` is the audio file to play.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpeechPayload {
+    pub session_id: SessionId,
+    pub message_id: MessageId,
+    pub path: PathBuf,
+}
+
+/// Emitted with an `ImportProgressPayload` after each conversation imported
+/// from another app
+pub const IMPORT_PROGRESS_EVENT: &str = "chat://import-progress";
+
+/// Payload of `IMPORT_PROGRESS_EVENT`
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgressPayload {
+    pub imported: usize,
+    pub total: usize,
+}
+
+/// Emitted to the overlay window when the quick-ask hotkey shows it, asking
+/// the frontend to focus its input
+pub const FOCUS_INPUT_EVENT: &str = "overlay://focus-input";
+
+/// Payload of `FOCUS_INPUT_EVENT`. `session_id` is the session started by the
+/// hotkey or the tray, if one was started.
+#[derive(Debug, Clone, Serialize)]
+pub struct FocusInputPayload {
+    pub session_id: Option<SessionId>,
+}
+
+/// Emitted with an `OverlayVisibilityPayload` when the overlay window is shown
+/// or hidden, from the tray, the hotkey or the frontend
+pub const OVERLAY_VISIBILITY_EVENT: &str = "overlay://visibility";
+
+/// Payload of `OVERLAY_VISIBILITY_EVENT`
+#[derive(Debug, Clone, Serialize)]
+pub struct OverlayVisibilityPayload {
+    pub visible: bool,
+}
+
+/// Emitted with a `WindowOpacityPayload` when the overlay's opacity is set,
+/// for the webview to draw itself with
+pub const WINDOW_OPACITY_EVENT: &str = "overlay://opacity";
+
+/// Payload of `WINDOW_OPACITY_EVENT`
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowOpacityPayload {
+    pub opacity: f32,
+}
+
+/// Emitted with a `HotkeyPausedPayload` when the quick-ask hotkey is paused or
+/// resumed
+pub const HOTKEY_PAUSED_EVENT: &str = "overlay://hotkey-paused";
+
+/// Payload of `HOTKEY_PAUSED_EVENT`
+#[derive(Debug, Clone, Serialize)]
+pub struct HotkeyPausedPayload {
+    pub paused: bool,
+}
+
+/// Emitted with the new `AppConfig` when a change to `config.toml` has been
+/// applied
+pub const CONFIG_CHANGED_EVENT: &str = "config://changed";
+
+/// Emitted with a `SessionSummary` when a session is added to the store,
+/// including forks, imports and sessions restored from the trash
+pub const SESSION_CREATED_EVENT: &str = "store://session-created";
+
+/// Emitted with a `SessionSummary` when the title, settings or tags of a
+/// session change
+pub const SESSION_UPDATED_EVENT: &str = "store://session-updated";
+
+/// Emitted with a `SessionPayload` when a session is deleted
+pub const SESSION_DELETED_EVENT: &str = "store://session-deleted";
+
+/// Emitted with a `MessagePayload` when a message is added to a session
+pub const MESSAGE_ADDED_EVENT: &str = "store://message-added";
+
+/// Emitted with a `MessagePayload` when a message is edited, regenerated or
+/// switches variant
+pub const MESSAGE_UPDATED_EVENT: &str = "store://message-updated";
+
+/// Emitted with a `MessageDeletedPayload` when a message is deleted
+pub const MESSAGE_DELETED_EVENT: &str = "store://message-deleted";
+
+/// Emitted with a `MessageDeltaPayload` along with each of
+/// `MESSAGE_ADDED_EVENT`, `MESSAGE_UPDATED_EVENT` and `MESSAGE_DELETED_EVENT`,
+/// for windows patching their copy of a session rather than fetching it again
+pub const MESSAGE_DELTA_EVENT: &str = "store://message-delta";
+
+/// Emitted with a `MessageStatusPayload` when a User message is queued while
+/// offline, then sent or fails
+pub const MESSAGE_STATUS_EVENT: &str = "store://message-status";
+
+/// Emitted without a payload when every session is replaced, such as when an
+/// encrypted store is unlocked
+pub const STORE_RELOADED_EVENT: &str = "store://reloaded";
+
+/// Emitted with a `QueueDepthPayload` when a request to a rate limited
+/// provider joins or leaves its queue
+pub const QUEUE_DEPTH_EVENT: &str = "chat://queue-depth";
+
+/// Emitted with a `BudgetStatus` the first time each month that spending
+/// reaches the warning level of the monthly budget, and again when it reaches
+/// the limit
+pub const BUDGET_EVENT: &str = "chat://budget";
+
+/// Payload of `SESSION_DELETED_EVENT`
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionPayload {
+    pub session_id: SessionId,
+}
+
+/// Payload of `MESSAGE_ADDED_EVENT` and `MESSAGE_UPDATED_EVENT`. `message`
+/// is a copy of the message as it now is.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessagePayload {
+    pub session_id: SessionId,
+    pub message: Message,
+}
+
+/// Payload of `MESSAGE_DELETED_EVENT`
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageDeletedPayload {
+    pub session_id: SessionId,
+    pub message_id: MessageId,
+}
+
+/// How a message changed in a `MessageDeltaPayload`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeltaOp {
+    Added,
+    Updated,
+    Deleted,
+}
+
+/// Payload of `MESSAGE_DELTA_EVENT`. `revision` counts the deltas of the
+/// session, starting from 1, so a window that sees a gap has missed one and
+/// fetches the session again. `position` is the index of the message in the
+/// session, and it and `message` are None for deleted messages.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageDeltaPayload {
+    pub session_id: SessionId,
+    pub revision: u64,
+    pub op: DeltaOp,
+    pub message_id: MessageId,
+    pub position: Option<usize>,
+    pub message: Option<Message>,
+}
+
+/// Payload of `MESSAGE_STATUS_EVENT`
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageStatusPayload {
+    pub session_id: SessionId,
+    pub message_id: MessageId,
+    pub status: MessageStatus,
+}
+
+/// Payload of `QUEUE_DEPTH_EVENT`. `provider` is None for the default
+/// provider, and `depth` the number of requests waiting to be sent.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueDepthPayload {
+    pub provider: Option<String>,
+    pub depth: usize,
+}
+
+/// A change made to a store. Each is emitted to every window as the event
+/// named by `name`, so windows showing the same sessions stay in sync
+/// without polling.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum StoreEvent {
+    SessionCreated(SessionSummary),
+    SessionUpdated(SessionSummary),
+    SessionDeleted(SessionPayload),
+    MessageAdded(MessagePayload),
+    MessageUpdated(MessagePayload),
+    MessageDeleted(MessageDeletedPayload),
+    MessageDelta(MessageDeltaPayload),
+    MessageStatus(MessageStatusPayload),
+    Reloaded,
+    /// Not a change to the store's contents, but emitted along with them so
+    /// every window can show how busy each provider is
+    QueueDepth(QueueDepthPayload),
+    /// Not a change to the store's contents either, but a warning that the
+    /// monthly budget is running out
+    Budget(BudgetStatus),
+}
+
+impl StoreEvent {
+    /// Returns the name of the Tauri event this change is emitted as
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoreEvent::SessionCreated(_) => SESSION_CREATED_EVENT,
+            StoreEvent::SessionUpdated(_) => SESSION_UPDATED_EVENT,
+            StoreEvent::SessionDeleted(_) => SESSION_DELETED_EVENT,
+            StoreEvent::MessageAdded(_) => MESSAGE_ADDED_EVENT,
+            StoreEvent::MessageUpdated(_) => MESSAGE_UPDATED_EVENT,
+            StoreEvent::MessageDeleted(_) => MESSAGE_DELETED_EVENT,
+            StoreEvent::MessageDelta(_) => MESSAGE_DELTA_EVENT,
+            StoreEvent::MessageStatus(_) => MESSAGE_STATUS_EVENT,
+            StoreEvent::Reloaded => STORE_RELOADED_EVENT,
+            StoreEvent::QueueDepth(_) => QUEUE_DEPTH_EVENT,
+            StoreEvent::Budget(_) => BUDGET_EVENT,
+        }
+    }
+}
+
+/// Something told about every change made to a store
+pub trait StoreListener: fmt::Debug + Send + Sync {
+    fn on_event(&self, event: &StoreEvent);
+}
+
+/// Emits the changes made to a store to every window of the app
+#[derive(Clone)]
+pub struct AppEmitter(pub AppHandle);
+
+impl fmt::Debug for AppEmitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AppEmitter")
+    }
+}
+
+impl StoreListener for AppEmitter {
+    fn on_event(&self, event: &StoreEvent) {
+        if let Err(e) = self.0.emit_all(event.name(), event.clone()) {
+            eprintln!("Could not emit {}: {}", event.name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::ChatRole;
+    use crate::Store;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the names of the events it is told about
+    #[derive(Debug, Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<&'static str>>>);
+
+    impl StoreListener for Recorder {
+        fn on_event(&self, event: &StoreEvent) {
+            self.0.lock().unwrap().push(event.name());
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<&'static str> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    /// Records the message deltas it is told about
+    #[derive(Debug, Clone, Default)]
+    struct DeltaRecorder(Arc<Mutex<Vec<MessageDeltaPayload>>>);
+
+    impl StoreListener for DeltaRecorder {
+        fn on_event(&self, event: &StoreEvent) {
+            if let StoreEvent::MessageDelta(delta) = event {
+                self.0.lock().unwrap().push(delta.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn test_store_emits_message_deltas() {
+        let recorder = DeltaRecorder::default();
+        let mut store = Store::new(MockProvider::default());
+        let id = store.add_empty_session(String::from("Patched"), "gpt-3.5-turbo");
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("First")),
+                (ChatRole::User, String::from("Second")),
+            ]);
+        store.set_listener(recorder.clone());
+        let first = store.get_session(id).unwrap().get_messages()[0].get_id();
+
+        store.delete_message(id, first).unwrap();
+        store.restore_message(id, first).unwrap();
+
+        let deltas = recorder.0.lock().unwrap().clone();
+        let ops: Vec<(u64, DeltaOp, Option<usize>)> = deltas
+            .iter()
+            .map(|x| (x.revision, x.op, x.position))
+            .collect();
+        assert_eq!(
+            vec![(1, DeltaOp::Deleted, None), (2, DeltaOp::Added, Some(0))],
+            ops
+        );
+        assert!(deltas.iter().all(|x| x.message_id == first));
+        assert_eq!(
+            Some("First".to_string()),
+            deltas[1].message.as_ref().map(|x| x.plain_text())
+        );
+        assert_eq!(2, store.session_revision(id));
+        assert_eq!(0, store.session_revision(SessionId::generate()));
+    }
+
+    #[test]
+    fn test_store_counts_revisions_without_listener() {
+        let mut store = Store::new(MockProvider::default());
+        let id = store.add_empty_session(String::from("Unwatched"), "gpt-3.5-turbo");
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![(ChatRole::User, String::from("First"))]);
+        let first = store.get_session(id).unwrap().get_messages()[0].get_id();
+
+        store.delete_message(id, first).unwrap();
+        store.restore_message(id, first).unwrap();
+        assert_eq!(2, store.session_revision(id));
+
+        // A listener set later carries on from the same revision
+        let recorder = DeltaRecorder::default();
+        store.set_listener(recorder.clone());
+        store.delete_message(id, first).unwrap();
+        let deltas = recorder.0.lock().unwrap().clone();
+        assert_eq!(
+            vec![3],
+            deltas.iter().map(|x| x.revision).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_store_emits_events() {
+        let recorder = Recorder::default();
+        let mut store = Store::new(MockProvider::default());
+        store.set_listener(recorder.clone());
+
+        let id = store.add_empty_session(String::from("Synced"), "gpt-3.5-turbo");
+        store.rename_session(id, String::from("Renamed")).unwrap();
+        store.add_session_tag(id, "work").unwrap();
+        store.add_session_tag(id, "work").unwrap();
+        assert_eq!(
+            vec![
+                SESSION_CREATED_EVENT,
+                SESSION_UPDATED_EVENT,
+                SESSION_UPDATED_EVENT
+            ],
+            recorder.take()
+        );
+
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![(ChatRole::User, String::from("Hello"))]);
+        let message_id = store.get_session(id).unwrap().get_messages()[0].get_id();
+        store.delete_message(id, message_id).unwrap();
+        store.restore_message(id, message_id).unwrap();
+        store.delete_session(id);
+        store.delete_session(id);
+        assert_eq!(
+            vec![
+                MESSAGE_DELETED_EVENT,
+                MESSAGE_DELTA_EVENT,
+                MESSAGE_ADDED_EVENT,
+                MESSAGE_DELTA_EVENT,
+                SESSION_DELETED_EVENT
+            ],
+            recorder.take()
+        );
+
+        let payload = StoreEvent::MessageDeleted(MessageDeletedPayload {
+            session_id: id,
+            message_id,
+        });
+        assert_eq!(
+            serde_json::json!({
+                "session_id": id.to_string(),
+                "message_id": message_id.to_string(),
+            }),
+            serde_json::to_value(payload).unwrap()
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! The global quick-ask hotkey that pops the overlay up from anywhere.
+//!
+//! Pressing the hotkey shows and focuses the overlay window, or hides it if it
+//! already has focus. The overlay is brought up on the monitor the user is
+//! working on. Scratch sessions the hotkey starts are ephemeral: they are
+//! never saved, and are discarded when the overlay hides unless promoted.
+//!
+//! The binding is saved to its own file in the app config directory so it can
+//! be changed from the frontend. The hotkey can be paused from the tray,
+//! leaving the key combination to other apps until resumed.
+
+use crate::app_config::AppConfigState;
+use crate::commands::{StoreState, DEFAULT_MODEL};
+use crate::events::{FocusInputPayload, FOCUS_INPUT_EVENT};
+use crate::persistence::JsonConfig;
+use crate::{monitors, tray, ChatError};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, GlobalShortcutManager, Manager};
+
+/// Binding used until the user picks another one
+pub const DEFAULT_HOTKEY: &str = "CmdOrCtrl+Shift+Space";
+
+/// Label of the overlay window the hotkey toggles
+pub const OVERLAY_WINDOW: &str = "main";
+
+/// Name of the file the hotkey is saved to inside the app config directory
+pub const HOTKEY_FILE_NAME: &str = "hotkey.json";
+
+/// Title of the sessions started by the hotkey
+const SCRATCH_TITLE: &str = "Scratch";
+
+/// The session the overlay input is focused on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputSession {
+    /// The session already open
+    Current,
+    /// A new session, saved like any other
+    New,
+    /// A new ephemeral session
+    Scratch,
+}
+
+/// How the quick-ask hotkey behaves
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotkeyConfig {
+    /// The key combination, in Tauri's accelerator format such as
+    /// `"CmdOrCtrl+Shift+Space"`
+    pub accelerator: String,
+
+    /// Whether showing the overlay starts a new, empty session
+    pub scratch_session: bool,
+}
+
+impl Default for HotkeyConfig {
+    fn default() -> Self {
+        HotkeyConfig {
+            accelerator: DEFAULT_HOTKEY.to_string(),
+            scratch_session: false,
+        }
+    }
+}
+
+impl JsonConfig for HotkeyConfig {}
+
+/// The Tauri managed state holding the registered hotkey
+#[derive(Debug)]
+pub struct HotkeyState {
+    config: Mutex<HotkeyConfig>,
+    path: PathBuf,
+    /// Whether the hotkey is unregistered until resumed. Not saved, so the
+    /// hotkey is always registered at startup.
+    paused: Mutex<bool>,
+}
+
+impl HotkeyState {
+    /// Loads the hotkey saved inside `app_config_dir`
+    pub fn in_app_config_dir(app_config_dir: &Path) -> Result<HotkeyState, ChatError> {
+        let path = app_config_dir.join(HOTKEY_FILE_NAME);
+
+        Ok(HotkeyState {
+            config: Mutex::new(HotkeyConfig::load(&path)?),
+            path,
+            paused: Mutex::new(false),
+        })
+    }
+
+    /// Returns a copy of the current hotkey config
+    pub fn get_config(&self) -> HotkeyConfig {
+        self.lock().clone()
+    }
+
+    /// Registers the saved hotkey with the OS
+    pub fn register(&self, app: &AppHandle) -> Result<(), tauri::Error> {
+        register(app, &self.lock())
+    }
+
+    /// Returns whether the hotkey is paused
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Unregisters the hotkey until it is resumed, or registers it again if
+    /// not `paused`
+    pub fn set_paused(&self, app: &AppHandle, paused: bool) -> Result<(), String> {
+        let current = self.lock();
+        let mut is_paused = self.paused.lock().unwrap_or_else(|e| e.into_inner());
+        if *is_paused == paused {
+            return Ok(());
+        }
+
+        match paused {
+            true => app
+                .global_shortcut_manager()
+                .unregister(&current.accelerator),
+            false => register(app, &current),
+        }
+        .map_err(|e| e.to_string())?;
+        *is_paused = paused;
+
+        Ok(())
+    }
+
+    /// Replaces the hotkey with `config`, registering it in place of the
+    /// current one and saving it. The current hotkey is kept if `config`
+    /// can not be registered. A paused hotkey is only saved, and registered
+    /// once resumed.
+    pub fn set_config(&self, app: &AppHandle, config: HotkeyConfig) -> Result<(), String> {
+        let mut current = self.lock();
+        if self.is_paused() {
+            config.save(&self.path).map_err(|e| e.to_string())?;
+            *current = config;
+            return Ok(());
+        }
+        let mut shortcuts = app.global_shortcut_manager();
+
+        shortcuts
+            .unregister(&current.accelerator)
+            .map_err(|e| e.to_string())?;
+        if let Err(e) = register(app, &config) {
+            register(app, &current).map_err(|e| e.to_string())?;
+            return Err(e.to_string());
+        }
+
+        config.save(&self.path).map_err(|e| e.to_string())?;
+        *current = config;
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HotkeyConfig> {
+        // The config is only ever replaced whole, so a poisoned lock is still usable
+        self.config.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Registers `config` with the OS so pressing it toggles the overlay window
+fn register(app: &AppHandle, config: &HotkeyConfig) -> Result<(), tauri::Error> {
+    let handle = app.clone();
+    let scratch_session = config.scratch_session;
+
+    app.global_shortcut_manager()
+        .register(&config.accelerator, move || {
+            toggle_overlay(&handle, scratch_session)
+        })
+}
+
+/// Hides the overlay window if it has focus. Otherwise moves it to the
+/// monitor the user is working on, shows and focuses it, then asks the
+/// frontend to focus the input, starting an ephemeral scratch session first
+/// if `scratch_session` is set.
+fn toggle_overlay(app: &AppHandle, scratch_session: bool) {
+    let Some(window) = app.get_window(OVERLAY_WINDOW) else {
+        eprintln!("No overlay window to toggle");
+        return;
+    };
+
+    if window.is_visible().unwrap_or(false) && window.is_focused().unwrap_or(false) {
+        tray::hide_overlay(app);
+        return;
+    }
+
+    monitors::follow_active_monitor(app);
+    tray::show_overlay(app);
+    let session = match scratch_session {
+        true => InputSession::Scratch,
+        false => InputSession::Current,
+    };
+    focus_input(app, session);
+}
+
+/// Asks the frontend of the overlay window to focus its input on `session`,
+/// starting it first if it is new
+pub(crate) fn focus_input(app: &AppHandle, session: InputSession) {
+    let Some(window) = app.get_window(OVERLAY_WINDOW) else {
+        return;
+    };
+
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let session_id = if session != InputSession::Current {
+            let model = app.try_state::<AppConfigState>().map_or_else(
+                || DEFAULT_MODEL.to_string(),
+                |x| x.get_config().default_model,
+            );
+            let state = app.state::<StoreState>();
+            let mut store = state.write().await;
+            let title = SCRATCH_TITLE.to_string();
+            Some(match session {
+                InputSession::Scratch => store.add_ephemeral_session(title, &model),
+                _ => store.add_empty_session(title, &model),
+            })
+        } else {
+            None
+        };
+
+        if let Err(e) = window.emit(FOCUS_INPUT_EVENT, FocusInputPayload { session_id }) {
+            eprintln!("Could not focus the overlay input: {}", e);
+        }
+    });
+}
+
+/// Discards the ephemeral sessions of `app`'s store, once the overlay they
+/// were started in is hidden
+pub(crate) fn discard_scratch_sessions(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let Some(state) = app.try_state::<StoreState>() else {
+            return;
+        };
+        state.write().await.discard_ephemeral_sessions();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_hotkey_config_round_trip() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-hotkey-{}", nanos));
+        let path = dir.join(HOTKEY_FILE_NAME);
+
+        assert_eq!(HotkeyConfig::default(), HotkeyConfig::load(&path).unwrap());
+
+        let config = HotkeyConfig {
+            accelerator: String::from("Alt+Space"),
+            scratch_session: true,
+        };
+        config.save(&path).unwrap();
+        assert_eq!(config, HotkeyConfig::load(&path).unwrap());
+
+        // Fields missing from older files fall back to their defaults
+        fs::write(&path, r#"{"accelerator": "Alt+Q"}"#).unwrap();
+        let loaded = HotkeyConfig::load(&path).unwrap();
+        assert_eq!("Alt+Q", loaded.accelerator);
+        assert!(!loaded.scratch_session);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Ids of sessions, messages and memories.
+//!
+//! Ids are UUIDv7s, which start with the time they were made, so they stay
+//! unique across runs and devices and sort in the order they were created.
+//! Each kind of item has its own id type so one can not be passed where
+//! another is expected. Ids are sent to the frontend and saved
+//! as hyphenated strings.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use uuid::{NoContext, Timestamp, Uuid};
+
+macro_rules! id_type {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(Uuid);
+
+        impl $name {
+            /// Creates a new id, sorting after every id created before it
+            pub fn generate() -> $name {
+                $name(Uuid::now_v7())
+            }
+
+            /// Creates a new id for something created at the unix timestamp
+            /// `secs`, such as an imported message
+            #[allow(dead_code)] // Not every kind of item is ever made in the past
+            pub(crate) fn from_timestamp(secs: u64) -> $name {
+                $name(Uuid::new_v7(Timestamp::from_unix(NoContext, secs, 0)))
+            }
+
+            /// Returns the UUID this id wraps
+            pub fn as_uuid(&self) -> Uuid {
+                self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = uuid::Error;
+
+            fn from_str(s: &str) -> Result<$name, uuid::Error> {
+                Uuid::parse_str(s).map($name)
+            }
+        }
+    };
+}
+
+id_type!(
+    /// The id of a chat session
+    SessionId
+);
+
+id_type!(
+    /// The id of a message
+    MessageId
+);
+
+id_type!(
+    /// The id of a remembered fact
+    MemoryId
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_sort_by_creation() {
+        let first = SessionId::generate();
+        let second = SessionId::generate();
+        assert!(first < second);
+
+        let old = MessageId::from_timestamp(1_700_000_000);
+        assert!(old < MessageId::generate());
+        assert_eq!(Some(uuid::Version::SortRand), old.as_uuid().get_version());
+
+        let text = first.to_string();
+        assert_eq!(Ok(first), text.parse());
+        assert_eq!(
+            serde_json::Value::from(text),
+            serde_json::to_value(first).unwrap()
+        );
+        assert!("7".parse::<SessionId>().is_err());
+    }
+}
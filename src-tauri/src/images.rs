@@ -0,0 +1,265 @@
+//! Generating images from prompts.
+//!
+//! Sessions in `SessionMode::Image` send their prompts to the provider's
+//! image endpoint instead of the chat model. Each generated image is saved as
+//! a PNG inside the app data directory and recorded in the session as an
+//! Assistant message holding the image.
+//!
+//! Like chat requests, an image request is prepared by the store, awaited
+//! without holding on to it, then committed back.
+
+use crate::cancellation::CancellationToken;
+use crate::ids::{MessageId, SessionId};
+use crate::providers::LlmProvider;
+use crate::retry::{self, RetryPolicy};
+use crate::ChatError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the directory generated images are saved to inside the app data
+/// directory
+pub const GENERATED_IMAGES_DIR_NAME: &str = "generated_images";
+
+/// Model used to generate images unless another one is picked
+pub const DEFAULT_IMAGE_MODEL: &str = "dall-e-3";
+
+/// What a session's prompts are sent to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionMode {
+    /// The chat model, which replies with text
+    #[default]
+    Chat,
+
+    /// The image endpoint, which replies with a generated image
+    Image,
+}
+
+/// Dimensions of a generated image, in pixels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageSize {
+    #[serde(rename = "256x256")]
+    S256x256,
+    #[serde(rename = "512x512")]
+    S512x512,
+    #[default]
+    #[serde(rename = "1024x1024")]
+    S1024x1024,
+    #[serde(rename = "1792x1024")]
+    S1792x1024,
+    #[serde(rename = "1024x1792")]
+    S1024x1792,
+}
+
+/// How much detail goes into a generated image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageQuality {
+    #[default]
+    Standard,
+    Hd,
+}
+
+/// How an image is generated
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageOptions {
+    pub model: String,
+    pub size: ImageSize,
+    pub quality: ImageQuality,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            model: DEFAULT_IMAGE_MODEL.to_string(),
+            size: ImageSize::default(),
+            quality: ImageQuality::default(),
+        }
+    }
+}
+
+impl ImageOptions {
+    /// Returns the price in USD of an image generated with these options, or
+    /// None if it is unknown
+    pub fn price(&self) -> Option<f64> {
+        use ImageQuality::*;
+        use ImageSize::*;
+
+        match (self.model.as_str(), self.quality, self.size) {
+            ("dall-e-2", _, S256x256) => Some(0.016),
+            ("dall-e-2", _, S512x512) => Some(0.018),
+            ("dall-e-2", _, S1024x1024) => Some(0.02),
+            ("dall-e-3", Standard, S1024x1024) => Some(0.04),
+            ("dall-e-3", Standard, S1792x1024 | S1024x1792) => Some(0.08),
+            ("dall-e-3", Hd, S1024x1024) => Some(0.08),
+            ("dall-e-3", Hd, S1792x1024 | S1024x1792) => Some(0.12),
+            _ => None,
+        }
+    }
+}
+
+/// An image returned by a provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedImage {
+    /// The PNG's bytes, base64 encoded
+    pub base64: String,
+
+    /// The prompt the image was actually generated from, if the provider
+    /// rewrote it
+    pub revised_prompt: Option<String>,
+}
+
+/// An image request prepared by the store. Await the image with `generate`,
+/// then hand the result to `Store::commit_image`.
+#[derive(Debug)]
+pub struct ImageRequest {
+    pub(crate) session_id: SessionId,
+    pub(crate) provider: Arc<dyn LlmProvider>,
+    pub(crate) prompt: String,
+    pub(crate) options: ImageOptions,
+    /// The message the image replaces, if it is regenerated
+    pub(crate) replaces: Option<MessageId>,
+    pub(crate) retry_policy: RetryPolicy,
+}
+
+/// A generated image saved to disk, waiting to be committed to the store
+#[derive(Debug)]
+pub struct CompletedImage {
+    pub(crate) session_id: SessionId,
+    pub(crate) prompt: String,
+    pub(crate) replaces: Option<MessageId>,
+    pub(crate) path: PathBuf,
+    pub(crate) revised_prompt: Option<String>,
+    /// The model that generated the image
+    pub(crate) model: String,
+    /// Cost in USD of the image, if the model's price is known
+    pub(crate) cost: Option<f64>,
+}
+
+impl ImageRequest {
+    /// Returns the id of the session this request was prepared for
+    pub fn get_session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    /// Waits for the generated image and saves it inside `dir`, retrying
+    /// transient failures as the store's retry policy allows. Returns
+    /// `ChatError::Cancelled` if `cancel` is cancelled before it arrives.
+    pub async fn generate(
+        self,
+        dir: &Path,
+        cancel: &CancellationToken,
+    ) -> Result<CompletedImage, ChatError> {
+        let (image, _) = retry::retry(&self.retry_policy, cancel, || {
+            self.provider.generate_image(&self.prompt, &self.options)
+        })
+        .await?;
+
+        let path = save_png(&image.base64, dir)?;
+
+        Ok(CompletedImage {
+            session_id: self.session_id,
+            prompt: self.prompt,
+            replaces: self.replaces,
+            path,
+            revised_prompt: image.revised_prompt,
+            cost: self.options.price(),
+            model: self.options.model,
+        })
+    }
+}
+
+/// Decodes the base64 encoded PNG and saves it inside `dir`, returning its
+/// path
+fn save_png(base64: &str, dir: &Path) -> Result<PathBuf, ChatError> {
+    let bytes = STANDARD
+        .decode(base64)
+        .map_err(|e| ChatError::Image(e.to_string()))?;
+
+    fs::create_dir_all(dir).map_err(|e| ChatError::Image(e.to_string()))?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos());
+    let path = dir.join(format!("generated-{}.png", nanos));
+
+    fs::write(&path, bytes).map_err(|e| ChatError::Image(e.to_string()))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::MessageContent;
+    use crate::providers::mock::MockProvider;
+    use crate::ChatRole;
+    use crate::Store;
+
+    #[tokio::test]
+    async fn test_store_generate_and_regenerate_image() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-images-{}", nanos));
+        let cancel = CancellationToken::new();
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider.clone());
+        let id = store.add_empty_session(String::from("Art"), "dall-e-3");
+        store.set_session_mode(id, SessionMode::Image).unwrap();
+
+        let request = store
+            .prepare_image(id, String::from("a cat"), ImageOptions::default())
+            .unwrap();
+        let completed = request.generate(&dir, &cancel).await.unwrap();
+        let image = store.commit_image(completed).unwrap();
+
+        assert_eq!(ChatRole::Assistant, image.get_role());
+        assert_eq!("A painting of a cat", image.plain_text());
+        assert_eq!(Some(DEFAULT_IMAGE_MODEL), image.get_model());
+        let first = image.get_image().unwrap().to_path_buf();
+        assert_eq!("a cat", fs::read_to_string(&first).unwrap());
+
+        let options = ImageOptions {
+            size: ImageSize::S512x512,
+            ..Default::default()
+        };
+        let request = store
+            .prepare_image_regenerate(id, image.get_id(), options)
+            .unwrap();
+        let completed = request.generate(&dir, &cancel).await.unwrap();
+        let regenerated = store.commit_image(completed).unwrap();
+
+        let messages = store.get_session(id).unwrap().get_messages();
+        assert_eq!(2, messages.len());
+        assert_eq!("a cat", messages[0].plain_text());
+        assert_eq!(image.get_id(), regenerated.get_id());
+        let second = regenerated.get_image().unwrap();
+        assert_eq!("a cat", fs::read_to_string(second).unwrap());
+        let sizes: Vec<ImageSize> = provider
+            .get_images()
+            .into_iter()
+            .map(|(_, options)| options.size)
+            .collect();
+        assert_eq!(vec![ImageSize::S1024x1024, ImageSize::S512x512], sizes);
+
+        // Only images can be regenerated
+        assert_eq!(
+            Some(ChatError::NotAnImage(messages[0].get_id())),
+            store
+                .prepare_image_regenerate(id, messages[0].get_id(), ImageOptions::default())
+                .err()
+        );
+        assert!(matches!(
+            messages[1].get_parts(),
+            [MessageContent::Image { .. }, MessageContent::Text { .. }]
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
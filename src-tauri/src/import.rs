@@ -0,0 +1,369 @@
+//! Importing conversations from other chat apps.
+//!
+//! ChatGPT's data export holds every conversation in `conversations.json`.
+//! Each conversation is a tree of messages, as edited and regenerated
+//! messages branch off, with `current_node` pointing at the end of the branch
+//! last shown. Only that branch is imported. Messages ChatGPT hides, such as
+//! tool output and empty system messages, are left out.
+//!
+//! Imported conversations start like sessions started in the app do, with a
+//! User message. System messages before it become the session's system
+//! prompt, and responses before it are left out.
+
+use crate::role::ChatRole;
+use crate::ChatError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Name of the file holding the conversations in a ChatGPT data export
+pub const CHATGPT_CONVERSATIONS_FILE: &str = "conversations.json";
+
+/// Title given to imported conversations that have none
+const UNTITLED: &str = "Imported chat";
+
+/// A conversation read from an export, ready to become a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedConversation {
+    pub title: String,
+
+    /// The unix timestamp when the conversation was started
+    pub created_at: u64,
+
+    /// The chat model that wrote the last response, if the export says
+    pub model: Option<String>,
+
+    /// The System messages sent before the first User message, joined
+    pub system_prompt: Option<String>,
+
+    /// The messages, oldest first
+    pub messages: Vec<ImportedMessage>,
+}
+
+/// A message read from an export
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedMessage {
+    pub role: ChatRole,
+    pub text: String,
+
+    /// The unix timestamp when the message was sent
+    pub created_at: u64,
+}
+
+#[derive(Deserialize)]
+struct Conversation {
+    title: Option<String>,
+    create_time: Option<f64>,
+    #[serde(default)]
+    mapping: HashMap<String, Node>,
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    message: Option<NodeMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NodeMessage {
+    author: Author,
+    create_time: Option<f64>,
+    content: Option<NodeContent>,
+    #[serde(default)]
+    metadata: NodeMetadata,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct NodeContent {
+    content_type: String,
+    /// Text parts are strings, other parts such as uploaded images are
+    /// objects
+    #[serde(default)]
+    parts: Vec<serde_json::Value>,
+}
+
+#[derive(Default, Deserialize)]
+struct NodeMetadata {
+    model_slug: Option<String>,
+    #[serde(default)]
+    is_visually_hidden_from_conversation: bool,
+}
+
+/// Reads the conversations of the ChatGPT data export at `path`. `path` is
+/// either `conversations.json` or the extracted export directory holding it.
+pub fn read_chatgpt_export(path: &Path) -> Result<Vec<ImportedConversation>, ChatError> {
+    let path = if path.is_dir() {
+        path.join(CHATGPT_CONVERSATIONS_FILE)
+    } else {
+        path.to_path_buf()
+    };
+    let contents = fs::read(&path).map_err(|e| ChatError::Import(e.to_string()))?;
+
+    parse_chatgpt_export(&contents)
+}
+
+/// Parses `contents`, the contents of `conversations.json` from a ChatGPT
+/// data export. Conversations without any visible message are left out.
+pub fn parse_chatgpt_export(contents: &[u8]) -> Result<Vec<ImportedConversation>, ChatError> {
+    let conversations: Vec<Conversation> =
+        serde_json::from_slice(contents).map_err(|e| ChatError::Import(e.to_string()))?;
+
+    Ok(conversations
+        .into_iter()
+        .map(convert)
+        .filter(|x| !x.messages.is_empty())
+        .collect())
+}
+
+fn convert(conversation: Conversation) -> ImportedConversation {
+    let created_at = to_timestamp(conversation.create_time);
+    let mut model = None;
+    let mut messages = vec![];
+
+    // Walk from the last message shown back to the root, then reverse
+    // Each node is visited at most once, in case the parents of a malformed
+    // export form a cycle
+    let mut next = conversation.current_node;
+    let mut steps = 0;
+    while let Some(node) = next.and_then(|id| conversation.mapping.get(&id)) {
+        steps += 1;
+        if steps > conversation.mapping.len() {
+            break;
+        }
+        next = node.parent.clone();
+
+        let Some(message) = &node.message else {
+            continue;
+        };
+        if model.is_none() && message.author.role == "assistant" {
+            model = message.metadata.model_slug.clone();
+        }
+        if let Some(message) = to_message(message, created_at) {
+            messages.push(message);
+        }
+    }
+    messages.reverse();
+    let system_prompt = normalize_roles(&mut messages);
+
+    let title = conversation
+        .title
+        .filter(|x| !x.trim().is_empty())
+        .unwrap_or_else(|| UNTITLED.to_string());
+
+    ImportedConversation {
+        title,
+        created_at,
+        model,
+        system_prompt,
+        messages,
+    }
+}
+
+/// Removes the messages before the first User message in `messages`,
+/// returning the System messages among them joined by blank lines, or None
+/// if there are none. Every message is removed if none is a User message.
+fn normalize_roles(messages: &mut Vec<ImportedMessage>) -> Option<String> {
+    let start = messages
+        .iter()
+        .position(|x| x.role == ChatRole::User)
+        .unwrap_or(messages.len());
+
+    let system: Vec<String> = messages
+        .drain(..start)
+        .filter(|x| x.role == ChatRole::System)
+        .map(|x| x.text)
+        .collect();
+
+    (!system.is_empty()).then(|| system.join("\n\n"))
+}
+
+/// Converts `message` if it is shown in ChatGPT. Messages without a time of
+/// their own are dated `fallback`.
+fn to_message(message: &NodeMessage, fallback: u64) -> Option<ImportedMessage> {
+    if message.metadata.is_visually_hidden_from_conversation {
+        return None;
+    }
+
+    let role = match message.author.role.as_str() {
+        "system" => ChatRole::System,
+        "user" => ChatRole::User,
+        "assistant" => ChatRole::Assistant,
+        _ => return None,
+    };
+
+    let content = message.content.as_ref()?;
+    if !matches!(content.content_type.as_str(), "text" | "multimodal_text") {
+        return None;
+    }
+    let text = content
+        .parts
+        .iter()
+        .filter_map(|x| x.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    let created_at = match message.create_time {
+        Some(_) => to_timestamp(message.create_time),
+        None => fallback,
+    };
+
+    Some(ImportedMessage {
+        role,
+        text,
+        created_at,
+    })
+}
+
+/// Converts a time in fractional unix seconds, or 0 if missing or invalid
+fn to_timestamp(time: Option<f64>) -> u64 {
+    time.filter(|x| x.is_finite() && *x >= 0.0)
+        .map_or(0, |x| x as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const EXPORT: &str = r#"[{
+        "title": "Rust lifetimes",
+        "create_time": 1700000000.5,
+        "current_node": "c",
+        "mapping": {
+            "root": {"id": "root", "message": null, "parent": null, "children": ["s"]},
+            "s": {"id": "s", "parent": "root", "children": ["u"], "message": {
+                "author": {"role": "system"}, "create_time": null,
+                "content": {"content_type": "text", "parts": [""]},
+                "metadata": {"is_visually_hidden_from_conversation": true}}},
+            "u": {"id": "u", "parent": "s", "children": ["a", "c"], "message": {
+                "author": {"role": "user"}, "create_time": 1700000001.2,
+                "content": {"content_type": "multimodal_text",
+                    "parts": [{"asset_pointer": "file-service://x"}, "What is 'a?"]},
+                "metadata": {}}},
+            "a": {"id": "a", "parent": "u", "children": [], "message": {
+                "author": {"role": "assistant"}, "create_time": 1700000002,
+                "content": {"content_type": "text", "parts": ["A discarded answer"]},
+                "metadata": {"model_slug": "gpt-3.5-turbo"}}},
+            "c": {"id": "c", "parent": "u", "children": [], "message": {
+                "author": {"role": "assistant"}, "create_time": 1700000003,
+                "content": {"content_type": "text", "parts": ["A lifetime."]},
+                "metadata": {"model_slug": "gpt-4"}}}
+        }
+    }, {
+        "title": null, "create_time": null, "current_node": null, "mapping": {}
+    }]"#;
+
+    #[test]
+    fn test_parse_chatgpt_export() {
+        let conversations = parse_chatgpt_export(EXPORT.as_bytes()).unwrap();
+
+        assert_eq!(1, conversations.len());
+        let conversation = &conversations[0];
+        assert_eq!("Rust lifetimes", conversation.title);
+        assert_eq!(1700000000, conversation.created_at);
+        assert_eq!(Some("gpt-4"), conversation.model.as_deref());
+        assert_eq!(
+            vec![
+                ImportedMessage {
+                    role: ChatRole::User,
+                    text: String::from("What is 'a?"),
+                    created_at: 1700000001,
+                },
+                ImportedMessage {
+                    role: ChatRole::Assistant,
+                    text: String::from("A lifetime."),
+                    created_at: 1700000003,
+                },
+            ],
+            conversation.messages
+        );
+
+        assert_eq!(None, conversation.system_prompt);
+
+        assert!(matches!(
+            parse_chatgpt_export(b"{}"),
+            Err(ChatError::Import(_))
+        ));
+    }
+
+    #[test]
+    fn test_normalize_roles() {
+        let message = |role: ChatRole, text: &str| ImportedMessage {
+            role,
+            text: String::from(text),
+            created_at: 0,
+        };
+        let mut messages = vec![
+            message(ChatRole::System, "Be brief."),
+            message(ChatRole::Assistant, "Hello! How can I help?"),
+            message(ChatRole::System, "Use British spelling."),
+            message(ChatRole::User, "Hi"),
+            message(ChatRole::System, "Kept"),
+            message(ChatRole::Assistant, "Hello"),
+        ];
+
+        assert_eq!(
+            Some("Be brief.\n\nUse British spelling."),
+            normalize_roles(&mut messages).as_deref()
+        );
+        let roles: Vec<ChatRole> = messages.iter().map(|x| x.role).collect();
+        assert_eq!(
+            vec![ChatRole::User, ChatRole::System, ChatRole::Assistant],
+            roles
+        );
+
+        let mut unanswered = vec![message(ChatRole::Assistant, "Hello")];
+        assert_eq!(None, normalize_roles(&mut unanswered));
+        assert!(unanswered.is_empty());
+    }
+
+    #[test]
+    fn test_store_import_chatgpt_export() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-import-{}", nanos));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(CHATGPT_CONVERSATIONS_FILE), EXPORT).unwrap();
+
+        let mut store = Store::new(MockProvider::default());
+        store.add_empty_session(String::from("Existing"), "gpt-3.5-turbo");
+
+        let mut progress = vec![];
+        let ids = store
+            .import_chatgpt_export(&dir, "gpt-3.5-turbo", |imported, total| {
+                progress.push((imported, total))
+            })
+            .unwrap();
+
+        assert_eq!(1, ids.len());
+        assert_eq!(vec![(1, 1)], progress);
+
+        let session = store.get_session(ids[0]).unwrap();
+        assert_eq!("Rust lifetimes", session.get_title());
+        assert_eq!(1700000000, session.get_created_at());
+        assert_eq!("gpt-4", session.get_model());
+        assert_eq!(2, session.get_messages().len());
+        assert_eq!(1700000003, session.get_messages()[1].get_created_at());
+
+        assert!(matches!(
+            store.import_chatgpt_export(&dir.join("missing.json"), "gpt-3.5-turbo", |_, _| {}),
+            Err(ChatError::Import(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+//! Sessions and messages kept in maps keyed by their ids.
+//!
+//! An `IndexMap` finds an item by id without scanning, while keeping the
+//! items in the order they were added for display. Maps are saved as plain
+//! lists of items, the layout used before they were keyed, so saved stores
+//! load unchanged.
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Deserializer, Serializer};
+use std::hash::Hash;
+
+/// Items keyed by their ids, in the order they were added
+pub type IdMap<T> = IndexMap<<T as Identified>::Id, T>;
+
+/// An item with an id unique among the items it is kept with
+pub trait Identified {
+    type Id: Copy + Eq + Hash;
+
+    fn id(&self) -> Self::Id;
+}
+
+/// Keys `items` by their ids, keeping their order. Later items replace
+/// earlier ones with the same id.
+pub fn from_items<T: Identified>(items: impl IntoIterator<Item = T>) -> IdMap<T> {
+    items.into_iter().map(|x| (x.id(), x)).collect()
+}
+
+/// Serializes `map` as a list of its items
+pub fn serialize<S: Serializer, T: serde::Serialize + Identified>(
+    map: &IdMap<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(map.values())
+}
+
+/// Deserializes a list of items into a map keyed by their ids
+pub fn deserialize<'de, D, T>(deserializer: D) -> Result<IdMap<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Identified,
+{
+    Vec::<T>::deserialize(deserializer).map(from_items)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ChatRole;
+    use crate::ChatSession;
+    use serde_json::Value;
+
+    #[test]
+    fn test_messages_saved_as_list() {
+        let mut chs = ChatSession::new(String::from("Keyed"), "gpt-3.5-turbo");
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("One")),
+            (ChatRole::Assistant, String::from("Two")),
+            (ChatRole::User, String::from("Three")),
+        ]);
+        let ids: Vec<_> = chs.get_messages().iter().map(|x| x.get_id()).collect();
+        chs.delete_message(ids[1]);
+
+        let saved = serde_json::to_value(&chs).unwrap();
+        let messages = saved["messages"].as_array().unwrap();
+        assert_eq!(2, messages.len());
+        assert_eq!(Value::from(ids[2].to_string()), messages[1]["id"]);
+
+        let loaded: ChatSession = serde_json::from_value(saved).unwrap();
+        assert_eq!("Three", loaded.get_message(ids[2]).unwrap().get_content());
+        assert!(loaded.get_message(ids[1]).is_none());
+        assert_eq!(
+            vec![ids[0], ids[2]],
+            loaded
+                .get_messages()
+                .iter()
+                .map(|x| x.get_id())
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Journaling changes to the store between full saves, for crash safety.
+//!
+//! Rather than rewriting the whole store file after every change, a
+//! `JsonFileBackend` appends the sessions that changed to a journal next to
+//! it, one line per save, and syncs it to disk. Only every
+//! `COMPACT_AFTER_ENTRIES` saves is the store file rewritten whole, after
+//! which the journal is emptied. When the store is loaded, a journal left
+//! behind is replayed on top of the stale store file and compacted into a
+//! fresh one. A line cut short by a crash mid-save is ignored, along with
+//! anything after it.
+//!
+//! Journal lines are encrypted with the key of the store file, if it has one.
+
+use crate::encryption::{self, EncryptionKey};
+use crate::ids::SessionId;
+use crate::memory::Memory;
+use crate::persistence::StoreSnapshot;
+use crate::prompts::PromptTemplate;
+use crate::trash::Trash;
+use crate::{ChatError, ChatSession};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::Path;
+
+/// Extension of the journal kept next to a store file
+pub const JOURNAL_EXTENSION: &str = "journal";
+
+/// The number of saves journaled before the store file is rewritten whole
+pub const COMPACT_AFTER_ENTRIES: usize = 100;
+
+/// A change recorded in the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Record {
+    /// A session was added or changed
+    Session { session: Box<ChatSession> },
+    /// The sessions were added, deleted or reordered. Sessions missing from
+    /// `ids` were deleted.
+    Order { ids: Vec<SessionId> },
+    /// The prompts, trash or memories changed
+    Library {
+        prompts: Vec<PromptTemplate>,
+        trash: Trash,
+        memories: Vec<Memory>,
+    },
+}
+
+/// The changes of a single save, written as one line of the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub records: Vec<Record>,
+}
+
+impl Entry {
+    /// Applies the changes of this entry to `snapshot`
+    pub fn apply(self, snapshot: &mut StoreSnapshot) {
+        for record in self.records {
+            match record {
+                Record::Session { session } => {
+                    match snapshot
+                        .sessions
+                        .iter_mut()
+                        .find(|x| x.get_id() == session.get_id())
+                    {
+                        Some(saved) => *saved = *session,
+                        None => snapshot.sessions.push(*session),
+                    }
+                }
+                Record::Order { ids } => {
+                    let mut sessions: HashMap<SessionId, ChatSession> = snapshot
+                        .sessions
+                        .drain(..)
+                        .map(|x| (x.get_id(), x))
+                        .collect();
+                    snapshot.sessions = ids.iter().filter_map(|x| sessions.remove(x)).collect();
+                }
+                Record::Library {
+                    prompts,
+                    trash,
+                    memories,
+                } => {
+                    snapshot.prompts = prompts;
+                    snapshot.trash = trash;
+                    snapshot.memories = memories;
+                }
+            }
+        }
+    }
+}
+
+/// What the store file and journal hold together, so each save only journals
+/// what changed since the last
+#[derive(Debug, Clone, Default)]
+pub struct JournalState {
+    /// Hashes of the saved sessions
+    sessions: HashMap<SessionId, u64>,
+    /// Ids of the saved sessions, in order
+    order: Vec<SessionId>,
+    /// Hash of the saved prompts, trash and memories
+    library: u64,
+    /// The number of entries in the journal
+    pub entries: usize,
+}
+
+impl JournalState {
+    /// Returns the state of a store file holding `snapshot` and an empty
+    /// journal
+    pub fn of(snapshot: &StoreSnapshot) -> JournalState {
+        let mut state = JournalState::default();
+        state.changes(snapshot);
+
+        state
+    }
+
+    /// Returns the records of what changed in `snapshot` since the state was
+    /// last updated, and updates it
+    pub fn changes(&mut self, snapshot: &StoreSnapshot) -> Vec<Record> {
+        let mut records = vec![];
+
+        let mut sessions = HashMap::new();
+        for session in &snapshot.sessions {
+            let hash = hash(session);
+            if self.sessions.get(&session.get_id()) != Some(&hash) {
+                records.push(Record::Session {
+                    session: Box::new(session.clone()),
+                });
+            }
+            sessions.insert(session.get_id(), hash);
+        }
+        self.sessions = sessions;
+
+        let order: Vec<SessionId> = snapshot.sessions.iter().map(|x| x.get_id()).collect();
+        if order != self.order {
+            records.push(Record::Order { ids: order.clone() });
+            self.order = order;
+        }
+
+        let library = hash(&(&snapshot.prompts, &snapshot.trash, &snapshot.memories));
+        if library != self.library {
+            records.push(Record::Library {
+                prompts: snapshot.prompts.clone(),
+                trash: snapshot.trash.clone(),
+                memories: snapshot.memories.clone(),
+            });
+            self.library = library;
+        }
+
+        records
+    }
+}
+
+/// Appends `entry` to the journal at `path` as a single line, encrypted with
+/// `key` if there is one, and syncs it to disk
+pub fn append(path: &Path, entry: &Entry, key: Option<&EncryptionKey>) -> Result<(), ChatError> {
+    let mut line =
+        serde_json::to_string(entry).map_err(|e| ChatError::Persistence(e.to_string()))?;
+    if let Some(key) = key {
+        // Encrypted contents are pretty printed, so they are compacted onto
+        // the line
+        let encrypted: Value = serde_json::from_slice(&key.encrypt(line.as_bytes())?)
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+        line = encrypted.to_string();
+    }
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| ChatError::Persistence(e.to_string()))?;
+    file.write_all(line.as_bytes())
+        .and_then(|_| file.sync_data())
+        .map_err(|e| ChatError::Persistence(e.to_string()))
+}
+
+/// Reads the entries of the journal at `path`, decrypting them with `key`.
+/// Stops at the first line that can not be read, such as one cut short by a
+/// crash. Returns no entries if there is no journal.
+pub fn read(path: &Path, key: Option<&EncryptionKey>) -> Result<Vec<Entry>, ChatError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(ChatError::Persistence(e.to_string())),
+    };
+
+    let mut entries = vec![];
+    for line in contents.lines() {
+        let line = match key {
+            _ if !encryption::is_encrypted(line.as_bytes()) => line.as_bytes().to_vec(),
+            Some(key) => match key.decrypt(line.as_bytes()) {
+                Ok(line) => line,
+                Err(_) => break,
+            },
+            None => break,
+        };
+        match serde_json::from_slice(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Deletes the journal at `path`, if there is one
+pub fn remove(path: &Path) -> Result<(), ChatError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(ChatError::Persistence(e.to_string())),
+    }
+}
+
+/// Hashes the JSON `value` is saved as
+fn hash<T: Serialize>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(value)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{JsonFileBackend, StorageBackend};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("chat-overlay-{}-{}", name, nanos))
+    }
+
+    #[test]
+    fn test_journal_replayed_over_stale_store() {
+        let dir = temp_dir("journal");
+        let backend = JsonFileBackend::in_app_data_dir(&dir);
+        let (first, second) = (
+            ChatSession::new(String::from("First"), "gpt-3.5-turbo"),
+            ChatSession::new(String::from("Second"), "gpt-3.5-turbo"),
+        );
+        let mut snapshot = StoreSnapshot {
+            sessions: vec![first.clone()],
+            ..Default::default()
+        };
+        backend.save(&snapshot).unwrap();
+        let saved = fs::read(backend.path()).unwrap();
+
+        // Later saves only reach the journal
+        snapshot.sessions.insert(0, second.clone());
+        backend.save(&snapshot).unwrap();
+        backend.save(&snapshot).unwrap();
+        snapshot.sessions.retain(|x| x.get_id() != first.get_id());
+        backend.save(&snapshot).unwrap();
+        assert_eq!(saved, fs::read(backend.path()).unwrap());
+        assert_eq!(2, read(&backend.journal_path(), None).unwrap().len());
+
+        // A line cut short by a crash is ignored
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(backend.journal_path())
+            .unwrap();
+        file.write_all(br#"{"records":[{"kind":"ord"#).unwrap();
+
+        let loaded = JsonFileBackend::in_app_data_dir(&dir)
+            .load()
+            .unwrap()
+            .unwrap();
+        let ids: Vec<SessionId> = loaded.sessions.iter().map(|x| x.get_id()).collect();
+        assert_eq!(vec![second.get_id()], ids);
+        // The journal was compacted into the store file
+        assert!(!backend.journal_path().exists());
+        assert_ne!(saved, fs::read(backend.path()).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_journal_encrypted() {
+        let dir = temp_dir("journal-encrypted");
+        let key = EncryptionKey::new("hunter2").unwrap();
+        let backend = JsonFileBackend::in_app_data_dir(&dir).with_key(Some(key));
+        let mut snapshot = StoreSnapshot::default();
+        backend.save(&snapshot).unwrap();
+
+        snapshot
+            .sessions
+            .push(ChatSession::new(String::from("Secret"), "gpt-3.5-turbo"));
+        backend.save(&snapshot).unwrap();
+        let journal = fs::read_to_string(backend.journal_path()).unwrap();
+        assert!(!journal.contains("Secret"));
+        assert_eq!(1, journal.lines().count());
+
+        let locked = JsonFileBackend::in_app_data_dir(&dir);
+        assert_eq!(Some(ChatError::StoreLocked), locked.load().err());
+        let loaded = locked.unlock("hunter2").unwrap().load().unwrap().unwrap();
+        assert_eq!(1, loaded.sessions.len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,47 +1,251 @@
-use async_openai::{
-    config::OpenAIConfig,
-    error::OpenAIError,
-    types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role},
-    Client,
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role};
+use budget::{Budget, BudgetConfig, BudgetStatus, SpendingLedger};
+use cache::{CacheConfig, ResponseCache};
+use cancellation::CancellationToken;
+use content::MessageContent;
+use embeddings::{
+    CompletedEmbeddings, EmbeddedQuery, Embedder, EmbeddingIndex, EmbeddingRequest, SemanticMatch,
+    SemanticQuery,
 };
+use encryption::EncryptionKey;
+use events::{
+    DeltaOp, MessageDeletedPayload, MessageDeltaPayload, MessagePayload, MessageStatusPayload,
+    SessionPayload, StoreEvent, StoreListener,
+};
+use ids::{MemoryId, MessageId, SessionId};
+use images::{CompletedImage, ImageOptions, ImageRequest, SessionMode};
+use import::ImportedConversation;
+use indexed::{IdMap, Identified};
+use memory::{ExtractedMemories, Memory, MemoryConfig, MemoryRequest};
+use models::{ModelCatalog, ModelInfo};
+use moderation::{ModerationCheck, ModerationConfig, ModerationResult};
+use pending::{CompletedRequest, PendingRequest, QueuedMessage, RequestTarget};
+use persistence::{StorageBackend, StorageKind, StoreSnapshot};
+use profiles::Profile;
+use prompts::PromptTemplate;
+use providers::{CompletionRequest, LlmProvider};
+use rate_limit::{RateLimitConfig, RateLimiter};
+use retry::RetryPolicy;
+use role::ChatRole;
+use serde::{Deserialize, Serialize};
+use speech::{SpeechOptions, SpeechRequest};
+use stats::{AggregateStats, SessionStats};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
+use summarize::{CompletedSummary, ConversationSummary, SummaryRequest};
+use titles::{CompletedTitle, TitleRequest};
+use tools::{Tool, ToolOutcome, ToolRegistry};
+use training::TrainingExample;
+use trash::{Trash, TrashConfig, TrashedItem};
+use usage::{DailyUsage, DateRange, ModelUsage, TokenUsage, UsageReport};
+use vision::ImageData;
+
+pub use error::ChatError;
+
+pub mod app_config;
+pub mod auto_backup;
+pub mod backup;
+pub mod budget;
+pub mod cache;
+pub mod cancellation;
+pub mod capture;
+pub mod commands;
+pub mod content;
+pub mod embeddings;
+pub mod encryption;
+pub mod error;
+pub mod events;
+pub mod hotkey;
+pub mod ids;
+pub mod images;
+pub mod import;
+pub mod indexed;
+pub mod journal;
+pub mod memory;
+pub mod models;
+pub mod moderation;
+pub mod monitors;
+pub mod notifications;
+pub mod offline;
+pub mod pending;
+pub mod persistence;
+pub mod profiles;
+pub mod prompts;
+pub mod providers;
+pub mod proxy;
+pub mod rate_limit;
+pub mod retry;
+pub mod role;
+pub mod search;
+pub mod secrets;
+pub mod speech;
+pub mod sqlite;
+pub mod stats;
+pub mod summarize;
+pub mod timeouts;
+pub mod titles;
+pub mod tokens;
+pub mod tools;
+pub mod training;
+pub mod trash;
+pub mod tray;
+pub mod usage;
+pub mod vision;
+pub mod window;
 
 pub mod chat_requests {
+    use crate::ChatError;
     use async_openai::{
-        config::OpenAIConfig,
-        error::OpenAIError,
+        config::Config,
         types::{
-            ChatCompletionRequestMessage, ChatCompletionResponseMessage,
-            CreateChatCompletionRequestArgs,
+            ChatChoice, ChatCompletionFunctions, ChatCompletionRequestMessage,
+            ChatCompletionResponseMessage, CreateChatCompletionRequest,
+            CreateChatCompletionRequestArgs, CreateChatCompletionResponse, FunctionCall, Role,
+            Usage,
         },
         Client,
     };
+    use futures::StreamExt;
 
     const CHAT_MODEL: &str = "gpt-3.5-turbo";
 
-    /// Asynchronously make a request to `CHAT_MODEL`, returning the Result.
-    /// The `"gpt-3.5-turbo` model is used if None is supplied for the model.
-    #[tokio::main]
-    pub async fn requeset_chat_model(
-        client: &Client<OpenAIConfig>,
+    /// The most tokens the chat model may respond with
+    pub const MAX_RESPONSE_TOKENS: u16 = 100;
+
+    /// Sampling temperature used if None is supplied
+    const DEFAULT_TEMPERATURE: f32 = 0.5;
+
+    /// Builds the request sent to the chat model by both the streamed and
+    /// non-streamed requests. `functions` are only sent if there are any.
+    fn build_request(
         messages: Vec<ChatCompletionRequestMessage>,
         model: Option<&str>,
-    ) -> Result<ChatCompletionResponseMessage, OpenAIError> {
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(model.unwrap_or(CHAT_MODEL))
+        functions: Vec<ChatCompletionFunctions>,
+        temperature: Option<f32>,
+        stream: bool,
+    ) -> Result<CreateChatCompletionRequest, ChatError> {
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.model(model.unwrap_or(CHAT_MODEL))
             .messages(messages)
-            .max_tokens(100_u16)
-            .temperature(0.5)
-            .build()?;
+            .max_tokens(MAX_RESPONSE_TOKENS)
+            .temperature(temperature.unwrap_or(DEFAULT_TEMPERATURE))
+            .stream(stream);
+
+        if !functions.is_empty() {
+            args.functions(functions);
+        }
+
+        Ok(args.build()?)
+    }
+
+    /// Asynchronously make a request to `CHAT_MODEL`, returning the first
+    /// choice of the response along with the tokens used, if reported.
+    /// The `"gpt-3.5-turbo` model is used if None is supplied for the model,
+    /// and a temperature of 0.5 if None is supplied for the temperature.
+    ///
+    /// Returns `ChatError::EmptyResponse` if the response has no choices.
+    pub async fn requeset_chat_model<C: Config>(
+        client: &Client<C>,
+        messages: Vec<ChatCompletionRequestMessage>,
+        model: Option<&str>,
+        functions: Vec<ChatCompletionFunctions>,
+        temperature: Option<f32>,
+    ) -> Result<(ChatChoice, Option<Usage>), ChatError> {
+        let request = build_request(messages, model, functions, temperature, false)?;
 
         let response = client.chat().create(request).await?;
 
-        Ok(response
+        first_choice(response)
+    }
+
+    /// Returns the first choice of `response` along with the tokens used, if
+    /// reported.
+    ///
+    /// Returns `ChatError::EmptyResponse` if the response has no choices.
+    pub fn first_choice(
+        response: CreateChatCompletionResponse,
+    ) -> Result<(ChatChoice, Option<Usage>), ChatError> {
+        let choice = response
             .choices
-            .first()
-            .expect("Response had an empty choice field")
-            .message
-            .to_owned())
+            .into_iter()
+            .next()
+            .ok_or(ChatError::EmptyResponse)?;
+
+        Ok((choice, response.usage))
+    }
+
+    /// Asynchronously make a streamed request to `CHAT_MODEL`, calling `on_token`
+    /// with each piece of content as it arrives. Once the stream ends, the
+    /// assembled message is returned along with why the chat model stopped,
+    /// if reported.
+    /// The `"gpt-3.5-turbo` model is used if None is supplied for the model,
+    /// and a temperature of 0.5 if None is supplied for the temperature.
+    ///
+    /// Returns `ChatError::EmptyResponse` if the stream ends without any
+    /// choices.
+    pub async fn request_chat_model_stream<C: Config, F: FnMut(&str)>(
+        client: &Client<C>,
+        messages: Vec<ChatCompletionRequestMessage>,
+        model: Option<&str>,
+        functions: Vec<ChatCompletionFunctions>,
+        temperature: Option<f32>,
+        mut on_token: F,
+    ) -> Result<(ChatCompletionResponseMessage, Option<String>), ChatError> {
+        let request = build_request(messages, model, functions, temperature, true)?;
+
+        let mut stream = client.chat().create_stream(request).await?;
+
+        let mut role = Role::Assistant;
+        let mut content = String::new();
+        let mut function_call: Option<FunctionCall> = None;
+        let mut finish_reason = None;
+        let mut received = false;
+
+        while let Some(response) = stream.next().await {
+            for choice in response?.choices {
+                received = true;
+
+                if let Some(delta_role) = choice.delta.role {
+                    role = delta_role;
+                }
+
+                if let Some(token) = choice.delta.content {
+                    on_token(&token);
+                    content.push_str(&token);
+                }
+
+                // Function calls arrive in pieces, the name first
+                if let Some(delta) = choice.delta.function_call {
+                    let call = function_call.get_or_insert_with(|| FunctionCall {
+                        name: String::new(),
+                        arguments: String::new(),
+                    });
+                    call.name.push_str(&delta.name.unwrap_or_default());
+                    call.arguments
+                        .push_str(&delta.arguments.unwrap_or_default());
+                }
+
+                // Only the last chunk says why the stream ended
+                if choice.finish_reason.is_some() {
+                    finish_reason = choice.finish_reason;
+                }
+            }
+        }
+
+        if !received {
+            return Err(ChatError::EmptyResponse);
+        }
+
+        Ok((
+            ChatCompletionResponseMessage {
+                role,
+                content: Some(content),
+                function_call,
+            },
+            finish_reason,
+        ))
     }
 }
 
@@ -80,42 +284,196 @@ impl ChatMessageTrait for ChatCompletionResponseMessage {
     }
 }
 
+/// Returns the lowercase name of `role`, as used by the OpenAI API.
+fn role_name(role: ChatRole) -> &'static str {
+    match role {
+        ChatRole::System => "system",
+        ChatRole::User => "user",
+        ChatRole::Assistant => "assistant",
+        ChatRole::Tool => "function",
+    }
+}
+
+/// Escapes the characters in `text` that have special meaning in HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Splits `text` into lowercase words, ignoring punctuation.
+fn index_words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
 /// Message parsed from a ChatMessageTrait trait object.
 /// This is mainly to add extra data to it
 ///
 /// I feel like this is causing more complexity than it's worth
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
-    id: usize,
-    content: String,
+    id: MessageId,
+    /// The parts this message is made of. Older saves hold a single string.
+    #[serde(deserialize_with = "content::deserialize_parts")]
+    content: Vec<MessageContent>,
     created_at: u64,
-    role: Role,
+    role: ChatRole,
+    /// The tokens used by the requests that produced this message. Only set
+    /// on responses from the chat model.
+    #[serde(default)]
+    usage: Option<TokenUsage>,
+    /// Every response generated for this message, oldest first. Empty if the
+    /// message was never regenerated.
+    #[serde(default)]
+    variants: Vec<String>,
+    /// Index of the variant currently used as this message's content
+    #[serde(default)]
+    active_variant: usize,
+    /// How the latest variant was produced. Only set on responses from the
+    /// chat model.
+    #[serde(default)]
+    metadata: Option<MessageMetadata>,
+    /// The chat model that produced the latest variant. Only set on
+    /// responses, and missing from responses saved by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// The moderation endpoint's verdict on this message. Only set on User
+    /// messages sent while moderation was on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    moderation: Option<ModerationResult>,
+    /// Whether this message has reached the chat model. Only User messages
+    /// queued while offline are ever anything but Sent.
+    #[serde(default)]
+    status: MessageStatus,
+    /// Whether the user saved this message to find it again quickly
+    #[serde(default)]
+    bookmarked: bool,
+    /// Whether the response was stopped before the chat model finished it.
+    /// Truncated responses can be picked up again with `continue_message`.
+    #[serde(default)]
+    truncated: bool,
+}
+
+/// Whether a User message has reached the chat model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    #[default]
+    Sent,
+    /// Queued while the chat model could not be reached, to be sent once it
+    /// can be
+    Pending,
+    /// Queued while offline, then failed for another reason once sent. It is
+    /// not sent again unless edited.
+    Failed,
+}
+
+impl Identified for Message {
+    type Id = MessageId;
+
+    fn id(&self) -> MessageId {
+        self.id
+    }
+}
+
+/// Details about how a response from the chat model was produced
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageMetadata {
+    /// How many times requests were sent before the response arrived,
+    /// counting retries and the rounds of any tools the chat model called
+    pub attempts: u32,
+    /// How long the response took to arrive, in milliseconds. 0 for responses
+    /// saved by older versions.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Why the chat model stopped, such as "stop" or "length", if reported
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+impl MessageMetadata {
+    /// Returns the metadata of the answer in `outcome`
+    fn from_outcome(outcome: &ToolOutcome) -> MessageMetadata {
+        MessageMetadata {
+            attempts: outcome.attempts,
+            latency_ms: outcome.latency.as_millis().try_into().unwrap_or(u64::MAX),
+            finish_reason: outcome.completion.finish_reason.clone(),
+        }
+    }
+
+    /// Returns true if the response was cut short by cancelling it
+    fn is_cancelled(&self) -> bool {
+        self.finish_reason.as_deref() == Some(pending::CANCELLED_FINISH_REASON)
+    }
+}
+
+/// Returns the current unix timestamp in seconds, or 0 if the system clock
+/// is set before the unix epoch.
+fn current_timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => 0,
+    }
 }
 
 impl Message {
-    /// Create a new message with the given `id`, `role`, and `content`.
-    fn new(id: usize, role: Role, content: String) -> Message {
-        let created_at = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => 0,
-        };
+    /// Create a new message with a new id and the given `role` and `content`.
+    fn new(role: ChatRole, content: Vec<MessageContent>) -> Message {
+        let created_at = current_timestamp();
 
         Message {
-            id,
+            id: MessageId::generate(),
             content,
             role,
             created_at,
+            usage: None,
+            variants: vec![],
+            active_variant: 0,
+            metadata: None,
+            model: None,
+            moderation: None,
+            status: MessageStatus::Sent,
+            bookmarked: false,
+            truncated: false,
         }
     }
 
     /// Returns a copy of the id of this message
-    pub fn get_id(&self) -> usize {
-        self.id.clone()
+    pub fn get_id(&self) -> MessageId {
+        self.id
     }
 
-    /// Returns a copy of the contents of this message
+    /// Returns a copy of the text of this message. Same as `plain_text`.
     pub fn get_content(&self) -> String {
-        self.content.clone()
+        self.plain_text()
+    }
+
+    /// Returns the text of this message, leaving out images and tool calls
+    pub fn plain_text(&self) -> String {
+        content::plain_text(&self.content)
+    }
+
+    /// Returns the parts this message is made of
+    pub fn get_parts(&self) -> &[MessageContent] {
+        &self.content
+    }
+
+    /// Returns the sources of the tool results in this message, for citing
+    pub fn get_sources(&self) -> Vec<&content::Source> {
+        content::sources(&self.content).collect()
     }
 
     /// Returns a copy of the unix timestamp when this message of created.
@@ -124,474 +482,5541 @@ impl Message {
     }
 
     /// Returns a copy of the role of this message
-    pub fn get_role(&self) -> Role {
-        self.role.clone()
+    pub fn get_role(&self) -> ChatRole {
+        self.role
+    }
+
+    /// Returns how many seconds ago this message was created. Messages with a
+    /// timestamp in the future have an age of 0.
+    pub fn age_secs(&self) -> u64 {
+        current_timestamp().saturating_sub(self.created_at)
+    }
+
+    /// Returns the tokens used by the requests that produced this message, if
+    /// known
+    pub fn get_usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+
+    /// Returns how the latest response of this message was produced, if it is
+    /// a response from the chat model
+    pub fn get_metadata(&self) -> Option<&MessageMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Returns the chat model that produced the latest response of this
+    /// message, if it is a response and the model was recorded
+    pub fn get_model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    /// Returns the moderation endpoint's verdict on this message, if it was
+    /// checked
+    pub fn get_moderation(&self) -> Option<&ModerationResult> {
+        self.moderation.as_ref()
+    }
+
+    /// Returns whether this message has reached the chat model
+    pub fn get_status(&self) -> MessageStatus {
+        self.status
+    }
+
+    /// Returns true if the user bookmarked this message
+    pub fn is_bookmarked(&self) -> bool {
+        self.bookmarked
+    }
+
+    /// Returns true if the latest response of this message was stopped
+    /// before the chat model finished it
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns the path of the first image sent with this message, if any
+    pub fn get_image(&self) -> Option<&Path> {
+        content::images(&self.content).next()
+    }
+
+    /// Returns every response generated for this message, oldest first. Empty
+    /// if the message was never regenerated.
+    pub fn get_variants(&self) -> &[String] {
+        &self.variants
+    }
+
+    /// Returns the index of the variant used as this message's content
+    pub fn get_active_variant(&self) -> usize {
+        self.active_variant
+    }
+
+    /// Makes the variant at `index` this message's content. Returns false if
+    /// there is no such variant.
+    pub fn select_variant(&mut self, index: usize) -> bool {
+        match self.variants.get(index) {
+            Some(variant) => {
+                self.content = vec![MessageContent::text(variant.clone())];
+                self.active_variant = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds `content` as a new variant of this message and makes it the
+    /// active one. The tokens used to generate it are added to this message's
+    /// usage.
+    fn add_variant(
+        &mut self,
+        content: Vec<MessageContent>,
+        usage: Option<TokenUsage>,
+        metadata: MessageMetadata,
+        model: String,
+    ) {
+        if self.variants.is_empty() {
+            self.variants.push(self.plain_text());
+        }
+
+        self.usage = usage::add_usage(self.usage, usage);
+
+        self.variants.push(content::plain_text(&content));
+        self.active_variant = self.variants.len() - 1;
+        self.content = content;
+        self.truncated = metadata.is_cancelled();
+        self.metadata = Some(metadata);
+        self.model = Some(model);
+    }
+
+    /// Appends `rest`, the chat model's continuation of this message, to its
+    /// active response. The tokens used to generate it are added to this
+    /// message's usage.
+    fn add_continuation(
+        &mut self,
+        rest: &str,
+        usage: Option<TokenUsage>,
+        metadata: MessageMetadata,
+        model: String,
+    ) {
+        let text = self.plain_text() + rest;
+        if let Some(variant) = self.variants.get_mut(self.active_variant) {
+            *variant = text.clone();
+        }
+
+        self.usage = usage::add_usage(self.usage, usage);
+        self.content = vec![MessageContent::text(text)];
+        self.truncated = metadata.is_cancelled();
+        self.metadata = Some(metadata);
+        self.model = Some(model);
+    }
+
+    /// Returns the number of tokens this message takes up in a request
+    pub fn token_count(&self) -> usize {
+        tokens::count_message_tokens(&self.to_chat_resquest_msg())
     }
 
     /// Parse this message into an async_openai::type::ChatCompletionRequestMessage
-    /// The `name` and `function_call` are left as None.
+    /// The `name` is only set on tool results, and images are left out.
     pub fn to_chat_resquest_msg(&self) -> ChatCompletionRequestMessage {
+        content::to_request_message(self.role, &self.content)
+    }
+
+    /// Parse this message into an async_openai::type::ChatCompletionRequestMessage
+    /// with `name` identifying the speaker.
+    ///
+    /// The API only accepts names of 1 to 64 letters, digits, underscores and
+    /// dashes, so any other character is replaced with an underscore and
+    /// longer names are truncated.
+    pub fn as_request_with_name(&self, name: String) -> ChatCompletionRequestMessage {
+        let mut name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .take(64)
+            .collect();
+
+        if name.is_empty() {
+            name.push('_');
+        }
+
         ChatCompletionRequestMessage {
-            role: self.role.clone(),
-            content: Some(self.content.clone()),
-            name: None,
-            function_call: None,
+            name: Some(name),
+            ..self.to_chat_resquest_msg()
         }
     }
 
     /// Parse this message into an async_openai::type::ChatCompletionRequestMessage
     pub fn to_chat_response_msg(&self) -> ChatCompletionResponseMessage {
-        ChatCompletionResponseMessage {
-            role: self.role.clone(),
-            content: Some(self.content.clone()),
-            function_call: None,
+        content::to_response_message(self.role, &self.content)
+    }
+}
+
+/// Metadata about a chat session, without the contents of its messages
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionSummary {
+    pub id: SessionId,
+    pub title: String,
+    pub message_count: usize,
+    /// Unix timestamp of the newest message, or of the session's creation if
+    /// it has no messages
+    pub last_activity: u64,
+    pub model: String,
+    pub provider: Option<String>,
+    /// Name of the profile the session uses, if any
+    pub profile: Option<String>,
+    /// Id of the session this session was forked from, if any
+    pub parent_id: Option<SessionId>,
+    pub tags: Vec<String>,
+    pub pinned: bool,
+    pub archived: bool,
+    pub ephemeral: bool,
+}
+
+/// A bookmarked message, along with the session it is in
+#[derive(Debug, Clone, Serialize)]
+pub struct Bookmark {
+    pub session_id: SessionId,
+    pub session_title: String,
+    pub message: Message,
+}
+
+/// A page of session summaries, as listed by `Store::get_sessions_page`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionPage {
+    pub sessions: Vec<SessionSummary>,
+    /// Number of sessions in the store, across every page
+    pub total: usize,
+}
+
+/// A range of the messages of a session, as listed by
+/// `ChatSession::get_messages_range`
+#[derive(Debug, Clone, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    /// Number of messages in the session, across every page
+    pub total: usize,
+    /// Revision of the session's messages the page was taken at, see
+    /// `MESSAGE_DELTA_EVENT`
+    pub revision: u64,
+}
+
+/// An order sessions can be listed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    /// Most recently active first, then by title
+    #[default]
+    LastActivity,
+    /// Newest first
+    CreatedAt,
+    /// Alphabetically, ignoring case
+    Title,
+}
+
+/// A format a session can be exported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A transcript with each message headed by its role
+    Markdown,
+    /// The session as it is saved, including every variant and its metadata
+    Json,
+    /// A standalone HTML document
+    Html,
+}
+
+impl ExportFormat {
+    /// Returns the file extension used for this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
         }
     }
 }
 
-/// Struct for each individual chat session
+/// Where a forked session branched off from its parent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForkPoint {
+    /// Id of the session that was forked
+    pub session_id: SessionId,
+    /// Id of the last message copied from the forked session
+    pub message_id: MessageId,
+}
+
+/// Instructions sent after a response to have the chat model finish it. They
+/// are not kept in the session.
+const CONTINUE_PROMPT: &str = "Continue your last message exactly where it stopped, \
+without repeating any of it or commenting on the interruption.";
+
+/// A change to a session that waits on a response from the chat model
 #[derive(Debug, Clone)]
+pub(crate) enum ResponseAction {
+    /// Send a new User message with `contents`
+    Append { contents: String },
+
+    /// Send a new User message with `contents` and the image at `image`
+    AppendImage { contents: String, image: PathBuf },
+
+    /// Add a new variant to the response with id `message_id`
+    Regenerate { message_id: MessageId },
+
+    /// Replace the User message with id `message_id` and re-run from there
+    Edit {
+        message_id: MessageId,
+        contents: String,
+    },
+
+    /// Send the User message with id `message_id`, queued while offline,
+    /// adding the response right after it
+    SendQueued { message_id: MessageId },
+
+    /// Append the rest of the response with id `message_id`, picking up
+    /// where it stopped
+    Continue { message_id: MessageId },
+}
+
+/// Struct for each individual chat session
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatSession {
-    /// A unique id number for this chat session
-    id: usize,
+    /// A unique id for this chat session
+    id: SessionId,
     /// The title of this session
     title: String,
-    /// messages in this session
-    messages: Vec<Message>,
-    /// Counter for the ids of this session's messages. Ids are
-    /// Guaranteed to be unique for each message in this session.
-    msg_id_counter: usize,
-
+    /// messages in this session, keyed by id
+    #[serde(with = "indexed")]
+    messages: IdMap<Message>,
     ///The chat model being used by this session
     model: String,
+
+    /// The unix timestamp when this session was created
+    created_at: u64,
+
+    /// Name of the provider this session's messages are sent to. The store's
+    /// default provider is used if None.
+    #[serde(default)]
+    provider: Option<String>,
+
+    /// Name of the profile this session was switched to, if it still uses
+    /// the profile's provider
+    #[serde(default)]
+    profile: Option<String>,
+
+    /// Where this session was forked from, if it is a fork
+    #[serde(default)]
+    parent: Option<ForkPoint>,
+
+    /// Sent as a System message before the messages of every request
+    #[serde(default)]
+    system_prompt: Option<String>,
+
+    /// Files read by tools while answering in this session, in the order
+    /// they were first read
+    #[serde(default)]
+    accessed_files: Vec<PathBuf>,
+
+    /// Whether prompts are sent to the chat model or generate images
+    #[serde(default)]
+    mode: SessionMode,
+
+    /// Labels the user organizes sessions by, in the order they were added
+    #[serde(default)]
+    tags: Vec<String>,
+
+    /// Whether this session is listed before the others
+    #[serde(default)]
+    pinned: bool,
+
+    /// Whether this session is hidden from the main list
+    #[serde(default)]
+    archived: bool,
+
+    /// Unix timestamp of the latest change the creation times of the
+    /// messages do not show, such as a regenerated response. 0 if there has
+    /// been none.
+    #[serde(default)]
+    last_activity: u64,
+
+    /// Sent in place of the older messages once they no longer fit
+    /// comfortably in the context window
+    #[serde(default)]
+    summary: Option<Box<ConversationSummary>>,
+
+    /// The remembered facts this session was given when it was created, sent
+    /// after the system prompt
+    #[serde(default)]
+    memory_prompt: Option<String>,
+
+    /// The prompt the user typed but has not sent, kept while the overlay is
+    /// hidden
+    #[serde(default)]
+    draft: Option<String>,
+
+    /// Whether this session is a scratchpad that is never saved, and is
+    /// discarded when the overlay hides unless promoted first
+    #[serde(default)]
+    ephemeral: bool,
+}
+
+impl Identified for ChatSession {
+    type Id = SessionId;
+
+    fn id(&self) -> SessionId {
+        self.id
+    }
 }
 
 impl ChatSession {
-    /// Create a new Chat session with the supplied id and title
-    fn new(id: usize, title: String, model: &str) -> ChatSession {
+    /// Create a new Chat session with a new id and the supplied title
+    fn new(title: String, model: &str) -> ChatSession {
         ChatSession {
-            id,
+            id: SessionId::generate(),
             title,
-            messages: vec![],
-            msg_id_counter: 0,
+            messages: IdMap::new(),
             model: model.to_string(),
+            created_at: current_timestamp(),
+            provider: None,
+            profile: None,
+            parent: None,
+            system_prompt: None,
+            accessed_files: vec![],
+            mode: SessionMode::default(),
+            tags: vec![],
+            pinned: false,
+            archived: false,
+            last_activity: 0,
+            summary: None,
+            memory_prompt: None,
+            draft: None,
+            ephemeral: false,
         }
     }
 
-    /// Processes a User message and makes a request to
-    /// The chat model. If successful, both the message and response
-    /// are stored, returning an Ok(()). Otherwise an Err(OpenAIError)
-    /// is returned
-    pub fn add_message(
-        &mut self,
-        contents: String,
-        client: &Client<OpenAIConfig>,
-    ) -> Result<(), OpenAIError> {
-        use chat_requests::requeset_chat_model;
+    /// Returns this session's system prompt, followed by the facts it was
+    /// given from memory, as a request message, if it has either
+    fn system_prompt_message(&self) -> Option<ChatCompletionRequestMessage> {
+        let prompt = match (&self.system_prompt, &self.memory_prompt) {
+            (Some(prompt), Some(memory)) => format!("{}\n\n{}", prompt, memory),
+            (Some(prompt), None) | (None, Some(prompt)) => prompt.clone(),
+            (None, None) => return None,
+        };
 
-        let chat_request_msg = ChatCompletionRequestMessage {
-            role: Role::User,
-            content: Some(contents.clone()),
+        Some(ChatCompletionRequestMessage {
+            role: Role::System,
+            content: Some(prompt),
             name: None,
             function_call: None,
-        };
+        })
+    }
 
-        let msg = Message::new(self.msg_id_counter.to_owned(), Role::User, contents);
+    /// Builds the request for the chat model's reply to `history`, followed by
+    /// a new User message with `contents` if any. The oldest messages are left
+    /// out if they do not fit in the context window of this session's model.
+    fn completion_request<'a>(
+        &'a self,
+        history: impl Iterator<Item = &'a Message>,
+        contents: Option<&str>,
+    ) -> CompletionRequest {
+        let messages = self.request_messages(history, contents);
 
-        let mut temp_messages = self.messages.to_vec();
-        temp_messages.push(msg);
+        CompletionRequest {
+            model: self.model.clone(),
+            messages: tokens::trim_to_context(messages, &self.model),
+            images: vec![],
+            functions: vec![],
+            temperature: None,
+        }
+    }
 
-        let response = requeset_chat_model(
-            client,
-            temp_messages
-                .to_vec()
-                .iter()
-                .map(|x| x.to_chat_resquest_msg())
-                .collect(),
-            Some(self.model.as_str()),
-        )?;
+    /// Returns the messages of a request for the chat model's reply to
+    /// `history`, untrimmed. The messages covered by this session's summary
+    /// are replaced by the summary if `history` holds all of them.
+    fn request_messages<'a>(
+        &'a self,
+        history: impl Iterator<Item = &'a Message>,
+        contents: Option<&str>,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        let mut history: Vec<&Message> = history.collect();
+        if let Some(summary) = &self.summary {
+            if let Some(index) = history.iter().position(|x| x.id == summary.through) {
+                history.splice(..=index, [&summary.message]);
+            }
+        }
 
-        self.add_chat_message(chat_request_msg);
-        self.add_chat_message(response);
+        let mut messages: Vec<ChatCompletionRequestMessage> = self
+            .system_prompt_message()
+            .into_iter()
+            .chain(history.into_iter().map(|x| x.to_chat_resquest_msg()))
+            .collect();
 
-        Ok(())
+        if let Some(contents) = contents {
+            messages.push(ChatCompletionRequestMessage {
+                role: Role::User,
+                content: Some(contents.to_string()),
+                name: None,
+                function_call: None,
+            });
+        }
+
+        messages
     }
 
-    /// Adds a new chat message to this session, consuming it in the process.
-    ///
-    /// Chat Message should implement the ChatMessageTrait. Currently only the
-    /// `async_openai::types::{ChatCompletionResponseMessage, ChatCompletionRequestMessage}`
-    /// implement this.
+    /// Processes a User message and makes a request to
+    /// The chat model through `provider`. If successful, both the message and
+    /// response are stored, returning an Ok(()). Otherwise an Err(ChatError)
+    /// is returned
     ///
-    /// The id and created at fields are automatically added to the message.
-    fn add_chat_message<T: ChatMessageTrait>(&mut self, msg: T) {
-        let id = self.msg_id_counter;
-        self.msg_id_counter += 1;
+    /// If `cancel` is cancelled before the response arrives, nothing is stored
+    /// and `ChatError::Cancelled` is returned.
+    pub async fn add_message(
+        &mut self,
+        contents: String,
+        provider: &dyn LlmProvider,
+        cancel: &CancellationToken,
+    ) -> Result<(), ChatError> {
+        let action = ResponseAction::Append { contents };
+        let request = self.prepare(&action)?;
+        let outcome = pending::complete_request(
+            provider,
+            request,
+            &RetryPolicy::default(),
+            &ToolRegistry::default(),
+            cancel,
+        )
+        .await?;
 
-        self.messages
-            .push(Message::new(id, msg.get_role(), msg.get_content()));
+        self.apply(action, outcome).map(|_| ())
     }
-    /// Deletes message with matching id in this chat session. The right most,
-    /// deleted message is returned if possible.
-    pub fn delete_message(&mut self, id: usize) -> Option<Message> {
-        // Same general approach like Store.delete_session()
-        let mut accumulator: Vec<Message> = Vec::new();
 
-        let target = self.messages.iter().fold(None, |acc, curr| {
-            if curr.id == id {
-                return Some(curr.to_owned());
-            } else {
-                accumulator.push(curr.to_owned());
-                return acc;
-            }
-        });
-        self.messages = accumulator;
+    /// Like `add_message`, but streams the response from the chat model,
+    /// calling `on_token` with each piece of content as it arrives. The
+    /// User message and the assembled response are only stored once the
+    /// stream has ended successfully.
+    ///
+    /// If `cancel` is cancelled mid-stream, the content received so far is
+    /// stored as the response. If nothing was received yet, nothing is stored
+    /// and `ChatError::Cancelled` is returned.
+    pub async fn add_message_streaming<F: FnMut(&str) + Send>(
+        &mut self,
+        contents: String,
+        provider: &dyn LlmProvider,
+        on_token: F,
+        cancel: &CancellationToken,
+    ) -> Result<(), ChatError> {
+        let action = ResponseAction::Append { contents };
+        let request = self.prepare(&action)?;
+        let outcome = pending::stream_request(
+            provider,
+            request,
+            &RetryPolicy::default(),
+            &ToolRegistry::default(),
+            on_token,
+            cancel,
+        )
+        .await?;
 
-        target
+        self.apply(action, outcome).map(|_| ())
     }
 
-    /// Renames this session to the `new_title` provided. `new_title` is
-    /// consumed in the process
-    pub fn rename_session(&mut self, new_title: String) {
-        self.title = new_title;
-    }
+    /// Requests a new response in place of the chat model's message with
+    /// matching id, resending the conversation up to the preceding message.
+    /// The new response is added as a variant of the message and made active,
+    /// keeping the earlier responses.
+    ///
+    /// If `cancel` is cancelled before the response arrives, the message is
+    /// left unchanged and `ChatError::Cancelled` is returned.
+    pub async fn regenerate(
+        &mut self,
+        message_id: MessageId,
+        provider: &dyn LlmProvider,
+        cancel: &CancellationToken,
+    ) -> Result<(), ChatError> {
+        let action = ResponseAction::Regenerate { message_id };
+        let request = self.prepare(&action)?;
+        let outcome = pending::complete_request(
+            provider,
+            request,
+            &RetryPolicy::default(),
+            &ToolRegistry::default(),
+            cancel,
+        )
+        .await?;
 
-    /// Returns a copy of the title of this session
-    pub fn get_title(&self) -> String {
-        self.title.clone()
+        self.apply(action, outcome).map(|_| ())
     }
 
-    /// Returns a copy of the id of this session
-    pub fn get_id(&self) -> usize {
-        self.id.clone()
-    }
-    /// Returns a reference to the collection of messages in this session
-    pub fn get_messages(&self) -> &Vec<Message> {
-        self.messages.as_ref()
+    /// Asks the chat model to pick up the response with matching id where it
+    /// stopped, appending what it writes to the response. Meant for responses
+    /// cut short by cancelling them, but works on any response.
+    ///
+    /// If `cancel` is cancelled before the rest arrives, the message is left
+    /// unchanged and `ChatError::Cancelled` is returned.
+    pub async fn continue_message(
+        &mut self,
+        message_id: MessageId,
+        provider: &dyn LlmProvider,
+        cancel: &CancellationToken,
+    ) -> Result<(), ChatError> {
+        let action = ResponseAction::Continue { message_id };
+        let request = self.prepare(&action)?;
+        let outcome = pending::complete_request(
+            provider,
+            request,
+            &RetryPolicy::default(),
+            &ToolRegistry::default(),
+            cancel,
+        )
+        .await?;
+
+        self.apply(action, outcome).map(|_| ())
     }
-}
 
-/// Struct for storing all Chat sessions
-/// Hold functions for creating and keeping track of new sessions
-#[derive(Debug, Clone)]
-pub struct Store {
-    sessions: Vec<ChatSession>,
+    /// Replaces the content of the User message with matching id, then
+    /// requests a new response to the conversation up to and including the
+    /// edited message. Every message after the edited one is removed once the
+    /// response arrives.
+    ///
+    /// If `cancel` is cancelled before the response arrives, the session is
+    /// left unchanged and `ChatError::Cancelled` is returned.
+    pub async fn edit_message(
+        &mut self,
+        message_id: MessageId,
+        new_content: String,
+        provider: &dyn LlmProvider,
+        cancel: &CancellationToken,
+    ) -> Result<(), ChatError> {
+        let action = ResponseAction::Edit {
+            message_id,
+            contents: new_content,
+        };
+        let request = self.prepare(&action)?;
+        let outcome = pending::complete_request(
+            provider,
+            request,
+            &RetryPolicy::default(),
+            &ToolRegistry::default(),
+            cancel,
+        )
+        .await?;
 
-    /// Counter for the ids of this store's sessions. Ids are
-    /// Guaranteed to be unique for each message in this session.
-    session_id_counter: usize,
+        self.apply(action, outcome).map(|_| ())
+    }
 
-    ///The client for this store. Only supports the
-    /// OpenAI REST API based on OpenAPI spec.
-    client: Client<OpenAIConfig>,
-}
+    /// Returns the index of the message with matching id, checking it has
+    /// `role`. `wrong_role` builds the error returned if it does not.
+    fn index_of(
+        &self,
+        message_id: MessageId,
+        role: ChatRole,
+        wrong_role: fn(MessageId) -> ChatError,
+    ) -> Result<usize, ChatError> {
+        let (index, _, message) = self
+            .messages
+            .get_full(&message_id)
+            .ok_or(ChatError::MessageNotFound(message_id))?;
 
-impl Store {
-    ///Create a new Store with no sessions. Ownership of the
-    /// client is moved to this struct
-    pub fn new(client: Client<OpenAIConfig>) -> Store {
-        Store {
-            sessions: Vec::new(),
-            session_id_counter: 0,
-            client,
+        if message.role != role {
+            return Err(wrong_role(message_id));
         }
-    }
 
-    /// Returns reference to the collection of sessions
-    /// in this store
-    pub fn get_all_sessions(&self) -> &Vec<ChatSession> {
-        self.sessions.as_ref()
+        Ok(index)
     }
 
-    /// Finds and returns a reference to the chat session with matching id if any exists
-    pub fn get_session(&self, id: usize) -> Option<&ChatSession> {
-        self.sessions.iter().find(|x| x.get_id() == id)
+    /// Builds the request whose response `action` needs, without changing
+    /// this session.
+    fn prepare(&self, action: &ResponseAction) -> Result<CompletionRequest, ChatError> {
+        match action {
+            ResponseAction::Append { contents } => {
+                Ok(self.completion_request(self.messages.values(), Some(contents)))
+            }
+            ResponseAction::AppendImage { contents, image } => {
+                let mut request = self.completion_request(self.messages.values(), Some(contents));
+                request.images.push(ImageData::load(image)?);
+
+                if !vision::is_vision_model(&request.model) {
+                    request.model = vision::VISION_MODEL.to_string();
+                }
+
+                Ok(request)
+            }
+            ResponseAction::Regenerate { message_id } => {
+                let index =
+                    self.index_of(*message_id, ChatRole::Assistant, ChatError::NotAResponse)?;
+                Ok(self.completion_request(self.messages.values().take(index), None))
+            }
+            ResponseAction::Edit {
+                message_id,
+                contents,
+            } => {
+                let index =
+                    self.index_of(*message_id, ChatRole::User, ChatError::NotAUserMessage)?;
+                Ok(self.completion_request(self.messages.values().take(index), Some(contents)))
+            }
+            ResponseAction::SendQueued { message_id } => {
+                let index =
+                    self.index_of(*message_id, ChatRole::User, ChatError::NotAUserMessage)?;
+                if self.messages[index].status != MessageStatus::Pending {
+                    return Err(ChatError::NotQueued(*message_id));
+                }
+                Ok(self.completion_request(self.messages.values().take(index + 1), None))
+            }
+            ResponseAction::Continue { message_id } => {
+                let index =
+                    self.index_of(*message_id, ChatRole::Assistant, ChatError::NotAResponse)?;
+                Ok(self.completion_request(
+                    self.messages.values().take(index + 1),
+                    Some(CONTINUE_PROMPT),
+                ))
+            }
+        }
     }
 
-    /// Add a new message to the store, creating a chat session  for it.
-    /// `title` is the title of the chat session created. The message is
-    /// consumed in the process.
-    /// `model` must be a valid chat model
-    ///
-    /// Only request messages can be used to create a new session
-    pub fn add_session(
+    /// Carries out `action` with the chat model's `response`, returning a copy
+    /// of the message holding the response. The messages `action` refers to
+    /// are looked up again, as this session may have changed since the
+    /// request was prepared.
+    fn apply(
         &mut self,
-        msg: ChatCompletionRequestMessage,
-        title: String,
-        model: &str,
-    ) -> Result<(), OpenAIError> {
-        let id = self.session_id_counter;
+        action: ResponseAction,
+        mut outcome: ToolOutcome,
+    ) -> Result<Message, ChatError> {
+        let accessed_files = std::mem::take(&mut outcome.accessed_files);
 
-        let mut chs = ChatSession::new(id, title, model);
+        match action {
+            ResponseAction::Append { contents } => {
+                self.push_message(ChatRole::User, vec![MessageContent::text(contents)]);
+                self.set_last_moderation(outcome.moderation.take());
+                self.add_completion(outcome);
+            }
+            ResponseAction::AppendImage { contents, image } => {
+                self.push_message(
+                    ChatRole::User,
+                    vec![MessageContent::text(contents), MessageContent::image(image)],
+                );
+                self.set_last_moderation(outcome.moderation.take());
+                self.add_completion(outcome);
+            }
+            ResponseAction::Regenerate { message_id } => {
+                let index =
+                    self.index_of(message_id, ChatRole::Assistant, ChatError::NotAResponse)?;
+                // Only the answer is kept as a variant, not the tools it called
+                let metadata = MessageMetadata::from_outcome(&outcome);
+                let completion = outcome.completion;
+                let content = content::from_response(&completion.message);
+                let message = &mut self.messages[index];
+                message.add_variant(content, completion.usage, metadata, outcome.model);
+                let message = message.clone();
+                self.add_accessed_files(accessed_files);
+                self.touch();
 
-        chs.add_message(msg.get_content(), &self.client)?;
+                return Ok(message);
+            }
+            ResponseAction::Edit {
+                message_id,
+                contents,
+            } => {
+                let index =
+                    self.index_of(message_id, ChatRole::User, ChatError::NotAUserMessage)?;
+                // A summary of the edited message no longer holds
+                let through = self.summary.as_ref().map(|x| x.through);
+                if through.and_then(|x| self.messages.get_index_of(&x)) >= Some(index) {
+                    self.summary = None;
+                }
+                self.messages.truncate(index + 1);
+                self.messages[index].content = vec![MessageContent::text(contents)];
+                self.messages[index].moderation = outcome.moderation.take();
+                self.messages[index].status = MessageStatus::Sent;
+                self.add_completion(outcome);
+            }
+            ResponseAction::SendQueued { message_id } => {
+                let index =
+                    self.index_of(message_id, ChatRole::User, ChatError::NotAUserMessage)?;
+                // Another request for the message may have been committed first
+                if self.messages[index].status != MessageStatus::Pending {
+                    return Err(ChatError::NotQueued(message_id));
+                }
+                self.messages[index].moderation = outcome.moderation.take();
+                self.messages[index].status = MessageStatus::Sent;
 
-        self.session_id_counter += 1;
-        self.sessions.push(chs);
+                // Messages queued after this one stay after its response
+                let end = self.messages.len();
+                self.add_completion(outcome);
+                let added = self.messages.len() - end;
+                for offset in 0..added {
+                    self.messages.move_index(end + offset, index + 1 + offset);
+                }
+                self.add_accessed_files(accessed_files);
 
-        Ok(())
+                return Ok(self.messages[index + added].clone());
+            }
+            ResponseAction::Continue { message_id } => {
+                let index =
+                    self.index_of(message_id, ChatRole::Assistant, ChatError::NotAResponse)?;
+                let metadata = MessageMetadata::from_outcome(&outcome);
+                let completion = outcome.completion;
+                let rest = content::plain_text(&content::from_response(&completion.message));
+                let message = &mut self.messages[index];
+                message.add_continuation(&rest, completion.usage, metadata, outcome.model);
+                let message = message.clone();
+                self.add_accessed_files(accessed_files);
+                self.touch();
+
+                return Ok(message);
+            }
+        }
+        self.add_accessed_files(accessed_files);
+
+        self.messages
+            .last()
+            .map(|(_, x)| x.clone())
+            .ok_or(ChatError::SessionNotFound(self.id))
+    }
+
+    /// Keeps `moderation` on the newest message
+    fn set_last_moderation(&mut self, moderation: Option<ModerationResult>) {
+        if let Some((_, msg)) = self.messages.last_mut() {
+            msg.moderation = moderation;
+        }
+    }
+
+    /// Records that tools read `files`, keeping the first time each was read
+    fn add_accessed_files(&mut self, files: Vec<PathBuf>) {
+        for file in files {
+            if !self.accessed_files.contains(&file) {
+                self.accessed_files.push(file);
+            }
+        }
+    }
+
+    /// Adds the chat model's answer to this session, after the tool calls
+    /// and results that led to it. The answer records the tokens used to
+    /// produce it and how it was produced.
+    fn add_completion(&mut self, mut outcome: ToolOutcome) {
+        let metadata = MessageMetadata::from_outcome(&outcome);
+        for (role, parts) in std::mem::take(&mut outcome.messages) {
+            let is_call = role == ChatRole::Assistant;
+            self.push_message(role, parts);
+
+            if let (true, Some((_, msg))) = (is_call, self.messages.last_mut()) {
+                msg.model = Some(outcome.model.clone());
+            }
+        }
+
+        let message = outcome.completion.message;
+        self.push_message(
+            message.role.clone().into(),
+            content::from_response(&message),
+        );
+
+        if let Some((_, msg)) = self.messages.last_mut() {
+            msg.usage = outcome.completion.usage;
+            msg.truncated = metadata.is_cancelled();
+            msg.metadata = Some(metadata);
+            msg.model = Some(outcome.model);
+        }
+    }
+
+    /// Adds a new chat message to this session, consuming it in the process.
+    ///
+    /// Chat Message should implement the ChatMessageTrait. Currently only the
+    /// `async_openai::types::{ChatCompletionResponseMessage, ChatCompletionRequestMessage}`
+    /// implement this.
+    ///
+    /// The id and created at fields are automatically added to the message.
+    fn add_chat_message<T: ChatMessageTrait>(&mut self, msg: T) {
+        self.push_message(
+            msg.get_role().into(),
+            vec![MessageContent::text(msg.get_content())],
+        );
+    }
+
+    /// Creates a session from `conversation`, keeping its title and
+    /// timestamps. Ids are made from the timestamps, so they sort in the
+    /// order the conversation was held. `model` is used if the conversation
+    /// does not say which model it was held with.
+    fn imported(conversation: ImportedConversation, model: &str) -> ChatSession {
+        let model = conversation.model.as_deref().unwrap_or(model);
+        let mut session = ChatSession::new(conversation.title, model);
+        session.id = SessionId::from_timestamp(conversation.created_at);
+        session.created_at = conversation.created_at;
+        session.system_prompt = conversation.system_prompt;
+
+        for imported in conversation.messages {
+            let mut message =
+                Message::new(imported.role, vec![MessageContent::text(imported.text)]);
+            message.id = MessageId::from_timestamp(imported.created_at);
+            message.created_at = imported.created_at;
+            session.messages.insert(message.id, message);
+        }
+
+        session
+    }
+
+    /// Adds a new message with `role` made of `parts` to this session
+    fn push_message(&mut self, role: ChatRole, parts: Vec<MessageContent>) {
+        let message = Message::new(role, parts);
+        self.messages.insert(message.id, message);
+    }
+
+    /// Appends each `(role, content)` pair as a message in this session without
+    /// making any request to the chat model. Useful for seeding sessions with
+    /// example conversations.
+    ///
+    /// In debug builds, panics if any content is empty.
+    pub fn add_message_batch_without_api(&mut self, messages: Vec<(ChatRole, String)>) {
+        for (role, content) in messages {
+            debug_assert!(!content.is_empty(), "Message content must not be empty");
+
+            self.add_chat_message(ChatCompletionRequestMessage {
+                role: role.into(),
+                content: Some(content),
+                name: None,
+                function_call: None,
+            });
+        }
+    }
+
+    /// Deletes the message with matching id in this chat session, returning
+    /// it if it existed
+    pub fn delete_message(&mut self, id: MessageId) -> Option<Message> {
+        self.messages.shift_remove(&id)
+    }
+
+    /// Renames this session to the `new_title` provided. `new_title` is
+    /// consumed in the process
+    pub fn rename_session(&mut self, new_title: String) {
+        self.title = new_title;
+    }
+
+    /// Returns a copy of the title of this session
+    pub fn get_title(&self) -> String {
+        self.title.clone()
+    }
+
+    /// Returns a copy of the id of this session
+    pub fn get_id(&self) -> SessionId {
+        self.id
+    }
+    /// Returns a copy of the unix timestamp when this session was created.
+    pub fn get_created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Returns the system prompt sent before this session's messages, if any
+    pub fn get_system_prompt(&self) -> Option<&str> {
+        self.system_prompt.as_deref()
+    }
+
+    /// Sets the system prompt sent before this session's messages. Empty
+    /// prompts are treated as no prompt.
+    pub fn set_system_prompt(&mut self, prompt: Option<String>) {
+        self.system_prompt = prompt.filter(|x| !x.trim().is_empty());
+    }
+
+    /// Returns where this session was forked from, if it is a fork
+    pub fn get_parent(&self) -> Option<ForkPoint> {
+        self.parent
+    }
+
+    /// Returns the summary sent in place of this session's older messages,
+    /// if they have been summarized
+    pub fn get_summary(&self) -> Option<&ConversationSummary> {
+        self.summary.as_deref()
+    }
+
+    /// Returns the files read by tools while answering in this session, in
+    /// the order they were first read
+    pub fn get_accessed_files(&self) -> &[PathBuf] {
+        &self.accessed_files
+    }
+
+    /// Returns whether prompts in this session are sent to the chat model or
+    /// generate images
+    pub fn get_mode(&self) -> SessionMode {
+        self.mode
+    }
+
+    /// Returns this session's tags, in the order they were added
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Returns whether this session is tagged `tag`, ignoring case
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = tag.trim();
+        self.tags.iter().any(|x| x.eq_ignore_ascii_case(tag))
+    }
+
+    /// Tags this session with `tag`, trimmed. Returns false if `tag` is empty
+    /// or the session already has it.
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        let tag = tag.trim();
+        if tag.is_empty() || self.has_tag(tag) {
+            return false;
+        }
+        self.tags.push(tag.to_string());
+
+        true
+    }
+
+    /// Removes `tag` from this session, ignoring case. Returns false if the
+    /// session did not have it.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let before = self.tags.len();
+        let tag = tag.trim();
+        self.tags.retain(|x| !x.eq_ignore_ascii_case(tag));
+
+        self.tags.len() != before
+    }
+
+    /// Returns whether this session is listed before the others
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Returns whether this session is hidden from the main list
+    pub fn is_archived(&self) -> bool {
+        self.archived
+    }
+
+    /// Returns whether this session is a scratchpad that is never saved
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// Lists this session before the others if `pinned`. Pinning an archived
+    /// session brings it back to the main list.
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+        if pinned {
+            self.archived = false;
+        }
+    }
+
+    /// Hides this session from the main list if `archived`. Archived
+    /// sessions are unpinned.
+    pub fn set_archived(&mut self, archived: bool) {
+        self.archived = archived;
+        if archived {
+            self.pinned = false;
+        }
+    }
+
+    /// Returns the chat model new messages in this session are sent to
+    pub fn get_model(&self) -> &str {
+        &self.model
+    }
+
+    /// Sends new messages in this session to `model`, keeping the messages
+    /// already answered by other models. Returns `ChatError::InvalidModel`
+    /// if `catalog`, the models offered by this session's provider, does not
+    /// list `model`. Any model is accepted if the catalog is empty.
+    pub fn set_model(&mut self, model: &str, catalog: &[ModelInfo]) -> Result<(), ChatError> {
+        if !catalog.is_empty() && !catalog.iter().any(|x| x.id == model) {
+            return Err(ChatError::InvalidModel(model.to_string()));
+        }
+        self.model = model.to_string();
+
+        Ok(())
+    }
+
+    /// Returns the name of the provider this session uses, if it does not use
+    /// the store's default provider.
+    pub fn get_provider(&self) -> Option<&str> {
+        self.provider.as_deref()
+    }
+
+    /// Returns the name of the profile this session uses, if any
+    pub fn get_profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Returns the prompt the user typed in this session but has not sent,
+    /// if any
+    pub fn get_draft(&self) -> Option<&str> {
+        self.draft.as_deref()
+    }
+
+    /// Returns the messages in this session, in order
+    pub fn get_messages(&self) -> Vec<&Message> {
+        self.messages.values().collect()
+    }
+
+    /// Returns up to `limit` messages in this session, in order, skipping the
+    /// first `offset`
+    pub fn get_messages_range(&self, offset: usize, limit: usize) -> Vec<&Message> {
+        self.messages.values().skip(offset).take(limit).collect()
+    }
+
+    /// Returns the message with matching id in this session, if any
+    pub fn get_message(&self, id: MessageId) -> Option<&Message> {
+        self.messages.get(&id)
+    }
+
+    /// Parse the messages in this session into request messages, using the role
+    /// of each message as its `name`.
+    pub fn to_request_messages_with_names(&self) -> Vec<ChatCompletionRequestMessage> {
+        self.messages
+            .values()
+            .map(|msg| msg.as_request_with_name(role_name(msg.role).to_string()))
+            .collect()
+    }
+
+    /// Returns the unix timestamp of the newest message in this session, or the
+    /// creation time of the session if it has no messages. Changes to older
+    /// messages, such as regenerating a response, count if they are newer.
+    pub fn last_activity(&self) -> u64 {
+        self.messages
+            .values()
+            .map(|msg| msg.created_at)
+            .max()
+            .unwrap_or(self.created_at)
+            .max(self.last_activity)
+    }
+
+    /// Records that this session changed just now
+    fn touch(&mut self) {
+        self.last_activity = current_timestamp();
+    }
+
+    /// Returns a summary of this session's metadata
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            id: self.id,
+            title: self.title.clone(),
+            message_count: self.message_count(),
+            last_activity: self.last_activity(),
+            model: self.model.clone(),
+            provider: self.provider.clone(),
+            profile: self.profile.clone(),
+            parent_id: self.parent.map(|x| x.session_id),
+            tags: self.tags.clone(),
+            pinned: self.pinned,
+            archived: self.archived,
+            ephemeral: self.ephemeral,
+        }
+    }
+
+    /// Returns the time in seconds between each user message and the assistant
+    /// message directly following it, in order. If the assistant message has
+    /// an earlier timestamp than the user message, 0 is returned for the pair.
+    pub fn get_response_times(&self) -> Vec<u64> {
+        self.get_messages()
+            .windows(2)
+            .filter(|pair| pair[0].role == ChatRole::User && pair[1].role == ChatRole::Assistant)
+            .map(|pair| pair[1].created_at.saturating_sub(pair[0].created_at))
+            .collect()
+    }
+
+    /// Returns the mean of `get_response_times`, rounded to two decimal places,
+    /// or None if this session has no user/assistant message pairs.
+    pub fn average_response_time_secs(&self) -> Option<f64> {
+        let times = self.get_response_times();
+
+        if times.is_empty() {
+            return None;
+        }
+
+        let mean = times.iter().sum::<u64>() as f64 / times.len() as f64;
+
+        Some((mean * 100.0).round() / 100.0)
+    }
+
+    /// Returns the roles of the messages in this session, in order.
+    pub fn message_role_sequence(&self) -> Vec<ChatRole> {
+        self.messages.values().map(|msg| msg.role).collect()
+    }
+
+    /// Returns the messages in this session with the given `role`, in order.
+    pub fn get_messages_by_role(&self, role: ChatRole) -> Vec<&Message> {
+        self.messages
+            .values()
+            .filter(|msg| msg.role == role)
+            .collect()
+    }
+
+    /// Returns the `ChatRole::System` messages stored in this session's messages.
+    pub fn get_system_messages(&self) -> Vec<&Message> {
+        self.get_messages_by_role(ChatRole::System)
+    }
+
+    /// Returns the number of messages in this session
+    pub fn message_count(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Returns the tokens used by all the requests made in this session
+    pub fn total_usage(&self) -> TokenUsage {
+        self.messages.values().filter_map(|msg| msg.usage).fold(
+            TokenUsage::default(),
+            |total, usage| TokenUsage {
+                prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+                completion_tokens: total.completion_tokens + usage.completion_tokens,
+            },
+        )
+    }
+
+    /// Returns the cost in USD of all the requests made in this session, or 0
+    /// if the price of this session's model is unknown
+    pub fn total_cost(&self) -> f64 {
+        usage::cost(&self.model, &self.total_usage()).unwrap_or(0.0)
+    }
+
+    /// Returns statistics about this session's messages, the tokens they
+    /// used and how long responses took
+    pub fn stats(&self) -> SessionStats {
+        SessionStats::of(self)
+    }
+
+    /// Returns the number of tokens this session's messages take up in a
+    /// request, including the tokens the response is primed with
+    pub fn token_count(&self) -> usize {
+        let system_prompt = self
+            .system_prompt_message()
+            .map_or(0, |msg| tokens::count_message_tokens(&msg));
+
+        self.messages
+            .values()
+            .map(Message::token_count)
+            .sum::<usize>()
+            + system_prompt
+            + tokens::TOKENS_PER_REPLY
+    }
+
+    /// Returns `(created_at, cumulative_word_count)` pairs, one per message, where
+    /// the count is the running total of words up to and including that message.
+    pub fn word_count_over_time(&self) -> Vec<(u64, usize)> {
+        let mut total = 0;
+
+        self.messages
+            .values()
+            .map(|msg| {
+                total += msg.plain_text().split_whitespace().count();
+                (msg.created_at, total)
+            })
+            .collect()
+    }
+
+    /// Renders this session in `format`
+    pub fn export(&self, format: ExportFormat) -> Result<String, ChatError> {
+        match format {
+            ExportFormat::Markdown => Ok(self.export_as_markdown()),
+            ExportFormat::Json => serde_json::to_string_pretty(self)
+                .map_err(|e| ChatError::Persistence(e.to_string())),
+            ExportFormat::Html => Ok(self.export_as_html()),
+        }
+    }
+
+    /// Renders this session as a Markdown transcript.
+    ///
+    /// Each message is headed by its role and the time it was sent. Images are
+    /// linked by their path, and the sources a message cites are listed after
+    /// it.
+    pub fn export_as_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n", self.title);
+
+        for msg in self.messages.values() {
+            let role = role_name(msg.role);
+            let datetime = chrono::NaiveDateTime::from_timestamp_opt(msg.created_at as i64, 0)
+                .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+
+            markdown.push_str(&format!("\n**{}** ({})\n\n", role, datetime));
+
+            for image in content::images(&msg.content) {
+                markdown.push_str(&format!("![image]({})\n\n", image.display()));
+            }
+
+            let text = msg.plain_text();
+            if !text.trim().is_empty() {
+                markdown.push_str(text.trim_end());
+                markdown.push('\n');
+            }
+
+            let sources = msg.get_sources();
+            if !sources.is_empty() {
+                markdown.push_str("\nSources:\n");
+                for source in sources {
+                    markdown.push_str(&format!("- [{}]({})\n", source.title, source.url));
+                }
+            }
+        }
+
+        markdown
+    }
+
+    /// Renders this session as a standalone HTML document.
+    ///
+    /// Each message becomes a `<div class="message {role}">`. Text is placed in
+    /// `<p>` elements while fenced (```) code blocks are placed in `<pre><code>`.
+    pub fn export_as_html(&self) -> String {
+        let title = escape_html(&self.title);
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n",
+            title, title
+        );
+
+        for msg in self.messages.values() {
+            let role = role_name(msg.role);
+            let datetime = chrono::NaiveDateTime::from_timestamp_opt(msg.created_at as i64, 0)
+                .map(|dt| dt.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+                .unwrap_or_default();
+
+            html.push_str(&format!("<div class=\"message {}\">\n", role));
+            html.push_str(&format!("<strong>{}</strong>\n", role));
+
+            // Segments alternate between plain text and code, starting with text.
+            for (i, segment) in msg.plain_text().split("```").enumerate() {
+                if i % 2 == 0 {
+                    let text = segment.trim();
+                    if !text.is_empty() {
+                        html.push_str(&format!("<p>{}</p>\n", escape_html(text)));
+                    }
+                } else {
+                    // The first line of a fence may name the language of the block.
+                    let (lang, code) = match segment.split_once('\n') {
+                        Some((first, rest)) if !first.trim().contains(' ') => (first.trim(), rest),
+                        _ => ("", segment),
+                    };
+
+                    if lang.is_empty() {
+                        html.push_str("<pre><code>");
+                    } else {
+                        html.push_str(&format!(
+                            "<pre><code class=\"language-{}\">",
+                            escape_html(lang)
+                        ));
+                    }
+                    html.push_str(&escape_html(code.trim_end()));
+                    html.push_str("</code></pre>\n");
+                }
+            }
+
+            html.push_str(&format!(
+                "<time datetime=\"{}\">{}</time>\n</div>\n",
+                datetime,
+                datetime.replace('T', " ").trim_end_matches('Z')
+            ));
+        }
+
+        html.push_str("</body>\n</html>\n");
+
+        html
+    }
+}
+
+/// Struct for storing all Chat sessions
+/// Hold functions for creating and keeping track of new sessions
+#[derive(Debug, Clone)]
+pub struct Store {
+    /// This store's sessions, keyed by id
+    sessions: IdMap<ChatSession>,
+
+    /// The provider this store's sessions send their messages to by default
+    provider: Arc<dyn LlmProvider>,
+
+    /// Additional providers sessions can pick by name
+    providers: HashMap<String, Arc<dyn LlmProvider>>,
+
+    /// Reusable prompts, in the order they were added
+    prompts: Vec<PromptTemplate>,
+
+    /// Embeds messages for semantic search. Semantic search is disabled if
+    /// None.
+    embedder: Option<Arc<dyn Embedder>>,
+
+    /// Embeddings of the messages in this store
+    embedding_index: EmbeddingIndex,
+
+    /// How requests that fail for transient reasons are retried
+    retry_policy: RetryPolicy,
+
+    /// Tools the chat model can call while answering
+    tools: ToolRegistry,
+
+    /// The models fetched from each provider
+    models: ModelCatalog,
+
+    /// Where this store is saved to after every change, if anywhere.
+    backend: Option<Arc<dyn StorageBackend>>,
+
+    /// Deleted sessions and messages that can still be restored
+    trash: Trash,
+
+    /// How long items are kept in the trash, in seconds
+    trash_retention: u64,
+
+    /// Told about every change made through this store, if anything
+    listener: Option<Arc<dyn StoreListener>>,
+
+    /// Facts remembered about the user, oldest first
+    memories: Vec<Memory>,
+
+    /// Whether facts are remembered, and how many new sessions are given
+    memory_config: MemoryConfig,
+
+    /// Queues requests to providers with rate limits
+    rate_limiter: RateLimiter,
+
+    /// Whether User messages are moderated, and what happens to flagged ones
+    moderation_config: ModerationConfig,
+
+    /// Sampling temperature of responses, or None for the provider's default
+    temperature: Option<f32>,
+
+    /// Answers kept to be reused when the same question is asked again
+    response_cache: ResponseCache,
+
+    /// How much may be spent each month, and the warnings given about it
+    budget: Budget,
+
+    /// The number of changes made to the messages of each session
+    revisions: Arc<Mutex<HashMap<SessionId, u64>>>,
+}
+
+impl Store {
+    ///Create a new Store with no sessions. Ownership of the
+    /// provider is moved to this struct
+    pub fn new<P: LlmProvider + 'static>(provider: P) -> Store {
+        Store {
+            sessions: IdMap::new(),
+            provider: Arc::new(provider),
+            providers: HashMap::new(),
+            prompts: Vec::new(),
+            embedder: None,
+            embedding_index: EmbeddingIndex::new(),
+            retry_policy: RetryPolicy::default(),
+            tools: ToolRegistry::default(),
+            models: ModelCatalog::default(),
+            backend: None,
+            trash: Trash::default(),
+            trash_retention: TrashConfig::default().retention_secs(),
+            listener: None,
+            memories: Vec::new(),
+            memory_config: MemoryConfig::default(),
+            rate_limiter: RateLimiter::default(),
+            moderation_config: ModerationConfig::default(),
+            temperature: None,
+            response_cache: ResponseCache::default(),
+            budget: Budget::default(),
+            revisions: Arc::default(),
+        }
+    }
+
+    /// Create a Store from the snapshot saved in `backend`, or an empty Store
+    /// if nothing has been saved yet. The store is saved back to `backend`
+    /// after every change made through it.
+    pub fn load<P: LlmProvider + 'static, B: StorageBackend + 'static>(
+        provider: P,
+        backend: B,
+    ) -> Result<Store, ChatError> {
+        let snapshot = backend.load()?.unwrap_or_default();
+
+        Ok(Store {
+            sessions: indexed::from_items(snapshot.sessions),
+            provider: Arc::new(provider),
+            providers: HashMap::new(),
+            prompts: snapshot.prompts,
+            embedder: None,
+            embedding_index: EmbeddingIndex::new(),
+            retry_policy: RetryPolicy::default(),
+            tools: ToolRegistry::default(),
+            models: ModelCatalog::default(),
+            backend: Some(Arc::new(backend)),
+            trash: snapshot.trash,
+            trash_retention: TrashConfig::default().retention_secs(),
+            listener: None,
+            memories: snapshot.memories,
+            memory_config: MemoryConfig::default(),
+            rate_limiter: RateLimiter::default(),
+            moderation_config: ModerationConfig::default(),
+            temperature: None,
+            response_cache: ResponseCache::default(),
+            budget: Budget::default(),
+            revisions: Arc::default(),
+        })
+    }
+
+    /// Returns a snapshot of the persisted state of this store
+    pub fn snapshot(&self) -> StoreSnapshot {
+        StoreSnapshot {
+            sessions: self
+                .sessions
+                .values()
+                .filter(|x| !x.ephemeral)
+                .cloned()
+                .collect(),
+            prompts: self.prompts.clone(),
+            trash: self.trash.clone(),
+            memories: self.memories.clone(),
+        }
+    }
+
+    /// Replaces the sessions and prompts of this store with the snapshot
+    /// saved in `backend`, saving to `backend` from then on. Used to open a
+    /// store that could not be loaded at startup as it was locked.
+    pub fn restore<B: StorageBackend + 'static>(&mut self, backend: B) -> Result<(), ChatError> {
+        let snapshot = backend.load()?.unwrap_or_default();
+
+        self.sessions = indexed::from_items(snapshot.sessions);
+        self.prompts = snapshot.prompts;
+        self.trash = snapshot.trash;
+        self.memories = snapshot.memories;
+        self.backend = Some(Arc::new(backend));
+        self.notify(StoreEvent::Reloaded);
+
+        Ok(())
+    }
+
+    /// Replaces the sessions, prompts, memories and trash of this store with
+    /// those of `snapshot`, such as one restored from a backup, and saves it.
+    /// Returns `ChatError::StoreLocked` for a store that is not saved yet,
+    /// since the snapshot would be lost once it is unlocked.
+    pub fn replace_snapshot(&mut self, snapshot: StoreSnapshot) -> Result<(), ChatError> {
+        if !self.is_persisted() {
+            return Err(ChatError::StoreLocked);
+        }
+
+        self.sessions = indexed::from_items(snapshot.sessions);
+        self.prompts = snapshot.prompts;
+        self.trash = snapshot.trash;
+        self.memories = snapshot.memories;
+        self.save()?;
+        self.notify(StoreEvent::Reloaded);
+
+        Ok(())
+    }
+
+    /// Saves this store to `backend` from now on, such as the same file with
+    /// encryption turned on, and saves it there right away
+    pub fn set_backend<B: StorageBackend + 'static>(
+        &mut self,
+        backend: B,
+    ) -> Result<(), ChatError> {
+        self.backend = Some(Arc::new(backend));
+        self.save()
+    }
+
+    /// Returns the kind of backend this store is saved to, or None if it is
+    /// not saved
+    pub fn storage_kind(&self) -> Option<StorageKind> {
+        self.backend.as_ref().map(|x| x.kind())
+    }
+
+    /// Returns the key this store is encrypted with when saved, or None if it
+    /// is saved unencrypted or not at all
+    pub fn encryption_key(&self) -> Option<EncryptionKey> {
+        self.backend
+            .as_ref()
+            .and_then(|x| x.encryption_key())
+            .cloned()
+    }
+
+    /// Returns whether this store is saved to a backend. Stores still locked
+    /// are not, so they can not overwrite the encrypted file.
+    pub fn is_persisted(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    /// Saves this store to its backend. Does nothing for stores that were not
+    /// created with `Store::load`.
+    pub fn save(&self) -> Result<(), ChatError> {
+        match &self.backend {
+            Some(backend) => backend.save(&self.snapshot()),
+            None => Ok(()),
+        }
+    }
+
+    /// Saves this store after a change. Failures are reported but otherwise
+    /// ignored so a failing disk does not stop the chat from working.
+    fn autosave(&self) {
+        if let Err(e) = self.save() {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Tells `listener` about every change made through this store from now
+    /// on, replacing any previous listener
+    pub fn set_listener<L: StoreListener + 'static>(&mut self, listener: L) {
+        self.listener = Some(Arc::new(listener));
+    }
+
+    /// Tells this store's listener, if any, about `event`. Changes to
+    /// messages are counted in their session's revision either way, so pages
+    /// taken before a listener is set are not mistaken for current ones.
+    fn notify(&self, event: StoreEvent) {
+        let delta = self.message_delta(&event);
+        if let Some(listener) = &self.listener {
+            listener.on_event(&event);
+            if let Some(delta) = delta {
+                listener.on_event(&StoreEvent::MessageDelta(delta));
+            }
+        }
+    }
+
+    /// Returns the delta patching a copy of a session with the change to a
+    /// message in `event`, counting it in the session's revision, or None if
+    /// no message changed
+    fn message_delta(&self, event: &StoreEvent) -> Option<MessageDeltaPayload> {
+        let (session_id, op, message_id, message) = match event {
+            StoreEvent::MessageAdded(x) => {
+                (x.session_id, DeltaOp::Added, x.message.id, Some(&x.message))
+            }
+            StoreEvent::MessageUpdated(x) => (
+                x.session_id,
+                DeltaOp::Updated,
+                x.message.id,
+                Some(&x.message),
+            ),
+            StoreEvent::MessageDeleted(x) => (x.session_id, DeltaOp::Deleted, x.message_id, None),
+            _ => return None,
+        };
+        let position = message
+            .and_then(|_| self.get_session(session_id))
+            .and_then(|x| x.messages.get_index_of(&message_id));
+
+        let mut revisions = self.revisions.lock().unwrap_or_else(|e| e.into_inner());
+        let revision = revisions.entry(session_id).or_default();
+        *revision += 1;
+
+        Some(MessageDeltaPayload {
+            session_id,
+            revision: *revision,
+            op,
+            message_id,
+            position,
+            message: message.cloned(),
+        })
+    }
+
+    /// Returns the number of changes made to the messages of the session with
+    /// matching id, the revision its messages are at
+    pub fn session_revision(&self, session_id: SessionId) -> u64 {
+        let revisions = self.revisions.lock().unwrap_or_else(|e| e.into_inner());
+
+        revisions.get(&session_id).copied().unwrap_or(0)
+    }
+
+    /// Tells the listener that the session with matching id was added, or
+    /// changed if not `created`
+    fn notify_session(&self, session_id: SessionId, created: bool) {
+        if self.listener.is_none() {
+            return;
+        }
+        if let Some(session) = self.get_session(session_id) {
+            let summary = session.summary();
+            self.notify(match created {
+                true => StoreEvent::SessionCreated(summary),
+                false => StoreEvent::SessionUpdated(summary),
+            });
+        }
+    }
+
+    /// Tells the listener the message with id `message_id` now has `status`
+    fn notify_status(&self, session_id: SessionId, message_id: MessageId, status: MessageStatus) {
+        self.notify(StoreEvent::MessageStatus(MessageStatusPayload {
+            session_id,
+            message_id,
+            status,
+        }));
+    }
+
+    /// Tells the listener which messages of the session with id `session_id`
+    /// were deleted or added since it held the messages with ids `before`,
+    /// and that the message with id `updated`, if any, changed
+    fn notify_messages(
+        &self,
+        session_id: SessionId,
+        before: &[MessageId],
+        updated: Option<MessageId>,
+    ) {
+        if self.listener.is_none() {
+            return;
+        }
+        let Some(session) = self.get_session(session_id) else {
+            return;
+        };
+
+        for &message_id in before {
+            if !session.messages.contains_key(&message_id) {
+                self.notify(StoreEvent::MessageDeleted(MessageDeletedPayload {
+                    session_id,
+                    message_id,
+                }));
+            }
+        }
+        if let Some(message) = updated.and_then(|id| session.messages.get(&id)) {
+            self.notify(StoreEvent::MessageUpdated(MessagePayload {
+                session_id,
+                message: message.clone(),
+            }));
+        }
+
+        let before: HashSet<&MessageId> = before.iter().collect();
+        for message in session.messages.values() {
+            if !before.contains(&message.id) {
+                self.notify(StoreEvent::MessageAdded(MessagePayload {
+                    session_id,
+                    message: message.clone(),
+                }));
+            }
+        }
+    }
+
+    /// Returns the sessions in this store, in the order they were added
+    pub fn get_all_sessions(&self) -> Vec<&ChatSession> {
+        self.sessions.values().collect()
+    }
+
+    /// Finds and returns a reference to the chat session with matching id if any exists
+    pub fn get_session(&self, id: SessionId) -> Option<&ChatSession> {
+        self.sessions.get(&id)
+    }
+
+    /// Replaces the provider sessions use by default
+    pub fn set_default_provider<P: LlmProvider + 'static>(&mut self, provider: P) {
+        self.provider = Arc::new(provider);
+        self.models.invalidate(None);
+    }
+
+    /// Registers `provider` under `name` so sessions can send their messages
+    /// to it, replacing any provider previously registered under that name.
+    pub fn register_provider<P: LlmProvider + 'static>(&mut self, name: &str, provider: P) {
+        self.providers.insert(name.to_string(), Arc::new(provider));
+        self.models.invalidate(Some(name));
+    }
+
+    /// Removes the provider registered under `name`, if any. Sessions using
+    /// it fail with `ChatError::ProviderNotFound` until another provider is
+    /// registered under that name.
+    pub fn unregister_provider(&mut self, name: &str) {
+        self.providers.remove(name);
+        self.models.invalidate(Some(name));
+    }
+
+    /// Replaces how requests that fail for transient reasons, such as rate
+    /// limiting, are retried
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Returns how requests that fail for transient reasons are retried
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    /// Offers `tool` to the chat model in every request sent through this
+    /// store, replacing any tool with the same name
+    pub fn register_tool<T: Tool + 'static>(&mut self, tool: T) {
+        self.tools.register(tool);
+    }
+
+    /// Stops offering the tool named `name`. Returns false if there was none.
+    pub fn unregister_tool(&mut self, name: &str) -> bool {
+        self.tools.unregister(name)
+    }
+
+    /// Returns the names of the tools offered to the chat model, sorted
+    pub fn get_tool_names(&self) -> Vec<&str> {
+        self.tools.get_names()
+    }
+
+    /// Returns the names of the registered providers, in alphabetical order.
+    /// The default provider is not included.
+    pub fn get_provider_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.providers.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Returns the provider registered under `name`, or the default provider
+    /// if `name` is None. Providers with rate limits have their requests
+    /// queued to keep within them.
+    fn provider_named(&self, name: Option<&str>) -> Result<Arc<dyn LlmProvider>, ChatError> {
+        let provider = match name {
+            None => self.provider.clone(),
+            Some(name) => self
+                .providers
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ChatError::ProviderNotFound(name.to_string()))?,
+        };
+
+        Ok(self
+            .rate_limiter
+            .wrap(name, provider, self.listener.clone()))
+    }
+
+    /// Returns how many requests and tokens may be sent to each provider
+    /// every minute
+    pub fn get_rate_limit_config(&self) -> &RateLimitConfig {
+        self.rate_limiter.get_config()
+    }
+
+    /// Replaces how many requests and tokens may be sent to each provider
+    /// every minute with `config`. Requests already waiting keep to the
+    /// limits they were queued under.
+    pub fn set_rate_limit_config(&mut self, config: RateLimitConfig) {
+        self.rate_limiter.set_config(config);
+    }
+
+    /// Returns the ids of the chat models available from the provider
+    /// registered under `provider`, or from the default provider if None.
+    pub async fn list_models(&self, provider: Option<&str>) -> Result<Vec<String>, ChatError> {
+        self.provider_named(provider)?.list_models().await
+    }
+
+    /// Like `list_models`, but describes each model and reuses the models
+    /// fetched within the last hour unless `refresh` is set. Models are
+    /// sorted by id.
+    pub async fn model_catalog(
+        &self,
+        provider: Option<&str>,
+        refresh: bool,
+    ) -> Result<Vec<ModelInfo>, ChatError> {
+        if !refresh {
+            if let Some(models) = self.models.get(provider) {
+                return Ok(models);
+            }
+        }
+
+        let ids = self.list_models(provider).await?;
+
+        Ok(self.models.insert(provider, ids))
+    }
+
+    /// Returns a mutable reference to the session with matching id, first
+    /// inserting the session returned by `f` if none exists. The inserted
+    /// session takes `id`, whatever id `f` gave it.
+    pub fn get_session_or_insert_with<F: FnOnce() -> ChatSession>(
+        &mut self,
+        id: SessionId,
+        f: F,
+    ) -> &mut ChatSession {
+        let index = match self.sessions.get_index_of(&id) {
+            Some(index) => index,
+            None => {
+                let mut session = f();
+                session.id = id;
+                let (index, _) = self.sessions.insert_full(id, session);
+                self.autosave();
+                self.notify_session(id, true);
+                index
+            }
+        };
+
+        &mut self.sessions[index]
+    }
+
+    /// Returns an iterator over the messages of every session in this store
+    pub fn iter_messages(&self) -> impl Iterator<Item = &Message> {
+        self.sessions
+            .values()
+            .flat_map(|session| session.messages.values())
+    }
+
+    /// Returns true if any message in this store contains `query`, ignoring case.
+    pub fn has_any_message_containing(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+
+        self.iter_messages()
+            .any(|msg| msg.plain_text().to_lowercase().contains(&query))
+    }
+
+    /// Searches the titles and message contents of every session for `query`,
+    /// ignoring case. Results are ranked from best to worst match.
+    pub fn search(&self, query: &str) -> Vec<search::SearchResult> {
+        search::search(self.sessions.values(), query)
+    }
+
+    /// Returns the `limit` results of `search` for `query` after skipping
+    /// `offset`. Stores saved to a backend with a full-text index, such as
+    /// `SqliteBackend`, query it instead of searching every message, matching
+    /// words that start with those of the query.
+    pub fn search_page(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<search::SearchResult> {
+        let hits = self
+            .backend
+            .as_ref()
+            .and_then(|x| x.search(query, offset, limit));
+        let hits = match hits {
+            Some(Ok(hits)) => hits,
+            Some(Err(e)) => {
+                eprintln!("Could not search the store's index: {}", e);
+                return vec![];
+            }
+            None => {
+                return self
+                    .search(query)
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit)
+                    .collect()
+            }
+        };
+
+        let count = hits.len();
+        hits.into_iter()
+            .enumerate()
+            .filter_map(|(i, (session_id, message_id))| {
+                let session = self.get_session(session_id)?;
+                search::result_of(session, message_id, query, count - i)
+            })
+            .collect()
+    }
+
+    /// Enables semantic search, embedding messages with `embedder` and keeping
+    /// their embeddings in `index`
+    pub fn enable_semantic_search<E: Embedder + 'static>(
+        &mut self,
+        embedder: E,
+        index: EmbeddingIndex,
+    ) {
+        self.embedder = Some(Arc::new(embedder));
+        self.embedding_index = index;
+    }
+
+    /// Replaces the embedder used for semantic search, enabling it if needed
+    pub fn set_embedder<E: Embedder + 'static>(&mut self, embedder: E) {
+        self.embedder = Some(Arc::new(embedder));
+    }
+
+    /// Returns the `k` messages most similar in meaning to `query`, best first.
+    /// Messages not embedded yet are embedded first.
+    pub async fn semantic_search(
+        &mut self,
+        query: &str,
+        k: usize,
+    ) -> Result<Vec<SemanticMatch>, ChatError> {
+        let embedder = self
+            .embedder
+            .clone()
+            .ok_or(ChatError::SemanticSearchDisabled)?;
+
+        self.embedding_index
+            .update(self.sessions.values(), embedder.as_ref())
+            .await?;
+        let query = self.prepare_semantic_query(query, k)?.complete().await?;
+
+        Ok(self.semantic_matches(query))
+    }
+
+    /// Prepares the next batch of messages to embed for semantic search,
+    /// returning None if every message is embedded. Commit its embeddings
+    /// with `commit_embeddings`.
+    pub fn prepare_embeddings(&self) -> Result<Option<EmbeddingRequest>, ChatError> {
+        let embedder = self
+            .embedder
+            .clone()
+            .ok_or(ChatError::SemanticSearchDisabled)?;
+
+        Ok(self
+            .embedding_index
+            .prepare(self.sessions.values(), embedder))
+    }
+
+    /// Adds embeddings prepared by `prepare_embeddings` to the semantic
+    /// search index
+    pub fn commit_embeddings(&mut self, completed: CompletedEmbeddings) -> Result<(), ChatError> {
+        self.embedding_index
+            .commit_embeddings(self.sessions.values(), completed)
+    }
+
+    /// Prepares `query` to be embedded, for a semantic search of the `k`
+    /// messages closest to it with `semantic_matches`
+    pub fn prepare_semantic_query(
+        &self,
+        query: &str,
+        k: usize,
+    ) -> Result<SemanticQuery, ChatError> {
+        let embedder = self
+            .embedder
+            .clone()
+            .ok_or(ChatError::SemanticSearchDisabled)?;
+
+        Ok(SemanticQuery::new(embedder, query, k))
+    }
+
+    /// Returns the embedded messages most similar in meaning to `query`,
+    /// best first
+    pub fn semantic_matches(&self, query: EmbeddedQuery) -> Vec<SemanticMatch> {
+        query.nearest(&self.embedding_index, self.sessions.values())
+    }
+
+    /// Returns the summary of the session with matching id, without the
+    /// contents of its messages.
+    pub fn get_session_summary(&self, session_id: SessionId) -> Result<SessionSummary, ChatError> {
+        self.get_session(session_id)
+            .map(|session| session.summary())
+            .ok_or(ChatError::SessionNotFound(session_id))
+    }
+
+    /// Add new messages to the store, creating a chat session for them.
+    /// `title` is the title of the chat session created. The messages are
+    /// consumed in the process.
+    /// `model` must be a valid chat model
+    ///
+    /// Only request messages can be used to create a new session, either a
+    /// User message or a System message, which becomes the session's system
+    /// prompt, then a User message. Returns the id of the created session, or
+    /// `ChatError::InvalidOpeningRoles` for messages of other roles.
+    pub async fn add_session(
+        &mut self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        title: String,
+        model: &str,
+    ) -> Result<SessionId, ChatError> {
+        self.add_session_with_provider(messages, title, model, None)
+            .await
+    }
+
+    /// Like `add_session`, but the created session sends its messages to the
+    /// provider registered under `provider`. The default provider is used if
+    /// None.
+    pub async fn add_session_with_provider(
+        &mut self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        title: String,
+        model: &str,
+        provider: Option<&str>,
+    ) -> Result<SessionId, ChatError> {
+        let pending = self.prepare_session(messages, title, model, provider)?;
+        let completed = pending.complete(&CancellationToken::new()).await?;
+
+        self.commit(completed).map(|(id, _)| id)
+    }
+
+    /// Returns a mutable reference to the session with matching id
+    fn session_mut(&mut self, session_id: SessionId) -> Result<&mut ChatSession, ChatError> {
+        self.sessions
+            .get_mut(&session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))
+    }
+
+    /// Prepares the request for `action` in the session with matching id
+    fn prepare_action(
+        &self,
+        session_id: SessionId,
+        action: ResponseAction,
+    ) -> Result<PendingRequest, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+        self.check_budget()?;
+        let mut provider = self.provider_named(session.provider.as_deref())?;
+        // Regenerating asks for a different answer, so it is never cached
+        if !matches!(action, ResponseAction::Regenerate { .. }) {
+            provider = self
+                .response_cache
+                .wrap(session.provider.as_deref(), provider);
+        }
+        let mut request = session.prepare(&action)?;
+        request.functions = self.tools.definitions();
+        request.temperature = self.temperature;
+
+        Ok(PendingRequest {
+            target: RequestTarget::Existing(session_id),
+            provider,
+            request,
+            moderation: self.moderation_check(&action),
+            action,
+            retry_policy: self.retry_policy,
+            tools: self.tools.clone(),
+        })
+    }
+
+    /// Returns the check of the User message `action` sends, or None if
+    /// moderation is off or it sends none. Messages are always checked by
+    /// the default provider, as other providers have no moderation endpoint.
+    fn moderation_check(&self, action: &ResponseAction) -> Option<ModerationCheck> {
+        let text = match action {
+            ResponseAction::Append { contents }
+            | ResponseAction::AppendImage { contents, .. }
+            | ResponseAction::Edit { contents, .. } => contents,
+            ResponseAction::Regenerate { .. }
+            | ResponseAction::SendQueued { .. }
+            | ResponseAction::Continue { .. } => return None,
+        };
+
+        self.moderation_of(text)
+    }
+
+    /// Returns the check of a User message with `text`, or None if
+    /// moderation is off
+    fn moderation_of(&self, text: &str) -> Option<ModerationCheck> {
+        self.moderation_config.enabled.then(|| ModerationCheck {
+            provider: self.provider.clone(),
+            text: text.to_string(),
+            on_flagged: self.moderation_config.on_flagged,
+        })
+    }
+
+    /// Returns whether User messages are moderated, and what happens to
+    /// flagged ones
+    pub fn get_moderation_config(&self) -> ModerationConfig {
+        self.moderation_config
+    }
+
+    /// Replaces whether User messages are moderated, and what happens to
+    /// flagged ones, with `config`
+    pub fn set_moderation_config(&mut self, config: ModerationConfig) {
+        self.moderation_config = config;
+    }
+
+    /// Returns how much may be spent each month, and what happens once it
+    /// has been
+    pub fn get_budget_config(&self) -> &BudgetConfig {
+        &self.budget.config
+    }
+
+    /// Replaces how much may be spent each month, and what happens once it
+    /// has been, with `config`
+    pub fn set_budget_config(&mut self, config: BudgetConfig) {
+        self.budget.config = config;
+    }
+
+    /// Lets requests through for the rest of the month even though its
+    /// budget has been spent, returning the config to save
+    pub fn override_budget(&mut self) -> BudgetConfig {
+        let (month, _) = budget::month_of(current_timestamp());
+        self.budget.config.override_month = Some(month);
+        self.budget.config.clone()
+    }
+
+    /// Keeps track of what is spent in `ledger`. An empty ledger, such as
+    /// one not saved before, starts with what this month's messages cost.
+    pub fn set_spending_ledger(&mut self, mut ledger: SpendingLedger) {
+        if ledger.is_empty() {
+            let (_, range) = budget::month_of(current_timestamp());
+            let spent = self.daily_usage(range).iter().map(|x| x.cost).sum();
+            if let Err(e) = ledger.record(current_timestamp(), spent) {
+                eprintln!("Could not save the spending ledger: {}", e);
+            }
+        }
+
+        self.budget.ledger = ledger;
+    }
+
+    /// Returns what has been spent this month, measured against the budget
+    pub fn budget_status(&self) -> BudgetStatus {
+        let (month, _) = budget::month_of(current_timestamp());
+        let spent = self.budget.ledger.spent(&month);
+
+        self.budget.config.status(&month, spent)
+    }
+
+    /// Adds `cost` in USD, if known, to what has been spent this month
+    fn record_spending(&mut self, cost: Option<f64>) {
+        let Some(cost) = cost else {
+            return;
+        };
+
+        if let Err(e) = self.budget.ledger.record(current_timestamp(), cost) {
+            eprintln!("Could not save the spending ledger: {}", e);
+        }
+    }
+
+    /// Returns `ChatError::BudgetExceeded` if requests are refused as this
+    /// month's budget has been spent
+    fn check_budget(&self) -> Result<(), ChatError> {
+        let limit = match self.budget.config.monthly_limit {
+            Some(limit) if self.budget.config.enforce => limit,
+            _ => return Ok(()),
+        };
+
+        match self.budget_status().blocked {
+            true => Err(ChatError::BudgetExceeded(limit)),
+            false => Ok(()),
+        }
+    }
+
+    /// Warns the listener if spending has reached a new level of this
+    /// month's budget
+    fn notify_budget(&mut self) {
+        if self.budget.config.monthly_limit.is_none() {
+            return;
+        }
+
+        let status = self.budget_status();
+        if self.budget.should_warn(&status) {
+            self.notify(StoreEvent::Budget(status));
+        }
+    }
+
+    /// Returns whether responses are cached, and for how long
+    pub fn get_cache_config(&self) -> CacheConfig {
+        self.response_cache.get_config()
+    }
+
+    /// Replaces whether responses are cached, and for how long, with
+    /// `config`. Turning the cache off drops every kept answer.
+    pub fn set_cache_config(&mut self, config: CacheConfig) {
+        self.response_cache.set_config(config);
+    }
+
+    /// Drops every cached answer, returning how many there were
+    pub fn clear_response_cache(&self) -> usize {
+        self.response_cache.clear()
+    }
+
+    /// Replaces the sampling temperature of responses with `temperature`, or
+    /// the provider's default if None
+    pub fn set_temperature(&mut self, temperature: Option<f32>) {
+        self.temperature = temperature;
+    }
+
+    /// Prepares the request for creating a session titled `title` with
+    /// `messages`, checked as in `add_session`. The session is only added to
+    /// the store once the request is committed. See
+    /// `add_session_with_provider`.
+    pub fn prepare_session(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        title: String,
+        model: &str,
+        provider: Option<&str>,
+    ) -> Result<PendingRequest, ChatError> {
+        let roles: Vec<ChatRole> = messages.iter().map(|x| x.get_role().into()).collect();
+        role::validate_opening(&roles)?;
+        self.check_budget()?;
+        let llm = self
+            .response_cache
+            .wrap(provider, self.provider_named(provider)?);
+
+        // The User message comes last, after any System message
+        let mut contents: Vec<String> = messages.iter().map(|x| x.get_content()).collect();
+        let user = contents.pop().unwrap_or_default();
+        let system = contents.pop();
+        let mut chs = ChatSession::new(title, model);
+        chs.provider = provider.map(String::from);
+        chs.system_prompt = system;
+        chs.memory_prompt = self.memory_prompt_for(&user);
+
+        let action = ResponseAction::Append { contents: user };
+        let mut request = chs.prepare(&action)?;
+        request.functions = self.tools.definitions();
+        request.temperature = self.temperature;
+
+        Ok(PendingRequest {
+            target: RequestTarget::New(Box::new(chs)),
+            provider: llm,
+            request,
+            moderation: self.moderation_check(&action),
+            action,
+            retry_policy: self.retry_policy,
+            tools: self.tools.clone(),
+        })
+    }
+
+    /// Prepares the request for sending a User message with `contents` in the
+    /// session with matching id. See `send_message`.
+    pub fn prepare_message(
+        &self,
+        session_id: SessionId,
+        contents: String,
+    ) -> Result<PendingRequest, ChatError> {
+        self.prepare_action(session_id, ResponseAction::Append { contents })
+    }
+
+    /// Prepares the request for sending a User message with `contents` and the
+    /// image at `image` in the session with matching id. Sessions whose model
+    /// can not see images ask `vision::VISION_MODEL` instead.
+    pub fn prepare_image_message(
+        &self,
+        session_id: SessionId,
+        contents: String,
+        image: PathBuf,
+    ) -> Result<PendingRequest, ChatError> {
+        self.prepare_action(session_id, ResponseAction::AppendImage { contents, image })
+    }
+
+    /// Prepares the request for regenerating the response with id
+    /// `message_id` in the session with id `session_id`. See
+    /// `regenerate_message`.
+    pub fn prepare_regenerate(
+        &self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<PendingRequest, ChatError> {
+        self.prepare_action(session_id, ResponseAction::Regenerate { message_id })
+    }
+
+    /// Prepares the request for continuing the response with id `message_id`
+    /// in the session with id `session_id`. See `continue_message`.
+    pub fn prepare_continue(
+        &self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<PendingRequest, ChatError> {
+        self.prepare_action(session_id, ResponseAction::Continue { message_id })
+    }
+
+    /// Prepares the request for editing the User message with id `message_id`
+    /// in the session with id `session_id`. See `edit_message`.
+    pub fn prepare_edit(
+        &self,
+        session_id: SessionId,
+        message_id: MessageId,
+        new_content: String,
+    ) -> Result<PendingRequest, ChatError> {
+        self.prepare_action(
+            session_id,
+            ResponseAction::Edit {
+                message_id,
+                contents: new_content,
+            },
+        )
+    }
+
+    /// Stores the response of a request prepared by this store, returning
+    /// the id of its session and a copy of the message holding the response.
+    ///
+    /// Fails if the session, or the message the request refers to, was
+    /// deleted while the request was running.
+    pub fn commit(
+        &mut self,
+        completed: CompletedRequest,
+    ) -> Result<(SessionId, Message), ChatError> {
+        let CompletedRequest {
+            target,
+            action,
+            outcome,
+        } = completed;
+
+        let updated = match &action {
+            ResponseAction::Regenerate { message_id }
+            | ResponseAction::Edit { message_id, .. }
+            | ResponseAction::SendQueued { message_id }
+            | ResponseAction::Continue { message_id } => Some(*message_id),
+            ResponseAction::Append { .. } | ResponseAction::AppendImage { .. } => None,
+        };
+
+        let sent = match &action {
+            ResponseAction::SendQueued { message_id } => Some(*message_id),
+            _ => None,
+        };
+        let cost = outcome
+            .completion
+            .usage
+            .and_then(|usage| usage::cost(&outcome.model, &usage));
+        self.record_spending(cost);
+
+        let (id, message, before) = match target {
+            RequestTarget::Existing(id) => {
+                let session = self.session_mut(id)?;
+                let before: Vec<MessageId> = session.messages.keys().copied().collect();
+                let message = session.apply(action, outcome)?;
+                (id, message, Some(before))
+            }
+            RequestTarget::New(mut chs) => {
+                let id = chs.id;
+                let message = chs.apply(action, outcome)?;
+
+                self.sessions.insert(id, *chs);
+                (id, message, None)
+            }
+        };
+        self.autosave();
+        match before {
+            Some(before) => self.notify_messages(id, &before, updated),
+            None => self.notify_session(id, true),
+        }
+        if let Some(message_id) = sent {
+            self.notify_status(id, message_id, MessageStatus::Sent);
+        }
+        self.notify_budget();
+
+        Ok((id, message))
+    }
+
+    /// Adds the User message of a request that failed because the chat model
+    /// could not be reached to its session, with a Pending status, so it can
+    /// be sent with `prepare_queued` once the chat model can be reached. New
+    /// sessions are added to the store. Returns the id of the session and a
+    /// copy of the message.
+    pub fn queue_offline(
+        &mut self,
+        queued: QueuedMessage,
+    ) -> Result<(SessionId, Message), ChatError> {
+        let QueuedMessage { target, contents } = queued;
+        let parts = vec![MessageContent::text(contents)];
+
+        let (id, before) = match target {
+            RequestTarget::Existing(id) => {
+                let session = self.session_mut(id)?;
+                let before: Vec<MessageId> = session.messages.keys().copied().collect();
+                session.push_message(ChatRole::User, parts);
+                (id, Some(before))
+            }
+            RequestTarget::New(mut chs) => {
+                let id = chs.id;
+                chs.push_message(ChatRole::User, parts);
+                self.sessions.insert(id, *chs);
+                (id, None)
+            }
+        };
+
+        let session = self.session_mut(id)?;
+        let (_, message) = session
+            .messages
+            .last_mut()
+            .ok_or(ChatError::SessionNotFound(id))?;
+        message.status = MessageStatus::Pending;
+        let message = message.clone();
+
+        self.autosave();
+        match before {
+            Some(before) => self.notify_messages(id, &before, None),
+            None => self.notify_session(id, true),
+        }
+        self.notify_status(id, message.id, MessageStatus::Pending);
+
+        Ok((id, message))
+    }
+
+    /// Returns the ids of the User messages queued while offline, along with
+    /// the ids of their sessions, oldest first
+    pub fn get_queued_messages(&self) -> Vec<(SessionId, MessageId)> {
+        let mut queued: Vec<(SessionId, MessageId)> = self
+            .sessions
+            .values()
+            .flat_map(|session| {
+                session
+                    .messages
+                    .values()
+                    .filter(|x| x.status == MessageStatus::Pending)
+                    .map(|x| (session.id, x.id))
+            })
+            .collect();
+        queued.sort_by_key(|(_, message_id)| *message_id);
+
+        queued
+    }
+
+    /// Prepares the request for sending the User message with id
+    /// `message_id`, queued while offline. Its response is added right after
+    /// it once committed. Returns `ChatError::NotQueued` if the message is no
+    /// longer Pending.
+    pub fn prepare_queued(
+        &self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<PendingRequest, ChatError> {
+        let mut pending =
+            self.prepare_action(session_id, ResponseAction::SendQueued { message_id })?;
+        let text = self
+            .get_session(session_id)
+            .and_then(|x| x.messages.get(&message_id))
+            .map(Message::get_content)
+            .unwrap_or_default();
+        pending.moderation = self.moderation_of(&text);
+
+        Ok(pending)
+    }
+
+    /// Marks the User message with id `message_id`, queued while offline, as
+    /// failed, so it is not sent again unless edited
+    pub fn fail_queued(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        self.session_mut(session_id)?
+            .messages
+            .get_mut(&message_id)
+            .ok_or(ChatError::MessageNotFound(message_id))?
+            .status = MessageStatus::Failed;
+        self.autosave();
+        self.notify_status(session_id, message_id, MessageStatus::Failed);
+
+        Ok(())
+    }
+
+    /// Prepares the request for summarizing the older messages of the session
+    /// with matching id, or returns None if they still fit comfortably in its
+    /// chat model's context window. Sessions on the default provider are
+    /// summarized with `summarize::SUMMARY_MODEL`, others with their own
+    /// model.
+    pub fn prepare_summary(
+        &self,
+        session_id: SessionId,
+    ) -> Result<Option<SummaryRequest>, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+
+        let messages = session.request_messages(session.messages.values(), None);
+        if !summarize::needs_summary(&messages, &session.model) {
+            return Ok(None);
+        }
+
+        let model = match session.provider {
+            Some(_) => session.model.as_str(),
+            None => summarize::SUMMARY_MODEL,
+        };
+        let Some((request, through)) = summarize::summary_request(session, model) else {
+            return Ok(None);
+        };
+        self.check_budget()?;
+
+        Ok(Some(SummaryRequest {
+            session_id,
+            provider: self.provider_named(session.provider.as_deref())?,
+            request,
+            through,
+            retry_policy: self.retry_policy,
+        }))
+    }
+
+    /// Stores a summary prepared by `prepare_summary` on its session, to be
+    /// sent in place of the messages it covers. Returns false, leaving the
+    /// session as is, if those messages changed while the summary was written
+    /// or a newer summary was stored in the meantime.
+    pub fn commit_summary(&mut self, completed: CompletedSummary) -> Result<bool, ChatError> {
+        self.record_spending(completed.cost);
+        let session = self.session_mut(completed.session_id)?;
+        let Some(index) = session.messages.get_index_of(&completed.through) else {
+            return Ok(false);
+        };
+        let current = session
+            .summary
+            .as_ref()
+            .and_then(|x| session.messages.get_index_of(&x.through));
+        if current >= Some(index) {
+            return Ok(false);
+        }
+
+        session.summary = Some(Box::new(ConversationSummary {
+            message: Message::new(
+                ChatRole::System,
+                vec![MessageContent::text(format!(
+                    "{}\n{}",
+                    summarize::SUMMARY_HEADING,
+                    completed.text
+                ))],
+            ),
+            through: completed.through,
+        }));
+        self.autosave();
+        self.notify_session(completed.session_id, false);
+
+        Ok(true)
+    }
+
+    /// Prepares the request for a new title of the session with matching id,
+    /// after what it is about now, or returns None if it has no messages.
+    /// Sessions on the default provider are titled with
+    /// `titles::TITLE_MODEL`, others with their own model.
+    pub fn prepare_title(&self, session_id: SessionId) -> Result<Option<TitleRequest>, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+
+        let model = match session.provider {
+            Some(_) => session.model.as_str(),
+            None => titles::TITLE_MODEL,
+        };
+        let Some(request) = titles::title_request(session, model) else {
+            return Ok(None);
+        };
+        self.check_budget()?;
+
+        Ok(Some(TitleRequest {
+            session_id,
+            provider: self.provider_named(session.provider.as_deref())?,
+            request,
+            retry_policy: self.retry_policy,
+        }))
+    }
+
+    /// Renames the session a title prepared by `prepare_title` was written
+    /// for to that title
+    pub fn commit_title(&mut self, completed: CompletedTitle) -> Result<(), ChatError> {
+        self.record_spending(completed.cost);
+        self.rename_session(completed.session_id, completed.title)
+    }
+
+    /// Sends a User message with `contents` in the session with matching id,
+    /// returning a copy of the chat model's response. The request is abandoned
+    /// if `cancel` is cancelled before the response arrives.
+    pub async fn send_message(
+        &mut self,
+        session_id: SessionId,
+        contents: String,
+        cancel: &CancellationToken,
+    ) -> Result<Message, ChatError> {
+        let completed = self
+            .prepare_message(session_id, contents)?
+            .complete(cancel)
+            .await?;
+
+        self.commit(completed).map(|(_, message)| message)
+    }
+
+    /// Like `send_message`, but streams the response, calling `on_token` with
+    /// each piece of content as it arrives. If `cancel` is cancelled
+    /// mid-stream, the content received so far is kept as the response.
+    pub async fn send_message_streaming<F: FnMut(&str) + Send>(
+        &mut self,
+        session_id: SessionId,
+        contents: String,
+        on_token: F,
+        cancel: &CancellationToken,
+    ) -> Result<Message, ChatError> {
+        let completed = self
+            .prepare_message(session_id, contents)?
+            .stream(on_token, cancel)
+            .await?;
+
+        self.commit(completed).map(|(_, message)| message)
+    }
+
+    /// Renames the session with matching id to `new_title`
+    pub fn rename_session(
+        &mut self,
+        session_id: SessionId,
+        new_title: String,
+    ) -> Result<(), ChatError> {
+        self.session_mut(session_id)?.rename_session(new_title);
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Makes the session with matching id send its messages to the provider
+    /// registered under `provider`, or the default provider if None.
+    pub fn set_session_provider(
+        &mut self,
+        session_id: SessionId,
+        provider: Option<String>,
+    ) -> Result<(), ChatError> {
+        self.provider_named(provider.as_deref())?;
+        let session = self.session_mut(session_id)?;
+        session.provider = provider;
+        session.profile = None;
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Switches the session with matching id to `profile`, sending its new
+    /// messages to the profile's provider and default model. The session is
+    /// given the profile's system prompt, if it has one. Switching to None
+    /// goes back to the default provider, keeping the model.
+    ///
+    /// Returns `ChatError::ProviderNotFound` if the profile is not registered
+    /// with this store. See `profiles::register`.
+    pub fn set_session_profile(
+        &mut self,
+        session_id: SessionId,
+        profile: Option<&Profile>,
+    ) -> Result<(), ChatError> {
+        let provider = profile.map(|x| profiles::provider_name(&x.name));
+        self.provider_named(provider.as_deref())?;
+
+        let session = self.session_mut(session_id)?;
+        session.provider = provider;
+        session.profile = profile.map(|x| x.name.clone());
+        if let Some(profile) = profile {
+            session.model = profile.default_model.clone();
+            if profile.system_prompt.is_some() {
+                session.set_system_prompt(profile.system_prompt.clone());
+            }
+        }
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Regenerates the response with id `message_id` in the session with id
+    /// `session_id`, returning a copy of the message with its new variant
+    /// active.
+    pub async fn regenerate_message(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+        cancel: &CancellationToken,
+    ) -> Result<Message, ChatError> {
+        let completed = self
+            .prepare_regenerate(session_id, message_id)?
+            .complete(cancel)
+            .await?;
+
+        self.commit(completed).map(|(_, message)| message)
+    }
+
+    /// Asks the chat model to finish the response with id `message_id` in the
+    /// session with id `session_id`, returning a copy of the message with the
+    /// rest appended.
+    pub async fn continue_message(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+        cancel: &CancellationToken,
+    ) -> Result<Message, ChatError> {
+        let completed = self
+            .prepare_continue(session_id, message_id)?
+            .complete(cancel)
+            .await?;
+
+        self.commit(completed).map(|(_, message)| message)
+    }
+
+    /// Replaces the content of the User message with id `message_id` in the
+    /// session with id `session_id` and re-runs the conversation from there,
+    /// returning a copy of the new response.
+    pub async fn edit_message(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+        new_content: String,
+        cancel: &CancellationToken,
+    ) -> Result<Message, ChatError> {
+        let completed = self
+            .prepare_edit(session_id, message_id, new_content)?
+            .complete(cancel)
+            .await?;
+
+        self.commit(completed).map(|(_, message)| message)
+    }
+
+    /// Makes the variant at `index` the content of the message with id
+    /// `message_id` in the session with id `session_id`, returning a copy of
+    /// the message.
+    pub fn select_message_variant(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+        index: usize,
+    ) -> Result<Message, ChatError> {
+        let message = self
+            .session_mut(session_id)?
+            .messages
+            .get_mut(&message_id)
+            .ok_or(ChatError::MessageNotFound(message_id))?;
+
+        if !message.select_variant(index) {
+            return Err(ChatError::VariantNotFound(index));
+        }
+        let message = message.clone();
+        self.session_mut(session_id)?.touch();
+        self.autosave();
+        self.notify(StoreEvent::MessageUpdated(MessagePayload {
+            session_id,
+            message: message.clone(),
+        }));
+
+        Ok(message)
+    }
+
+    /// Sets the system prompt of the session with matching id
+    pub fn set_system_prompt(
+        &mut self,
+        session_id: SessionId,
+        prompt: Option<String>,
+    ) -> Result<(), ChatError> {
+        self.session_mut(session_id)?.set_system_prompt(prompt);
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Sends new messages in the session with matching id to `model`, once it
+    /// is checked against `catalog`, the models offered by the session's
+    /// provider. See `ChatSession::set_model`.
+    pub fn set_session_model(
+        &mut self,
+        session_id: SessionId,
+        model: &str,
+        catalog: &[ModelInfo],
+    ) -> Result<(), ChatError> {
+        self.session_mut(session_id)?.set_model(model, catalog)?;
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Sets whether prompts in the session with matching id are sent to the
+    /// chat model or generate images
+    pub fn set_session_mode(
+        &mut self,
+        session_id: SessionId,
+        mode: SessionMode,
+    ) -> Result<(), ChatError> {
+        self.session_mut(session_id)?.mode = mode;
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Tags the session with matching id with `tag`. Returns false if `tag`
+    /// is empty or the session already has it.
+    pub fn add_session_tag(&mut self, session_id: SessionId, tag: &str) -> Result<bool, ChatError> {
+        let added = self.session_mut(session_id)?.add_tag(tag);
+        if added {
+            self.autosave();
+            self.notify_session(session_id, false);
+        }
+
+        Ok(added)
+    }
+
+    /// Removes `tag` from the session with matching id. Returns false if the
+    /// session did not have it.
+    pub fn remove_session_tag(
+        &mut self,
+        session_id: SessionId,
+        tag: &str,
+    ) -> Result<bool, ChatError> {
+        let removed = self.session_mut(session_id)?.remove_tag(tag);
+        if removed {
+            self.autosave();
+            self.notify_session(session_id, false);
+        }
+
+        Ok(removed)
+    }
+
+    /// Returns the sessions tagged `tag`, ignoring case
+    pub fn filter_by_tag(&self, tag: &str) -> Vec<&ChatSession> {
+        self.sessions.values().filter(|x| x.has_tag(tag)).collect()
+    }
+
+    /// Returns every tag used by a session, sorted and without duplicates
+    /// differing only in case
+    pub fn get_tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = vec![];
+        for tag in self.sessions.values().flat_map(|x| x.tags.iter()) {
+            if !tags.iter().any(|x| x.eq_ignore_ascii_case(tag)) {
+                tags.push(tag);
+            }
+        }
+        tags.sort_by_key(|x| x.to_lowercase());
+
+        tags
+    }
+
+    /// Prepares the request for generating an image from `prompt` in the
+    /// session with matching id
+    pub fn prepare_image(
+        &self,
+        session_id: SessionId,
+        prompt: String,
+        options: ImageOptions,
+    ) -> Result<ImageRequest, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+        self.check_budget()?;
+
+        Ok(ImageRequest {
+            session_id,
+            provider: self.provider_named(session.provider.as_deref())?,
+            prompt,
+            options,
+            replaces: None,
+            retry_policy: self.retry_policy,
+        })
+    }
+
+    /// Prepares the request for generating the image with id `message_id`
+    /// again with `options`, from the prompt before it. The new image
+    /// replaces the old one once committed.
+    pub fn prepare_image_regenerate(
+        &self,
+        session_id: SessionId,
+        message_id: MessageId,
+        options: ImageOptions,
+    ) -> Result<ImageRequest, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+        let (index, _, message) = session
+            .messages
+            .get_full(&message_id)
+            .ok_or(ChatError::MessageNotFound(message_id))?;
+
+        if message.role != ChatRole::Assistant || message.get_image().is_none() {
+            return Err(ChatError::NotAnImage(message_id));
+        }
+        let prompt = session
+            .messages
+            .values()
+            .take(index)
+            .rev()
+            .find(|x| x.role == ChatRole::User)
+            .map(Message::plain_text)
+            .ok_or(ChatError::NotAnImage(message_id))?;
+
+        Ok(ImageRequest {
+            replaces: Some(message_id),
+            ..self.prepare_image(session_id, prompt, options)?
+        })
+    }
+
+    /// Stores a generated image as an Assistant message after its prompt,
+    /// or in place of the image it was regenerated from. Returns a copy of
+    /// the message holding the image.
+    pub fn commit_image(&mut self, completed: CompletedImage) -> Result<Message, ChatError> {
+        self.record_spending(completed.cost);
+        let mut parts = vec![MessageContent::image(completed.path)];
+        if let Some(revised) = completed.revised_prompt {
+            parts.push(MessageContent::text(revised));
+        }
+
+        let session_id = completed.session_id;
+        let session = self.session_mut(session_id)?;
+        let before: Vec<MessageId> = session.messages.keys().copied().collect();
+        let message = match completed.replaces {
+            Some(message_id) => {
+                let msg = session
+                    .messages
+                    .get_mut(&message_id)
+                    .ok_or(ChatError::MessageNotFound(message_id))?;
+                msg.content = parts;
+                msg.model = Some(completed.model);
+                let msg = msg.clone();
+                session.touch();
+                msg
+            }
+            None => {
+                session.push_message(ChatRole::User, vec![MessageContent::text(completed.prompt)]);
+                session.push_message(ChatRole::Assistant, parts);
+                let (_, msg) = session
+                    .messages
+                    .last_mut()
+                    .ok_or(ChatError::SessionNotFound(session_id))?;
+                msg.model = Some(completed.model);
+                msg.clone()
+            }
+        };
+        self.autosave();
+        self.notify_messages(session_id, &before, completed.replaces);
+
+        Ok(message)
+    }
+
+    /// Prepares the request for reading the response with id `message_id`
+    /// aloud with `options`
+    pub fn prepare_speech(
+        &self,
+        session_id: SessionId,
+        message_id: MessageId,
+        options: SpeechOptions,
+    ) -> Result<SpeechRequest, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+        let message = session
+            .messages
+            .values()
+            .find(|x| x.id == message_id)
+            .ok_or(ChatError::MessageNotFound(message_id))?;
+        if message.role != ChatRole::Assistant {
+            return Err(ChatError::NotAResponse(message_id));
+        }
+        self.check_budget()?;
+
+        let text = message.plain_text();
+        let file_name = speech::cache_file_name(session_id, message_id, &text, &options);
+
+        Ok(SpeechRequest {
+            provider: self.provider_named(session.provider.as_deref())?,
+            text,
+            options,
+            file_name,
+            retry_policy: self.retry_policy,
+        })
+    }
+
+    /// Deletes the message with id `message_id` from the session with id
+    /// `session_id`, returning the deleted message if it existed. The message
+    /// is moved to the trash, from which `restore_message` brings it back,
+    /// unless the session is ephemeral.
+    pub fn delete_message(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<Option<Message>, ChatError> {
+        let session = self.session_mut(session_id)?;
+        let ephemeral = session.ephemeral;
+        let deleted = session.messages.shift_remove_full(&message_id);
+
+        if let Some((position, _, message)) = deleted.as_ref().filter(|_| !ephemeral) {
+            self.trash.push(TrashedItem::Message {
+                session_id,
+                message: message.clone(),
+                position: *position,
+                deleted_at: current_timestamp(),
+            });
+        }
+        self.autosave();
+        if deleted.is_some() {
+            self.notify(StoreEvent::MessageDeleted(MessageDeletedPayload {
+                session_id,
+                message_id,
+            }));
+        }
+
+        Ok(deleted.map(|(_, _, message)| message))
+    }
+
+    /// Puts the message with id `message_id` deleted from the session with
+    /// id `session_id` back where it was. The message stays in the trash if
+    /// its session no longer exists.
+    pub fn restore_message(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        if !self.trash.has_message(session_id, message_id) {
+            return Err(ChatError::MessageNotFound(message_id));
+        }
+        self.session_mut(session_id)?;
+
+        if let Some((message, position)) = self.trash.take_message(session_id, message_id) {
+            let session = self.session_mut(session_id)?;
+            let position = position.min(session.messages.len());
+            session
+                .messages
+                .shift_insert(position, message.id, message.clone());
+            self.autosave();
+            self.notify(StoreEvent::MessageAdded(MessagePayload {
+                session_id,
+                message,
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new session with copies of the messages of the session with id
+    /// `session_id`, up to and including the message with id `message_id`. The
+    /// new session records where it was forked from. Returns the id of the
+    /// new session.
+    pub fn fork_session(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<SessionId, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+        let index = session
+            .messages
+            .get_index_of(&message_id)
+            .ok_or(ChatError::MessageNotFound(message_id))?;
+
+        let mut fork = ChatSession::new(session.title.clone(), &session.model);
+        let id = fork.id;
+        fork.messages = session
+            .messages
+            .values()
+            .take(index + 1)
+            .map(|msg| (msg.id, msg.clone()))
+            .collect();
+        fork.provider = session.provider.clone();
+        fork.profile = session.profile.clone();
+        fork.system_prompt = session.system_prompt.clone();
+        fork.accessed_files = session.accessed_files.clone();
+        fork.summary = session
+            .summary
+            .clone()
+            .filter(|x| fork.messages.contains_key(&x.through));
+        fork.parent = Some(ForkPoint {
+            session_id,
+            message_id,
+        });
+
+        self.sessions.insert(id, fork);
+        self.autosave();
+        self.notify_session(id, true);
+
+        Ok(id)
+    }
+
+    /// Creates a copy of the session with id `session_id`, titled like it
+    /// with a " (copy)" suffix. The copy and its messages get ids of their own,
+    /// so either can change without affecting the other. It is not pinned,
+    /// archived, ephemeral or given the original's draft. Returns the id of
+    /// the copy.
+    pub fn duplicate_session(&mut self, session_id: SessionId) -> Result<SessionId, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+
+        let mut copy = session.clone();
+        copy.id = SessionId::generate();
+        copy.title = format!("{} (copy)", session.title);
+        copy.created_at = current_timestamp();
+        copy.pinned = false;
+        copy.archived = false;
+        copy.last_activity = 0;
+        copy.draft = None;
+        copy.ephemeral = false;
+
+        let mut ids = HashMap::new();
+        copy.messages = session
+            .messages
+            .values()
+            .map(|msg| {
+                let mut copy = msg.clone();
+                copy.id = MessageId::generate();
+                ids.insert(msg.id, copy.id);
+                (copy.id, copy)
+            })
+            .collect();
+        copy.summary = session.summary.clone().and_then(|mut summary| {
+            summary.through = *ids.get(&summary.through)?;
+            Some(summary)
+        });
+
+        let id = copy.id;
+        self.sessions.insert(id, copy);
+        self.autosave();
+        self.notify_session(id, true);
+
+        Ok(id)
+    }
+
+    /// Appends the messages of the session with id `source_id` to the session
+    /// with id `target_id`, then deletes the source without moving it to the
+    /// trash, as its messages live on in the target. The appended messages get
+    /// ids of their own but keep their timestamps. The target also gains the
+    /// source's tags and the files read while answering in it. Merging a
+    /// session into itself changes nothing.
+    pub fn merge_sessions(
+        &mut self,
+        target_id: SessionId,
+        source_id: SessionId,
+    ) -> Result<(), ChatError> {
+        if !self.sessions.contains_key(&target_id) {
+            return Err(ChatError::SessionNotFound(target_id));
+        }
+        let source = self
+            .get_session(source_id)
+            .ok_or(ChatError::SessionNotFound(source_id))?;
+        if target_id == source_id {
+            return Ok(());
+        }
+
+        let messages: Vec<Message> = source
+            .messages
+            .values()
+            .map(|msg| Message {
+                id: MessageId::generate(),
+                ..msg.clone()
+            })
+            .collect();
+        let tags = source.tags.clone();
+        let accessed_files = source.accessed_files.clone();
+
+        let target = self.session_mut(target_id)?;
+        let before: Vec<MessageId> = target.messages.keys().copied().collect();
+        target
+            .messages
+            .extend(messages.into_iter().map(|x| (x.id, x)));
+        for tag in tags {
+            target.add_tag(&tag);
+        }
+        target.add_accessed_files(accessed_files);
+        target.touch();
+
+        self.sessions.shift_remove(&source_id);
+        self.autosave();
+        self.notify_messages(target_id, &before, None);
+        self.notify_session(target_id, false);
+        self.notify(StoreEvent::SessionDeleted(SessionPayload {
+            session_id: source_id,
+        }));
+
+        Ok(())
+    }
+
+    /// Creates a session titled `title` with no messages, returning its id
+    pub fn add_empty_session(&mut self, title: String, model: &str) -> SessionId {
+        let mut session = ChatSession::new(title, model);
+        session.memory_prompt = self.memory_prompt_for("");
+        let id = session.id;
+
+        self.sessions.insert(id, session);
+        self.autosave();
+        self.notify_session(id, true);
+
+        id
+    }
+
+    /// Creates a scratchpad session titled `title` with no messages,
+    /// returning its id. The session is never saved, and is dropped by
+    /// `discard_ephemeral_sessions` unless `promote_session` keeps it first.
+    pub fn add_ephemeral_session(&mut self, title: String, model: &str) -> SessionId {
+        let mut session = ChatSession::new(title, model);
+        session.memory_prompt = self.memory_prompt_for("");
+        session.ephemeral = true;
+        let id = session.id;
+
+        self.sessions.insert(id, session);
+        self.notify_session(id, true);
+
+        id
+    }
+
+    /// Keeps the ephemeral session with matching id as a saved session
+    pub fn promote_session(&mut self, session_id: SessionId) -> Result<(), ChatError> {
+        self.session_mut(session_id)?.ephemeral = false;
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Drops every ephemeral session without moving them to the trash,
+    /// returning their ids
+    pub fn discard_ephemeral_sessions(&mut self) -> Vec<SessionId> {
+        let ids: Vec<SessionId> = self
+            .sessions
+            .values()
+            .filter(|x| x.ephemeral)
+            .map(|x| x.id)
+            .collect();
+
+        for &id in &ids {
+            self.sessions.shift_remove(&id);
+            self.notify(StoreEvent::SessionDeleted(SessionPayload {
+                session_id: id,
+            }));
+        }
+        if let Err(e) = self.embedding_index.remove_sessions(&ids) {
+            eprintln!("{}", e);
+        }
+
+        ids
+    }
+
+    /// Imports every conversation in the ChatGPT data export at `path` as a
+    /// new session, returning their ids. `path` is either the export's
+    /// `conversations.json` or the directory holding it. `model` is used for
+    /// conversations that do not say which model they were held with.
+    ///
+    /// `on_progress` is called with the number of conversations imported so
+    /// far and the total after each one. The store is saved once at the end.
+    pub fn import_chatgpt_export(
+        &mut self,
+        path: &Path,
+        model: &str,
+        mut on_progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<SessionId>, ChatError> {
+        let conversations = import::read_chatgpt_export(path)?;
+        let total = conversations.len();
+        let mut ids = Vec::with_capacity(total);
+
+        for (index, conversation) in conversations.into_iter().enumerate() {
+            let session = ChatSession::imported(conversation, model);
+            ids.push(session.id);
+            self.sessions.insert(session.id, session);
+
+            on_progress(index + 1, total);
+        }
+        self.autosave();
+        for &id in &ids {
+            self.notify_session(id, true);
+        }
+
+        Ok(ids)
+    }
+
+    /// Returns the sessions forked from the session with matching id
+    pub fn get_forks(&self, session_id: SessionId) -> Vec<&ChatSession> {
+        self.sessions
+            .values()
+            .filter(|x| x.parent.map(|p| p.session_id) == Some(session_id))
+            .collect()
+    }
+
+    /// Deletes the chat session with matching id in this store, returning it
+    /// if it existed. The session is moved to the trash, from which
+    /// `restore_session` brings it back. Ephemeral sessions are dropped
+    /// instead, since the trash is saved.
+    pub fn delete_session(&mut self, id: SessionId) -> Option<ChatSession> {
+        let target = self.sessions.shift_remove_full(&id);
+
+        if let Some((position, _, session)) = target.as_ref().filter(|x| !x.2.ephemeral) {
+            self.trash.push(TrashedItem::Session {
+                session: session.clone(),
+                position: *position,
+                deleted_at: current_timestamp(),
+            });
+        }
+        self.autosave();
+        if target.is_some() {
+            self.notify(StoreEvent::SessionDeleted(SessionPayload {
+                session_id: id,
+            }));
+        }
+
+        target.map(|(_, _, session)| session)
+    }
+
+    /// Puts the session with matching id back from the trash, along with
+    /// its messages
+    pub fn restore_session(&mut self, id: SessionId) -> Result<(), ChatError> {
+        let (session, position) = self
+            .trash
+            .take_session(id)
+            .ok_or(ChatError::SessionNotFound(id))?;
+
+        let position = position.min(self.sessions.len());
+        self.sessions.shift_insert(position, session.id, session);
+        self.autosave();
+        self.notify_session(id, true);
+
+        Ok(())
+    }
+
+    /// Returns the deleted sessions and messages that can still be
+    /// restored, oldest first
+    pub fn get_trash(&self) -> &[TrashedItem] {
+        self.trash.get_items()
+    }
+
+    /// Deletes everything in the trash for good, returning how many items
+    /// were deleted
+    pub fn empty_trash(&mut self) -> usize {
+        let purged = self.trash.clear();
+        self.autosave();
+
+        purged
+    }
+
+    /// Keeps deleted items in the trash for `retention_secs` seconds,
+    /// purging those already older
+    pub fn set_trash_retention(&mut self, retention_secs: u64) {
+        self.trash_retention = retention_secs;
+        self.purge_trash();
+    }
+
+    /// Deletes the items that have been in the trash longer than the
+    /// retention period for good, returning how many were deleted
+    pub fn purge_trash(&mut self) -> usize {
+        let purged = self.trash.purge(self.trash_retention, current_timestamp());
+        if purged > 0 {
+            self.autosave();
+        }
+
+        purged
+    }
+
+    /// Returns the sessions that have changed after `timestamp`. A session has
+    /// changed if its newest message was created after `timestamp`, or, for
+    /// sessions without messages, if the session itself was created after it.
+    pub fn find_sessions_modified_since(&self, timestamp: u64) -> Vec<&ChatSession> {
+        self.sessions
+            .values()
+            .filter(|session| session.last_activity() > timestamp)
+            .collect()
+    }
+
+    /// Returns the sessions sorted by most recent activity first, with sessions
+    /// active at the same time sorted by title.
+    pub fn sessions_sorted_by_activity_then_title(&self) -> Vec<&ChatSession> {
+        let mut sessions: Vec<&ChatSession> = self.sessions.values().collect();
+
+        sessions.sort_by(|a, b| {
+            b.last_activity()
+                .cmp(&a.last_activity())
+                .then_with(|| a.title.cmp(&b.title))
+        });
+
+        sessions
+    }
+
+    /// Keeps `draft` as the unsent prompt of the session with matching id,
+    /// replacing any kept before. Empty drafts clear it.
+    pub fn set_session_draft(
+        &mut self,
+        session_id: SessionId,
+        draft: String,
+    ) -> Result<(), ChatError> {
+        let session = self.session_mut(session_id)?;
+        let draft = Some(draft).filter(|x| !x.trim().is_empty());
+        if session.draft != draft {
+            session.draft = draft;
+            self.autosave();
+        }
+
+        Ok(())
+    }
+
+    /// Removes and returns the unsent prompt of the session with matching
+    /// id, if one was kept
+    pub fn take_session_draft(
+        &mut self,
+        session_id: SessionId,
+    ) -> Result<Option<String>, ChatError> {
+        let draft = self.session_mut(session_id)?.draft.take();
+        if draft.is_some() {
+            self.autosave();
+        }
+
+        Ok(draft)
+    }
+
+    /// Bookmarks the message with id `message_id` in the session with id
+    /// `session_id`, or removes its bookmark if it has one. Returns whether
+    /// the message is now bookmarked.
+    pub fn toggle_bookmark(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Result<bool, ChatError> {
+        let message = self
+            .session_mut(session_id)?
+            .messages
+            .get_mut(&message_id)
+            .ok_or(ChatError::MessageNotFound(message_id))?;
+        message.bookmarked = !message.bookmarked;
+        let bookmarked = message.bookmarked;
+
+        self.autosave();
+        self.notify_messages(session_id, &[], Some(message_id));
+
+        Ok(bookmarked)
+    }
+
+    /// Returns the bookmarked messages across every session, newest first
+    pub fn get_bookmarked_messages(&self) -> Vec<Bookmark> {
+        let mut bookmarks: Vec<Bookmark> = self
+            .sessions
+            .values()
+            .flat_map(|session| {
+                session
+                    .messages
+                    .values()
+                    .filter(|x| x.bookmarked)
+                    .map(|x| Bookmark {
+                        session_id: session.id,
+                        session_title: session.title.clone(),
+                        message: x.clone(),
+                    })
+            })
+            .collect();
+        bookmarks.sort_by(|a, b| {
+            b.message
+                .created_at
+                .cmp(&a.message.created_at)
+                .then_with(|| b.message.id.cmp(&a.message.id))
+        });
+
+        bookmarks
+    }
+
+    /// Pins or unpins the session with matching id
+    pub fn set_session_pinned(
+        &mut self,
+        session_id: SessionId,
+        pinned: bool,
+    ) -> Result<(), ChatError> {
+        self.session_mut(session_id)?.set_pinned(pinned);
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Archives or unarchives the session with matching id
+    pub fn set_session_archived(
+        &mut self,
+        session_id: SessionId,
+        archived: bool,
+    ) -> Result<(), ChatError> {
+        self.session_mut(session_id)?.set_archived(archived);
+        self.autosave();
+        self.notify_session(session_id, false);
+
+        Ok(())
+    }
+
+    /// Returns the pinned sessions, sorted like
+    /// `sessions_sorted_by_activity_then_title`
+    pub fn get_pinned(&self) -> Vec<&ChatSession> {
+        self.sessions_sorted_by_activity_then_title()
+            .into_iter()
+            .filter(|x| x.pinned)
+            .collect()
+    }
+
+    /// Returns the sessions that are not archived, pinned sessions first.
+    /// Sessions are otherwise sorted like
+    /// `sessions_sorted_by_activity_then_title`.
+    pub fn get_active(&self) -> Vec<&ChatSession> {
+        let mut sessions: Vec<&ChatSession> = self
+            .sessions_sorted_by_activity_then_title()
+            .into_iter()
+            .filter(|x| !x.archived)
+            .collect();
+        // The sort is stable, so each group keeps its order
+        sessions.sort_by_key(|x| !x.pinned);
+
+        sessions
+    }
+
+    /// Returns the archived sessions, sorted like
+    /// `sessions_sorted_by_activity_then_title`
+    pub fn get_archived(&self) -> Vec<&ChatSession> {
+        self.sessions_sorted_by_activity_then_title()
+            .into_iter()
+            .filter(|x| x.archived)
+            .collect()
+    }
+
+    /// Returns up to `limit` sessions sorted by `sort`, skipping the first
+    /// `offset`. Ties are broken by id so pages do not overlap.
+    pub fn get_sessions_page(
+        &self,
+        offset: usize,
+        limit: usize,
+        sort: SortBy,
+    ) -> Vec<&ChatSession> {
+        let mut sessions: Vec<&ChatSession> = self.sessions.values().collect();
+
+        match sort {
+            SortBy::LastActivity => sessions.sort_by(|a, b| {
+                b.last_activity()
+                    .cmp(&a.last_activity())
+                    .then_with(|| a.title.cmp(&b.title))
+                    .then_with(|| a.id.cmp(&b.id))
+            }),
+            SortBy::CreatedAt => sessions.sort_by(|a, b| {
+                b.created_at
+                    .cmp(&a.created_at)
+                    .then_with(|| b.id.cmp(&a.id))
+            }),
+            SortBy::Title => sessions.sort_by(|a, b| {
+                a.title
+                    .to_lowercase()
+                    .cmp(&b.title.to_lowercase())
+                    .then_with(|| a.id.cmp(&b.id))
+            }),
+        }
+
+        sessions.into_iter().skip(offset).take(limit).collect()
+    }
+
+    /// Returns the sessions created between `start` and `end`, both inclusive.
+    /// Returns an empty vec if `start` is after `end`.
+    pub fn sessions_created_between(&self, start: u64, end: u64) -> Vec<&ChatSession> {
+        self.sessions
+            .values()
+            .filter(|session| (start..=end).contains(&session.created_at))
+            .collect()
+    }
+
+    /// Returns pairs of session ids that appear to share context, i.e. where the
+    /// first message of one session is contained in a message of the other.
+    ///
+    /// To avoid false positives from short first messages, the first message
+    /// must make up more than 70% of the message containing it. Each pair is
+    /// reported once, with the lower id first.
+    pub fn find_session_pairs_with_shared_context(&self) -> Vec<(SessionId, SessionId)> {
+        const MIN_SIMILARITY: f64 = 0.7;
+
+        let mut pairs: Vec<(SessionId, SessionId)> = Vec::new();
+
+        for source in self.sessions.values() {
+            let first = match source.messages.values().next().map(Message::plain_text) {
+                Some(text) if !text.is_empty() => text,
+                _ => continue,
+            };
+
+            for other in self.sessions.values() {
+                if other.id == source.id {
+                    continue;
+                }
+
+                let shares_context = other.messages.values().any(|msg| {
+                    let text = msg.plain_text();
+                    text.contains(first.as_str())
+                        && first.len() as f64 / text.len() as f64 > MIN_SIMILARITY
+                });
+
+                let pair = (source.id.min(other.id), source.id.max(other.id));
+                if shares_context && !pairs.contains(&pair) {
+                    pairs.push(pair);
+                }
+            }
+        }
+
+        pairs
+    }
+
+    /// Builds an inverted index mapping each lowercase word to the
+    /// `(session_id, message_id)` pairs of the messages containing it.
+    ///
+    /// The index is a snapshot and is not updated as the store changes, so it
+    /// must be rebuilt by the caller after any mutation.
+    pub fn message_search_index(&self) -> HashMap<String, Vec<(SessionId, MessageId)>> {
+        let mut index: HashMap<String, Vec<(SessionId, MessageId)>> = HashMap::new();
+
+        for session in self.sessions.values() {
+            for msg in session.messages.values() {
+                for word in index_words(&msg.plain_text()) {
+                    let entry = index.entry(word).or_default();
+                    if entry.last() != Some(&(session.id, msg.id)) {
+                        entry.push((session.id, msg.id));
+                    }
+                }
+            }
+        }
+
+        index
+    }
+
+    /// Searches for messages containing every word in `query` using an `index`
+    /// built by `message_search_index`. Entries in the index that no longer
+    /// exist in this store are skipped.
+    pub fn search_indexed(
+        &self,
+        index: &HashMap<String, Vec<(SessionId, MessageId)>>,
+        query: &str,
+    ) -> Vec<(&ChatSession, &Message)> {
+        let mut words = index_words(query);
+
+        let mut matches: Vec<(SessionId, MessageId)> = match words.next() {
+            Some(word) => index.get(&word).cloned().unwrap_or_default(),
+            None => return Vec::new(),
+        };
+
+        for word in words {
+            let found = index.get(&word).map(|x| x.as_slice()).unwrap_or_default();
+            matches.retain(|pair| found.contains(pair));
+        }
+
+        matches
+            .into_iter()
+            .filter_map(|(session_id, msg_id)| {
+                let session = self.get_session(session_id)?;
+                let msg = session.messages.get(&msg_id)?;
+                Some((session, msg))
+            })
+            .collect()
+    }
+
+    /// Returns the tokens used and their cost across every session in this
+    /// store, grouped by chat model
+    pub fn usage_report(&self) -> UsageReport {
+        let mut models: Vec<ModelUsage> = Vec::new();
+
+        for session in self.sessions.values() {
+            let usage = session.total_usage();
+            let index = match models.iter().position(|x| x.model == session.model) {
+                Some(index) => index,
+                None => {
+                    models.push(ModelUsage {
+                        model: session.model.clone(),
+                        ..ModelUsage::default()
+                    });
+                    models.len() - 1
+                }
+            };
+
+            let entry = &mut models[index];
+            entry.prompt_tokens += usage.prompt_tokens as u64;
+            entry.completion_tokens += usage.completion_tokens as u64;
+            entry.cost += session.total_cost();
+        }
+
+        models.sort_by(|a, b| a.model.cmp(&b.model));
+        let total_cost = models.iter().map(|x| x.cost).sum();
+
+        UsageReport { models, total_cost }
+    }
+
+    /// Writes the sessions with ids `session_ids` to `path` as a fine-tuning
+    /// dataset in OpenAI's JSONL chat format, one session per line. Sessions
+    /// without a response are left out. Returns the number of sessions
+    /// written.
+    pub fn export_training_jsonl(
+        &self,
+        session_ids: &[SessionId],
+        path: &Path,
+    ) -> Result<usize, ChatError> {
+        let mut examples = Vec::with_capacity(session_ids.len());
+        for &id in session_ids {
+            let session = self.get_session(id).ok_or(ChatError::SessionNotFound(id))?;
+            examples.extend(TrainingExample::of(session));
+        }
+
+        std::fs::write(path, training::to_jsonl(&examples))
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        Ok(examples.len())
+    }
+
+    /// Returns the tokens used and their cost within `range`, one row per day
+    /// and chat model, oldest day first. Each response counts on the day it
+    /// was created, against the model that produced it.
+    pub fn daily_usage(&self, range: DateRange) -> Vec<DailyUsage> {
+        let mut rows: Vec<DailyUsage> = Vec::new();
+
+        for session in self.sessions.values() {
+            for msg in session.messages.values() {
+                let usage = match msg.usage {
+                    Some(usage) if range.contains(msg.created_at) => usage,
+                    _ => continue,
+                };
+                let model = msg.model.as_deref().unwrap_or(&session.model);
+                let date = usage::day_of(msg.created_at);
+
+                let index = match rows.iter().position(|x| x.date == date && x.model == model) {
+                    Some(index) => index,
+                    None => {
+                        rows.push(DailyUsage {
+                            date,
+                            model: model.to_string(),
+                            ..DailyUsage::default()
+                        });
+                        rows.len() - 1
+                    }
+                };
+
+                let row = &mut rows[index];
+                row.prompt_tokens += usage.prompt_tokens as u64;
+                row.completion_tokens += usage.completion_tokens as u64;
+                row.cost += usage::cost(model, &usage).unwrap_or(0.0);
+            }
+        }
+
+        rows.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.model.cmp(&b.model)));
+        rows
+    }
+
+    /// Returns the statistics of every session in this store added up
+    pub fn aggregate_stats(&self) -> AggregateStats {
+        stats::aggregate(self.sessions.values())
+    }
+
+    /// Returns every prompt template in this store, in the order they were added
+    pub fn get_prompts(&self) -> &Vec<PromptTemplate> {
+        &self.prompts
+    }
+
+    /// Returns the prompt template with matching name, if it exists
+    pub fn get_prompt(&self, name: &str) -> Option<&PromptTemplate> {
+        self.prompts.iter().find(|x| x.name == name)
+    }
+
+    /// Adds `prompt` to this store, replacing any prompt with the same name
+    pub fn save_prompt(&mut self, prompt: PromptTemplate) {
+        match self.prompts.iter_mut().find(|x| x.name == prompt.name) {
+            Some(existing) => *existing = prompt,
+            None => self.prompts.push(prompt),
+        }
+        self.autosave();
+    }
+
+    /// Deletes the prompt template with matching name, returning it if it
+    /// existed
+    pub fn delete_prompt(&mut self, name: &str) -> Option<PromptTemplate> {
+        let index = self.prompts.iter().position(|x| x.name == name)?;
+        let deleted = self.prompts.remove(index);
+        self.autosave();
+
+        Some(deleted)
+    }
+
+    /// Renders the prompt template with matching name using `vars`
+    pub fn render_prompt(
+        &self,
+        name: &str,
+        vars: &HashMap<String, String>,
+    ) -> Result<String, ChatError> {
+        self.get_prompt(name)
+            .ok_or_else(|| ChatError::PromptNotFound(name.to_string()))?
+            .render(vars)
+    }
+
+    /// Returns the facts remembered about the user, oldest first
+    pub fn get_memories(&self) -> &[Memory] {
+        &self.memories
+    }
+
+    /// Returns whether facts are remembered, and how many new sessions are
+    /// given
+    pub fn get_memory_config(&self) -> MemoryConfig {
+        self.memory_config
+    }
+
+    /// Replaces whether facts are remembered, and how many new sessions are
+    /// given, with `config`
+    pub fn set_memory_config(&mut self, config: MemoryConfig) {
+        self.memory_config = config;
+    }
+
+    /// Remembers `text` as a fact about the user, returning a copy of the
+    /// memory
+    pub fn add_memory(&mut self, text: String) -> Memory {
+        let memory = Memory {
+            id: MemoryId::generate(),
+            text,
+            updated_at: current_timestamp(),
+            session_id: None,
+        };
+        self.memories.push(memory.clone());
+        self.autosave();
+
+        memory
+    }
+
+    /// Replaces the text of the memory with matching id with `text`
+    pub fn update_memory(&mut self, memory_id: MemoryId, text: String) -> Result<(), ChatError> {
+        let memory = self
+            .memories
+            .iter_mut()
+            .find(|x| x.id == memory_id)
+            .ok_or(ChatError::MemoryNotFound(memory_id))?;
+        memory.text = text;
+        memory.updated_at = current_timestamp();
+        self.autosave();
+
+        Ok(())
+    }
+
+    /// Forgets the memory with matching id, returning it if it existed
+    pub fn delete_memory(&mut self, memory_id: MemoryId) -> Option<Memory> {
+        let index = self.memories.iter().position(|x| x.id == memory_id)?;
+        let deleted = self.memories.remove(index);
+        self.autosave();
+
+        Some(deleted)
+    }
+
+    /// Returns the part of the system prompt giving a new session the facts
+    /// most relevant to `first_message`, or None if memory is off or nothing
+    /// is remembered
+    fn memory_prompt_for(&self, first_message: &str) -> Option<String> {
+        if !self.memory_config.enabled {
+            return None;
+        }
+        let memories = memory::relevant(
+            &self.memories,
+            first_message,
+            self.memory_config.max_injected,
+        );
+
+        memory::memory_prompt(&memories)
+    }
+
+    /// Prepares the request for picking facts worth remembering out of the
+    /// latest exchange of the session with matching id: its last User
+    /// message and everything after it. Returns None if memory is off or the
+    /// session has no User message. Sessions on the default provider use
+    /// `memory::MEMORY_MODEL`, others their own model.
+    pub fn prepare_memory_extraction(
+        &self,
+        session_id: SessionId,
+    ) -> Result<Option<MemoryRequest>, ChatError> {
+        let session = self
+            .get_session(session_id)
+            .ok_or(ChatError::SessionNotFound(session_id))?;
+        if !self.memory_config.enabled {
+            return Ok(None);
+        }
+
+        let messages: Vec<&Message> = session.messages.values().collect();
+        let Some(start) = messages.iter().rposition(|x| x.role == ChatRole::User) else {
+            return Ok(None);
+        };
+        let model = match session.provider {
+            Some(_) => session.model.as_str(),
+            None => memory::MEMORY_MODEL,
+        };
+        self.check_budget()?;
+
+        Ok(Some(MemoryRequest {
+            session_id,
+            provider: self.provider_named(session.provider.as_deref())?,
+            request: memory::extraction_request(model, &messages[start..], &self.memories),
+            retry_policy: self.retry_policy,
+        }))
+    }
+
+    /// Remembers the facts picked out by a request from
+    /// `prepare_memory_extraction`, leaving out those already remembered.
+    /// Returns copies of the new memories.
+    pub fn commit_memories(&mut self, extracted: ExtractedMemories) -> Vec<Memory> {
+        self.record_spending(extracted.cost);
+        let mut added = vec![];
+        for fact in extracted.facts {
+            let known = self
+                .memories
+                .iter()
+                .any(|x| x.text.eq_ignore_ascii_case(&fact));
+            if known {
+                continue;
+            }
+
+            let memory = Memory {
+                id: MemoryId::generate(),
+                text: fact,
+                updated_at: current_timestamp(),
+                session_id: Some(extracted.session_id),
+            };
+            self.memories.push(memory.clone());
+            added.push(memory);
+        }
+        if !added.is_empty() {
+            self.autosave();
+        }
+
+        added
+    }
+
+    /// Deletes every session in this store for good, returning the deleted
+    /// sessions. They are not moved to the trash, and the messages deleted
+    /// from them are dropped from it, so none of them can be restored.
+    pub fn delete_all_sessions(&mut self) -> Vec<ChatSession> {
+        let deleted = std::mem::take(&mut self.sessions);
+        self.trash.purge_sessions(|x| deleted.contains_key(&x));
+        {
+            let mut revisions = self.revisions.lock().unwrap_or_else(|e| e.into_inner());
+            revisions.retain(|x, _| !deleted.contains_key(x));
+        }
+        self.autosave();
+        for &session_id in deleted.keys() {
+            self.notify(StoreEvent::SessionDeleted(SessionPayload { session_id }));
+        }
+
+        deleted.into_values().collect()
+    }
+
+    /// Deletes every message, across all sessions, that is older than `age_secs`
+    /// seconds. If `delete_empty` is true, sessions this leaves with no
+    /// messages are deleted as well, while sessions that were already empty
+    /// are kept. Returns the total number of messages deleted.
+    pub fn prune_messages_older_than(&mut self, age_secs: u64, delete_empty: bool) -> usize {
+        let mut deleted = vec![];
+        let mut pruned_empty = vec![];
+
+        for session in self.sessions.values_mut() {
+            let session_id = session.id;
+            let was_empty = session.messages.is_empty();
+            session.messages.retain(|&message_id, msg| {
+                let keep = msg.age_secs() <= age_secs;
+                if !keep {
+                    deleted.push(MessageDeletedPayload {
+                        session_id,
+                        message_id,
+                    });
+                }
+                keep
+            });
+            if !was_empty && session.messages.is_empty() {
+                pruned_empty.push(session_id);
+            }
+        }
+
+        let mut emptied = vec![];
+        if delete_empty {
+            self.sessions.retain(|&session_id, _| {
+                let keep = !pruned_empty.contains(&session_id);
+                if !keep {
+                    emptied.push(SessionPayload { session_id });
+                }
+                keep
+            });
+        }
+        self.autosave();
+
+        let count = deleted.len();
+        for payload in deleted {
+            self.notify(StoreEvent::MessageDeleted(payload));
+        }
+        for payload in emptied {
+            self.notify(StoreEvent::SessionDeleted(payload));
+        }
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role};
+    use providers::mock::MockProvider;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const MODEL: &str = "gpt-3.5-turbo";
+
+    /// Adds `session` to `store` as is, returning its id
+    fn push_session(store: &mut Store, session: ChatSession) -> SessionId {
+        let id = session.id;
+        store.sessions.insert(id, session);
+
+        id
+    }
+
+    /// Returns the ids of the messages of `session`, in order
+    fn message_ids(session: &ChatSession) -> Vec<MessageId> {
+        session.messages.keys().copied().collect()
+    }
+
+    #[test]
+    fn test_create_chat_thread() {
+        let title = String::from("Something");
+        let mut ctd = ChatSession::new(title.clone(), MODEL);
+
+        assert_ne!(
+            ctd.get_id(),
+            ChatSession::new(title.clone(), MODEL).get_id()
+        );
+        assert_eq!(ctd.get_title(), title);
+        assert!(ctd.get_messages().is_empty());
+
+        ctd.rename_session("New title".to_string());
+        assert_eq!(ctd.title, "New title".to_string());
+    }
+
+    #[test]
+    fn test_response_msg() {
+        let role = Role::Assistant;
+        let content = String::from("Some content");
+        let msg = ChatCompletionResponseMessage {
+            role: role.clone(),
+            content: Some(content.clone()),
+            function_call: None,
+        };
+
+        assert_eq!(role, msg.get_role());
+        assert_eq!(content, msg.get_content());
+    }
+
+    #[test]
+    fn test_request_msg() {
+        let role = Role::System;
+        let msg = ChatCompletionRequestMessage {
+            role: role.clone(),
+            content: None,
+            name: None,
+            function_call: None,
+        };
+
+        assert_eq!(role, msg.get_role());
+        assert_eq!("", msg.get_content());
+    }
+
+    #[test]
+    fn test_create_message() {
+        let role = ChatRole::User;
+        let contents = String::from("Some random content");
+        let time_before = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(n) => n.as_secs(),
+            Err(_) => panic!("Should not get here"),
+        };
+
+        let msg = Message::new(role, vec![MessageContent::text(contents.clone())]);
+
+        assert!(msg.get_id() < MessageId::generate());
+        assert_eq!(msg.get_role(), role);
+        assert_eq!(msg.get_content(), contents);
+        assert_eq!(time_before, msg.get_created_at());
+    }
+
+    #[test]
+    fn test_session_add_and_remove() {
+        let mut chs = ChatSession::new(String::from("Testing adding messages"), MODEL);
+
+        for i in 0..6 {
+            if i % 2 == 0 {
+                let msg = ChatCompletionRequestMessage {
+                    role: Role::User,
+                    content: Some(String::from("Request chat")),
+                    name: None,
+                    function_call: None,
+                };
+                chs.add_chat_message(msg);
+            } else {
+                let msg = ChatCompletionResponseMessage {
+                    role: Role::Assistant,
+                    content: Some(String::from("Response chat")),
+                    function_call: None,
+                };
+                chs.add_chat_message(msg);
+            }
+        }
+
+        assert_eq!(6, chs.get_messages().len());
+        assert_eq!(
+            chs.get_messages()[0].get_content(),
+            String::from("Request chat")
+        );
+        assert_eq!(
+            chs.get_messages()[1].get_content(),
+            String::from("Response chat")
+        );
+
+        let ids = message_ids(&chs);
+        assert_eq!(ids[0], chs.delete_message(ids[0]).unwrap().get_id());
+        assert_eq!(5, chs.get_messages().len());
+
+        assert_eq!(ids[3], chs.delete_message(ids[3]).unwrap().get_id());
+        assert_eq!(4, chs.get_messages().len());
+
+        assert!(chs.delete_message(ids[0]).is_none());
+        assert_eq!(4, chs.get_messages().len())
+    }
+
+    #[test]
+    fn test_new_store() {
+        let provider = MockProvider::default();
+        let store = Store::new(provider);
+
+        assert!(store.get_all_sessions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_add_session() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        assert!(store.get_all_sessions().is_empty());
+
+        let msg1 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg1")),
+            name: None,
+            function_call: None,
+        };
+        let first = store
+            .add_session(vec![msg1], "Test Message 1".to_string(), MODEL)
+            .await
+            .unwrap();
+        assert_eq!(1, store.get_all_sessions().len());
+        assert_eq!(first, store.get_all_sessions()[0].get_id());
+        assert_eq!(
+            "Test Message 1".to_string(),
+            store.get_all_sessions()[0].get_title()
+        );
+
+        let system = ChatCompletionRequestMessage {
+            role: Role::System,
+            content: Some(String::from("Be brief")),
+            name: None,
+            function_call: None,
+        };
+        let msg2 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg2")),
+            name: None,
+            function_call: None,
+        };
+        let second = store
+            .add_session(vec![system, msg2], "Test msg 2".to_string(), MODEL)
+            .await
+            .unwrap();
+        assert_eq!(2, store.get_all_sessions().len());
+        assert_eq!(second, store.get_all_sessions()[1].get_id());
+        assert!(first < second);
+        assert_eq!(
+            String::from("msg2"),
+            store.get_all_sessions()[1].get_messages()[0].get_content()
+        );
+        assert_eq!(
+            ChatRole::User,
+            store.get_all_sessions()[1].get_messages()[0].get_role()
+        );
+        assert_eq!(
+            Some("Be brief"),
+            store.get_all_sessions()[1].get_system_prompt()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_add_session_rejects_invalid_roles() {
+        let mut store = Store::new(MockProvider::default());
+        let msg = |role: Role| ChatCompletionRequestMessage {
+            role,
+            content: Some(String::from("Hi")),
+            name: None,
+            function_call: None,
+        };
+
+        for roles in [
+            vec![Role::Assistant],
+            vec![Role::System],
+            vec![Role::User, Role::System],
+        ] {
+            let messages = roles.iter().cloned().map(msg).collect();
+            let expected = roles.into_iter().map(ChatRole::from).collect();
+            assert_eq!(
+                Some(ChatError::InvalidOpeningRoles(expected)),
+                store
+                    .add_session(messages, String::from("Invalid"), MODEL)
+                    .await
+                    .err()
+            );
+        }
+        assert!(store.get_all_sessions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_get_specific() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let msg1 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg1")),
+            name: None,
+            function_call: None,
+        };
+        let msg2 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg2")),
+            name: None,
+            function_call: None,
+        };
+        let msg3 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg3")),
+            name: None,
+            function_call: None,
+        };
+
+        store
+            .add_session(vec![msg1], "Tired".to_string(), MODEL)
+            .await
+            .unwrap();
+        store
+            .add_session(vec![msg2], "Tired".to_string(), MODEL)
+            .await
+            .unwrap();
+        let id = store
+            .add_session(vec![msg3], "Tired".to_string(), MODEL)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get_session(id).unwrap().get_title(),
+            "Tired".to_string()
+        );
+        assert!(store.get_session(SessionId::generate()).is_none());
+        assert_eq!(store.get_all_sessions().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_store_delete_sessions() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let msg1 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg1")),
+            name: None,
+            function_call: None,
+        };
+        let msg2 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg2")),
+            name: None,
+            function_call: None,
+        };
+        let msg3 = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("msg3")),
+            name: None,
+            function_call: None,
+        };
+
+        store
+            .add_session(vec![msg1], "One".to_string(), MODEL)
+            .await
+            .unwrap();
+        store
+            .add_session(vec![msg2], "Two".to_string(), MODEL)
+            .await
+            .unwrap();
+        let id = store
+            .add_session(vec![msg3], "Three".to_string(), MODEL)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.delete_session(id).unwrap().get_title(),
+            "Three".to_string()
+        );
+        assert_eq!(store.get_all_sessions().len(), 2);
+        assert!(store.get_session(id).is_none());
+        assert!(store.delete_session(id).is_none());
+    }
+
+    #[test]
+    fn test_store_prune_old_messages() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let mut old = ChatSession::new("Old".to_string(), MODEL);
+        let mut mixed = ChatSession::new("Mixed".to_string(), MODEL);
+        for chs in [&mut old, &mut mixed] {
+            for _ in 0..2 {
+                let msg = ChatCompletionRequestMessage {
+                    role: Role::User,
+                    content: Some(String::from("Some content")),
+                    name: None,
+                    function_call: None,
+                };
+                chs.add_chat_message(msg);
+            }
+        }
+        old.messages
+            .values_mut()
+            .for_each(|msg| msg.created_at -= 1000);
+        mixed.messages[0].created_at -= 1000;
+        push_session(&mut store, old.clone());
+        push_session(&mut store, mixed.clone());
+
+        assert!(store.get_all_sessions()[0].get_messages()[0].age_secs() >= 1000);
+        assert_eq!(0, store.prune_messages_older_than(5000, true));
+
+        assert_eq!(3, store.prune_messages_older_than(500, false));
+        assert_eq!(2, store.get_all_sessions().len());
+        assert!(store.get_session(old.id).unwrap().get_messages().is_empty());
+        assert_eq!(1, store.get_session(mixed.id).unwrap().get_messages().len());
+
+        // Only sessions the prune empties are deleted, not ones that were
+        // empty already
+        let old_id = old.id;
+        let fresh = ChatSession::new("Fresh".to_string(), MODEL);
+        let fresh_id = fresh.id;
+        store.sessions = indexed::from_items(vec![old, mixed, fresh]);
+        assert_eq!(3, store.prune_messages_older_than(500, true));
+        assert_eq!(2, store.get_all_sessions().len());
+        assert!(store.get_session(old_id).is_none());
+        assert!(store.get_session(fresh_id).is_some());
+    }
+
+    #[test]
+    fn test_store_session_tags() {
+        let mut store = Store::new(MockProvider::default());
+        let work = store.add_empty_session(String::from("Work"), MODEL);
+        let home = store.add_empty_session(String::from("Home"), MODEL);
+
+        assert_eq!(Ok(true), store.add_session_tag(work, " Project X "));
+        assert_eq!(Ok(false), store.add_session_tag(work, "project x"));
+        assert_eq!(Ok(false), store.add_session_tag(work, "  "));
+        assert_eq!(Ok(true), store.add_session_tag(work, "work"));
+        assert_eq!(Ok(true), store.add_session_tag(home, "personal"));
+        let missing = SessionId::generate();
+        assert_eq!(
+            Err(ChatError::SessionNotFound(missing)),
+            store.add_session_tag(missing, "work")
+        );
+
+        assert_eq!(
+            vec!["Project X", "work"],
+            store.get_session(work).unwrap().get_tags()
+        );
+        assert_eq!(vec!["personal", "Project X", "work"], store.get_tags());
+
+        let tagged = store.filter_by_tag("PROJECT X");
+        assert_eq!(1, tagged.len());
+        assert_eq!(work, tagged[0].get_id());
+        assert!(store.filter_by_tag("travel").is_empty());
+
+        assert_eq!(Ok(true), store.remove_session_tag(work, "project x"));
+        assert_eq!(Ok(false), store.remove_session_tag(work, "project x"));
+        assert!(store.filter_by_tag("Project X").is_empty());
+        assert_eq!(
+            vec!["work"],
+            store.get_session(work).unwrap().summary().tags
+        );
+    }
+
+    #[test]
+    fn test_store_pinned_and_archived_sessions() {
+        let mut store = Store::new(MockProvider::default());
+        let mut ids = vec![];
+        for (i, title) in ["A", "B", "C", "D"].into_iter().enumerate() {
+            let mut session = ChatSession::new(title.to_string(), MODEL);
+            session.created_at = 100 + i as u64;
+            ids.push(push_session(&mut store, session));
+        }
+
+        store.set_session_pinned(ids[1], true).unwrap();
+        store.set_session_archived(ids[2], true).unwrap();
+        let missing = SessionId::generate();
+        assert_eq!(
+            Err(ChatError::SessionNotFound(missing)),
+            store.set_session_pinned(missing, true)
+        );
+
+        // Positions of the listed sessions in `ids`
+        let positions = |sessions: Vec<&ChatSession>| -> Vec<usize> {
+            sessions
+                .iter()
+                .map(|x| ids.iter().position(|id| *id == x.get_id()).unwrap())
+                .collect()
+        };
+        assert_eq!(vec![1, 3, 0], positions(store.get_active()));
+        assert_eq!(vec![1], positions(store.get_pinned()));
+        assert_eq!(vec![2], positions(store.get_archived()));
+
+        // Archiving unpins, and pinning unarchives
+        store.set_session_archived(ids[1], true).unwrap();
+        assert!(store.get_pinned().is_empty());
+        store.set_session_pinned(ids[2], true).unwrap();
+        assert_eq!(vec![2, 3, 0], positions(store.get_active()));
+        assert_eq!(vec![1], positions(store.get_archived()));
+
+        let summary = store.get_session(ids[2]).unwrap().summary();
+        assert!(summary.pinned && !summary.archived);
+    }
+
+    #[test]
+    fn test_session_get_messages_range() {
+        let mut session = ChatSession::new(String::from("Long"), "gpt-3.5-turbo");
+        session.add_message_batch_without_api(
+            (0..5)
+                .map(|i| (ChatRole::User, format!("Message {}", i)))
+                .collect(),
+        );
+        let contents = |messages: Vec<&Message>| -> Vec<String> {
+            messages.iter().map(|x| x.plain_text()).collect()
+        };
+
+        assert_eq!(5, session.message_count());
+        assert_eq!(
+            vec!["Message 1", "Message 2"],
+            contents(session.get_messages_range(1, 2))
+        );
+        assert_eq!(
+            vec!["Message 4"],
+            contents(session.get_messages_range(4, 10))
+        );
+        assert!(session.get_messages_range(5, 10).is_empty());
+        assert!(session.get_messages_range(0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_store_get_sessions_page() {
+        let mut store = Store::new(MockProvider::default());
+        let mut ids = vec![];
+        for (title, created_at) in [("beta", 300), ("Alpha", 100), ("gamma", 200)] {
+            let mut session = ChatSession::new(title.to_string(), MODEL);
+            session.created_at = created_at;
+            ids.push(push_session(&mut store, session));
+        }
+        store
+            .session_mut(ids[1])
+            .unwrap()
+            .add_message_batch_without_api(vec![(ChatRole::User, String::from("Hi"))]);
+        store.session_mut(ids[1]).unwrap().messages[0].created_at = 400;
+
+        // Positions of the listed sessions in `ids`
+        let positions = |sessions: Vec<&ChatSession>| -> Vec<usize> {
+            sessions
+                .iter()
+                .map(|x| ids.iter().position(|id| *id == x.get_id()).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            vec![1, 0, 2],
+            positions(store.get_sessions_page(0, 10, SortBy::LastActivity))
+        );
+        assert_eq!(
+            vec![0, 2],
+            positions(store.get_sessions_page(0, 2, SortBy::CreatedAt))
+        );
+        assert_eq!(
+            vec![2, 1],
+            positions(store.get_sessions_page(1, 2, SortBy::CreatedAt))
+        );
+        assert_eq!(
+            vec![1, 0, 2],
+            positions(store.get_sessions_page(0, 3, SortBy::Title))
+        );
+        assert!(store.get_sessions_page(3, 2, SortBy::Title).is_empty());
+
+        // Selecting a variant is activity, even though no message is added
+        let session = store.session_mut(ids[2]).unwrap();
+        session.add_message_batch_without_api(vec![(ChatRole::Assistant, String::from("A"))]);
+        session.messages[0].created_at = 250;
+        session.messages[0].add_variant(
+            vec![MessageContent::text("B")],
+            None,
+            MessageMetadata {
+                attempts: 1,
+                latency_ms: 0,
+                finish_reason: None,
+            },
+            String::from(MODEL),
+        );
+        let message_id = message_ids(session)[0];
+        store.select_message_variant(ids[2], message_id, 0).unwrap();
+        assert_eq!(
+            vec![2, 1, 0],
+            positions(store.get_sessions_page(0, 10, SortBy::LastActivity))
+        );
+    }
+
+    #[test]
+    fn test_session_export_as_markdown_and_json() {
+        let mut chs = ChatSession::new(String::from("Rust questions"), MODEL);
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("How do I print in Rust?")),
+            (ChatRole::Assistant, String::from("Use `println!`.\n\n")),
+        ]);
+        for msg in chs.messages.values_mut() {
+            msg.created_at = 0;
+        }
+
+        let markdown = chs.export(ExportFormat::Markdown).unwrap();
+        assert_eq!(
+            "# Rust questions\n\
+             \n**user** (1970-01-01 00:00:00)\n\nHow do I print in Rust?\n\
+             \n**assistant** (1970-01-01 00:00:00)\n\nUse `println!`.\n",
+            markdown
+        );
+
+        let json = chs.export(ExportFormat::Json).unwrap();
+        let parsed: ChatSession = serde_json::from_str(&json).unwrap();
+        assert_eq!(chs.get_messages().len(), parsed.get_messages().len());
+        assert_eq!("Rust questions", parsed.title);
+
+        assert_eq!(
+            chs.export_as_html(),
+            chs.export(ExportFormat::Html).unwrap()
+        );
+        assert_eq!("md", ExportFormat::Markdown.extension());
+    }
+
+    #[test]
+    fn test_session_export_as_html() {
+        use regex::Regex;
+
+        let mut chs = ChatSession::new(String::from("Rust <questions>"), MODEL);
+        let request = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("How do I print in Rust?")),
+            name: None,
+            function_call: None,
+        };
+        let response = ChatCompletionResponseMessage {
+            role: Role::Assistant,
+            content: Some(String::from(
+                "Use the macro:\n```rust\nprintln!(\"{} & {}\", a, b);\n```\nThat's it.",
+            )),
+            function_call: None,
+        };
+        chs.add_chat_message(request);
+        chs.add_chat_message(response);
+        chs.messages[0].created_at = 0;
+
+        let html = chs.export_as_html();
+
+        assert!(html.contains("<title>Rust &lt;questions&gt;</title>"));
+        assert!(html.contains("<div class=\"message user\">"));
+        assert!(html.contains("<div class=\"message assistant\">"));
+        assert!(html.contains("<strong>assistant</strong>"));
+        assert!(html.contains("<p>How do I print in Rust?</p>"));
+        assert!(html.contains("<time datetime=\"1970-01-01T00:00:00Z\">"));
+        assert!(html.contains(
+            "<pre><code class=\"language-rust\">println!(&quot;{} &amp; {}&quot;, a, b);</code></pre>"
+        ));
+        assert!(html.contains("<p>That&#39;s it.</p>"));
+
+        // Every opened tag must be closed in the reverse order it was opened.
+        let tags = Regex::new(r"<(/?)([a-zA-Z][a-zA-Z0-9]*)[^>]*>").unwrap();
+        let mut open: Vec<String> = Vec::new();
+        for cap in tags.captures_iter(&html) {
+            let name = cap[2].to_string();
+            if name == "meta" {
+                continue;
+            }
+            if cap[1].is_empty() {
+                open.push(name);
+            } else {
+                assert_eq!(Some(name), open.pop());
+            }
+        }
+        assert!(open.is_empty());
+    }
+
+    #[test]
+    fn test_store_find_modified_since() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        assert!(store.find_sessions_modified_since(0).is_empty());
+
+        let mut with_msgs = ChatSession::new("With messages".to_string(), MODEL);
+        let msg = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("Some content")),
+            name: None,
+            function_call: None,
+        };
+        with_msgs.add_chat_message(msg.clone());
+        with_msgs.add_chat_message(msg);
+        with_msgs.created_at = 100;
+        with_msgs.messages[0].created_at = 200;
+        with_msgs.messages[1].created_at = 300;
+
+        let mut empty = ChatSession::new("Empty".to_string(), MODEL);
+        empty.created_at = 250;
+
+        let with_msgs = push_session(&mut store, with_msgs);
+        let empty = push_session(&mut store, empty);
+
+        let ids = |sessions: Vec<&ChatSession>| -> Vec<SessionId> {
+            sessions.iter().map(|x| x.get_id()).collect()
+        };
+
+        assert_eq!(
+            vec![with_msgs, empty],
+            ids(store.find_sessions_modified_since(99))
+        );
+        assert_eq!(
+            vec![with_msgs, empty],
+            ids(store.find_sessions_modified_since(200))
+        );
+        assert_eq!(
+            vec![with_msgs],
+            ids(store.find_sessions_modified_since(250))
+        );
+        assert!(store.find_sessions_modified_since(300).is_empty());
+        assert!(store.find_sessions_modified_since(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_session_word_count_over_time() {
+        let mut chs = ChatSession::new(String::from("Word counts"), MODEL);
+        assert!(chs.word_count_over_time().is_empty());
+
+        for content in ["one two three", "", "  four   five "] {
+            let msg = ChatCompletionRequestMessage {
+                role: Role::User,
+                content: Some(String::from(content)),
+                name: None,
+                function_call: None,
+            };
+            chs.add_chat_message(msg);
+        }
+        for (i, msg) in chs.messages.values_mut().enumerate() {
+            msg.created_at = 10 * i as u64;
+        }
+
+        let counts = chs.word_count_over_time();
+        assert_eq!(chs.message_count(), counts.len());
+        assert_eq!(vec![(0, 3), (10, 3), (20, 5)], counts);
+    }
+
+    #[test]
+    fn test_store_shared_context_pairs() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let session_with = |title: &str, contents: &[&str]| {
+            let mut chs = ChatSession::new(title.to_string(), MODEL);
+            for content in contents {
+                let msg = ChatCompletionRequestMessage {
+                    role: Role::User,
+                    content: Some(content.to_string()),
+                    name: None,
+                    function_call: None,
+                };
+                chs.add_chat_message(msg);
+            }
+            chs
+        };
+
+        let first = push_session(
+            &mut store,
+            session_with("A", &["Explain lifetimes in Rust"]),
+        );
+        assert!(store.find_session_pairs_with_shared_context().is_empty());
+
+        let second = push_session(
+            &mut store,
+            session_with("B", &["Hello", "Explain lifetimes in Rust!", "Sure"]),
+        );
+        // "Hello" is far too short a part of the message it appears in.
+        push_session(
+            &mut store,
+            session_with("C", &["Hello there, explain lifetimes to me"]),
+        );
+        push_session(&mut store, session_with("D", &[]));
+
+        assert_eq!(
+            vec![(first, second)],
+            store.find_session_pairs_with_shared_context()
+        );
+    }
+
+    #[test]
+    fn test_request_msg_with_name() {
+        let msg = Message::new(ChatRole::User, vec![MessageContent::text("Hi")]);
+
+        let named = msg.as_request_with_name(String::from("Emmanuel_Dodoo-1"));
+        assert_eq!(Some(String::from("Emmanuel_Dodoo-1")), named.name);
+        assert_eq!(Some(String::from("Hi")), named.content);
+        assert_eq!(Role::User, named.role);
+
+        let coerced = msg.as_request_with_name(String::from("Emmanuel Dodoo!"));
+        assert_eq!(Some(String::from("Emmanuel_Dodoo_")), coerced.name);
+
+        let long = msg.as_request_with_name("a".repeat(100));
+        assert_eq!(64, long.name.unwrap().len());
+
+        let mut chs = ChatSession::new(String::from("Names"), MODEL);
+        chs.add_chat_message(msg.to_chat_resquest_msg());
+        chs.add_chat_message(ChatCompletionResponseMessage {
+            role: Role::Assistant,
+            content: Some(String::from("Hello")),
+            function_call: None,
+        });
+
+        let names: Vec<Option<String>> = chs
+            .to_request_messages_with_names()
+            .into_iter()
+            .map(|x| x.name)
+            .collect();
+        assert_eq!(
+            vec![Some(String::from("user")), Some(String::from("assistant"))],
+            names
+        );
+    }
+
+    #[test]
+    fn test_store_search_index() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let mut ids = vec![];
+        for contents in [
+            ["Rust lifetimes, explained", "Borrowing in Rust"],
+            ["Python typing", "rust on a bike chain"],
+        ] {
+            let mut chs = ChatSession::new(String::from("Session"), MODEL);
+            for content in contents {
+                let msg = ChatCompletionRequestMessage {
+                    role: Role::User,
+                    content: Some(content.to_string()),
+                    name: None,
+                    function_call: None,
+                };
+                chs.add_chat_message(msg);
+            }
+            let messages = message_ids(&chs);
+            ids.push((push_session(&mut store, chs), messages));
+        }
+        let rust = vec![
+            (ids[0].0, ids[0].1[0]),
+            (ids[0].0, ids[0].1[1]),
+            (ids[1].0, ids[1].1[1]),
+        ];
+
+        let index = store.message_search_index();
+        assert_eq!(Some(&rust), index.get("rust"));
+        assert_eq!(Some(&vec![rust[0]]), index.get("lifetimes"));
+
+        let found: Vec<(SessionId, MessageId)> = store
+            .search_indexed(&index, "RUST")
+            .iter()
+            .map(|(chs, msg)| (chs.get_id(), msg.get_id()))
+            .collect();
+        assert_eq!(rust, found);
+
+        let found = store.search_indexed(&index, "rust, borrowing");
+        assert_eq!(1, found.len());
+        assert_eq!("Borrowing in Rust", found[0].1.get_content());
+
+        assert!(store.search_indexed(&index, "go").is_empty());
+        assert!(store.search_indexed(&index, "").is_empty());
+
+        // Stale entries are skipped rather than resolved.
+        store.delete_session(ids[1].0);
+        assert_eq!(2, store.search_indexed(&index, "rust").len());
+    }
+
+    #[test]
+    fn test_store_sessions_created_between() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let mut ids = vec![];
+        for created_at in [100, 200, 300] {
+            let mut chs = ChatSession::new(format!("Session {}", created_at), MODEL);
+            chs.created_at = created_at;
+            ids.push(push_session(&mut store, chs));
+        }
+
+        let found = |sessions: Vec<&ChatSession>| -> Vec<SessionId> {
+            sessions.iter().map(|x| x.get_id()).collect()
+        };
+
+        assert_eq!(ids, found(store.sessions_created_between(0, 1000)));
+        assert_eq!(
+            ids[..2].to_vec(),
+            found(store.sessions_created_between(100, 200))
+        );
+        assert_eq!(
+            vec![ids[2]],
+            found(store.sessions_created_between(300, 300))
+        );
+        assert!(store.sessions_created_between(101, 199).is_empty());
+        assert!(store.sessions_created_between(300, 100).is_empty());
+    }
+
+    #[test]
+    fn test_session_system_messages() {
+        let mut chs = ChatSession::new(String::from("System messages"), MODEL);
+        assert!(chs.get_system_messages().is_empty());
+
+        for role in [Role::System, Role::User, Role::Assistant, Role::System] {
+            let msg = ChatCompletionRequestMessage {
+                role,
+                content: Some(String::from("Some content")),
+                name: None,
+                function_call: None,
+            };
+            chs.add_chat_message(msg);
+        }
+
+        let ids: Vec<MessageId> = chs
+            .get_system_messages()
+            .iter()
+            .map(|x| x.get_id())
+            .collect();
+        let all = message_ids(&chs);
+        assert_eq!(vec![all[0], all[3]], ids);
+        assert_eq!(1, chs.get_messages_by_role(ChatRole::User).len());
+    }
+
+    #[test]
+    fn test_store_session_summary() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let mut chs = ChatSession::new(String::from("Summarised"), MODEL);
+        chs.created_at = 100;
+        let id = push_session(&mut store, chs.clone());
+
+        let summary = store.get_session_summary(id).unwrap();
+        assert_eq!(id, summary.id);
+        assert_eq!("Summarised", summary.title);
+        assert_eq!(0, summary.message_count);
+        assert_eq!(100, summary.last_activity);
+        assert_eq!(MODEL, summary.model);
+
+        let msg = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("Some content")),
+            name: None,
+            function_call: None,
+        };
+        chs.add_chat_message(msg);
+        chs.messages[0].created_at = 500;
+        store.sessions = indexed::from_items(vec![chs]);
+
+        let summary = store.get_session_summary(id).unwrap();
+        assert_eq!(1, summary.message_count);
+        assert_eq!(500, summary.last_activity);
+
+        let missing = SessionId::generate();
+        assert_eq!(
+            Err(ChatError::SessionNotFound(missing)),
+            store.get_session_summary(missing)
+        );
+    }
+
+    #[test]
+    fn test_session_add_batch_without_api() {
+        let mut chs = ChatSession::new(String::from("Seeded"), MODEL);
+
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::System, String::from("Be brief")),
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+        ]);
+        chs.add_message_batch_without_api(vec![(ChatRole::User, String::from("Bye"))]);
+
+        assert_eq!(4, chs.message_count());
+        assert_eq!(ChatRole::Assistant, chs.get_messages()[2].get_role());
+        assert_eq!("Bye", chs.get_messages()[3].get_content());
+        assert!(chs.get_messages()[2].get_id() < chs.get_messages()[3].get_id());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_session_add_batch_empty_content() {
+        let mut chs = ChatSession::new(String::from("Seeded"), MODEL);
+        chs.add_message_batch_without_api(vec![(ChatRole::User, String::new())]);
     }
 
-    /// Deletes any chat session with matching id in this store. The right most,
-    /// deleted session is returned if possible.
-    pub fn delete_session(&mut self, id: usize) -> Option<ChatSession> {
-        let mut accumulator: Vec<ChatSession> = Vec::new();
+    #[test]
+    fn test_store_sort_by_activity_then_title() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        // iter().filter() yields a new iterator and would not have returned any elements that
-        // fail the predicate. So I settled on this approach.
-        let target = self.sessions.iter().fold(None, |acc, curr| {
-            if curr.get_id() == id {
-                return Some(curr.to_owned());
-            } else {
-                accumulator.push(curr.to_owned());
-                return acc;
-            }
-        });
-        self.sessions = accumulator;
+        for (title, created_at) in [
+            ("Banana", 100),
+            ("Cherry", 300),
+            ("Apple", 100),
+            ("Date", 50),
+        ] {
+            let mut chs = ChatSession::new(title.to_string(), MODEL);
+            chs.created_at = created_at;
+            push_session(&mut store, chs);
+        }
+        store.sessions[3].add_message_batch_without_api(vec![(ChatRole::User, "Hi".to_string())]);
+        store.sessions[3].messages[0].created_at = 200;
 
-        target
+        let titles: Vec<String> = store
+            .sessions_sorted_by_activity_then_title()
+            .iter()
+            .map(|x| x.get_title())
+            .collect();
+        assert_eq!(vec!["Cherry", "Date", "Apple", "Banana"], titles);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role};
-    use std::time::{SystemTime, UNIX_EPOCH};
+    #[test]
+    fn test_session_response_times() {
+        let mut chs = ChatSession::new(String::from("Timing"), MODEL);
+        assert!(chs.get_response_times().is_empty());
 
-    const MODEL: &str = "gpt-3.5-turbo";
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::System, String::from("Be brief")),
+            (ChatRole::User, String::from("One")),
+            (ChatRole::Assistant, String::from("Two")),
+            (ChatRole::User, String::from("Three")),
+            (ChatRole::Assistant, String::from("Four")),
+            (ChatRole::User, String::from("Five")),
+        ]);
+        for (msg, created_at) in chs.messages.values_mut().zip([0, 10, 13, 20, 15, 30]) {
+            msg.created_at = created_at;
+        }
+
+        assert_eq!(vec![3, 0], chs.get_response_times());
+    }
 
     #[test]
-    fn test_create_chat_thread() {
-        let id = 2;
-        let title = String::from("Something");
-        let mut ctd = ChatSession::new(id.clone(), title.clone(), MODEL);
+    fn test_store_delete_all_sessions() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        assert_eq!(ctd.get_id(), id);
-        assert_eq!(ctd.get_title(), title);
-        assert!(ctd.get_messages().is_empty());
-        assert_eq!(ctd.msg_id_counter, 0);
+        assert!(store.delete_all_sessions().is_empty());
 
-        ctd.rename_session("New title".to_string());
-        assert_eq!(ctd.title, "New title".to_string());
+        let mut ids = vec![];
+        for i in 0..3 {
+            let mut chs = ChatSession::new(format!("Session {}", i), MODEL);
+            chs.add_message_batch_without_api(vec![(ChatRole::User, String::from("Hello"))]);
+            ids.push(push_session(&mut store, chs));
+        }
+        let message_id = message_ids(store.get_session(ids[0]).unwrap())[0];
+        store.delete_message(ids[0], message_id).unwrap();
+        assert_eq!(1, store.get_trash().len());
+        assert_eq!(1, store.session_revision(ids[0]));
+
+        let deleted = store.delete_all_sessions();
+        assert_eq!(3, deleted.len());
+        assert_eq!(ids[2], deleted[2].get_id());
+        assert!(store.get_all_sessions().is_empty());
+        assert!(store.get_trash().is_empty());
+        assert_eq!(0, store.session_revision(ids[0]));
+
+        // Nothing of the deleted sessions can be brought back
+        let restored = deleted.into_iter().next().unwrap();
+        push_session(&mut store, restored);
+        assert!(matches!(
+            store.restore_message(ids[0], message_id),
+            Err(ChatError::MessageNotFound(id)) if id == message_id
+        ));
+        assert!(matches!(
+            store.restore_session(ids[1]),
+            Err(ChatError::SessionNotFound(id)) if id == ids[1]
+        ));
     }
 
     #[test]
-    fn test_response_msg() {
-        let role = Role::Assistant;
-        let content = String::from("Some content");
-        let msg = ChatCompletionResponseMessage {
-            role: role.clone(),
-            content: Some(content.clone()),
-            function_call: None,
-        };
+    fn test_session_average_response_time() {
+        let mut chs = ChatSession::new(String::from("Timing"), MODEL);
+        assert_eq!(None, chs.average_response_time_secs());
 
-        assert_eq!(role, msg.get_role());
-        assert_eq!(content, msg.get_content());
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("One")),
+            (ChatRole::Assistant, String::from("Two")),
+            (ChatRole::User, String::from("Three")),
+            (ChatRole::Assistant, String::from("Four")),
+            (ChatRole::User, String::from("Five")),
+            (ChatRole::Assistant, String::from("Six")),
+        ]);
+        for (msg, created_at) in chs.messages.values_mut().zip([0, 1, 10, 11, 20, 22]) {
+            msg.created_at = created_at;
+        }
+
+        assert_eq!(Some(1.33), chs.average_response_time_secs());
     }
 
     #[test]
-    fn test_request_msg() {
-        let role = Role::System;
-        let msg = ChatCompletionRequestMessage {
-            role: role.clone(),
-            content: None,
-            name: None,
-            function_call: None,
-        };
+    fn test_store_has_any_message_containing() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        assert_eq!(role, msg.get_role());
-        assert_eq!("", msg.get_content());
+        assert!(!store.has_any_message_containing("rust"));
+        assert!(!store.has_any_message_containing(""));
+
+        let mut chs = ChatSession::new(String::from("Searchable"), MODEL);
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Tell me about Rust")),
+            (
+                ChatRole::Assistant,
+                String::from("It is a systems language"),
+            ),
+        ]);
+        push_session(&mut store, chs);
+
+        assert_eq!(2, store.iter_messages().count());
+        assert!(store.has_any_message_containing("rUST"));
+        assert!(store.has_any_message_containing("systems lang"));
+        assert!(!store.has_any_message_containing("python"));
     }
 
     #[test]
-    fn test_create_message() {
-        let id = 25;
-        let role = Role::User;
-        let contents = String::from("Some random content");
-        let time_before = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => panic!("Should not get here"),
+    fn test_session_role_sequence() {
+        let mut chs = ChatSession::new(String::from("Roles"), MODEL);
+        assert!(chs.message_role_sequence().is_empty());
+
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::System, String::from("Be brief")),
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+        ]);
+
+        assert_eq!(
+            vec![ChatRole::System, ChatRole::User, ChatRole::Assistant],
+            chs.message_role_sequence()
+        );
+    }
+
+    #[test]
+    fn test_store_get_session_or_insert_with() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let inserted = ChatSession::new(String::from("Inserted"), MODEL);
+        let id = inserted.get_id();
+        let chs = store.get_session_or_insert_with(id, || inserted);
+        chs.rename_session(String::from("Renamed"));
+
+        assert_eq!(1, store.get_all_sessions().len());
+
+        let chs = store.get_session_or_insert_with(id, || panic!("Should not be called"));
+        assert_eq!("Renamed", chs.get_title());
+        assert_eq!(1, store.get_all_sessions().len());
+
+        // A session made with another id is kept under the one asked for
+        let id = SessionId::generate();
+        let chs =
+            store.get_session_or_insert_with(id, || ChatSession::new(String::from("Other"), MODEL));
+        assert_eq!(id, chs.get_id());
+        assert_eq!("Other", store.get_session(id).unwrap().get_title());
+        store.get_session_or_insert_with(id, || panic!("Should not be called"));
+        assert_eq!(2, store.get_all_sessions().len());
+    }
+
+    #[test]
+    fn test_store_load_and_autosave() {
+        use persistence::JsonFileBackend;
+
+        let path = std::env::temp_dir().join(format!(
+            "chat-overlay-store-{}.json",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+
+        let provider = MockProvider::default();
+        let mut store = Store::load(provider.clone(), JsonFileBackend::new(&path)).unwrap();
+        assert!(store.get_all_sessions().is_empty());
+
+        let id = store.add_empty_session(String::from("Persisted"), MODEL);
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![(ChatRole::User, String::from("Hi"))]);
+        store.save().unwrap();
+
+        let mut loaded = Store::load(provider.clone(), JsonFileBackend::new(&path)).unwrap();
+        assert_eq!("Persisted", loaded.get_session(id).unwrap().get_title());
+        assert_eq!(
+            "Hi",
+            loaded.get_session(id).unwrap().get_messages()[0].get_content()
+        );
+
+        // Unsent prompts are kept until taken
+        loaded
+            .set_session_draft(id, String::from("Half typed"))
+            .unwrap();
+        let mut loaded = Store::load(provider.clone(), JsonFileBackend::new(&path)).unwrap();
+        assert_eq!(
+            Some("Half typed"),
+            loaded.get_session(id).unwrap().get_draft()
+        );
+        assert_eq!(
+            Some(String::from("Half typed")),
+            loaded.take_session_draft(id).unwrap()
+        );
+        assert_eq!(None, loaded.take_session_draft(id).unwrap());
+        loaded.set_session_draft(id, String::from(" ")).unwrap();
+        assert_eq!(None, loaded.get_session(id).unwrap().get_draft());
+
+        loaded.delete_session(id);
+        let reloaded = Store::load(provider, JsonFileBackend::new(&path)).unwrap();
+        assert!(reloaded.get_all_sessions().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_store_ephemeral_sessions() {
+        use persistence::JsonFileBackend;
+
+        let path = std::env::temp_dir().join(format!(
+            "chat-overlay-ephemeral-{}.json",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+
+        let ids = |store: &Store| -> Vec<SessionId> {
+            store
+                .get_all_sessions()
+                .iter()
+                .map(|x| x.get_id())
+                .collect()
         };
+        let provider = MockProvider::default();
+        let mut store = Store::load(provider.clone(), JsonFileBackend::new(&path)).unwrap();
+        let saved = store.add_empty_session(String::from("Saved"), MODEL);
+        let kept = store.add_ephemeral_session(String::from("Kept"), MODEL);
+        let scratch = store.add_ephemeral_session(String::from("Scratch"), MODEL);
+        let deleted = store.add_ephemeral_session(String::from("Deleted"), MODEL);
+        assert!(store.get_session(scratch).unwrap().is_ephemeral());
+        assert!(store.get_session(scratch).unwrap().summary().ephemeral);
 
-        let msg = Message::new(id.clone(), role.clone(), contents.clone());
+        // Ephemeral sessions are never saved, nor put in the trash
+        store.save().unwrap();
+        let loaded = Store::load(provider.clone(), JsonFileBackend::new(&path)).unwrap();
+        assert_eq!(vec![saved], ids(&loaded));
+        store.delete_session(deleted);
+        assert!(store.get_trash().is_empty());
 
-        assert_eq!(msg.get_id(), id);
-        assert_eq!(msg.get_role(), role);
-        assert_eq!(msg.get_content(), contents);
-        assert_eq!(time_before, msg.get_created_at());
+        // Nor are the messages deleted from them
+        let session = store.session_mut(scratch).unwrap();
+        session.add_message_batch_without_api(vec![(ChatRole::User, String::from("Secret"))]);
+        let message = message_ids(session)[0];
+        assert!(store.delete_message(scratch, message).unwrap().is_some());
+        assert!(store.get_trash().is_empty());
+        store.save().unwrap();
+        let loaded = Store::load(provider.clone(), JsonFileBackend::new(&path)).unwrap();
+        assert!(loaded.get_trash().is_empty());
+
+        store.promote_session(kept).unwrap();
+        assert!(!store.get_session(kept).unwrap().is_ephemeral());
+        assert_eq!(vec![scratch], store.discard_ephemeral_sessions());
+        assert_eq!(vec![saved, kept], ids(&store));
+
+        let loaded = Store::load(provider, JsonFileBackend::new(&path)).unwrap();
+        assert_eq!(vec![saved, kept], ids(&loaded));
+        assert_eq!(
+            Err(ChatError::SessionNotFound(scratch)),
+            store.promote_session(scratch)
+        );
+
+        std::fs::remove_file(path).unwrap();
     }
 
     #[test]
-    fn test_session_add_and_remove() {
-        let mut chs = ChatSession::new(25, String::from("Testing adding messages"), MODEL);
+    fn test_store_rename_and_delete_message() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        for i in 0..6 {
-            if i % 2 == 0 {
-                let msg = ChatCompletionRequestMessage {
-                    role: Role::User,
-                    content: Some(String::from("Request chat")),
-                    name: None,
-                    function_call: None,
-                };
-                chs.add_chat_message(msg);
-            } else {
-                let msg = ChatCompletionResponseMessage {
-                    role: Role::Assistant,
-                    content: Some(String::from("Response chat")),
-                    function_call: None,
-                };
-                chs.add_chat_message(msg);
-            }
-        }
+        let mut chs = ChatSession::new(String::from("Before"), MODEL);
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+        ]);
+        let messages = message_ids(&chs);
+        let id = push_session(&mut store, chs);
 
-        assert_eq!(6, chs.get_messages().len());
+        store.rename_session(id, String::from("After")).unwrap();
+        assert_eq!("After", store.get_session(id).unwrap().get_title());
+
+        let deleted = store.delete_message(id, messages[1]).unwrap().unwrap();
+        assert_eq!("Hello", deleted.get_content());
+        assert_eq!(1, store.get_session(id).unwrap().message_count());
+        assert!(store.delete_message(id, messages[1]).unwrap().is_none());
+
+        let missing = SessionId::generate();
         assert_eq!(
-            chs.get_messages()[0].get_content(),
-            String::from("Request chat")
+            Err(ChatError::SessionNotFound(missing)),
+            store.rename_session(missing, String::from("Missing"))
         );
         assert_eq!(
-            chs.get_messages()[1].get_content(),
-            String::from("Response chat")
+            ChatError::SessionNotFound(missing),
+            store.delete_message(missing, messages[0]).unwrap_err()
         );
+    }
 
-        assert_eq!(0, chs.delete_message(0).unwrap().get_id());
-        assert_eq!(5, chs.get_messages().len());
+    #[test]
+    fn test_store_usage_report() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        assert_eq!(3, chs.delete_message(3).unwrap().get_id());
-        assert_eq!(4, chs.get_messages().len());
+        let mut ids = vec![];
+        for model in ["gpt-4", MODEL, "gpt-4", "llama3"] {
+            let mut chs = ChatSession::new(String::from("Usage"), model);
+            chs.add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("Hi")),
+                (ChatRole::Assistant, String::from("Hello")),
+            ]);
+            chs.messages[1].usage = Some(TokenUsage {
+                prompt_tokens: 1000,
+                completion_tokens: 500,
+            });
+            ids.push(push_session(&mut store, chs));
+        }
 
-        assert!(chs.delete_message(0).is_none());
-        assert_eq!(4, chs.get_messages().len())
+        let session = store.get_session(ids[0]).unwrap();
+        assert_eq!(1500, session.total_usage().total_tokens());
+        assert!((session.total_cost() - 0.06).abs() < 1e-9);
+        assert_eq!(0.0, store.get_session(ids[3]).unwrap().total_cost());
+
+        let report = store.usage_report();
+        let models: Vec<_> = report.models.iter().map(|x| x.model.as_str()).collect();
+        assert_eq!(vec![MODEL, "gpt-4", "llama3"], models);
+        assert_eq!(2000, report.models[1].prompt_tokens);
+        assert_eq!(1000, report.models[1].completion_tokens);
+        assert!((report.total_cost - 0.1225).abs() < 1e-9);
     }
 
     #[test]
-    fn test_new_store() {
-        let config = OpenAIConfig::default();
-        let client = Client::with_config(config);
-        let store = Store::new(client);
+    fn test_store_daily_usage() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+        let day = 86_400;
 
-        assert!(store.get_all_sessions().is_empty());
-        assert_eq!(0, store.session_id_counter);
+        let mut chs = ChatSession::new(String::from("Usage"), "gpt-4");
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+            (ChatRole::User, String::from("Again")),
+            (ChatRole::Assistant, String::from("Hello again")),
+            (ChatRole::Assistant, String::from("And again")),
+        ]);
+        for (index, created_at) in [(1, day), (3, 2 * day), (4, 2 * day + 60)] {
+            chs.messages[index].created_at = created_at;
+            chs.messages[index].usage = Some(TokenUsage {
+                prompt_tokens: 1000,
+                completion_tokens: 500,
+            });
+        }
+        chs.messages[4].model = Some(String::from("llama3"));
+        push_session(&mut store, chs);
+
+        let rows = store.daily_usage(DateRange::default());
+        let keys: Vec<_> = rows
+            .iter()
+            .map(|x| (x.date.as_str(), x.model.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("1970-01-02", "gpt-4"),
+                ("1970-01-03", "gpt-4"),
+                ("1970-01-03", "llama3")
+            ],
+            keys
+        );
+        assert!((rows[0].cost - 0.06).abs() < 1e-9);
+        assert_eq!(0.0, rows[2].cost);
+
+        let rows = store.daily_usage(DateRange {
+            start: Some(2 * day),
+            end: Some(2 * day + 59),
+        });
+        assert_eq!(1, rows.len());
+        assert_eq!(1000, rows[0].prompt_tokens);
     }
 
     #[test]
-    fn test_store_add_session() {
-        let config = OpenAIConfig::default();
-        let client = Client::with_config(config);
-        let mut store = Store::new(client);
+    fn test_store_fork_session() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        assert!(store.get_all_sessions().is_empty());
+        let mut chs = ChatSession::new(String::from("Original"), MODEL);
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+            (ChatRole::User, String::from("Bye")),
+        ]);
+        let messages = message_ids(&chs);
+        let original = push_session(&mut store, chs);
 
-        let msg1 = ChatCompletionRequestMessage {
-            role: Role::User,
-            content: Some(String::from("msg1")),
-            name: None,
-            function_call: None,
-        };
-        store
-            .add_session(msg1, "Test Message 1".to_string(), MODEL)
-            .unwrap();
-        assert_eq!(1, store.get_all_sessions().len());
-        assert_eq!(0, store.get_all_sessions()[0].get_id());
+        let id = store.fork_session(original, messages[1]).unwrap();
+        assert_ne!(original, id);
+
+        let fork = store.get_session(id).unwrap();
+        let contents: Vec<String> = fork.messages.values().map(|x| x.get_content()).collect();
+        assert_eq!(vec!["Hi", "Hello"], contents);
+        assert_eq!(messages[..2].to_vec(), message_ids(fork));
         assert_eq!(
-            "Test Message 1".to_string(),
-            store.get_all_sessions()[0].get_title()
+            Some(ForkPoint {
+                session_id: original,
+                message_id: messages[1]
+            }),
+            fork.get_parent()
         );
+        assert_eq!(Some(original), fork.summary().parent_id);
 
-        let msg2 = ChatCompletionRequestMessage {
-            role: Role::System,
-            content: Some(String::from("msg2")),
-            name: None,
-            function_call: None,
-        };
+        // New messages get ids of their own
         store
-            .add_session(msg2, "Test msg 2".to_string(), MODEL)
-            .unwrap();
-        assert_eq!(2, store.get_all_sessions().len());
-        assert_eq!(1, store.get_all_sessions()[1].get_id());
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![(ChatRole::User, String::from("Hey"))]);
+        assert!(!messages.contains(&store.get_session(id).unwrap().messages[2].get_id()));
+
+        let forks: Vec<SessionId> = store
+            .get_forks(original)
+            .iter()
+            .map(|x| x.get_id())
+            .collect();
+        assert_eq!(vec![id], forks);
+        assert!(store.get_forks(id).is_empty());
+
+        let missing = MessageId::generate();
         assert_eq!(
-            String::from("msg2"),
-            store.get_all_sessions()[1].get_messages()[0].get_content()
+            Err(ChatError::MessageNotFound(missing)),
+            store.fork_session(original, missing)
         );
+        let missing = SessionId::generate();
         assert_eq!(
-            Role::User,
-            store.get_all_sessions()[1].get_messages()[0].get_role()
+            Err(ChatError::SessionNotFound(missing)),
+            store.fork_session(missing, messages[0])
         );
     }
 
     #[test]
-    fn test_store_get_specific() {
-        let config = OpenAIConfig::default();
-        let client = Client::with_config(config);
-        let mut store = Store::new(client);
+    fn test_store_duplicate_session() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        let msg1 = ChatCompletionRequestMessage {
-            role: Role::User,
-            content: Some(String::from("msg1")),
-            name: None,
-            function_call: None,
-        };
-        let msg2 = ChatCompletionRequestMessage {
-            role: Role::System,
-            content: Some(String::from("msg2")),
-            name: None,
-            function_call: None,
-        };
-        let msg3 = ChatCompletionRequestMessage {
-            role: Role::Assistant,
-            content: Some(String::from("msg3")),
-            name: None,
-            function_call: None,
-        };
+        let mut chs = ChatSession::new(String::from("Careful"), MODEL);
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+        ]);
+        chs.set_system_prompt(Some(String::from("Be brief")));
+        chs.pinned = true;
+        let messages = message_ids(&chs);
+        let original = push_session(&mut store, chs);
+        store.add_session_tag(original, "work").unwrap();
 
-        store.add_session(msg1, "Tired".to_string(), MODEL).unwrap();
-        store.add_session(msg2, "Tired".to_string(), MODEL).unwrap();
-        store.add_session(msg3, "Tired".to_string(), MODEL).unwrap();
+        let id = store.duplicate_session(original).unwrap();
+        assert_ne!(original, id);
+        let copy = store.get_session(id).unwrap();
+        assert_eq!("Careful (copy)", copy.get_title());
+        assert_eq!(Some("Be brief"), copy.get_system_prompt());
+        assert_eq!(vec!["work"], copy.get_tags());
+        assert!(!copy.summary().pinned);
+        let contents: Vec<String> = copy.messages.values().map(|x| x.get_content()).collect();
+        assert_eq!(vec!["Hi", "Hello"], contents);
+        assert!(message_ids(copy).iter().all(|x| !messages.contains(x)));
 
+        // Changing the copy leaves the original as it was
+        let first = message_ids(copy)[0];
+        store.delete_message(id, first).unwrap();
+        assert_eq!(2, store.get_session(original).unwrap().message_count());
+
+        let missing = SessionId::generate();
         assert_eq!(
-            store.get_session(2).unwrap().get_title(),
-            "Tired".to_string()
+            Err(ChatError::SessionNotFound(missing)),
+            store.duplicate_session(missing)
         );
-        assert!(store.get_session(40).is_none());
-        assert_eq!(store.get_all_sessions().len(), 3);
     }
 
     #[test]
-    fn test_store_delete_sessions() {
-        let config = OpenAIConfig::default();
-        let client = Client::with_config(config);
-        let mut store = Store::new(client);
+    fn test_store_merge_sessions() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
 
-        let msg1 = ChatCompletionRequestMessage {
-            role: Role::User,
-            content: Some(String::from("msg1")),
-            name: None,
-            function_call: None,
-        };
-        let msg2 = ChatCompletionRequestMessage {
-            role: Role::System,
-            content: Some(String::from("msg2")),
-            name: None,
-            function_call: None,
-        };
-        let msg3 = ChatCompletionRequestMessage {
-            role: Role::Assistant,
-            content: Some(String::from("msg3")),
-            name: None,
-            function_call: None,
-        };
+        let mut chs = ChatSession::new(String::from("Target"), MODEL);
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+        ]);
+        let target = push_session(&mut store, chs);
+        let mut chs = ChatSession::new(String::from("Scratch"), MODEL);
+        chs.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Why?")),
+            (ChatRole::Assistant, String::from("Because")),
+        ]);
+        let source_messages: Vec<Message> = chs.messages.values().cloned().collect();
+        let source = push_session(&mut store, chs);
+        store.add_session_tag(source, "ideas").unwrap();
+
+        store.merge_sessions(target, target).unwrap();
+        assert_eq!(2, store.get_session(target).unwrap().message_count());
 
-        store.add_session(msg1, "One".to_string(), MODEL).unwrap();
-        store.add_session(msg2, "Two".to_string(), MODEL).unwrap();
-        store.add_session(msg3, "Three".to_string(), MODEL).unwrap();
+        store.merge_sessions(target, source).unwrap();
+        assert!(store.get_session(source).is_none());
+        let merged = store.get_session(target).unwrap();
+        let contents: Vec<String> = merged.messages.values().map(|x| x.get_content()).collect();
+        assert_eq!(vec!["Hi", "Hello", "Why?", "Because"], contents);
+        assert_eq!(vec!["ideas"], merged.get_tags());
+        for (merged, original) in merged.messages.values().skip(2).zip(&source_messages) {
+            assert_ne!(original.get_id(), merged.get_id());
+            assert_eq!(original.created_at, merged.created_at);
+        }
 
+        // The source is not trashed, so its messages can not come back twice
+        assert!(store.get_trash().is_empty());
         assert_eq!(
-            store.delete_session(2).unwrap().get_title(),
-            "Three".to_string()
+            Err(ChatError::SessionNotFound(source)),
+            store.restore_session(source)
+        );
+
+        let missing = SessionId::generate();
+        assert_eq!(
+            Err(ChatError::SessionNotFound(missing)),
+            store.merge_sessions(missing, source)
+        );
+        assert_eq!(
+            Err(ChatError::SessionNotFound(missing)),
+            store.merge_sessions(target, missing)
+        );
+    }
+
+    #[test]
+    fn test_store_bookmarks() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let mut ids = vec![];
+        for title in ["First", "Second"] {
+            let mut chs = ChatSession::new(title.to_string(), MODEL);
+            chs.add_message_batch_without_api(vec![
+                (ChatRole::User, format!("Question in {}", title)),
+                (ChatRole::Assistant, format!("Answer in {}", title)),
+            ]);
+            ids.push((message_ids(&chs), push_session(&mut store, chs)));
+        }
+        assert!(store.get_bookmarked_messages().is_empty());
+
+        let (first_messages, first) = &ids[0];
+        let (second_messages, second) = &ids[1];
+        assert!(store.toggle_bookmark(*first, first_messages[1]).unwrap());
+        assert!(store.toggle_bookmark(*second, second_messages[1]).unwrap());
+        assert!(store.toggle_bookmark(*second, second_messages[0]).unwrap());
+        assert!(!store.toggle_bookmark(*second, second_messages[0]).unwrap());
+
+        let bookmarks = store.get_bookmarked_messages();
+        let contents: Vec<String> = bookmarks.iter().map(|x| x.message.get_content()).collect();
+        assert_eq!(vec!["Answer in Second", "Answer in First"], contents);
+        assert_eq!("First", bookmarks[1].session_title);
+        assert!(bookmarks[0].message.is_bookmarked());
+
+        let missing = MessageId::generate();
+        assert_eq!(
+            Err(ChatError::MessageNotFound(missing)),
+            store.toggle_bookmark(*first, missing)
+        );
+    }
+
+    #[test]
+    fn test_store_add_empty_session() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        let id = store.add_empty_session(String::from("Scratch"), MODEL);
+        assert!(id < store.add_empty_session(String::from("Later"), MODEL));
+
+        let session = store.get_session(id).unwrap();
+        assert_eq!("Scratch", session.get_title());
+        assert_eq!(0, session.message_count());
+    }
+
+    #[test]
+    fn test_session_system_prompt() {
+        let mut chs = ChatSession::new(String::from("Prompted"), MODEL);
+        chs.add_message_batch_without_api(vec![(ChatRole::User, String::from("Hi"))]);
+        let unprompted_tokens = chs.token_count();
+
+        chs.set_system_prompt(Some(String::from("Answer in French")));
+        assert_eq!(Some("Answer in French"), chs.get_system_prompt());
+        assert!(chs.token_count() > unprompted_tokens);
+
+        let request = chs.completion_request(chs.messages.values(), Some("Bye"));
+        let roles: Vec<Role> = request.messages.iter().map(|x| x.role.clone()).collect();
+        assert_eq!(vec![Role::System, Role::User, Role::User], roles);
+        assert_eq!(
+            Some(String::from("Answer in French")),
+            request.messages[0].content
+        );
+
+        chs.set_system_prompt(Some(String::from("  ")));
+        assert_eq!(None, chs.get_system_prompt());
+        let request = chs.completion_request(chs.messages.values(), None);
+        assert_eq!(1, request.messages.len());
+    }
+
+    #[test]
+    fn test_store_prompts() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        store.save_prompt(PromptTemplate::new("greet", "Greets", "Hi {{name}}"));
+        store.save_prompt(PromptTemplate::new("bye", "", "Bye"));
+        store.save_prompt(PromptTemplate::new("greet", "Greets", "Hello {{name}}"));
+        assert_eq!(2, store.get_prompts().len());
+
+        let mut vars = HashMap::new();
+        vars.insert(String::from("name"), String::from("there"));
+        assert_eq!("Hello there", store.render_prompt("greet", &vars).unwrap());
+        assert_eq!(
+            Err(ChatError::PromptNotFound(String::from("missing"))),
+            store.render_prompt("missing", &vars)
+        );
+
+        assert!(store.delete_prompt("greet").is_some());
+        assert!(store.delete_prompt("greet").is_none());
+        assert_eq!("bye", store.get_prompts()[0].name);
+    }
+
+    #[tokio::test]
+    async fn test_store_semantic_search_disabled() {
+        let provider = MockProvider::default();
+        let mut store = Store::new(provider);
+
+        assert_eq!(
+            Err(ChatError::SemanticSearchDisabled),
+            store.semantic_search("anything", 3).await
         );
-        assert_eq!(store.get_all_sessions().len(), 2);
-        assert!(store.get_session(2).is_none());
-        assert!(store.delete_session(2).is_none());
     }
 }
@@ -1,6 +1,41 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use chat_overlay::{
+    app_config::AppConfigState,
+    auto_backup::{self, AutoBackupState},
+    budget::{BudgetConfig, SpendingLedger, BUDGET_FILE_NAME},
+    cache::{CacheConfig, CACHE_FILE_NAME},
+    cancellation::CancellationRegistry,
+    commands,
+    embeddings::EmbeddingIndex,
+    events::AppEmitter,
+    hotkey::{HotkeyState, OVERLAY_WINDOW},
+    memory::{MemoryConfig, MEMORY_FILE_NAME},
+    moderation::{ModerationConfig, MODERATION_FILE_NAME},
+    notifications::NotificationState,
+    offline,
+    persistence::{JsonConfig, JsonFileBackend, StorageKind},
+    profiles::{self, ProfilesConfig, PROFILES_FILE_NAME},
+    providers::anthropic::{AnthropicProvider, ANTHROPIC_PROVIDER},
+    providers::azure::{self, AzureSettings, AZURE_FILE_NAME},
+    providers::compatible::{self, EndpointsConfig, ENDPOINTS_FILE_NAME},
+    providers::ollama::OllamaProvider,
+    rate_limit::{RateLimitConfig, RATE_LIMIT_FILE_NAME},
+    secrets,
+    speech::SpeechState,
+    sqlite::SqliteBackend,
+    timeouts::Timeouts,
+    tools::file_reader::{self, FileReaderConfig, FILE_READER_FILE_NAME},
+    tools::web_search::{self, WebSearchConfig, WEB_SEARCH_FILE_NAME},
+    trash::{TrashConfig, TRASH_FILE_NAME},
+    tray::{self, OverlayState},
+    window::WindowState,
+    ChatError, Store,
+};
+use tauri::{Manager, WindowEvent};
+use tokio::sync::RwLock;
+
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -9,7 +44,272 @@ fn greet(name: &str) -> String {
 
 fn main() {
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![greet])
+        .system_tray(tray::system_tray())
+        .on_system_tray_event(tray::handle_event)
+        .on_window_event(|event| {
+            if event.window().label() != OVERLAY_WINDOW {
+                return;
+            }
+            let state = event.window().state::<WindowState>();
+            match event.event() {
+                WindowEvent::Moved(_) | WindowEvent::Resized(_) => {
+                    if let Err(e) = state.remember_geometry(event.window()) {
+                        eprintln!("Could not remember where the overlay is: {}", e);
+                    }
+                }
+                WindowEvent::Focused(false) => state.hide_on_blur(&event.window().app_handle()),
+                _ => {}
+            }
+        })
+        .setup(|app| {
+            let app_data_dir = app
+                .path_resolver()
+                .app_data_dir()
+                .expect("Could not resolve the app data directory");
+            let app_config_dir = app
+                .path_resolver()
+                .app_config_dir()
+                .expect("Could not resolve the app config directory");
+            let app_config = AppConfigState::in_app_config_dir(&app_config_dir);
+
+            // An encrypted store starts out empty until it is unlocked. A
+            // damaged one does too, without being saved over, until a backup
+            // is restored.
+            let json = JsonFileBackend::in_app_data_dir(&app_data_dir);
+            let loaded = match app_config.get_config().storage {
+                StorageKind::Json => Store::load(secrets::stored_openai_client(), json),
+                StorageKind::Sqlite => match SqliteBackend::in_app_data_dir(&app_data_dir) {
+                    Ok(backend) => Store::load(secrets::stored_openai_client(), backend),
+                    Err(e) => {
+                        eprintln!("Could not open the SQLite store, using JSON: {}", e);
+                        Store::load(secrets::stored_openai_client(), json)
+                    }
+                },
+            };
+            let mut store = match loaded {
+                Err(ChatError::StoreLocked) => Store::new(secrets::stored_openai_client()),
+                Err(ChatError::Persistence(e)) => {
+                    eprintln!("Could not load the store, restore a backup: {}", e);
+                    Store::new(secrets::stored_openai_client())
+                }
+                store => store?,
+            };
+            store.register_provider("ollama", OllamaProvider::default());
+            match secrets::get_anthropic_api_key() {
+                Ok(Some(key)) => {
+                    store.register_provider(ANTHROPIC_PROVIDER, AnthropicProvider::new(key))
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Could not read the Anthropic API key: {}", e),
+            }
+            store.set_spending_ledger(SpendingLedger::in_app_data_dir(&app_data_dir)?);
+            store.enable_semantic_search(
+                Timeouts::default().wrap(secrets::stored_openai_client()),
+                EmbeddingIndex::in_app_data_dir(&app_data_dir)?,
+            );
+
+            let web_search_config =
+                WebSearchConfig::load(&app_config_dir.join(WEB_SEARCH_FILE_NAME))?;
+            if let Err(e) = web_search::configure(&mut store, &web_search_config) {
+                eprintln!("Could not enable web search: {}", e);
+            }
+            let file_reader_config =
+                FileReaderConfig::load(&app_config_dir.join(FILE_READER_FILE_NAME))?;
+            file_reader::configure(&mut store, &file_reader_config);
+            let azure_settings = AzureSettings::load(&app_config_dir.join(AZURE_FILE_NAME))?;
+            if let Err(e) = azure::configure(&mut store, &azure_settings) {
+                eprintln!("Could not enable Azure OpenAI: {}", e);
+            }
+            let endpoints = EndpointsConfig::load(&app_config_dir.join(ENDPOINTS_FILE_NAME))?;
+            if let Err(e) = compatible::configure(&mut store, &endpoints) {
+                eprintln!("Could not add every endpoint: {}", e);
+            }
+            let profiles_config = ProfilesConfig::load(&app_config_dir.join(PROFILES_FILE_NAME))?;
+            if let Err(e) = profiles::configure(&mut store, &profiles_config) {
+                eprintln!("Could not add every profile: {}", e);
+            }
+
+            let trash_config = TrashConfig::load(&app_config_dir.join(TRASH_FILE_NAME))?;
+            store.set_trash_retention(trash_config.retention_secs());
+            let memory_config = MemoryConfig::load(&app_config_dir.join(MEMORY_FILE_NAME))?;
+            store.set_memory_config(memory_config);
+            let rate_limits = RateLimitConfig::load(&app_config_dir.join(RATE_LIMIT_FILE_NAME))?;
+            store.set_rate_limit_config(rate_limits);
+            let moderation_config =
+                ModerationConfig::load(&app_config_dir.join(MODERATION_FILE_NAME))?;
+            store.set_moderation_config(moderation_config);
+            let cache_config = CacheConfig::load(&app_config_dir.join(CACHE_FILE_NAME))?;
+            store.set_cache_config(cache_config);
+            let budget_config = BudgetConfig::load(&app_config_dir.join(BUDGET_FILE_NAME))?;
+            store.set_budget_config(budget_config);
+            store.set_listener(AppEmitter(app.handle()));
+
+            app.manage(RwLock::new(store));
+            app.manage(CancellationRegistry::new());
+            app.manage(offline::Outbox::new());
+            offline::spawn_outbox(app.handle());
+            app.manage(AutoBackupState::in_app_dirs(
+                &app_config_dir,
+                &app_data_dir,
+            )?);
+            auto_backup::spawn_auto_backups(app.handle());
+            let hotkey = HotkeyState::in_app_config_dir(&app_config_dir)?;
+            if let Err(e) = hotkey.register(&app.handle()) {
+                eprintln!("Could not register the quick-ask hotkey: {}", e);
+            }
+            app.manage(hotkey);
+            let visible = app
+                .get_window(OVERLAY_WINDOW)
+                .and_then(|x| x.is_visible().ok())
+                .unwrap_or(false);
+            app.manage(OverlayState::new(visible));
+            let window_state = WindowState::in_app_config_dir(&app_config_dir)?;
+            if let Err(e) = window_state.apply(&app.handle()) {
+                eprintln!("Could not restore the overlay window: {}", e);
+            }
+            app.manage(window_state);
+            app.manage(SpeechState::in_app_config_dir(&app_config_dir)?);
+            app.manage(NotificationState::in_app_config_dir(&app_config_dir)?);
+
+            app.manage(app_config);
+            let app_config = app.state::<AppConfigState>();
+            app_config.apply(&app.handle());
+            if let Err(e) = app_config.watch(&app.handle()) {
+                eprintln!("Could not watch the config file: {}", e);
+            }
+
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            commands::create_session,
+            commands::send_message,
+            commands::send_message_streaming,
+            commands::send_captured_text,
+            commands::list_capture_templates,
+            commands::ask_about_screen,
+            commands::regenerate_message,
+            commands::continue_message,
+            commands::select_message_variant,
+            commands::edit_message,
+            commands::fork_session,
+            commands::duplicate_session,
+            commands::merge_sessions,
+            commands::list_sessions,
+            commands::list_sessions_page,
+            commands::list_tags,
+            commands::toggle_bookmark,
+            commands::list_bookmarks,
+            commands::set_draft,
+            commands::take_draft,
+            commands::set_session_pinned,
+            commands::set_session_archived,
+            commands::promote_session,
+            commands::add_session_tag,
+            commands::remove_session_tag,
+            commands::get_session,
+            commands::get_messages_range,
+            commands::search,
+            commands::search_page,
+            commands::semantic_search,
+            commands::rename_session,
+            commands::regenerate_title,
+            commands::set_system_prompt,
+            commands::delete_session,
+            commands::delete_message,
+            commands::restore_session,
+            commands::restore_message,
+            commands::list_trash,
+            commands::empty_trash,
+            commands::get_trash_config,
+            commands::set_trash_config,
+            commands::set_session_provider,
+            commands::list_providers,
+            commands::list_models,
+            commands::send_queued_messages,
+            commands::get_app_config,
+            commands::get_cache_config,
+            commands::set_cache_config,
+            commands::clear_cache,
+            commands::usage_report,
+            commands::export_usage_csv,
+            commands::session_stats,
+            commands::aggregate_stats,
+            commands::list_prompts,
+            commands::save_prompt,
+            commands::delete_prompt,
+            commands::render_prompt,
+            commands::list_memories,
+            commands::add_memory,
+            commands::update_memory,
+            commands::delete_memory,
+            commands::get_memory_config,
+            commands::set_memory_config,
+            commands::get_rate_limit_config,
+            commands::set_rate_limit_config,
+            commands::get_budget_config,
+            commands::set_budget_config,
+            commands::budget_status,
+            commands::override_budget,
+            commands::get_moderation_config,
+            commands::set_moderation_config,
+            commands::set_api_key,
+            commands::validate_api_key,
+            commands::clear_api_key,
+            commands::cancel_request,
+            commands::get_hotkey,
+            commands::set_hotkey,
+            commands::set_hotkey_paused,
+            commands::get_overlay_status,
+            commands::set_overlay_visible,
+            commands::get_window_settings,
+            commands::set_always_on_top,
+            commands::set_window_opacity,
+            commands::set_click_through,
+            commands::snap_overlay,
+            commands::set_follow_active_monitor,
+            commands::set_overlay_pinned,
+            commands::get_web_search,
+            commands::set_web_search,
+            commands::get_file_reader,
+            commands::set_file_reader,
+            commands::get_azure,
+            commands::set_azure,
+            commands::clear_azure_api_key,
+            commands::set_anthropic_api_key,
+            commands::clear_anthropic_api_key,
+            commands::get_endpoints,
+            commands::set_endpoint,
+            commands::delete_endpoint,
+            commands::get_profiles,
+            commands::set_profile,
+            commands::delete_profile,
+            commands::set_session_profile,
+            commands::ping_provider,
+            commands::set_session_model,
+            commands::encryption_status,
+            commands::unlock_store,
+            commands::enable_encryption,
+            commands::disable_encryption,
+            commands::export_session,
+            commands::export_training_jsonl,
+            commands::backup_store,
+            commands::restore_store,
+            commands::list_backups,
+            commands::restore_backup,
+            commands::get_auto_backup_config,
+            commands::set_auto_backup_config,
+            commands::import_chatgpt_export,
+            commands::set_session_mode,
+            commands::generate_image,
+            commands::regenerate_image,
+            commands::speak_message,
+            commands::get_notification_config,
+            commands::set_notification_config,
+            commands::set_session_muted,
+            commands::get_speech,
+            commands::set_speech,
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
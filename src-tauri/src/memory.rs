@@ -0,0 +1,297 @@
+//! Long-term memory of durable facts about the user.
+//!
+//! With memory turned on, each new exchange is sent to a cheap model, which
+//! picks out facts worth remembering across sessions, such as the user's
+//! name or the languages they prefer. The facts are saved with the store.
+//! New sessions are given the facts most relevant to their first message, or
+//! the newest facts, as part of their system prompt. Memory is off unless
+//! turned on in its own file in the app config directory.
+
+use crate::cancellation::CancellationToken;
+use crate::ids::{MemoryId, SessionId};
+use crate::persistence::JsonConfig;
+use crate::providers::{CompletionRequest, LlmProvider};
+use crate::retry::{self, RetryPolicy};
+use crate::usage;
+use crate::{role_name, ChatError, Message};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Name of the file the memory config is saved to inside the app config
+/// directory
+pub const MEMORY_FILE_NAME: &str = "memory.json";
+
+/// Model facts are picked out with for sessions on the default provider.
+/// Sessions on other providers use their own model.
+pub const MEMORY_MODEL: &str = "gpt-3.5-turbo";
+
+/// How many facts new sessions are given unless configured otherwise
+pub const DEFAULT_MAX_INJECTED: usize = 5;
+
+/// Instructions sent with the exchange to pick facts out of
+const EXTRACTION_PROMPT: &str = "Pick out durable facts about the user from the conversation \
+below that are worth remembering in future conversations, such as their name, their work or \
+their preferences. Leave out anything that only matters to this conversation, and anything \
+already known. Answer with a JSON array of short sentences, or [] if there is nothing new.";
+
+/// Heads the facts given to new sessions
+const MEMORY_HEADING: &str = "What you remember about the user:";
+
+/// Whether facts are remembered, and how many new sessions are given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    pub enabled: bool,
+    pub max_injected: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            enabled: false,
+            max_injected: DEFAULT_MAX_INJECTED,
+        }
+    }
+}
+
+impl JsonConfig for MemoryConfig {}
+
+/// A remembered fact about the user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Memory {
+    pub id: MemoryId,
+    pub text: String,
+
+    /// The unix timestamp when the fact was remembered or last edited
+    pub updated_at: u64,
+
+    /// The session the fact was picked out of, or None if the user added it
+    pub session_id: Option<SessionId>,
+}
+
+/// A request to pick facts out of the latest exchange of a session, prepared
+/// by the store
+#[derive(Debug)]
+pub struct MemoryRequest {
+    pub(crate) session_id: SessionId,
+    pub(crate) provider: Arc<dyn LlmProvider>,
+    pub(crate) request: CompletionRequest,
+    pub(crate) retry_policy: RetryPolicy,
+}
+
+/// Facts waiting to be committed with `Store::commit_memories`
+#[derive(Debug)]
+pub struct ExtractedMemories {
+    pub(crate) session_id: SessionId,
+    pub(crate) facts: Vec<String>,
+    /// Cost in USD of the request, if the model's price is known
+    pub(crate) cost: Option<f64>,
+}
+
+impl MemoryRequest {
+    /// Returns the id of the session facts are picked out of
+    pub fn get_session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    /// Waits for the facts, retrying transient failures as the store's retry
+    /// policy allows. Returns `ChatError::Cancelled` if `cancel` is cancelled
+    /// before they arrive.
+    pub async fn complete(
+        self,
+        cancel: &CancellationToken,
+    ) -> Result<ExtractedMemories, ChatError> {
+        let (completion, _) = retry::retry(&self.retry_policy, cancel, || {
+            self.provider.complete(self.request.clone())
+        })
+        .await?;
+
+        Ok(ExtractedMemories {
+            session_id: self.session_id,
+            facts: parse_facts(completion.message.content.as_deref().unwrap_or_default()),
+            cost: completion
+                .usage
+                .and_then(|usage| usage::cost(&self.request.model, &usage)),
+        })
+    }
+}
+
+/// Builds the request picking facts out of `exchange` with `model`. `known`
+/// are sent along so they are not picked again.
+pub(crate) fn extraction_request(
+    model: &str,
+    exchange: &[&Message],
+    known: &[Memory],
+) -> CompletionRequest {
+    let mut prompt = EXTRACTION_PROMPT.to_string();
+    if !known.is_empty() {
+        prompt.push_str("\n\nAlready known:");
+        for memory in known {
+            prompt.push_str(&format!("\n- {}", memory.text));
+        }
+    }
+
+    let transcript = exchange
+        .iter()
+        .map(|x| format!("{}: {}", role_name(x.role), x.plain_text()))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    CompletionRequest {
+        model: model.to_string(),
+        messages: vec![
+            ChatCompletionRequestMessage {
+                role: Role::System,
+                content: Some(prompt),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionRequestMessage {
+                role: Role::User,
+                content: Some(transcript),
+                name: None,
+                function_call: None,
+            },
+        ],
+        images: vec![],
+        functions: vec![],
+        temperature: None,
+    }
+}
+
+/// Reads the facts out of the chat model's answer, a JSON array of strings
+/// that may be wrapped in other text. Anything else holds no facts.
+fn parse_facts(answer: &str) -> Vec<String> {
+    let (Some(start), Some(end)) = (answer.find('['), answer.rfind(']')) else {
+        return vec![];
+    };
+    if start > end {
+        return vec![];
+    }
+
+    serde_json::from_str::<Vec<String>>(&answer[start..=end])
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Returns the lowercased words of `text` long enough to say something
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| x.chars().count() > 2)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Returns up to `limit` of `memories`, those sharing the most words with
+/// `query` first, then the newest
+pub(crate) fn relevant<'a>(memories: &'a [Memory], query: &str, limit: usize) -> Vec<&'a Memory> {
+    let query = words(query);
+    // Newest first, so facts ranked the same stay that way
+    let mut ranked: Vec<(usize, &Memory)> = memories
+        .iter()
+        .rev()
+        .map(|x| (words(&x.text).intersection(&query).count(), x))
+        .collect();
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.updated_at.cmp(&a.1.updated_at)));
+
+    ranked.into_iter().take(limit).map(|(_, x)| x).collect()
+}
+
+/// Returns the part of a system prompt giving `memories`, or None if there
+/// are none
+pub(crate) fn memory_prompt(memories: &[&Memory]) -> Option<String> {
+    if memories.is_empty() {
+        return None;
+    }
+
+    let mut prompt = MEMORY_HEADING.to_string();
+    for memory in memories {
+        prompt.push_str(&format!("\n- {}", memory.text));
+    }
+
+    Some(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::{ChatRole, Store};
+
+    /// Remembers the user's name from every exchange
+    fn rememberer() -> MockProvider {
+        MockProvider::canned(
+            "Sure:\n```json\n[\"The user's name is Ada\", \"The user prefers Rust\"]\n```",
+        )
+    }
+
+    #[test]
+    fn test_parse_facts() {
+        assert_eq!(
+            vec!["Likes tea"],
+            parse_facts("Here you go: [\"Likes tea\", \" \"]")
+        );
+        assert!(parse_facts("[]").is_empty());
+        assert!(parse_facts("Nothing new").is_empty());
+        assert!(parse_facts("] oops [").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_remember_and_inject() {
+        let mut store = Store::new(rememberer());
+        let id = store.add_empty_session(String::from("Intro"), MEMORY_MODEL);
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("Hi, I'm Ada and I write Rust")),
+                (ChatRole::Assistant, String::from("Nice to meet you, Ada")),
+            ]);
+
+        // Nothing is remembered while memory is off
+        assert!(store.prepare_memory_extraction(id).unwrap().is_none());
+
+        store.set_memory_config(MemoryConfig {
+            enabled: true,
+            max_injected: 1,
+        });
+        let request = store.prepare_memory_extraction(id).unwrap().unwrap();
+        let extracted = request.complete(&CancellationToken::new()).await.unwrap();
+        assert_eq!(2, store.commit_memories(extracted).len());
+
+        // Facts already remembered are not added again
+        let request = store.prepare_memory_extraction(id).unwrap().unwrap();
+        let extracted = request.complete(&CancellationToken::new()).await.unwrap();
+        assert!(store.commit_memories(extracted).is_empty());
+        assert_eq!(2, store.get_memories().len());
+
+        let rust = store.get_memories()[1].id;
+        store
+            .update_memory(rust, String::from("The user prefers Rust and Go"))
+            .unwrap();
+        let fresh = store.add_empty_session(String::from("Fresh"), MEMORY_MODEL);
+        let prompt = store
+            .get_session(fresh)
+            .unwrap()
+            .system_prompt_message()
+            .unwrap()
+            .content
+            .unwrap();
+        assert_eq!(
+            format!("{}\n- The user prefers Rust and Go", MEMORY_HEADING),
+            prompt
+        );
+
+        assert!(store.delete_memory(rust).is_some());
+        assert_eq!(
+            Some(ChatError::MemoryNotFound(rust)),
+            store.update_memory(rust, String::from("Gone")).err()
+        );
+        assert_eq!(1, store.get_memories().len());
+    }
+}
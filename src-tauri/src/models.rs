@@ -0,0 +1,140 @@
+//! The chat models each provider offers, and what they can do.
+//!
+//! Listing a provider's models takes a request, so the list is cached for a
+//! while per provider. Each model is described with what is known about it
+//! locally, such as its context window, whether it can see images, and its
+//! price, so the frontend can offer a model picker.
+
+use crate::tokens::context_window;
+use crate::usage::{model_price, ModelPrice};
+use crate::vision::is_vision_model;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a provider's list of models is reused before it is fetched again
+pub const MODEL_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A chat model and what it can do
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelInfo {
+    pub id: String,
+
+    /// Size of the model's context window in tokens
+    pub context_window: usize,
+
+    /// Whether images can be attached to messages sent to the model
+    pub supports_vision: bool,
+
+    /// The model's price, if known
+    pub price: Option<ModelPrice>,
+}
+
+impl ModelInfo {
+    /// Describes the model with id `id` from what is known about it
+    pub fn new(id: impl Into<String>) -> ModelInfo {
+        let id = id.into();
+
+        ModelInfo {
+            context_window: context_window(&id),
+            supports_vision: is_vision_model(&id),
+            price: model_price(&id),
+            id,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedModels {
+    models: Vec<ModelInfo>,
+    fetched_at: Instant,
+}
+
+/// The lists of models fetched from each provider, keyed by provider name.
+/// The default provider's list is kept under the empty name. Clones share
+/// the same cache.
+#[derive(Debug, Clone, Default)]
+pub struct ModelCatalog {
+    cache: Arc<Mutex<HashMap<String, CachedModels>>>,
+}
+
+impl ModelCatalog {
+    /// Returns the cached models of the provider registered under
+    /// `provider`, unless they are older than `MODEL_CACHE_TTL`
+    pub fn get(&self, provider: Option<&str>) -> Option<Vec<ModelInfo>> {
+        let cache = self.lock();
+        let cached = cache.get(provider.unwrap_or_default())?;
+
+        (cached.fetched_at.elapsed() < MODEL_CACHE_TTL).then(|| cached.models.clone())
+    }
+
+    /// Caches `ids` as the models of the provider registered under
+    /// `provider`, returning them described
+    pub fn insert(&self, provider: Option<&str>, ids: Vec<String>) -> Vec<ModelInfo> {
+        let mut models: Vec<ModelInfo> = ids.into_iter().map(ModelInfo::new).collect();
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+
+        self.lock().insert(
+            provider.unwrap_or_default().to_string(),
+            CachedModels {
+                models: models.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+
+        models
+    }
+
+    /// Forgets the models of the provider registered under `provider`, so
+    /// they are fetched again
+    pub fn invalidate(&self, provider: Option<&str>) {
+        self.lock().remove(provider.unwrap_or_default());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, CachedModels>> {
+        // Entries are only ever replaced whole, so a poisoned lock is still usable
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+
+    /// Offers two models
+    fn listing() -> MockProvider {
+        let provider = MockProvider::default();
+        provider.set_models(&["gpt-4o", "gpt-3.5-turbo"]);
+        provider
+    }
+
+    #[tokio::test]
+    async fn test_store_model_catalog_is_cached() {
+        let provider = listing();
+        let mut store = Store::new(provider.clone());
+
+        let models = store.model_catalog(None, false).await.unwrap();
+        assert_eq!(2, models.len());
+        assert_eq!("gpt-3.5-turbo", models[0].id);
+        assert!(!models[0].supports_vision);
+        assert!(models[0].price.is_some());
+        assert!(models[1].supports_vision);
+        assert!(models[1].context_window > models[0].context_window);
+
+        store.model_catalog(None, false).await.unwrap();
+        assert_eq!(1, provider.models_listed());
+
+        store.model_catalog(None, true).await.unwrap();
+        assert_eq!(2, provider.models_listed());
+
+        // Replacing the provider forgets its models
+        let other = listing();
+        store.register_provider("other", other.clone());
+        store.model_catalog(Some("other"), false).await.unwrap();
+        store.register_provider("other", listing());
+        store.model_catalog(Some("other"), false).await.unwrap();
+        assert_eq!(1, other.models_listed());
+    }
+}
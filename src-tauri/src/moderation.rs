@@ -0,0 +1,153 @@
+//! Checking the user's messages with a moderation endpoint before they are
+//! sent.
+//!
+//! With moderation turned on, each new or edited User message is first sent
+//! to the default provider's moderation endpoint. The scores it gives each
+//! category are kept on the message. Flagged messages are either sent anyway,
+//! leaving the frontend to warn about them, or blocked before they reach the
+//! chat model. Moderation is off unless turned on in its own file in the app
+//! config directory.
+
+use crate::cancellation::CancellationToken;
+use crate::persistence::JsonConfig;
+use crate::providers::LlmProvider;
+use crate::retry::{self, RetryPolicy};
+use crate::ChatError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Name of the file the moderation config is saved to inside the app config
+/// directory
+pub const MODERATION_FILE_NAME: &str = "moderation.json";
+
+/// What happens to a message the moderation endpoint flags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlaggedAction {
+    /// The message is sent, with its scores kept for the frontend to warn
+    /// about
+    #[default]
+    Warn,
+    /// The message is not sent, failing with `ChatError::Flagged`
+    Block,
+}
+
+/// Whether messages are moderated, and what happens to flagged ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    pub enabled: bool,
+    pub on_flagged: FlaggedAction,
+}
+
+impl JsonConfig for ModerationConfig {}
+
+/// The moderation endpoint's verdict on a message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModerationResult {
+    pub flagged: bool,
+
+    /// The categories the message was flagged for, in alphabetical order
+    pub categories: Vec<String>,
+
+    /// How strongly the message belongs to each category, from 0 to 1
+    pub scores: BTreeMap<String, f32>,
+}
+
+/// A check of a User message, prepared by the store and run before the
+/// message is sent
+#[derive(Debug)]
+pub(crate) struct ModerationCheck {
+    pub(crate) provider: Arc<dyn LlmProvider>,
+    pub(crate) text: String,
+    pub(crate) on_flagged: FlaggedAction,
+}
+
+impl ModerationCheck {
+    /// Runs the check, retrying transient failures as `policy` allows.
+    ///
+    /// Returns `ChatError::Flagged` if the message is flagged and flagged
+    /// messages are blocked. Blocked messages are also not sent if the check
+    /// fails, while warned ones are sent without a verdict. Providers without
+    /// a moderation endpoint let every message through.
+    pub async fn run(
+        &self,
+        policy: &RetryPolicy,
+        cancel: &CancellationToken,
+    ) -> Result<Option<ModerationResult>, ChatError> {
+        let result = retry::retry(policy, cancel, || self.provider.moderate(&self.text)).await;
+
+        match (result, self.on_flagged) {
+            (Ok((result, _)), FlaggedAction::Block) if result.flagged => {
+                Err(ChatError::Flagged(result.categories))
+            }
+            (Ok((result, _)), _) => Ok(Some(result)),
+            (Err(ChatError::Unsupported(_)), _) => Ok(None),
+            (Err(ChatError::Cancelled), _) => Err(ChatError::Cancelled),
+            (Err(e), FlaggedAction::Block) => Err(e),
+            (Err(_), FlaggedAction::Warn) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::{MockProvider, MOCK_FLAGGED_CATEGORY};
+    use crate::Store;
+
+    #[tokio::test]
+    async fn test_store_moderates_user_messages() {
+        let provider = MockProvider::canned("Hi");
+        provider.flag("spam");
+        let mut store = Store::new(provider);
+        let id = store.add_empty_session(String::from("Moderated"), "moderator");
+        let cancel = CancellationToken::new();
+
+        // Nothing is checked while moderation is off
+        let request = store.prepare_message(id, String::from("Hello")).unwrap();
+        let (_, reply) = store
+            .commit(request.complete(&cancel).await.unwrap())
+            .unwrap();
+        assert_eq!("Hi", reply.get_content());
+        let messages = store.get_session(id).unwrap().get_messages();
+        assert_eq!(None, messages[0].get_moderation());
+
+        store.set_moderation_config(ModerationConfig {
+            enabled: true,
+            on_flagged: FlaggedAction::Warn,
+        });
+        let request = store.prepare_message(id, String::from("Buy spam")).unwrap();
+        store
+            .commit(request.complete(&cancel).await.unwrap())
+            .unwrap();
+        let messages = store.get_session(id).unwrap().get_messages();
+        let moderation = messages[2].get_moderation().unwrap();
+        assert!(moderation.flagged);
+        assert_eq!(vec![MOCK_FLAGGED_CATEGORY], moderation.categories);
+        assert_eq!(Some(&1.0), moderation.scores.get(MOCK_FLAGGED_CATEGORY));
+        // Responses are not checked
+        assert_eq!(None, messages[3].get_moderation());
+
+        store.set_moderation_config(ModerationConfig {
+            enabled: true,
+            on_flagged: FlaggedAction::Block,
+        });
+        let request = store
+            .prepare_message(id, String::from("More spam"))
+            .unwrap();
+        assert_eq!(
+            Some(ChatError::Flagged(vec![MOCK_FLAGGED_CATEGORY.to_string()])),
+            request.complete(&cancel).await.err()
+        );
+        assert_eq!(4, store.get_session(id).unwrap().message_count());
+
+        let request = store.prepare_message(id, String::from("Sorry")).unwrap();
+        store
+            .commit(request.complete(&cancel).await.unwrap())
+            .unwrap();
+        let messages = store.get_session(id).unwrap().get_messages();
+        assert!(!messages[4].get_moderation().unwrap().flagged);
+    }
+}
@@ -0,0 +1,94 @@
+//! Finding the monitor the user is working on.
+//!
+//! Tauri can not tell where the cursor is, so the monitor the user is working
+//! on is taken to be the one under the middle of the frontmost window of
+//! another app. Windows and macOS list windows front to back. Other platforms
+//! list them in the order the window manager keeps, which may not be, so there
+//! the first window listed is used. When no window can be found the overlay is
+//! left where it is.
+
+use crate::hotkey::OVERLAY_WINDOW;
+use crate::window::WindowState;
+use tauri::{AppHandle, Manager, Monitor};
+
+/// The top left corner and size of an area of the screen, in physical pixels
+pub type Bounds = ((i32, i32), (u32, u32));
+
+/// Returns the index of the area of `monitors` holding `point`
+pub fn monitor_at(point: (i32, i32), monitors: &[Bounds]) -> Option<usize> {
+    monitors.iter().position(|((x, y), (width, height))| {
+        (*x..*x + *width as i32).contains(&point.0) && (*y..*y + *height as i32).contains(&point.1)
+    })
+}
+
+/// Returns the middle of the frontmost window that is not minimized and does
+/// not belong to `own_app`
+fn active_window_center(own_app: &str) -> Option<(i32, i32)> {
+    let windows = match xcap::Window::all() {
+        Ok(windows) => windows,
+        Err(e) => {
+            eprintln!("Could not list the open windows: {}", e);
+            return None;
+        }
+    };
+
+    windows
+        .iter()
+        .find(|x| !x.is_minimized() && x.width() > 0 && x.height() > 0 && x.app_name() != own_app)
+        .map(|x| {
+            (
+                x.x() + (x.width() / 2) as i32,
+                x.y() + (x.height() / 2) as i32,
+            )
+        })
+}
+
+/// Returns the monitor the user is working on, if it can be told
+pub fn active_monitor(app: &AppHandle) -> Option<Monitor> {
+    let window = app.get_window(OVERLAY_WINDOW)?;
+    let center = active_window_center(&app.package_info().name)?;
+    let mut monitors = window.available_monitors().ok()?;
+    let bounds: Vec<Bounds> = monitors
+        .iter()
+        .map(|x| {
+            let (origin, size) = (x.position(), x.size());
+            ((origin.x, origin.y), (size.width, size.height))
+        })
+        .collect();
+
+    monitor_at(center, &bounds).map(|index| monitors.swap_remove(index))
+}
+
+/// Moves the overlay onto the monitor the user is working on, if the user
+/// wants it to follow them there
+pub fn follow_active_monitor(app: &AppHandle) {
+    let Some(state) = app.try_state::<WindowState>() else {
+        return;
+    };
+    if !state.get_settings().follow_active_monitor {
+        return;
+    }
+    let Some(monitor) = active_monitor(app) else {
+        return;
+    };
+
+    if let Err(e) = state.move_to_monitor(app, &monitor) {
+        eprintln!("Could not move the overlay to the active monitor: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monitor_at() {
+        let monitors = [((0, 0), (1920, 1080)), ((-1280, 56), (1280, 1024))];
+
+        assert_eq!(Some(0), monitor_at((960, 540), &monitors));
+        assert_eq!(Some(1), monitor_at((-1, 600), &monitors));
+        assert_eq!(Some(0), monitor_at((0, 0), &monitors));
+        assert_eq!(None, monitor_at((1920, 0), &monitors));
+        assert_eq!(None, monitor_at((-640, 20), &monitors));
+    }
+}
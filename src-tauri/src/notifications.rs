@@ -0,0 +1,193 @@
+//! Telling the user a response arrived while the overlay was hidden.
+//!
+//! When a response is committed while the overlay is hidden, a native
+//! notification shows the title of its session and the start of the
+//! response. Notifications can be muted for every session, or for single
+//! sessions only. The settings are saved to their own file in the app config
+//! directory.
+
+use crate::ids::SessionId;
+use crate::persistence::JsonConfig;
+use crate::{tray, ChatError, ChatRole, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::api::notification::Notification;
+use tauri::{AppHandle, Manager};
+
+/// Name of the file the notification settings are saved to inside the app
+/// config directory
+pub const NOTIFICATIONS_FILE_NAME: &str = "notifications.json";
+
+/// The most characters of a response shown in its notification
+pub const SNIPPET_CHARS: usize = 120;
+
+/// When notifications are shown
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationConfig {
+    /// Whether any notifications are shown
+    pub enabled: bool,
+
+    /// Sessions whose responses are never notified
+    pub muted_sessions: BTreeSet<SessionId>,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            enabled: true,
+            muted_sessions: BTreeSet::new(),
+        }
+    }
+}
+
+impl JsonConfig for NotificationConfig {}
+
+impl NotificationConfig {
+    /// Returns whether responses in the session with matching id are notified
+    pub fn notifies(&self, session_id: SessionId) -> bool {
+        self.enabled && !self.muted_sessions.contains(&session_id)
+    }
+}
+
+/// The Tauri managed state holding the notification settings
+#[derive(Debug)]
+pub struct NotificationState {
+    config: Mutex<NotificationConfig>,
+    path: PathBuf,
+}
+
+impl NotificationState {
+    /// Loads the notification settings saved inside `app_config_dir`
+    pub fn in_app_config_dir(app_config_dir: &Path) -> Result<NotificationState, ChatError> {
+        let path = app_config_dir.join(NOTIFICATIONS_FILE_NAME);
+
+        Ok(NotificationState {
+            config: Mutex::new(NotificationConfig::load(&path)?),
+            path,
+        })
+    }
+
+    /// Returns a copy of the current notification settings
+    pub fn get_config(&self) -> NotificationConfig {
+        self.lock().clone()
+    }
+
+    /// Replaces the notification settings with `config` and saves them
+    pub fn set_config(&self, config: NotificationConfig) -> Result<(), ChatError> {
+        let mut current = self.lock();
+
+        config.save(&self.path)?;
+        *current = config;
+
+        Ok(())
+    }
+
+    /// Mutes the session with matching id, or unmutes it if not `muted`
+    pub fn set_session_muted(&self, session_id: SessionId, muted: bool) -> Result<(), ChatError> {
+        let mut config = self.get_config();
+        match muted {
+            true => config.muted_sessions.insert(session_id),
+            false => config.muted_sessions.remove(&session_id),
+        };
+
+        self.set_config(config)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, NotificationConfig> {
+        // The config is only ever replaced whole, so a poisoned lock is still usable
+        self.config.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returns the start of `text` on a single line, cut at a word after at most
+/// `SNIPPET_CHARS` characters
+pub fn snippet(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= SNIPPET_CHARS {
+        return line;
+    }
+
+    let cut: String = line.chars().take(SNIPPET_CHARS).collect();
+    let cut = match cut.rsplit_once(' ') {
+        Some((words, _)) if !words.is_empty() => words,
+        _ => &cut,
+    };
+
+    format!("{}…", cut.trim_end())
+}
+
+/// Shows a notification of `message`, a response in the session titled
+/// `title` with matching id, if the overlay is hidden and the session is not
+/// muted
+pub(crate) fn notify_response(
+    app: &AppHandle,
+    session_id: SessionId,
+    title: &str,
+    message: &Message,
+) {
+    if message.get_role() != ChatRole::Assistant || tray::status(app).visible {
+        return;
+    }
+    let Some(state) = app.try_state::<NotificationState>() else {
+        return;
+    };
+    if !state.get_config().notifies(session_id) {
+        return;
+    }
+
+    let shown = Notification::new(&app.config().tauri.bundle.identifier)
+        .title(title)
+        .body(snippet(&message.plain_text()))
+        .show();
+    if let Err(e) = shown {
+        eprintln!("Could not show a notification: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!("Hello there", snippet("  Hello\n\nthere "));
+
+        let long = "word ".repeat(40);
+        let snippet = snippet(&long);
+        assert!(snippet.ends_with("word…"));
+        assert!(snippet.chars().count() <= SNIPPET_CHARS + 1);
+    }
+
+    #[test]
+    fn test_notification_state_mutes_sessions() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-notifications-{}", nanos));
+        let state = NotificationState::in_app_config_dir(&dir).unwrap();
+        let (muted, other) = (SessionId::generate(), SessionId::generate());
+        assert!(state.get_config().notifies(muted));
+
+        state.set_session_muted(muted, true).unwrap();
+        let loaded = NotificationState::in_app_config_dir(&dir).unwrap();
+        assert!(!loaded.get_config().notifies(muted));
+        assert!(loaded.get_config().notifies(other));
+
+        loaded.set_session_muted(muted, false).unwrap();
+        loaded
+            .set_config(NotificationConfig {
+                enabled: false,
+                ..loaded.get_config()
+            })
+            .unwrap();
+        assert!(!loaded.get_config().notifies(other));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
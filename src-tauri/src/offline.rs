@@ -0,0 +1,179 @@
+//! Holding on to messages sent while the chat model can not be reached.
+//!
+//! A message whose request fails because the network is down is not lost.
+//! It is added to its session with a Pending status instead, and the queued
+//! messages are sent again, oldest first, every `OUTBOX_INTERVAL` until the
+//! chat model can be reached. Each response is added right after its
+//! message, and every change of status is emitted as
+//! `events::MESSAGE_STATUS_EVENT`. Only one flush of the queue runs at a
+//! time, so a message is never sent twice.
+
+use crate::cancellation::CancellationToken;
+use crate::commands::{self, StoreState};
+use crate::ChatError;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+/// How often the messages queued while offline are sent again
+pub const OUTBOX_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Lets a single flush of the queued messages run at a time, whether started
+/// by the interval or by the user
+#[derive(Debug, Default)]
+pub struct Outbox {
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl Outbox {
+    /// Create an outbox no flush is running in
+    pub fn new() -> Outbox {
+        Outbox::default()
+    }
+}
+
+/// Sends the messages queued while offline every `OUTBOX_INTERVAL`, for as
+/// long as the app runs
+pub fn spawn_outbox(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(OUTBOX_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush(&app).await;
+        }
+    });
+}
+
+/// Sends the messages queued while offline, oldest first, committing their
+/// responses. Stops at the first message that still can not reach the chat
+/// model. Messages failing for another reason are marked as failed, so they
+/// are not sent again. Waits for any flush already running to finish first.
+pub async fn flush(app: &AppHandle) {
+    let outbox = app.state::<Outbox>();
+    let _flushing = outbox.flushing.lock().await;
+    let state = app.state::<StoreState>();
+    let queued = state.read().await.get_queued_messages();
+
+    for (session_id, message_id) in queued {
+        let pending = state.read().await.prepare_queued(session_id, message_id);
+        let completed = match pending {
+            Ok(pending) => pending.complete(&CancellationToken::new()).await,
+            Err(e) => Err(e),
+        };
+
+        match completed {
+            // Queued messages wait for the budget to be overridden
+            Err(e) if e.is_offline() || matches!(e, ChatError::BudgetExceeded(_)) => return,
+            // Sent since the queue was read, such as by editing it
+            Err(ChatError::NotQueued(_)) => {}
+            Err(e) => {
+                eprintln!("Could not send a queued message: {}", e);
+                if let Err(e) = state.write().await.fail_queued(session_id, message_id) {
+                    eprintln!("{}", e);
+                }
+            }
+            Ok(completed) => {
+                if let Err(e) = commands::commit(app, &state, Ok(completed)).await {
+                    eprintln!("Could not send a queued message: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cancellation::CancellationToken;
+    use crate::providers::mock::MockProvider;
+    use crate::retry::RetryPolicy;
+    use crate::{ChatError, MessageStatus, Store};
+
+    #[tokio::test]
+    async fn test_store_queues_messages_while_offline() {
+        // Can not be reached for the first three requests, then echoes
+        let provider = MockProvider::echoing();
+        for _ in 0..3 {
+            provider.push_error(ChatError::Network(String::from("offline")));
+        }
+        let mut store = Store::new(provider.clone());
+        store.set_retry_policy(RetryPolicy::never());
+        let id = store.add_empty_session(String::from("Offline"), "flaky");
+        let cancel = CancellationToken::new();
+
+        for contents in ["First", "Second"] {
+            let pending = store.prepare_message(id, contents.to_string()).unwrap();
+            let queued = pending.to_queued().unwrap();
+            let error = pending.complete(&cancel).await.unwrap_err();
+            assert!(error.is_offline());
+
+            let (session_id, message) = store.queue_offline(queued).unwrap();
+            assert_eq!(id, session_id);
+            assert_eq!(contents, message.get_content());
+            assert_eq!(MessageStatus::Pending, message.get_status());
+        }
+        let queued = store.get_queued_messages();
+        assert_eq!(2, queued.len());
+
+        // Still offline, so nothing changes
+        let (session_id, message_id) = queued[0];
+        let pending = store.prepare_queued(session_id, message_id).unwrap();
+        assert!(pending.complete(&cancel).await.unwrap_err().is_offline());
+
+        assert_eq!(0, provider.remaining());
+        for (session_id, message_id) in queued {
+            let pending = store.prepare_queued(session_id, message_id).unwrap();
+            store
+                .commit(pending.complete(&cancel).await.unwrap())
+                .unwrap();
+        }
+        assert!(store.get_queued_messages().is_empty());
+
+        // Each response follows its own message
+        let messages = store.get_session(id).unwrap().get_messages();
+        let contents: Vec<String> = messages.iter().map(|x| x.get_content()).collect();
+        assert_eq!(vec!["First", "First", "Second", "Second"], contents);
+        assert!(messages
+            .iter()
+            .all(|x| x.get_status() == MessageStatus::Sent));
+
+        let pending = store.prepare_message(id, String::from("Third")).unwrap();
+        let queued = pending.to_queued().unwrap();
+        let (_, message) = store.queue_offline(queued).unwrap();
+        store.fail_queued(id, message.get_id()).unwrap();
+        assert!(store.get_queued_messages().is_empty());
+        let messages = store.get_session(id).unwrap().get_messages();
+        assert_eq!(MessageStatus::Failed, messages[4].get_status());
+    }
+
+    #[tokio::test]
+    async fn test_store_sends_queued_message_once() {
+        let provider = MockProvider::echoing();
+        provider.push_error(ChatError::Network(String::from("offline")));
+        let mut store = Store::new(provider);
+        store.set_retry_policy(RetryPolicy::never());
+        let id = store.add_empty_session(String::from("Offline"), "flaky");
+        let cancel = CancellationToken::new();
+
+        let pending = store.prepare_message(id, String::from("Once")).unwrap();
+        let queued = pending.to_queued().unwrap();
+        assert!(pending.complete(&cancel).await.unwrap_err().is_offline());
+        let (_, message) = store.queue_offline(queued).unwrap();
+        let message_id = message.get_id();
+
+        // Two flushes read the queue before either commits
+        let first = store.prepare_queued(id, message_id).unwrap();
+        let second = store.prepare_queued(id, message_id).unwrap();
+        let first = first.complete(&cancel).await.unwrap();
+        let second = second.complete(&cancel).await.unwrap();
+
+        store.commit(first).unwrap();
+        assert_eq!(
+            Some(ChatError::NotQueued(message_id)),
+            store.commit(second).err()
+        );
+        assert_eq!(
+            Some(ChatError::NotQueued(message_id)),
+            store.prepare_queued(id, message_id).err()
+        );
+        assert_eq!(2, store.get_session(id).unwrap().message_count());
+    }
+}
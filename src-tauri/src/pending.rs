@@ -0,0 +1,254 @@
+//! Chat requests that run without holding on to the store.
+//!
+//! A request is prepared from a shared borrow of the store, awaited with no
+//! borrow at all, then committed back with a short mutable borrow. This lets
+//! the store sit behind a read-write lock where other sessions can still be
+//! read, or changed, while a response is on its way.
+
+use crate::cancellation::CancellationToken;
+use crate::ids::SessionId;
+use crate::moderation::{ModerationCheck, ModerationResult};
+use crate::providers::{Completion, CompletionRequest, LlmProvider};
+use crate::retry::{self, RetryPolicy};
+use crate::tools::{ToolLoop, ToolOutcome, ToolRegistry};
+use crate::{ChatError, ChatSession, ResponseAction};
+use async_openai::types::{ChatCompletionResponseMessage, Role};
+use std::sync::Arc;
+
+/// Finish reason of responses cut short by cancelling them mid-stream
+pub const CANCELLED_FINISH_REASON: &str = "cancelled";
+
+/// The session a request's response is committed to
+#[derive(Debug, Clone)]
+pub(crate) enum RequestTarget {
+    /// An existing session with matching id
+    Existing(SessionId),
+
+    /// A new session, added to the store once its first response arrives
+    New(Box<ChatSession>),
+}
+
+/// A request to the chat model prepared by the store. Await its response with
+/// `complete` or `stream`, then hand the result to `Store::commit`.
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub(crate) target: RequestTarget,
+    pub(crate) provider: Arc<dyn LlmProvider>,
+    pub(crate) request: CompletionRequest,
+    pub(crate) action: ResponseAction,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) tools: ToolRegistry,
+    /// Checks the User message before it is sent, if moderation is on
+    pub(crate) moderation: Option<ModerationCheck>,
+}
+
+/// The User message of a request, kept to be queued with
+/// `Store::queue_offline` if the chat model can not be reached
+#[derive(Debug)]
+pub struct QueuedMessage {
+    pub(crate) target: RequestTarget,
+    pub(crate) contents: String,
+}
+
+/// A response from the chat model waiting to be committed to the store
+#[derive(Debug)]
+pub struct CompletedRequest {
+    pub(crate) target: RequestTarget,
+    pub(crate) action: ResponseAction,
+    pub(crate) outcome: ToolOutcome,
+}
+
+impl PendingRequest {
+    /// Returns the id of the session this request was prepared for, or None
+    /// if it creates a new session.
+    pub fn get_session_id(&self) -> Option<SessionId> {
+        match self.target {
+            RequestTarget::Existing(id) => Some(id),
+            RequestTarget::New(_) => None,
+        }
+    }
+
+    /// Returns the User message this request sends, to be queued if it fails
+    /// while offline. Returns None if it sends no new text message.
+    pub fn to_queued(&self) -> Option<QueuedMessage> {
+        match &self.action {
+            ResponseAction::Append { contents } => Some(QueuedMessage {
+                target: self.target.clone(),
+                contents: contents.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Waits for the chat model's response, retrying transient failures as
+    /// the store's retry policy allows and running any tools it calls.
+    /// Returns `ChatError::Cancelled` if `cancel` is cancelled before it
+    /// arrives.
+    ///
+    /// With moderation on, the User message is checked first. Returns
+    /// `ChatError::Flagged` without sending it if it is blocked.
+    pub async fn complete(self, cancel: &CancellationToken) -> Result<CompletedRequest, ChatError> {
+        let moderation = self.moderate(cancel).await?;
+        let mut outcome = complete_request(
+            self.provider.as_ref(),
+            self.request,
+            &self.retry_policy,
+            &self.tools,
+            cancel,
+        )
+        .await?;
+        outcome.moderation = moderation;
+
+        Ok(CompletedRequest {
+            target: self.target,
+            action: self.action,
+            outcome,
+        })
+    }
+
+    /// Like `complete`, but streams the response, calling `on_token` with each
+    /// piece of content as it arrives. If `cancel` is cancelled mid-stream, the
+    /// content received so far is kept as the response.
+    pub async fn stream<F: FnMut(&str) + Send>(
+        self,
+        on_token: F,
+        cancel: &CancellationToken,
+    ) -> Result<CompletedRequest, ChatError> {
+        let moderation = self.moderate(cancel).await?;
+        let mut outcome = stream_request(
+            self.provider.as_ref(),
+            self.request,
+            &self.retry_policy,
+            &self.tools,
+            on_token,
+            cancel,
+        )
+        .await?;
+        outcome.moderation = moderation;
+
+        Ok(CompletedRequest {
+            target: self.target,
+            action: self.action,
+            outcome,
+        })
+    }
+
+    /// Runs the moderation check of the User message, if there is one
+    async fn moderate(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Result<Option<ModerationResult>, ChatError> {
+        match &self.moderation {
+            Some(check) => check.run(&self.retry_policy, cancel).await,
+            None => Ok(None),
+        }
+    }
+}
+
+/// Sends `request` to `provider`, retrying transient failures as `policy`
+/// allows. Tool calls in the response are answered with `tools` and the
+/// request sent again, until the chat model answers without calling a tool.
+/// The request is abandoned if `cancel` is cancelled before the answer
+/// arrives.
+pub(crate) async fn complete_request(
+    provider: &dyn LlmProvider,
+    mut request: CompletionRequest,
+    policy: &RetryPolicy,
+    tools: &ToolRegistry,
+    cancel: &CancellationToken,
+) -> Result<ToolOutcome, ChatError> {
+    let mut tool_loop = ToolLoop::new(tools);
+
+    loop {
+        let (completion, attempts) =
+            retry::retry(policy, cancel, || provider.complete(request.clone())).await?;
+
+        if let Some(outcome) = tool_loop
+            .step(&mut request, completion, attempts, cancel)
+            .await?
+        {
+            return Ok(outcome);
+        }
+    }
+}
+
+/// Like `complete_request`, but streams each response from `provider`,
+/// calling `on_token` with each piece of content.
+pub(crate) async fn stream_request<F: FnMut(&str) + Send>(
+    provider: &dyn LlmProvider,
+    mut request: CompletionRequest,
+    policy: &RetryPolicy,
+    tools: &ToolRegistry,
+    mut on_token: F,
+    cancel: &CancellationToken,
+) -> Result<ToolOutcome, ChatError> {
+    let mut tool_loop = ToolLoop::new(tools);
+
+    loop {
+        let (completion, attempts) =
+            stream_once(provider, &request, policy, &mut on_token, cancel).await?;
+
+        if let Some(outcome) = tool_loop
+            .step(&mut request, completion, attempts, cancel)
+            .await?
+        {
+            return Ok(outcome);
+        }
+    }
+}
+
+/// Streams the response to `request` from `provider`, calling `on_token` with
+/// each piece of content. Transient failures are retried as `policy` allows,
+/// as long as no content has been received yet. Returns the response along
+/// with the number of attempts made.
+///
+/// If `cancel` is cancelled mid-stream, the content received so far is
+/// returned as the response, finishing for `CANCELLED_FINISH_REASON`, or
+/// `ChatError::Cancelled` if nothing was received yet.
+async fn stream_once<F: FnMut(&str) + Send>(
+    provider: &dyn LlmProvider,
+    request: &CompletionRequest,
+    policy: &RetryPolicy,
+    on_token: &mut F,
+    cancel: &CancellationToken,
+) -> Result<(Completion, u32), ChatError> {
+    let mut partial = String::new();
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+
+        let mut record_token = |token: &str| {
+            partial.push_str(token);
+            on_token(token);
+        };
+        let streamed = tokio::select! {
+            response = provider.stream(request.clone(), &mut record_token) => Some(response),
+            _ = cancel.cancelled() => None,
+        };
+
+        let completion = match streamed {
+            Some(Err(e))
+                if e.is_transient() && partial.is_empty() && attempts < policy.max_attempts =>
+            {
+                tokio::select! {
+                    _ = tokio::time::sleep(policy.delay(attempts, e.retry_after())) => continue,
+                    _ = cancel.cancelled() => return Err(ChatError::Cancelled),
+                }
+            }
+            Some(response) => response?,
+            None if partial.is_empty() => return Err(ChatError::Cancelled),
+            None => Completion {
+                message: ChatCompletionResponseMessage {
+                    role: Role::Assistant,
+                    content: Some(partial),
+                    function_call: None,
+                },
+                usage: None,
+                finish_reason: Some(String::from(CANCELLED_FINISH_REASON)),
+            },
+        };
+
+        return Ok((completion, attempts));
+    }
+}
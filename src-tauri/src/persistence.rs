@@ -0,0 +1,664 @@
+//! Saving and loading the sessions of a `Store` to disk.
+//!
+//! Stores are written as snapshots tagged with their schema version, so files
+//! written by older versions of the app can be migrated when they are loaded.
+//! Each schema change adds a migration to `MIGRATIONS`, upgrading the data by
+//! one version, and bumps `SCHEMA_VERSION`. Snapshots can be
+//! encrypted with a passphrase, see `encryption`. Between full saves, changes
+//! are appended to a journal next to the store file, see `journal`.
+
+use crate::encryption::{self, EncryptionKey};
+use crate::ids::{MessageId, SessionId};
+use crate::journal::{self, Entry, JournalState, COMPACT_AFTER_ENTRIES, JOURNAL_EXTENSION};
+use crate::memory::Memory;
+use crate::prompts::PromptTemplate;
+use crate::trash::Trash;
+use crate::{ChatError, ChatSession};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Version of the persisted store format written by this build
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Upgrades the data of a snapshot by one schema version
+pub struct Migration {
+    /// The version the data is upgraded to
+    pub to: u32,
+    /// What changed in the version, for errors
+    pub description: &'static str,
+    pub migrate: fn(&mut Value) -> Result<(), ChatError>,
+}
+
+/// The migrations of every schema version after the first, in order. The
+/// last upgrades the data to `SCHEMA_VERSION`.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    to: 2,
+    description: "ids are UUIDs",
+    migrate: migrate_to_uuids,
+}];
+
+/// Name of the file the store is saved to inside the app data directory
+pub const STORE_FILE_NAME: &str = "store.json";
+
+/// The persisted state of a `Store`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreSnapshot {
+    pub sessions: Vec<ChatSession>,
+    #[serde(default)]
+    pub prompts: Vec<PromptTemplate>,
+    /// Deleted sessions and messages that can still be restored
+    #[serde(default)]
+    pub trash: Trash,
+    /// Facts remembered about the user
+    #[serde(default)]
+    pub memories: Vec<Memory>,
+}
+
+/// The kinds of `StorageBackend` the store can be saved with, picked in
+/// `config.toml`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    /// `JsonFileBackend`
+    #[default]
+    Json,
+    /// `SqliteBackend`
+    Sqlite,
+}
+
+/// The session and message ids of a match found by a backend's full-text
+/// index. Title matches have no message id.
+pub type SearchHit = (SessionId, Option<MessageId>);
+
+/// A place a `StoreSnapshot` can be saved to and loaded from
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Persists `snapshot`, replacing any previously saved snapshot
+    fn save(&self, snapshot: &StoreSnapshot) -> Result<(), ChatError>;
+
+    /// Loads the saved snapshot, or None if nothing has been saved yet
+    fn load(&self) -> Result<Option<StoreSnapshot>, ChatError>;
+
+    /// Returns the kind of this backend
+    fn kind(&self) -> StorageKind;
+
+    /// Returns the key snapshots are encrypted with, or None if they are
+    /// saved unencrypted
+    fn encryption_key(&self) -> Option<&EncryptionKey> {
+        None
+    }
+
+    /// Searches the saved titles and messages for `query` with a full-text
+    /// index, returning at most `limit` matches after skipping `offset`, best
+    /// first. Returns None if this backend has no index.
+    fn search(
+        &self,
+        _query: &str,
+        _offset: usize,
+        _limit: usize,
+    ) -> Option<Result<Vec<SearchHit>, ChatError>> {
+        None
+    }
+}
+
+/// Stores snapshots as a single JSON file, encrypted if the backend has a
+/// key, journaling the changes between full saves
+#[derive(Debug, Clone)]
+pub struct JsonFileBackend {
+    path: PathBuf,
+    key: Option<EncryptionKey>,
+    /// What the file and its journal hold, or None if unknown, in which case
+    /// the next save rewrites the file whole
+    journal: Arc<Mutex<Option<JournalState>>>,
+}
+
+/// The on-disk layout of a snapshot, tagged with the schema version it was
+/// written with.
+#[derive(Serialize)]
+pub(crate) struct VersionedSnapshot<'a> {
+    schema_version: u32,
+    data: &'a StoreSnapshot,
+}
+
+impl<'a> VersionedSnapshot<'a> {
+    /// Tags `data` with the schema version of this build
+    pub(crate) fn new(data: &'a StoreSnapshot) -> VersionedSnapshot<'a> {
+        VersionedSnapshot {
+            schema_version: SCHEMA_VERSION,
+            data,
+        }
+    }
+}
+
+impl JsonFileBackend {
+    /// Create a backend saving to the file at `path`
+    pub fn new(path: impl Into<PathBuf>) -> JsonFileBackend {
+        JsonFileBackend {
+            path: path.into(),
+            key: None,
+            journal: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Encrypts snapshots with `key` when saving them, or saves them as plain
+    /// JSON if None
+    pub fn with_key(mut self, key: Option<EncryptionKey>) -> JsonFileBackend {
+        self.key = key;
+        // The journal was written with the old key, so the next save
+        // rewrites the file whole
+        self.journal = Arc::new(Mutex::new(None));
+        self
+    }
+
+    /// Derives the key the saved snapshot was encrypted with from
+    /// `passphrase`, so it can be loaded. Returns
+    /// `ChatError::WrongPassphrase` if the passphrase is wrong.
+    pub fn unlock(self, passphrase: &str) -> Result<JsonFileBackend, ChatError> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) if encryption::is_encrypted(&contents) => contents,
+            Ok(_) => {
+                return Err(ChatError::Encryption(String::from(
+                    "The store is not encrypted",
+                )))
+            }
+            Err(e) => return Err(ChatError::Persistence(e.to_string())),
+        };
+
+        let key = EncryptionKey::unlock(passphrase, &contents)?;
+
+        Ok(self.with_key(Some(key)))
+    }
+
+    /// Returns whether the saved snapshot is encrypted. False if nothing has
+    /// been saved yet.
+    pub fn is_encrypted(&self) -> Result<bool, ChatError> {
+        match fs::read(&self.path) {
+            Ok(contents) => Ok(encryption::is_encrypted(&contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(ChatError::Persistence(e.to_string())),
+        }
+    }
+
+    /// Create a backend saving to `STORE_FILE_NAME` inside `app_data_dir`
+    pub fn in_app_data_dir(app_data_dir: &Path) -> JsonFileBackend {
+        JsonFileBackend::new(app_data_dir.join(STORE_FILE_NAME))
+    }
+
+    /// Returns the path of the file snapshots are saved to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of the journal of changes made since the file was
+    /// last saved whole
+    pub fn journal_path(&self) -> PathBuf {
+        self.path.with_extension(JOURNAL_EXTENSION)
+    }
+
+    /// Deletes the journal without replaying it, such as when the file is
+    /// replaced by a backup the changes were not made to
+    pub fn discard_journal(&self) -> Result<(), ChatError> {
+        let mut state = self.lock_journal();
+        *state = None;
+
+        journal::remove(&self.journal_path())
+    }
+
+    /// Saves `snapshot` to the file whole and empties the journal
+    fn compact(&self, snapshot: &StoreSnapshot) -> Result<(), ChatError> {
+        let contents = serde_json::to_vec_pretty(&VersionedSnapshot::new(snapshot))
+            .map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        match &self.key {
+            Some(key) => write_atomically(&self.path, &key.encrypt(&contents)?)?,
+            None => write_atomically(&self.path, &contents)?,
+        }
+
+        journal::remove(&self.journal_path())
+    }
+
+    /// Reads the snapshot saved to the file, without its journal
+    fn load_file(&self) -> Result<Option<StoreSnapshot>, ChatError> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ChatError::Persistence(e.to_string())),
+        };
+        let contents = match &self.key {
+            _ if !encryption::is_encrypted(&contents) => contents,
+            Some(key) => key.decrypt(&contents)?,
+            None => return Err(ChatError::StoreLocked),
+        };
+
+        let value: Value =
+            serde_json::from_slice(&contents).map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+        migrate(value).map(Some)
+    }
+
+    fn lock_journal(&self) -> std::sync::MutexGuard<'_, Option<JournalState>> {
+        // A poisoned state is discarded, so the next save rewrites the file whole
+        self.journal.lock().unwrap_or_else(|e| {
+            let mut state = e.into_inner();
+            *state = None;
+            state
+        })
+    }
+}
+
+impl StorageBackend for JsonFileBackend {
+    fn save(&self, snapshot: &StoreSnapshot) -> Result<(), ChatError> {
+        let mut state = self.lock_journal();
+
+        match state.as_mut() {
+            Some(journaled) if journaled.entries < COMPACT_AFTER_ENTRIES => {
+                let records = journaled.changes(snapshot);
+                if records.is_empty() {
+                    return Ok(());
+                }
+
+                let appended =
+                    journal::append(&self.journal_path(), &Entry { records }, self.key.as_ref());
+                match appended {
+                    Ok(()) => journaled.entries += 1,
+                    Err(_) => *state = None,
+                }
+                appended
+            }
+            _ => {
+                *state = None;
+                self.compact(snapshot)?;
+                *state = Some(JournalState::of(snapshot));
+
+                Ok(())
+            }
+        }
+    }
+
+    fn load(&self) -> Result<Option<StoreSnapshot>, ChatError> {
+        let mut state = self.lock_journal();
+        *state = None;
+
+        let snapshot = self.load_file()?;
+        let entries = journal::read(&self.journal_path(), self.key.as_ref())?;
+        if entries.is_empty() {
+            *state = snapshot.as_ref().map(JournalState::of);
+            return Ok(snapshot);
+        }
+
+        // The file is stale, so the changes made since are replayed on top
+        // and saved whole
+        let mut snapshot = snapshot.unwrap_or_default();
+        for entry in entries {
+            entry.apply(&mut snapshot);
+        }
+        match self.compact(&snapshot) {
+            Ok(()) => *state = Some(JournalState::of(&snapshot)),
+            Err(e) => eprintln!("Could not compact the store journal: {}", e),
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    fn kind(&self) -> StorageKind {
+        StorageKind::Json
+    }
+
+    fn encryption_key(&self) -> Option<&EncryptionKey> {
+        self.key.as_ref()
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path`, and syncs it to disk,
+/// before renaming it over `path`, so a crash mid-write can not leave a half
+/// written file.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), ChatError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| ChatError::Persistence(e.to_string()))?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+
+    File::create(&tmp_path)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .map_err(|e| ChatError::Persistence(e.to_string()))?;
+    fs::rename(&tmp_path, path).map_err(|e| ChatError::Persistence(e.to_string()))
+}
+
+/// Reads the JSON saved at `path`, or returns the default value if nothing
+/// has been saved there yet
+pub fn load_json_or_default<T: DeserializeOwned + Default>(path: &Path) -> Result<T, ChatError> {
+    match fs::read(path) {
+        Ok(contents) => {
+            serde_json::from_slice(&contents).map_err(|e| ChatError::Persistence(e.to_string()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(ChatError::Persistence(e.to_string())),
+    }
+}
+
+/// Saves `value` to `path` as pretty printed JSON, replacing any file there
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), ChatError> {
+    let contents =
+        serde_json::to_vec_pretty(value).map_err(|e| ChatError::Persistence(e.to_string()))?;
+
+    write_atomically(path, &contents)
+}
+
+/// Settings saved to a JSON file of their own, which are the defaults until
+/// they are first saved
+pub trait JsonConfig: Serialize + DeserializeOwned + Default {
+    /// Loads the settings saved at `path`, or the defaults if nothing has been
+    /// saved there yet
+    fn load(path: &Path) -> Result<Self, ChatError> {
+        load_json_or_default(path)
+    }
+
+    /// Saves these settings to `path`, replacing any saved there
+    fn save(&self, path: &Path) -> Result<(), ChatError> {
+        save_json(path, self)
+    }
+}
+
+/// Upgrades a versioned snapshot to `SCHEMA_VERSION` through `MIGRATIONS`,
+/// then parses it. Snapshots written before the schema version was called
+/// `schema_version` have it as `version`.
+pub(crate) fn migrate(mut value: Value) -> Result<StoreSnapshot, ChatError> {
+    let version = value
+        .get("schema_version")
+        .or_else(|| value.get("version"))
+        .and_then(Value::as_u64)
+        .ok_or_else(|| ChatError::Persistence("Store file has no schema version".to_string()))?
+        as u32;
+
+    if version > SCHEMA_VERSION {
+        return Err(ChatError::Persistence(format!(
+            "Store file has schema version {}, but only versions up to {} are supported",
+            version, SCHEMA_VERSION
+        )));
+    }
+
+    let mut data = value
+        .get_mut("data")
+        .map(Value::take)
+        .ok_or_else(|| ChatError::Persistence("Store file has no data".to_string()))?;
+
+    for migration in MIGRATIONS.iter().filter(|x| x.to > version) {
+        (migration.migrate)(&mut data).map_err(|e| {
+            ChatError::Persistence(format!(
+                "Could not migrate the store file to schema version {}, where {}: {}",
+                migration.to, migration.description, e
+            ))
+        })?;
+    }
+
+    serde_json::from_value(data).map_err(|e| ChatError::Persistence(e.to_string()))
+}
+
+/// Version 2 replaced the numeric ids of sessions and messages, given out by
+/// counters, with UUIDs. Ids are made from the creation time of what they
+/// identify so they keep sorting in the order it was created. The sessions
+/// forks were made from, and the sessions of trashed messages, are looked up
+/// by their old ids.
+fn migrate_to_uuids(data: &mut Value) -> Result<(), ChatError> {
+    let invalid = || ChatError::Persistence("Store file has an invalid id".to_string());
+
+    let Some(data) = data.as_object_mut() else {
+        return Ok(());
+    };
+    data.remove("session_id_counter");
+
+    // The saved sessions, including those in the trash, and the trashed
+    // messages
+    let mut sessions: Vec<&mut Value> = vec![];
+    let mut messages: Vec<&mut Value> = vec![];
+    for (key, value) in data.iter_mut() {
+        match key.as_str() {
+            "sessions" => sessions.extend(value.as_array_mut().into_iter().flatten()),
+            "trash" => {
+                let items = value.get_mut("items").and_then(Value::as_array_mut);
+                for item in items.into_iter().flatten() {
+                    if item.get("session").is_some() {
+                        sessions.extend(item.pointer_mut("/session/session"));
+                    } else {
+                        messages.extend(item.get_mut("message"));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Old ids of sessions, and of messages along with their session's, mapped
+    // to their new ids
+    let mut session_ids: HashMap<u64, SessionId> = HashMap::new();
+    let mut message_ids: HashMap<(u64, u64), MessageId> = HashMap::new();
+    for session in sessions.iter_mut() {
+        let old = session
+            .get("id")
+            .and_then(Value::as_u64)
+            .ok_or_else(invalid)?;
+        let id = SessionId::from_timestamp(created_at(session));
+        session["id"] = serde_json::to_value(id).map_err(|_| invalid())?;
+        session_ids.insert(old, id);
+
+        if let Some(session) = session.as_object_mut() {
+            session.remove("msg_id_counter");
+        }
+        for message in session
+            .get_mut("messages")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten()
+        {
+            let old_message = message
+                .get("id")
+                .and_then(Value::as_u64)
+                .ok_or_else(invalid)?;
+            let id = MessageId::from_timestamp(created_at(message));
+            message["id"] = serde_json::to_value(id).map_err(|_| invalid())?;
+            message_ids.insert((old, old_message), id);
+        }
+    }
+
+    // Ids of sessions deleted for good are replaced with new ones, as nothing
+    // can be looked up by them anymore
+    let session_id = |old: Option<u64>| {
+        old.and_then(|x| session_ids.get(&x).copied())
+            .unwrap_or_else(|| SessionId::from_timestamp(0))
+    };
+
+    for session in sessions {
+        let Some(parent) = session.get_mut("parent").filter(|x| x.is_object()) else {
+            continue;
+        };
+        let old_session = parent.get("session_id").and_then(Value::as_u64);
+        let old_message = parent.get("message_id").and_then(Value::as_u64);
+        let message_id = old_session
+            .zip(old_message)
+            .and_then(|x| message_ids.get(&x).copied())
+            .unwrap_or_else(|| MessageId::from_timestamp(0));
+
+        parent["session_id"] =
+            serde_json::to_value(session_id(old_session)).map_err(|_| invalid())?;
+        parent["message_id"] = serde_json::to_value(message_id).map_err(|_| invalid())?;
+    }
+
+    for item in messages {
+        let old_session = item.get("session_id").and_then(Value::as_u64);
+        item["session_id"] =
+            serde_json::to_value(session_id(old_session)).map_err(|_| invalid())?;
+
+        let message = item.get_mut("message").ok_or_else(invalid)?;
+        let id = MessageId::from_timestamp(created_at(message));
+        message["id"] = serde_json::to_value(id).map_err(|_| invalid())?;
+    }
+
+    Ok(())
+}
+
+/// Returns the `created_at` timestamp of a saved session or message, or 0 if
+/// it has none
+fn created_at(value: &Value) -> u64 {
+    value.get("created_at").and_then(Value::as_u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trash::TrashedItem;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Returns a path in the temp dir that no other test uses
+    fn temp_path(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("chat-overlay-{}-{}", name, nanos))
+    }
+
+    #[test]
+    fn test_json_backend_round_trip() {
+        let dir = temp_path("round-trip");
+        let backend = JsonFileBackend::in_app_data_dir(&dir);
+
+        assert!(backend.load().unwrap().is_none());
+
+        let session = ChatSession::new(String::from("Saved"), "gpt-3.5-turbo");
+        let snapshot = StoreSnapshot {
+            sessions: vec![session.clone()],
+            prompts: vec![],
+            trash: Trash::default(),
+            memories: vec![],
+        };
+        backend.save(&snapshot).unwrap();
+
+        let loaded = backend.load().unwrap().unwrap();
+        assert_eq!(1, loaded.sessions.len());
+        assert_eq!(session.get_id(), loaded.sessions[0].get_id());
+        assert!(!backend.path().with_extension("tmp").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_backend_encrypted() {
+        let dir = temp_path("encrypted");
+        let key = EncryptionKey::new("hunter2").unwrap();
+        let backend = JsonFileBackend::in_app_data_dir(&dir).with_key(Some(key));
+
+        let snapshot = StoreSnapshot {
+            sessions: vec![ChatSession::new(String::from("Secret"), "gpt-3.5-turbo")],
+            ..Default::default()
+        };
+        backend.save(&snapshot).unwrap();
+        assert!(backend.is_encrypted().unwrap());
+        assert_eq!(1, backend.load().unwrap().unwrap().sessions.len());
+
+        let locked = JsonFileBackend::in_app_data_dir(&dir);
+        assert_eq!(Some(ChatError::StoreLocked), locked.load().err());
+        assert_eq!(
+            Some(ChatError::WrongPassphrase),
+            locked.clone().unlock("hunter3").err()
+        );
+
+        let unlocked = locked.unlock("hunter2").unwrap();
+        assert_eq!(1, unlocked.load().unwrap().unwrap().sessions.len());
+
+        // Saving without a key turns encryption off again
+        let plain = unlocked.with_key(None);
+        plain.save(&snapshot).unwrap();
+        assert!(!plain.is_encrypted().unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_backend_rejects_unknown_versions() {
+        let path = temp_path("versions");
+        let backend = JsonFileBackend::new(&path);
+
+        fs::write(&path, r#"{"version": 999, "data": {}}"#).unwrap();
+        assert!(backend.load().is_err());
+
+        fs::write(&path, r#"{"schema_version": 999, "data": {}}"#).unwrap();
+        assert!(backend.load().is_err());
+
+        fs::write(&path, r#"{"data": {}}"#).unwrap();
+        assert!(backend.load().is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(i as u32 + 2, migration.to);
+        }
+        assert_eq!(
+            SCHEMA_VERSION,
+            MIGRATIONS.last().map_or(1, |x| x.to),
+            "SCHEMA_VERSION has no migration"
+        );
+
+        // Snapshots are written with the current version and read back as is
+        let snapshot = StoreSnapshot::default();
+        let value = serde_json::to_value(VersionedSnapshot::new(&snapshot)).unwrap();
+        assert_eq!(
+            Some(SCHEMA_VERSION as u64),
+            value["schema_version"].as_u64()
+        );
+        assert!(migrate(value).unwrap().sessions.is_empty());
+    }
+
+    #[test]
+    fn test_migrate_numeric_ids() {
+        let message = |id: u64, created_at: u64| {
+            serde_json::json!({
+                "id": id, "role": "user", "content": "Hi", "created_at": created_at
+            })
+        };
+        let v1 = serde_json::json!({
+            "version": 1,
+            "data": {
+                "session_id_counter": 2,
+                "sessions": [{
+                    "id": 0, "title": "Original", "model": "gpt-3.5-turbo",
+                    "created_at": 1700000000, "msg_id_counter": 2,
+                    "messages": [message(0, 1700000001), message(1, 1700000002)]
+                }, {
+                    "id": 1, "title": "Fork", "model": "gpt-3.5-turbo",
+                    "created_at": 1700000010, "msg_id_counter": 1,
+                    "messages": [message(0, 1700000001)],
+                    "parent": {"session_id": 0, "message_id": 1}
+                }],
+                "trash": {"items": [{"message": {
+                    "session_id": 0, "message": message(2, 1700000003),
+                    "position": 2, "deleted_at": 1700000020
+                }}]}
+            }
+        });
+
+        let snapshot = migrate(v1).unwrap();
+        let original = &snapshot.sessions[0];
+        let fork = &snapshot.sessions[1];
+        assert!(original.get_id() < fork.get_id());
+
+        let parent = fork.get_parent().unwrap();
+        assert_eq!(original.get_id(), parent.session_id);
+        assert_eq!(original.get_messages()[1].get_id(), parent.message_id);
+
+        match &snapshot.trash.get_items()[0] {
+            TrashedItem::Message { session_id, .. } => assert_eq!(original.get_id(), *session_id),
+            TrashedItem::Session { .. } => panic!("Expected a trashed message"),
+        }
+    }
+}
@@ -0,0 +1,356 @@
+//! Named configuration profiles, such as "work Azure", "personal OpenAI" and
+//! "local Ollama".
+//!
+//! A profile bundles a provider, where it is served from, the API key it
+//! authenticates with and the defaults given to sessions using it. Each
+//! profile is registered as a provider under `provider_name` of its name, so
+//! sessions can switch between profiles without restarting the app. The
+//! profiles are saved to their own file in the app config directory, and
+//! keys of their own in the platform keyring. Each profile has its own
+//! connect and read timeouts, and may send its requests through a proxy.
+
+use crate::persistence::JsonConfig;
+use crate::providers::anthropic::AnthropicProvider;
+use crate::providers::azure::{self, AzureSettings};
+use crate::providers::compatible::{self, Endpoint};
+use crate::providers::ollama::{OllamaProvider, DEFAULT_OLLAMA_URL};
+use crate::providers::openai::OpenAiProvider;
+use crate::proxy::ProxyConfig;
+use crate::timeouts::Timeouts;
+use crate::{secrets, ChatError, Store};
+use serde::{Deserialize, Serialize};
+
+/// Name of the file the profiles are saved to inside the app config
+/// directory
+pub const PROFILES_FILE_NAME: &str = "profiles.json";
+
+/// Prefix of the names profiles are registered under as providers, followed
+/// by the profile's name
+pub const PROFILE_PROVIDER_PREFIX: &str = "profile:";
+
+/// The kind of provider a profile sends its requests to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileKind {
+    /// The OpenAI API, or an OpenAI-compatible server at the profile's base
+    /// URL
+    OpenAi,
+    /// An Azure OpenAI resource. The base URL is the resource endpoint, and
+    /// the default model the deployment.
+    Azure,
+    Anthropic,
+    Ollama,
+}
+
+/// Where a profile's API key is read from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum ApiKeyRef {
+    /// No key is sent
+    #[default]
+    None,
+    /// The key stored for the profile itself
+    Own,
+    /// The OpenAI key, falling back to the `OPENAI_API_KEY` environment
+    /// variable
+    OpenAi,
+    /// The Azure OpenAI key
+    Azure,
+    /// The Anthropic key
+    Anthropic,
+    /// The key of the OpenAI-compatible endpoint named `name`
+    Endpoint { name: String },
+}
+
+impl ApiKeyRef {
+    /// Reads the key referred to for the profile named `profile`, returning
+    /// None if no key is referred to or none has been stored
+    fn resolve(&self, profile: &str) -> Result<Option<String>, ChatError> {
+        match self {
+            ApiKeyRef::None => Ok(None),
+            ApiKeyRef::Own => secrets::get_profile_api_key(profile),
+            ApiKeyRef::OpenAi => secrets::get_api_key(),
+            ApiKeyRef::Azure => secrets::get_azure_api_key(),
+            ApiKeyRef::Anthropic => secrets::get_anthropic_api_key(),
+            ApiKeyRef::Endpoint { name } => secrets::get_endpoint_api_key(name),
+        }
+    }
+}
+
+/// A named set of provider settings and session defaults
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub kind: ProfileKind,
+
+    /// Address the API is served from, or None for the usual address of the
+    /// kind. Required for Azure.
+    #[serde(default)]
+    pub base_url: Option<String>,
+
+    #[serde(default)]
+    pub api_key: ApiKeyRef,
+
+    /// Model sessions switched to this profile use
+    pub default_model: String,
+
+    /// System prompt sessions switched to this profile are given, if any
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+
+    #[serde(default)]
+    pub timeouts: Timeouts,
+
+    /// Proxy the profile's requests are sent through, if any
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+}
+
+/// The profiles added by the user
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfilesConfig {
+    pub profiles: Vec<Profile>,
+}
+
+impl JsonConfig for ProfilesConfig {}
+
+impl ProfilesConfig {
+    /// Returns the profile named `name`, if any
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|x| x.name == name)
+    }
+
+    /// Adds `profile`, replacing any profile with the same name
+    pub fn upsert(&mut self, profile: Profile) {
+        match self.profiles.iter_mut().find(|x| x.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    /// Removes the profile named `name`, returning it if it existed
+    pub fn remove(&mut self, name: &str) -> Option<Profile> {
+        let index = self.profiles.iter().position(|x| x.name == name)?;
+
+        Some(self.profiles.remove(index))
+    }
+}
+
+/// Returns the name the profile named `profile` is registered under as a
+/// provider
+pub fn provider_name(profile: &str) -> String {
+    format!("{}{}", PROFILE_PROVIDER_PREFIX, profile)
+}
+
+/// Registers `profile` as a provider in `store` under `provider_name` of its
+/// name, replacing any provider it was registered as before.
+///
+/// Returns `ChatError::MissingApiKey` if the profile needs a key and none is
+/// stored, and `ChatError::InvalidEndpoint` if its name or address can not be
+/// used.
+pub fn register(store: &mut Store, profile: &Profile) -> Result<(), ChatError> {
+    if profile.name.trim().is_empty() || profile.name.trim() != profile.name {
+        return Err(ChatError::InvalidEndpoint(format!(
+            "\"{}\" can not be used as a name",
+            profile.name
+        )));
+    }
+
+    let name = provider_name(&profile.name);
+    let key = profile.api_key.resolve(&profile.name)?;
+    let timeouts = profile.timeouts;
+    let http = http_client(profile)?;
+    let base_url = profile
+        .base_url
+        .as_deref()
+        .map(str::trim)
+        .filter(|x| !x.is_empty());
+
+    match profile.kind {
+        ProfileKind::OpenAi => match base_url {
+            Some(base_url) => {
+                let endpoint = Endpoint {
+                    name: name.clone(),
+                    base_url: base_url.to_string(),
+                    headers: Default::default(),
+                };
+                let client = compatible::endpoint_client(&endpoint, key.as_deref())?;
+                let provider = OpenAiProvider::new(client).with_http_client(http);
+                store.register_provider(&name, timeouts.wrap(provider));
+            }
+            None => {
+                let client = secrets::openai_client(key.as_deref());
+                let provider = OpenAiProvider::new(client).with_http_client(http);
+                store.register_provider(&name, timeouts.wrap(provider));
+            }
+        },
+        ProfileKind::Azure => {
+            let settings = AzureSettings {
+                endpoint: base_url.unwrap_or_default().to_string(),
+                deployment: profile.default_model.clone(),
+                ..Default::default()
+            };
+            if !settings.is_complete() {
+                return Err(ChatError::InvalidEndpoint(String::from(
+                    "Azure profiles need a resource endpoint and a deployment",
+                )));
+            }
+            let key = key.ok_or(ChatError::MissingApiKey)?;
+            let client = azure::azure_client(&settings, &key);
+            let provider = OpenAiProvider::new(client).with_http_client(http);
+            store.register_provider(&name, timeouts.wrap(provider));
+        }
+        ProfileKind::Anthropic => {
+            let provider =
+                AnthropicProvider::new(key.ok_or(ChatError::MissingApiKey)?).with_http_client(http);
+            match base_url {
+                Some(base_url) => {
+                    store.register_provider(&name, timeouts.wrap(provider.with_base_url(base_url)))
+                }
+                None => store.register_provider(&name, timeouts.wrap(provider)),
+            }
+        }
+        ProfileKind::Ollama => {
+            let provider =
+                OllamaProvider::new(base_url.unwrap_or(DEFAULT_OLLAMA_URL)).with_http_client(http);
+            store.register_provider(&name, timeouts.wrap(provider));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the HTTP client the provider of `profile` sends its requests
+/// with, applying its connect timeout and proxy
+fn http_client(profile: &Profile) -> Result<reqwest::Client, ChatError> {
+    let mut builder = reqwest::Client::builder().connect_timeout(profile.timeouts.connect());
+
+    if let Some(proxy) = &profile.proxy {
+        let password = match proxy.has_auth() {
+            true => secrets::get_profile_proxy_password(&profile.name)?,
+            false => None,
+        };
+        builder = proxy.apply(builder, password.as_deref())?;
+    }
+
+    builder
+        .build()
+        .map_err(|e| ChatError::InvalidEndpoint(e.to_string()))
+}
+
+/// Unregisters the profile named `name` from `store`. Sessions using it fail
+/// with `ChatError::ProviderNotFound` until it is registered again.
+pub fn unregister(store: &mut Store, name: &str) {
+    store.unregister_provider(&provider_name(name));
+}
+
+/// Registers every profile in `config` in `store`. Profiles that can not be
+/// registered are skipped, and the first error returned once the rest are
+/// registered.
+pub fn configure(store: &mut Store, config: &ProfilesConfig) -> Result<(), ChatError> {
+    let mut result = Ok(());
+
+    for profile in &config.profiles {
+        if let Err(e) = register(store, profile) {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn local_profile() -> Profile {
+        Profile {
+            name: String::from("local Ollama"),
+            kind: ProfileKind::Ollama,
+            base_url: None,
+            api_key: ApiKeyRef::None,
+            default_model: String::from("llama3"),
+            system_prompt: Some(String::from("Answer briefly")),
+            timeouts: Timeouts::default(),
+            proxy: None,
+        }
+    }
+
+    #[test]
+    fn test_profiles_config_round_trip() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("chat-overlay-profiles-{}.json", nanos));
+        assert_eq!(
+            ProfilesConfig::default(),
+            ProfilesConfig::load(&path).unwrap()
+        );
+
+        let mut config = ProfilesConfig::default();
+        config.upsert(local_profile());
+        config.upsert(Profile {
+            default_model: String::from("mistral"),
+            ..local_profile()
+        });
+        assert_eq!(1, config.profiles.len());
+        assert_eq!("mistral", config.get("local Ollama").unwrap().default_model);
+
+        config.save(&path).unwrap();
+        assert_eq!(config, ProfilesConfig::load(&path).unwrap());
+        assert!(config.remove("local Ollama").is_some());
+        assert!(config.remove("local Ollama").is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_store_switch_session_profile() {
+        let mut store = Store::new(MockProvider::default());
+        let profile = local_profile();
+        register(&mut store, &profile).unwrap();
+        assert_eq!(vec!["profile:local Ollama"], store.get_provider_names());
+
+        let id = store.add_empty_session(String::from("Local"), "gpt-3.5-turbo");
+        store.set_session_profile(id, Some(&profile)).unwrap();
+        let session = store.get_session(id).unwrap();
+        assert_eq!(Some("local Ollama"), session.get_profile());
+        assert_eq!(Some("profile:local Ollama"), session.get_provider());
+        assert_eq!("llama3", session.get_model());
+        assert_eq!(Some("Answer briefly"), session.get_system_prompt());
+
+        // Picking a provider by hand leaves the profile
+        store.set_session_provider(id, None).unwrap();
+        assert_eq!(None, store.get_session(id).unwrap().get_profile());
+
+        store.set_session_profile(id, Some(&profile)).unwrap();
+        store.set_session_profile(id, None).unwrap();
+        let session = store.get_session(id).unwrap();
+        assert_eq!(None, session.get_profile());
+        assert_eq!(None, session.get_provider());
+
+        unregister(&mut store, &profile.name);
+        assert_eq!(
+            Some(ChatError::ProviderNotFound(String::from(
+                "profile:local Ollama"
+            ))),
+            store.set_session_profile(id, Some(&profile)).err()
+        );
+
+        let unnamed = Profile {
+            name: String::from(" "),
+            ..local_profile()
+        };
+        assert!(matches!(
+            register(&mut store, &unnamed),
+            Err(ChatError::InvalidEndpoint(_))
+        ));
+    }
+}
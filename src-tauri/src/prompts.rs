@@ -0,0 +1,124 @@
+//! Reusable prompt templates.
+//!
+//! A template's body may contain `{{placeholders}}` which are filled in when
+//! the template is rendered.
+
+use crate::ChatError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A reusable prompt, stored by the `Store` alongside its sessions
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptTemplate {
+    /// Unique name of this template
+    pub name: String,
+    pub description: String,
+    /// The prompt, with `{{placeholders}}` for the parts that change
+    pub body: String,
+}
+
+/// A `{{placeholder}}` found in a template body
+struct Placeholder<'a> {
+    /// Byte range of the placeholder, braces included
+    start: usize,
+    end: usize,
+    name: &'a str,
+}
+
+/// Returns the placeholders in `body`, in order. Placeholder names are
+/// trimmed of whitespace, and unclosed braces are not placeholders.
+fn find_placeholders(body: &str) -> Vec<Placeholder<'_>> {
+    let mut placeholders = Vec::new();
+    let mut offset = 0;
+
+    while let Some(open) = body[offset..].find("{{") {
+        let start = offset + open;
+        let Some(close) = body[start + 2..].find("}}") else {
+            break;
+        };
+        let end = start + 2 + close + 2;
+
+        placeholders.push(Placeholder {
+            start,
+            end,
+            name: body[start + 2..end - 2].trim(),
+        });
+        offset = end;
+    }
+
+    placeholders
+}
+
+impl PromptTemplate {
+    /// Create a template named `name`
+    pub fn new(name: &str, description: &str, body: &str) -> PromptTemplate {
+        PromptTemplate {
+            name: name.to_string(),
+            description: description.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Returns the names of the placeholders in this template's body, in the
+    /// order they first appear
+    pub fn placeholders(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+
+        for placeholder in find_placeholders(&self.body) {
+            if !names.contains(&placeholder.name) {
+                names.push(placeholder.name);
+            }
+        }
+
+        names
+    }
+
+    /// Returns this template's body with every placeholder replaced by its
+    /// value in `vars`. Returns `ChatError::MissingPromptVariable` if a
+    /// placeholder has no value.
+    pub fn render(&self, vars: &HashMap<String, String>) -> Result<String, ChatError> {
+        let mut rendered = String::with_capacity(self.body.len());
+        let mut offset = 0;
+
+        for placeholder in find_placeholders(&self.body) {
+            let value = vars
+                .get(placeholder.name)
+                .ok_or_else(|| ChatError::MissingPromptVariable(placeholder.name.to_string()))?;
+
+            rendered.push_str(&self.body[offset..placeholder.start]);
+            rendered.push_str(value);
+            offset = placeholder.end;
+        }
+        rendered.push_str(&self.body[offset..]);
+
+        Ok(rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_render() {
+        let template = PromptTemplate::new(
+            "translate",
+            "Translates text",
+            "Translate {{ text }} into {{language}}. Only {{language}}! {{unclosed",
+        );
+        assert_eq!(vec!["text", "language"], template.placeholders());
+
+        let mut vars = HashMap::new();
+        vars.insert(String::from("text"), String::from("hello"));
+        assert_eq!(
+            Err(ChatError::MissingPromptVariable(String::from("language"))),
+            template.render(&vars)
+        );
+
+        vars.insert(String::from("language"), String::from("French"));
+        assert_eq!(
+            "Translate hello into French. Only French! {{unclosed",
+            template.render(&vars).unwrap()
+        );
+    }
+}
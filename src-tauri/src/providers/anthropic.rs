@@ -0,0 +1,477 @@
+//! `LlmProvider` implementation for Anthropic's Claude models.
+//!
+//! See https://docs.anthropic.com/en/api/messages for the API. Unlike OpenAI,
+//! the Messages API takes the system prompt separately from the
+//! conversation, and the conversation must alternate between user and
+//! assistant turns. Function results are sent as user turns, and function
+//! calls are not offered, as Claude names its tools differently.
+
+use super::{Completion, CompletionRequest, LlmProvider, OnToken};
+use crate::chat_requests::MAX_RESPONSE_TOKENS;
+use crate::retry;
+use crate::timeouts;
+use crate::usage::TokenUsage;
+use crate::vision::ImageData;
+use crate::ChatError;
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Address of the Anthropic API
+pub const ANTHROPIC_URL: &str = "https://api.anthropic.com";
+
+/// Version of the Anthropic API the provider speaks
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Name the Anthropic provider is registered under
+pub const ANTHROPIC_PROVIDER: &str = "anthropic";
+
+/// Talks to Claude models through the Anthropic Messages API
+#[derive(Clone)]
+pub struct AnthropicProvider {
+    api_key: String,
+    base_url: String,
+    http: reqwest::Client,
+}
+
+// Written by hand so the API key is never printed
+impl fmt::Debug for AnthropicProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnthropicProvider")
+            .field("base_url", &self.base_url)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<ClaudeMessage>,
+    max_tokens: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    stream: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct ClaudeMessage {
+    /// Either "user" or "assistant"
+    role: &'static str,
+    /// Content blocks, such as text and images
+    content: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: ClaudeUsage,
+    /// Why Claude stopped, such as "end_turn" or "max_tokens"
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ClaudeUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+/// A single event of a streamed response
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StartedMessage,
+    },
+    ContentBlockDelta {
+        delta: Delta,
+    },
+    MessageDelta {
+        #[serde(default)]
+        delta: MessageDeltaBody,
+        usage: ClaudeUsage,
+    },
+    MessageStop,
+    Error {
+        error: ApiError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Default, Deserialize)]
+struct MessageDeltaBody {
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StartedMessage {
+    usage: ClaudeUsage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Delta {
+    TextDelta {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelInfo>,
+}
+
+#[derive(Deserialize)]
+struct ModelInfo {
+    id: String,
+}
+
+impl From<ClaudeUsage> for TokenUsage {
+    fn from(usage: ClaudeUsage) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        }
+    }
+}
+
+impl AnthropicProvider {
+    /// Create a provider sending requests with `api_key`
+    pub fn new(api_key: impl Into<String>) -> AnthropicProvider {
+        AnthropicProvider {
+            api_key: api_key.into(),
+            base_url: ANTHROPIC_URL.to_string(),
+            http: timeouts::default_http_client(),
+        }
+    }
+
+    /// Sends requests to the API at `base_url` instead, such as a proxy
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> AnthropicProvider {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Sends requests with `http` instead, such as a client with timeouts
+    pub fn with_http_client(mut self, http: reqwest::Client) -> AnthropicProvider {
+        self.http = http;
+        self
+    }
+
+    /// Returns the address of the API
+    pub fn get_base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn headers(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        builder
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+    }
+
+    /// Sends a messages request, returning the response once its status has
+    /// been checked.
+    async fn post_messages(
+        &self,
+        request: CompletionRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, ChatError> {
+        let (system, messages) = to_claude_messages(request.messages, request.images);
+        let body = MessagesRequest {
+            model: &request.model,
+            system,
+            messages,
+            max_tokens: MAX_RESPONSE_TOKENS,
+            temperature: request.temperature,
+            stream,
+        };
+
+        let response = self
+            .headers(self.http.post(format!("{}/v1/messages", self.base_url)))
+            .json(&body)
+            .send()
+            .await?;
+
+        retry::check_status(response)
+    }
+}
+
+/// Splits `messages` into the system prompt and the conversation Claude
+/// expects, attaching `images` to the last message. System messages are
+/// joined into the system prompt, and consecutive messages with the same
+/// role are merged into one turn.
+fn to_claude_messages(
+    messages: Vec<ChatCompletionRequestMessage>,
+    images: Vec<ImageData>,
+) -> (Option<String>, Vec<ClaudeMessage>) {
+    let mut system: Vec<String> = vec![];
+    let mut turns: Vec<ClaudeMessage> = vec![];
+
+    for msg in messages {
+        let content = msg.content.unwrap_or_default();
+        let (role, text) = match msg.role {
+            Role::System => {
+                system.push(content);
+                continue;
+            }
+            Role::Assistant => ("assistant", content),
+            Role::User => ("user", content),
+            Role::Function => {
+                let name = msg.name.unwrap_or_default();
+                ("user", format!("Result of {}:\n{}", name, content))
+            }
+        };
+
+        // Claude rejects empty turns, such as bare function calls
+        if text.is_empty() {
+            continue;
+        }
+        let block = json!({ "type": "text", "text": text });
+
+        match turns.last_mut() {
+            Some(last) if last.role == role => last.content.push(block),
+            _ => turns.push(ClaudeMessage {
+                role,
+                content: vec![block],
+            }),
+        }
+    }
+
+    if let Some(last) = turns.last_mut() {
+        let blocks = images.into_iter().map(|image| {
+            json!({
+                "type": "image",
+                "source": { "type": "base64", "media_type": image.mime, "data": image.base64 }
+            })
+        });
+        // Images go before the text asking about them
+        last.content.splice(0..0, blocks);
+    }
+
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+
+    (system, turns)
+}
+
+/// Parses the data of a single server-sent event of a streamed response
+fn parse_event(data: &str) -> Result<StreamEvent, ChatError> {
+    let event: StreamEvent =
+        serde_json::from_str(data).map_err(|e| ChatError::Request(e.to_string()))?;
+
+    match event {
+        StreamEvent::Error { error } => Err(ChatError::Request(error.message)),
+        event => Ok(event),
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        let response: MessagesResponse = self.post_messages(request, false).await?.json().await?;
+
+        let content: String = response
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text),
+                ContentBlock::Other => None,
+            })
+            .collect();
+
+        Ok(Completion {
+            message: ChatCompletionResponseMessage {
+                role: Role::Assistant,
+                content: Some(content),
+                function_call: None,
+            },
+            usage: Some(response.usage.into()),
+            finish_reason: response.stop_reason,
+        })
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        let mut body = self.post_messages(request, true).await?.bytes_stream();
+
+        // Events arrive as "data: {...}" lines, which may be split across or
+        // share chunks of the body
+        let mut buffer = Vec::new();
+        let mut content = String::new();
+        let mut usage = ClaudeUsage::default();
+        let mut finish_reason = None;
+        let mut done = false;
+
+        while let Some(bytes) = body.next().await {
+            buffer.extend_from_slice(&bytes?);
+
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+
+                match parse_event(data.trim())? {
+                    StreamEvent::MessageStart { message } => {
+                        usage.input_tokens = message.usage.input_tokens;
+                    }
+                    StreamEvent::ContentBlockDelta {
+                        delta: Delta::TextDelta { text },
+                    } => {
+                        on_token(&text);
+                        content.push_str(&text);
+                    }
+                    StreamEvent::MessageDelta {
+                        delta,
+                        usage: delta_usage,
+                    } => {
+                        usage.output_tokens = delta_usage.output_tokens;
+                        finish_reason = delta.stop_reason.or(finish_reason);
+                    }
+                    StreamEvent::MessageStop => done = true,
+                    _ => {}
+                }
+            }
+        }
+
+        if !done {
+            return Err(ChatError::Request(
+                "Anthropic ended the stream early".to_string(),
+            ));
+        }
+
+        Ok(Completion {
+            message: ChatCompletionResponseMessage {
+                role: Role::Assistant,
+                content: Some(content),
+                function_call: None,
+            },
+            usage: Some(usage.into()),
+            finish_reason,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        let models: ModelsResponse = self
+            .headers(self.http.get(format!("{}/v1/models", self.base_url)))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestMessage {
+            role,
+            content: Some(content.to_string()),
+            name: None,
+            function_call: None,
+        }
+    }
+
+    #[test]
+    fn test_anthropic_role_mapping() {
+        let messages = vec![
+            message(Role::System, "Be brief"),
+            message(Role::User, "Hi"),
+            message(Role::User, "Are you there?"),
+            message(Role::Assistant, ""),
+            ChatCompletionRequestMessage {
+                name: Some("clock".to_string()),
+                ..message(Role::Function, "12:00")
+            },
+            message(Role::Assistant, "It is noon"),
+            message(Role::System, "Use a 24 hour clock"),
+            message(Role::User, "What is this?"),
+        ];
+        let image = ImageData {
+            mime: "image/png".to_string(),
+            base64: "AAAA".to_string(),
+        };
+
+        let (system, turns) = to_claude_messages(messages, vec![image]);
+
+        assert_eq!(Some("Be brief\n\nUse a 24 hour clock".to_string()), system);
+        let roles: Vec<&str> = turns.iter().map(|x| x.role).collect();
+        assert_eq!(vec!["user", "assistant", "user"], roles);
+        assert_eq!(
+            vec![
+                json!({ "type": "text", "text": "Hi" }),
+                json!({ "type": "text", "text": "Are you there?" }),
+                json!({ "type": "text", "text": "Result of clock:\n12:00" }),
+            ],
+            turns[0].content
+        );
+        assert_eq!("image", turns[2].content[0]["type"]);
+        assert_eq!("What is this?", turns[2].content[1]["text"]);
+    }
+
+    #[test]
+    fn test_anthropic_parse_event() {
+        let event = parse_event(
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hi"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            event,
+            StreamEvent::ContentBlockDelta {
+                delta: Delta::TextDelta { text }
+            } if text == "Hi"
+        ));
+
+        let event = parse_event(
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":15}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            event,
+            StreamEvent::MessageDelta { delta, usage }
+                if delta.stop_reason.as_deref() == Some("end_turn") && usage.output_tokens == 15
+        ));
+
+        let event = parse_event(r#"{"type":"ping"}"#).unwrap();
+        assert!(matches!(event, StreamEvent::Other));
+
+        assert_eq!(
+            Err(ChatError::Request("Overloaded".to_string())),
+            parse_event(
+                r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#
+            )
+            .map(|_| ())
+        );
+    }
+}
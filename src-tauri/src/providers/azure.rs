@@ -0,0 +1,124 @@
+//! Azure-hosted OpenAI deployments.
+//!
+//! Azure serves the OpenAI API from a resource endpoint, with the chat model
+//! picked by the deployment rather than the request. The async-openai client
+//! already implements `LlmProvider` for any config, so an Azure client is
+//! registered as a provider like any other and sessions pick it by name. The
+//! endpoint settings are saved to their own file in the app config
+//! directory, and the key in the platform keyring.
+
+use crate::persistence::JsonConfig;
+use crate::{retry, secrets, timeouts, ChatError, Store};
+use async_openai::{config::AzureConfig, Client};
+use serde::{Deserialize, Serialize};
+
+/// Name of the file the Azure settings are saved to inside the app config
+/// directory
+pub const AZURE_FILE_NAME: &str = "azure.json";
+
+/// Name the Azure provider is registered under
+pub const AZURE_PROVIDER: &str = "azure";
+
+/// API version used unless another one is picked
+pub const DEFAULT_API_VERSION: &str = "2023-05-15";
+
+/// Where the Azure OpenAI deployment is hosted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AzureSettings {
+    /// The resource endpoint, such as `https://my-resource.openai.azure.com`.
+    /// The provider is not registered if empty.
+    pub endpoint: String,
+
+    /// Name of the deployment serving the chat model
+    pub deployment: String,
+
+    pub api_version: String,
+}
+
+impl Default for AzureSettings {
+    fn default() -> Self {
+        AzureSettings {
+            endpoint: String::new(),
+            deployment: String::new(),
+            api_version: DEFAULT_API_VERSION.to_string(),
+        }
+    }
+}
+
+impl JsonConfig for AzureSettings {}
+
+impl AzureSettings {
+    /// Returns whether both the endpoint and deployment are set
+    pub fn is_complete(&self) -> bool {
+        !self.endpoint.trim().is_empty() && !self.deployment.trim().is_empty()
+    }
+}
+
+/// Create a client sending requests to the deployment in `settings` with
+/// `key`
+pub fn azure_client(settings: &AzureSettings, key: &str) -> Client<AzureConfig> {
+    let config = AzureConfig::new()
+        .with_api_base(settings.endpoint.trim().trim_end_matches('/'))
+        .with_deployment_id(settings.deployment.trim())
+        .with_api_version(&settings.api_version)
+        .with_api_key(key);
+
+    let client = Client::with_config(config).with_http_client(timeouts::default_http_client());
+
+    retry::without_backoff(client)
+}
+
+/// Registers the Azure provider in `store` under `AZURE_PROVIDER` if
+/// `settings` is complete, or unregisters it otherwise. Returns
+/// `ChatError::MissingApiKey` if no Azure key has been stored.
+pub fn configure(store: &mut Store, settings: &AzureSettings) -> Result<(), ChatError> {
+    if !settings.is_complete() {
+        store.unregister_provider(AZURE_PROVIDER);
+        return Ok(());
+    }
+
+    match secrets::get_azure_api_key()? {
+        Some(key) => {
+            store.register_provider(AZURE_PROVIDER, azure_client(settings, &key));
+            Ok(())
+        }
+        None => {
+            store.unregister_provider(AZURE_PROVIDER);
+            Err(ChatError::MissingApiKey)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_azure_settings_round_trip() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("chat-overlay-azure-{}.json", nanos));
+
+        let settings = AzureSettings::load(&path).unwrap();
+        assert_eq!(AzureSettings::default(), settings);
+        assert!(!settings.is_complete());
+
+        let settings = AzureSettings {
+            endpoint: String::from("https://work.openai.azure.com/"),
+            deployment: String::from("gpt-35"),
+            ..Default::default()
+        };
+        settings.save(&path).unwrap();
+
+        let loaded = AzureSettings::load(&path).unwrap();
+        assert_eq!(settings, loaded);
+        assert!(loaded.is_complete());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! OpenAI-compatible servers, such as OpenRouter, LM Studio and vLLM.
+//!
+//! These serve the OpenAI API from their own base URL, sometimes expecting
+//! extra headers. Each endpoint the user adds is registered as a provider
+//! under its name, using the async-openai client with a config pointing at
+//! it. The endpoints are saved to their own file in the app config
+//! directory, and their keys in the platform keyring.
+
+use super::anthropic::ANTHROPIC_PROVIDER;
+use super::azure::AZURE_PROVIDER;
+use crate::persistence::JsonConfig;
+use crate::{retry, secrets, timeouts, ChatError, Store};
+use async_openai::{config::Config, Client};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Name of the file the endpoints are saved to inside the app config
+/// directory
+pub const ENDPOINTS_FILE_NAME: &str = "endpoints.json";
+
+/// Provider names endpoints can not take, as built-in providers use them
+pub const RESERVED_NAMES: [&str; 3] = ["ollama", AZURE_PROVIDER, ANTHROPIC_PROVIDER];
+
+/// An OpenAI-compatible server added by the user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endpoint {
+    /// Name the endpoint is registered under as a provider
+    pub name: String,
+
+    /// Address the API is served from, such as
+    /// `https://openrouter.ai/api/v1`
+    pub base_url: String,
+
+    /// Headers sent with every request, on top of the API key
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// The endpoints added by the user
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointsConfig {
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl JsonConfig for EndpointsConfig {}
+
+impl EndpointsConfig {
+    /// Adds `endpoint`, replacing any endpoint with the same name
+    pub fn upsert(&mut self, endpoint: Endpoint) {
+        match self.endpoints.iter_mut().find(|x| x.name == endpoint.name) {
+            Some(existing) => *existing = endpoint,
+            None => self.endpoints.push(endpoint),
+        }
+    }
+
+    /// Removes the endpoint named `name`, returning it if it existed
+    pub fn remove(&mut self, name: &str) -> Option<Endpoint> {
+        let index = self.endpoints.iter().position(|x| x.name == name)?;
+
+        Some(self.endpoints.remove(index))
+    }
+}
+
+/// An async-openai config sending requests to an `Endpoint`
+#[derive(Clone)]
+pub struct EndpointConfig {
+    api_base: String,
+    api_key: String,
+    headers: HeaderMap,
+}
+
+// Written by hand so the API key and headers are never printed
+impl fmt::Debug for EndpointConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EndpointConfig")
+            .field("api_base", &self.api_base)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EndpointConfig {
+    /// Create a config for `endpoint`, authenticating with `api_key` if
+    /// given. Returns `ChatError::InvalidEndpoint` if the endpoint's name,
+    /// address or headers are invalid.
+    pub fn new(endpoint: &Endpoint, api_key: Option<&str>) -> Result<EndpointConfig, ChatError> {
+        let invalid = |reason: String| ChatError::InvalidEndpoint(reason);
+
+        let name = endpoint.name.as_str();
+        if name.trim().is_empty() || name.trim() != name || RESERVED_NAMES.contains(&name) {
+            return Err(invalid(format!("\"{}\" can not be used as a name", name)));
+        }
+        let base_url = endpoint.base_url.trim().trim_end_matches('/');
+        reqwest::Url::parse(base_url).map_err(|e| invalid(format!("{}: {}", base_url, e)))?;
+
+        let mut headers = HeaderMap::new();
+        if let Some(key) = api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {}", key))
+                .map_err(|_| invalid(String::from("the API key is not a valid header")))?;
+            headers.insert(AUTHORIZATION, value);
+        }
+        for (name, value) in &endpoint.headers {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| invalid(format!("\"{}\" is not a valid header name", name)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| invalid(format!("the value of \"{}\" is not valid", name)))?;
+            headers.insert(header, value);
+        }
+
+        Ok(EndpointConfig {
+            api_base: base_url.to_string(),
+            api_key: api_key.unwrap_or_default().to_string(),
+            headers,
+        })
+    }
+}
+
+impl Config for EndpointConfig {
+    fn headers(&self) -> HeaderMap {
+        self.headers.clone()
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.api_base, path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        vec![]
+    }
+
+    fn api_base(&self) -> &str {
+        &self.api_base
+    }
+
+    fn api_key(&self) -> &str {
+        &self.api_key
+    }
+}
+
+/// Create a client for `endpoint`, authenticating with `api_key` if given
+pub fn endpoint_client(
+    endpoint: &Endpoint,
+    api_key: Option<&str>,
+) -> Result<Client<EndpointConfig>, ChatError> {
+    let config = EndpointConfig::new(endpoint, api_key)?;
+
+    let client = Client::with_config(config).with_http_client(timeouts::default_http_client());
+
+    Ok(retry::without_backoff(client))
+}
+
+/// Registers `endpoint` as a provider in `store` under its name, using the
+/// key stored for it if any
+pub fn register(store: &mut Store, endpoint: &Endpoint) -> Result<(), ChatError> {
+    let key = secrets::get_endpoint_api_key(&endpoint.name)?;
+    let client = endpoint_client(endpoint, key.as_deref())?;
+    store.register_provider(&endpoint.name, client);
+
+    Ok(())
+}
+
+/// Registers every endpoint in `config` as a provider in `store`. Endpoints
+/// that can not be registered are skipped, and the first error returned once
+/// the rest are registered.
+pub fn configure(store: &mut Store, config: &EndpointsConfig) -> Result<(), ChatError> {
+    let mut result = Ok(());
+
+    for endpoint in &config.endpoints {
+        if let Err(e) = register(store, endpoint) {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn openrouter() -> Endpoint {
+        Endpoint {
+            name: String::from("openrouter"),
+            base_url: String::from("https://openrouter.ai/api/v1/"),
+            headers: BTreeMap::from([(String::from("X-Title"), String::from("Chat Overlay"))]),
+        }
+    }
+
+    #[test]
+    fn test_endpoint_config() {
+        let config = EndpointConfig::new(&openrouter(), Some("sk-or")).unwrap();
+
+        assert_eq!("https://openrouter.ai/api/v1/models", config.url("/models"));
+        let headers = config.headers();
+        assert_eq!("Bearer sk-or", headers[AUTHORIZATION]);
+        assert_eq!("Chat Overlay", headers["x-title"]);
+
+        let reserved = Endpoint {
+            name: String::from("ollama"),
+            ..openrouter()
+        };
+        assert!(matches!(
+            EndpointConfig::new(&reserved, None),
+            Err(ChatError::InvalidEndpoint(_))
+        ));
+
+        let bad_header = Endpoint {
+            headers: BTreeMap::from([(String::from("bad header"), String::new())]),
+            ..openrouter()
+        };
+        assert!(matches!(
+            EndpointConfig::new(&bad_header, None),
+            Err(ChatError::InvalidEndpoint(_))
+        ));
+    }
+
+    #[test]
+    fn test_endpoints_config_round_trip() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("chat-overlay-endpoints-{}.json", nanos));
+
+        let mut config = EndpointsConfig::load(&path).unwrap();
+        assert!(config.endpoints.is_empty());
+
+        config.upsert(openrouter());
+        config.upsert(Endpoint {
+            base_url: String::from("http://localhost:1234/v1"),
+            ..openrouter()
+        });
+        config.save(&path).unwrap();
+
+        let mut loaded = EndpointsConfig::load(&path).unwrap();
+        assert_eq!(config, loaded);
+        assert_eq!(1, loaded.endpoints.len());
+        assert_eq!("http://localhost:1234/v1", loaded.endpoints[0].base_url);
+
+        assert!(loaded.remove("openrouter").is_some());
+        assert!(loaded.remove("openrouter").is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+//! A provider answering from a script, for tests and offline development.
+//!
+//! `MockProvider` never touches the network. Each request takes the next
+//! response from its script, which can be text, an echo of the last message,
+//! a function call, an error or a stream that never finishes, and the canned
+//! reply once the script runs out. Responses can be delayed to simulate a
+//! slow chat model, and every request sent is recorded. Clones share their
+//! script and records, so a clone kept outside a `Store` can be scripted and
+//! inspected while the store sends its requests.
+//!
+//! The other endpoints are mocked as well: images "paint" their prompt,
+//! speech returns the text it was given, and moderation flags any text
+//! containing one of the words it was told to flag.
+
+use super::{Completion, CompletionRequest, LlmProvider, OnToken};
+use crate::images::{GeneratedImage, ImageOptions};
+use crate::moderation::ModerationResult;
+use crate::speech::SpeechOptions;
+use crate::tokens::{count_message_tokens, count_tokens, TOKENS_PER_REPLY};
+use crate::usage::TokenUsage;
+use crate::ChatError;
+use async_openai::types::{ChatCompletionResponseMessage, FunctionCall, Role};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Reply of a `MockProvider` whose script has run out, unless replaced
+pub const DEFAULT_MOCK_REPLY: &str = "This is a mock response";
+
+/// The category flagged messages are put in by `MockProvider::moderate`
+pub const MOCK_FLAGGED_CATEGORY: &str = "harassment";
+
+/// A single scripted response
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockResponse {
+    Text(String),
+    /// Replies with the content of the last message sent
+    Echo,
+    /// A call to the function `name` with JSON `arguments`
+    FunctionCall {
+        name: String,
+        arguments: String,
+    },
+    /// The request fails with the error
+    Error(ChatError),
+    /// Streams the partial reply, then never finishes
+    Stall(String),
+}
+
+#[derive(Debug)]
+struct MockState {
+    script: VecDeque<MockResponse>,
+    canned: MockResponse,
+    latency: Duration,
+    usage: Option<TokenUsage>,
+    requests: Vec<CompletionRequest>,
+    models: Vec<String>,
+    listed: usize,
+    flagged_words: Vec<String>,
+    images: Vec<(String, ImageOptions)>,
+    spoken: Vec<String>,
+}
+
+/// A provider answering from a script
+#[derive(Debug, Clone)]
+pub struct MockProvider {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockProvider {
+    fn default() -> Self {
+        MockProvider::canned(DEFAULT_MOCK_REPLY)
+    }
+}
+
+impl MockProvider {
+    /// Creates a provider replying `reply` to every request
+    pub fn canned(reply: &str) -> MockProvider {
+        MockProvider::with_canned(MockResponse::Text(reply.to_string()))
+    }
+
+    /// Creates a provider replying to every request with the content of the
+    /// last message sent
+    pub fn echoing() -> MockProvider {
+        MockProvider::with_canned(MockResponse::Echo)
+    }
+
+    fn with_canned(canned: MockResponse) -> MockProvider {
+        MockProvider {
+            state: Arc::new(Mutex::new(MockState {
+                script: VecDeque::new(),
+                canned,
+                latency: Duration::ZERO,
+                usage: None,
+                requests: vec![],
+                models: vec![String::from("mock")],
+                listed: 0,
+                flagged_words: vec![],
+                images: vec![],
+                spoken: vec![],
+            })),
+        }
+    }
+
+    /// Adds `response` to the end of the script
+    pub fn push(&self, response: MockResponse) -> &Self {
+        self.lock().script.push_back(response);
+        self
+    }
+
+    /// Adds a text reply to the end of the script
+    pub fn push_text(&self, text: &str) -> &Self {
+        self.push(MockResponse::Text(text.to_string()))
+    }
+
+    /// Adds a failed request to the end of the script
+    pub fn push_error(&self, error: ChatError) -> &Self {
+        self.push(MockResponse::Error(error))
+    }
+
+    /// Delays every response by `latency`
+    pub fn set_latency(&self, latency: Duration) {
+        self.lock().latency = latency;
+    }
+
+    /// Reports `usage` for every response instead of counting its tokens
+    pub fn set_usage(&self, usage: TokenUsage) {
+        self.lock().usage = Some(usage);
+    }
+
+    /// Offers `models` when asked for its models
+    pub fn set_models(&self, models: &[&str]) {
+        self.lock().models = models.iter().map(|x| x.to_string()).collect();
+    }
+
+    /// Flags every moderated text containing `word`
+    pub fn flag(&self, word: &str) {
+        self.lock().flagged_words.push(word.to_string());
+    }
+
+    /// Returns the number of scripted responses not yet sent
+    pub fn remaining(&self) -> usize {
+        self.lock().script.len()
+    }
+
+    /// Returns every request sent so far, oldest first
+    pub fn get_requests(&self) -> Vec<CompletionRequest> {
+        self.lock().requests.clone()
+    }
+
+    /// Returns how often the models were listed
+    pub fn models_listed(&self) -> usize {
+        self.lock().listed
+    }
+
+    /// Returns the prompt and options of every image generated so far,
+    /// oldest first
+    pub fn get_images(&self) -> Vec<(String, ImageOptions)> {
+        self.lock().images.clone()
+    }
+
+    /// Returns every text read aloud so far, oldest first
+    pub fn get_spoken(&self) -> Vec<String> {
+        self.lock().spoken.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        // The state is only changed in single steps, so a poisoned lock is
+        // still usable
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records `request`, waits out the latency and returns the next
+    /// response, streaming its words to `on_token`
+    async fn respond(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        let prompt_tokens = request
+            .messages
+            .iter()
+            .map(count_message_tokens)
+            .sum::<usize>()
+            + TOKENS_PER_REPLY;
+        let last = request.messages.last().and_then(|x| x.content.clone());
+
+        let (response, latency, usage) = {
+            let mut state = self.lock();
+            state.requests.push(request);
+            let canned = state.canned.clone();
+            let response = state.script.pop_front().unwrap_or(canned);
+            (response, state.latency, state.usage)
+        };
+
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let (content, function_call) = match response {
+            MockResponse::Text(text) => (Some(text), None),
+            MockResponse::Echo => (Some(last.unwrap_or_default()), None),
+            MockResponse::FunctionCall { name, arguments } => {
+                (None, Some(FunctionCall { name, arguments }))
+            }
+            MockResponse::Error(e) => return Err(e),
+            MockResponse::Stall(partial) => {
+                on_token(&partial);
+                return futures::future::pending().await;
+            }
+        };
+        for word in content.iter().flat_map(|c| c.split_inclusive(' ')) {
+            on_token(word);
+        }
+        let completion_tokens = content.as_deref().map(count_tokens).unwrap_or_default();
+
+        Ok(Completion {
+            finish_reason: Some(String::from(if function_call.is_some() {
+                "function_call"
+            } else {
+                "stop"
+            })),
+            message: ChatCompletionResponseMessage {
+                role: Role::Assistant,
+                content,
+                function_call,
+            },
+            usage: Some(usage.unwrap_or(TokenUsage {
+                prompt_tokens: prompt_tokens as u32,
+                completion_tokens: completion_tokens as u32,
+            })),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        self.respond(request, &mut |_| {}).await
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        self.respond(request, on_token).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        let mut state = self.lock();
+        state.listed += 1;
+
+        Ok(state.models.clone())
+    }
+
+    async fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> Result<GeneratedImage, ChatError> {
+        let mut state = self.lock();
+        state.images.push((prompt.to_string(), options.clone()));
+
+        Ok(GeneratedImage {
+            base64: STANDARD.encode(prompt),
+            revised_prompt: Some(format!("A painting of {}", prompt)),
+        })
+    }
+
+    async fn synthesize_speech(
+        &self,
+        text: &str,
+        _options: &SpeechOptions,
+    ) -> Result<Vec<u8>, ChatError> {
+        self.lock().spoken.push(text.to_string());
+
+        Ok(text.as_bytes().to_vec())
+    }
+
+    async fn moderate(&self, text: &str) -> Result<ModerationResult, ChatError> {
+        let state = self.lock();
+        let flagged = state
+            .flagged_words
+            .iter()
+            .any(|x| text.contains(x.as_str()));
+        let score = if flagged { 1.0 } else { 0.0 };
+
+        Ok(ModerationResult {
+            flagged,
+            categories: match flagged {
+                true => vec![MOCK_FLAGGED_CATEGORY.to_string()],
+                false => vec![],
+            },
+            scores: BTreeMap::from([(MOCK_FLAGGED_CATEGORY.to_string(), score)]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::retry::RetryPolicy;
+    use crate::{ChatRole, ChatSession, Store};
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_session_add_message_with_mock() {
+        let provider = MockProvider::default();
+        provider.push_text("Hello").push_text("Rust is a language");
+        let mut session = ChatSession::new(String::from("Mocked"), "mock");
+        let cancel = CancellationToken::new();
+
+        for contents in ["Hi", "What is Rust?", "Thanks"] {
+            session
+                .add_message(contents.to_string(), &provider, &cancel)
+                .await
+                .unwrap();
+        }
+
+        let contents: Vec<String> = session
+            .get_messages()
+            .iter()
+            .map(|x| x.get_content())
+            .collect();
+        assert_eq!(
+            vec![
+                "Hi",
+                "Hello",
+                "What is Rust?",
+                "Rust is a language",
+                "Thanks",
+                DEFAULT_MOCK_REPLY
+            ],
+            contents
+        );
+        let roles = session.message_role_sequence();
+        assert_eq!(ChatRole::Assistant, roles[5]);
+        assert!(session.get_messages()[1].get_usage().is_some());
+
+        // Each request carries the conversation so far
+        let requests = provider.get_requests();
+        let sent: Vec<usize> = requests.iter().map(|x| x.messages.len()).collect();
+        assert_eq!(vec![1, 3, 5], sent);
+        assert_eq!(0, provider.remaining());
+    }
+
+    #[tokio::test]
+    async fn test_store_mock_errors_and_latency() {
+        let provider = MockProvider::canned("Done");
+        let script = provider.clone();
+        let mut store = Store::new(provider);
+        store.set_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        });
+        let id = store.add_empty_session(String::from("Mocked"), "mock");
+        let cancel = CancellationToken::new();
+
+        // Transient failures are retried
+        script.push_error(ChatError::Transient(String::from("busy"), None));
+        let message = store
+            .send_message(id, String::from("Hi"), &cancel)
+            .await
+            .unwrap();
+        assert_eq!("Done", message.get_content());
+        assert_eq!(2, script.get_requests().len());
+
+        // Other failures leave the session as it was
+        script.push_error(ChatError::InvalidModel(String::from("mock")));
+        assert_eq!(
+            Err(ChatError::InvalidModel(String::from("mock"))),
+            store
+                .send_message(id, String::from("Again"), &cancel)
+                .await
+                .map(|_| ())
+        );
+        assert_eq!(2, store.get_session(id).unwrap().message_count());
+
+        let mut tokens = vec![];
+        script.set_latency(Duration::from_millis(20));
+        script.push_text("One two three");
+        let start = Instant::now();
+        let message = store
+            .send_message_streaming(
+                id,
+                String::from("Count"),
+                |x| tokens.push(x.to_string()),
+                &cancel,
+            )
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!("One two three", message.get_content());
+        assert_eq!(vec!["One ", "two ", "three"], tokens);
+
+        // Slow responses can still be cancelled
+        script.set_latency(Duration::from_secs(60));
+        let pending = store.prepare_message(id, String::from("Slow")).unwrap();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            canceller.cancel();
+        });
+        assert_eq!(
+            Some(ChatError::Cancelled),
+            pending.complete(&cancel).await.err()
+        );
+        assert_eq!(4, store.get_session(id).unwrap().message_count());
+    }
+}
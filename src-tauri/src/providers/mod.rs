@@ -0,0 +1,420 @@
+//! Chat model backends.
+//!
+//! A `ChatSession` talks to its chat model through the `LlmProvider` trait, so
+//! any backend implementing it can be plugged into a `Store`. The OpenAI
+//! client is implemented in `openai`, local Ollama servers in `ollama`, and
+//! Claude models in `anthropic`. Azure-hosted deployments are set up in
+//! `azure`, and other OpenAI-compatible servers in `compatible`. `mock`
+//! answers from a script without touching the network.
+
+use crate::images::{GeneratedImage, ImageOptions};
+use crate::moderation::ModerationResult;
+use crate::speech::SpeechOptions;
+use crate::usage::TokenUsage;
+use crate::vision::ImageData;
+use crate::ChatError;
+use async_openai::types::{
+    ChatCompletionFunctions, ChatCompletionRequestMessage, ChatCompletionResponseMessage,
+};
+use async_trait::async_trait;
+use std::fmt;
+
+pub mod anthropic;
+pub mod azure;
+pub mod compatible;
+pub mod mock;
+pub mod ollama;
+pub mod openai;
+
+/// A request for the next message in a conversation
+#[derive(Debug, Clone)]
+pub struct CompletionRequest {
+    /// The chat model to complete the conversation with
+    pub model: String,
+    /// The conversation so far, oldest message first
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    /// Images attached to the last message, for models that can see them
+    pub images: Vec<ImageData>,
+    /// Functions the chat model may call instead of answering. Providers
+    /// without function calling ignore them.
+    pub functions: Vec<ChatCompletionFunctions>,
+    /// Sampling temperature of the response, or None for the provider's
+    /// default
+    pub temperature: Option<f32>,
+}
+
+/// A chat model's response to a `CompletionRequest`
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub message: ChatCompletionResponseMessage,
+    /// The tokens used by the request, if known
+    pub usage: Option<TokenUsage>,
+    /// Why the chat model stopped, such as "stop" or "length", if reported
+    pub finish_reason: Option<String>,
+}
+
+/// Called with each piece of a streamed response's content as it arrives
+pub type OnToken<'a> = dyn FnMut(&str) + Send + 'a;
+
+/// A backend able to complete chat conversations
+#[async_trait]
+pub trait LlmProvider: fmt::Debug + Send + Sync {
+    /// Returns the chat model's next message in the conversation
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError>;
+
+    /// Like `complete`, but calls `on_token` with each piece of the message's
+    /// content as it arrives. The assembled message is returned once the
+    /// response ends.
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError>;
+
+    /// Returns the ids of the chat models available from this provider
+    async fn list_models(&self) -> Result<Vec<String>, ChatError>;
+
+    /// Generates an image from `prompt`. Providers without an image endpoint
+    /// return `ChatError::Unsupported`.
+    async fn generate_image(
+        &self,
+        _prompt: &str,
+        _options: &ImageOptions,
+    ) -> Result<GeneratedImage, ChatError> {
+        Err(ChatError::Unsupported(String::from("image generation")))
+    }
+
+    /// Reads `text` aloud, returning the audio as MP3. Providers without a
+    /// speech endpoint return `ChatError::Unsupported`.
+    async fn synthesize_speech(
+        &self,
+        _text: &str,
+        _options: &SpeechOptions,
+    ) -> Result<Vec<u8>, ChatError> {
+        Err(ChatError::Unsupported(String::from("text to speech")))
+    }
+
+    /// Checks `text` with a moderation endpoint. Providers without one return
+    /// `ChatError::Unsupported`.
+    async fn moderate(&self, _text: &str) -> Result<ModerationResult, ChatError> {
+        Err(ChatError::Unsupported(String::from("moderation")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::chat_requests::first_choice;
+    use crate::ids::MessageId;
+    use crate::{ChatRole, Store};
+    use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionResponse, Role};
+    use mock::MockProvider;
+
+    #[tokio::test]
+    async fn test_store_with_custom_provider() {
+        let mut store = Store::new(MockProvider::echoing());
+
+        let msg = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("Hello there")),
+            name: None,
+            function_call: None,
+        };
+        let id = store
+            .add_session(vec![msg], String::from("Echo"), "echo")
+            .await
+            .unwrap();
+
+        let mut tokens = Vec::new();
+        let response = store
+            .send_message_streaming(
+                id,
+                String::from("General Kenobi"),
+                |token| tokens.push(token.to_string()),
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!("General Kenobi", response.get_content());
+        assert_eq!(vec!["General ", "Kenobi"], tokens);
+        let metadata = response.get_metadata().unwrap();
+        assert_eq!(1, metadata.attempts);
+        assert_eq!(Some("stop"), metadata.finish_reason.as_deref());
+        assert_eq!(4, store.get_session(id).unwrap().message_count());
+    }
+
+    #[tokio::test]
+    async fn test_store_named_providers() {
+        let mut store = Store::new(MockProvider::default());
+        store.register_provider("echo", MockProvider::echoing());
+
+        assert_eq!(vec!["echo"], store.get_provider_names());
+        assert_eq!(vec!["mock"], store.list_models(Some("echo")).await.unwrap());
+
+        let msg = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("Hello there")),
+            name: None,
+            function_call: None,
+        };
+        let id = store
+            .add_session_with_provider(vec![msg], String::from("Echo"), "llama3", Some("echo"))
+            .await
+            .unwrap();
+
+        let response = store
+            .send_message(id, String::from("Hi"), &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!("Hi", response.get_content());
+        assert_eq!(Some("echo"), store.get_session(id).unwrap().get_provider());
+
+        assert_eq!(
+            Err(ChatError::ProviderNotFound(String::from("missing"))),
+            store.set_session_provider(id, Some(String::from("missing")))
+        );
+        assert_eq!(
+            Err(ChatError::ProviderNotFound(String::from("missing"))),
+            store.list_models(Some("missing")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_switch_model_mid_conversation() {
+        let mut store = Store::new(MockProvider::echoing());
+        let msg = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("Hello there")),
+            name: None,
+            function_call: None,
+        };
+        let id = store
+            .add_session(vec![msg], String::from("Echo"), "first")
+            .await
+            .unwrap();
+
+        let catalog = store.model_catalog(None, false).await.unwrap();
+        assert_eq!(
+            Err(ChatError::InvalidModel(String::from("missing"))),
+            store.set_session_model(id, "missing", &catalog)
+        );
+        store.set_session_model(id, "mock", &catalog).unwrap();
+        store
+            .send_message(id, String::from("Hi"), &CancellationToken::new())
+            .await
+            .unwrap();
+
+        let session = store.get_session(id).unwrap();
+        assert_eq!("mock", session.get_model());
+        let models: Vec<Option<&str>> = session
+            .get_messages()
+            .iter()
+            .map(|x| x.get_model())
+            .collect();
+        assert_eq!(vec![None, Some("first"), None, Some("mock")], models);
+    }
+
+    #[tokio::test]
+    async fn test_store_regenerate_message() {
+        let mut store = Store::new(MockProvider::echoing());
+        let id = store.add_empty_session(String::from("Echo"), "echo");
+        let session = store.session_mut(id).unwrap();
+        session.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+        ]);
+        let (hi, hello) = (
+            session.get_messages()[0].get_id(),
+            session.get_messages()[1].get_id(),
+        );
+
+        let cancel = CancellationToken::new();
+        let message = store.regenerate_message(id, hello, &cancel).await.unwrap();
+        assert_eq!("Hi", message.get_content());
+        assert_eq!(["Hello", "Hi"], message.get_variants());
+        assert_eq!(1, message.get_active_variant());
+        assert_eq!(2, store.get_session(id).unwrap().message_count());
+
+        let message = store.select_message_variant(id, hello, 0).unwrap();
+        assert_eq!("Hello", message.get_content());
+        assert_eq!(0, message.get_active_variant());
+
+        assert_eq!(
+            Err(ChatError::VariantNotFound(2)),
+            store.select_message_variant(id, hello, 2).map(|_| ())
+        );
+        assert_eq!(
+            Err(ChatError::NotAResponse(hi)),
+            store.regenerate_message(id, hi, &cancel).await.map(|_| ())
+        );
+        let missing = MessageId::generate();
+        assert_eq!(
+            Err(ChatError::MessageNotFound(missing)),
+            store
+                .regenerate_message(id, missing, &cancel)
+                .await
+                .map(|_| ())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_edit_message() {
+        let mut store = Store::new(MockProvider::echoing());
+        let id = store.add_empty_session(String::from("Echo"), "echo");
+        let session = store.session_mut(id).unwrap();
+        session.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+            (ChatRole::User, String::from("How are you?")),
+            (ChatRole::Assistant, String::from("Good")),
+        ]);
+        let hi = session.get_messages()[0].get_id();
+
+        let cancel = CancellationToken::new();
+        let response = store
+            .edit_message(id, hi, String::from("Hey"), &cancel)
+            .await
+            .unwrap();
+        assert_eq!("Hey", response.get_content());
+
+        let session = store.get_session(id).unwrap();
+        let contents: Vec<String> = session
+            .get_messages()
+            .iter()
+            .map(|x| x.get_content())
+            .collect();
+        assert_eq!(vec!["Hey", "Hey"], contents);
+
+        let reply = session.get_messages()[1].get_id();
+        assert_eq!(
+            Err(ChatError::NotAUserMessage(reply)),
+            store
+                .edit_message(id, reply, String::from("Nope"), &cancel)
+                .await
+                .map(|_| ())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_commit_after_changes() {
+        let mut store = Store::new(MockProvider::echoing());
+        let id = store.add_empty_session(String::from("Echo"), "echo");
+        let session = store.session_mut(id).unwrap();
+        session.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("Hi")),
+            (ChatRole::Assistant, String::from("Hello")),
+        ]);
+        let hello = session.get_messages()[1].get_id();
+
+        let cancel = CancellationToken::new();
+        let pending = store
+            .prepare_message(id, String::from("How are you?"))
+            .unwrap();
+        let regenerate = store.prepare_regenerate(id, hello).unwrap();
+        assert_eq!(Some(id), pending.get_session_id());
+
+        // The store can be changed while the requests are running
+        store.rename_session(id, String::from("Renamed")).unwrap();
+        let completed = pending.complete(&cancel).await.unwrap();
+        let (committed, response) = store.commit(completed).unwrap();
+        assert_eq!(id, committed);
+        assert_eq!("How are you?", response.get_content());
+        assert_eq!(4, store.get_session(id).unwrap().message_count());
+
+        store.delete_session(id);
+        let completed = regenerate.complete(&cancel).await.unwrap();
+        assert_eq!(
+            Err(ChatError::SessionNotFound(id)),
+            store.commit(completed).map(|_| ())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_image_message() {
+        let mut store = Store::new(MockProvider::echoing());
+        let id = store.add_empty_session(String::from("Vision"), "gpt-3.5-turbo");
+
+        let image = std::env::temp_dir().join("chat-overlay-image-message.png");
+        std::fs::write(&image, b"png").unwrap();
+
+        let pending = store
+            .prepare_image_message(id, String::from("What is this?"), image.clone())
+            .unwrap();
+        assert_eq!(crate::vision::VISION_MODEL, pending.request.model);
+        assert_eq!(1, pending.request.images.len());
+
+        let completed = pending.complete(&CancellationToken::new()).await.unwrap();
+        store.commit(completed).unwrap();
+
+        let messages = store.get_session(id).unwrap().get_messages();
+        assert_eq!(Some(image.as_path()), messages[0].get_image());
+        assert_eq!("What is this?", messages[0].plain_text());
+        assert_eq!(None, messages[1].get_image());
+
+        std::fs::remove_file(&image).unwrap();
+        assert!(store
+            .prepare_image_message(id, String::from("And this?"), image)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store_empty_response() {
+        let response = CreateChatCompletionResponse {
+            id: String::from("chatcmpl-empty"),
+            object: String::from("chat.completion"),
+            created: 0,
+            model: String::from("empty"),
+            choices: vec![],
+            usage: None,
+        };
+        assert_eq!(Some(ChatError::EmptyResponse), first_choice(response).err());
+
+        let provider = MockProvider::default();
+        for _ in 0..3 {
+            provider.push_error(ChatError::EmptyResponse);
+        }
+        let mut store = Store::new(provider.clone());
+        let id = store.add_empty_session(String::from("Empty"), "empty");
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("Hi")),
+                (ChatRole::Assistant, String::from("Hello")),
+            ]);
+        let cancel = CancellationToken::new();
+
+        assert_eq!(
+            Err(ChatError::EmptyResponse),
+            store
+                .send_message(id, String::from("Anyone there?"), &cancel)
+                .await
+                .map(|_| ())
+        );
+        assert_eq!(
+            Err(ChatError::EmptyResponse),
+            store
+                .send_message_streaming(id, String::from("Anyone there?"), |_| {}, &cancel)
+                .await
+                .map(|_| ())
+        );
+
+        // The User message is not kept without its response
+        let mut session = store.get_session(id).unwrap().clone();
+        assert_eq!(2, session.message_count());
+        assert_eq!(
+            Err(ChatError::EmptyResponse),
+            session
+                .add_message(String::from("Hello?"), &provider, &cancel)
+                .await
+        );
+        let contents: Vec<String> = session
+            .get_messages()
+            .iter()
+            .map(|x| x.get_content())
+            .collect();
+        assert_eq!(vec!["Hi", "Hello"], contents);
+    }
+}
@@ -0,0 +1,304 @@
+//! `LlmProvider` implementation for a local Ollama server.
+//!
+//! See https://github.com/ollama/ollama/blob/main/docs/api.md for the API.
+
+use super::{Completion, CompletionRequest, LlmProvider, OnToken};
+use crate::retry;
+use crate::timeouts;
+use crate::usage::TokenUsage;
+use crate::ChatError;
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+/// Address Ollama listens on by default
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Talks to the chat models served by an Ollama server
+#[derive(Debug, Clone)]
+pub struct OllamaProvider {
+    base_url: String,
+    http: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<ChatOptions>,
+}
+
+/// Model parameters overriding the ones set in the model's Modelfile
+#[derive(Serialize)]
+struct ChatOptions {
+    temperature: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaMessage {
+    role: Role,
+    content: String,
+    /// Base64 encoded images, for multimodal models
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+/// A full response, or a single chunk of a streamed response
+#[derive(Deserialize)]
+struct ChatResponse {
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    error: Option<String>,
+    /// Tokens in the prompt, sent once the response is done
+    prompt_eval_count: Option<u32>,
+    /// Tokens in the response, sent once the response is done
+    eval_count: Option<u32>,
+    /// Why the model stopped, such as "stop" or "length", sent once the
+    /// response is done
+    done_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TagsResponse {
+    models: Vec<ModelTag>,
+}
+
+#[derive(Deserialize)]
+struct ModelTag {
+    name: String,
+}
+
+impl From<ChatCompletionRequestMessage> for OllamaMessage {
+    fn from(msg: ChatCompletionRequestMessage) -> Self {
+        OllamaMessage {
+            role: msg.role,
+            content: msg.content.unwrap_or_default(),
+            images: vec![],
+        }
+    }
+}
+
+impl Default for OllamaProvider {
+    fn default() -> Self {
+        OllamaProvider::new(DEFAULT_OLLAMA_URL)
+    }
+}
+
+impl OllamaProvider {
+    /// Create a provider for the Ollama server at `base_url`
+    pub fn new(base_url: impl Into<String>) -> OllamaProvider {
+        OllamaProvider {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http: timeouts::default_http_client(),
+        }
+    }
+
+    /// Sends requests with `http` instead, such as a client with timeouts
+    pub fn with_http_client(mut self, http: reqwest::Client) -> OllamaProvider {
+        self.http = http;
+        self
+    }
+
+    /// Returns the address of the Ollama server
+    pub fn get_base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Returns the HTTP client requests are sent with
+    pub fn get_http_client(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Sends a chat request, returning the response once its status has been
+    /// checked.
+    async fn post_chat(
+        &self,
+        request: CompletionRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, ChatError> {
+        let mut messages: Vec<OllamaMessage> = request
+            .messages
+            .into_iter()
+            .map(OllamaMessage::from)
+            .collect();
+        if let Some(last) = messages.last_mut() {
+            last.images = request.images.into_iter().map(|x| x.base64).collect();
+        }
+
+        let body = ChatRequest {
+            model: &request.model,
+            messages,
+            stream,
+            options: request
+                .temperature
+                .map(|temperature| ChatOptions { temperature }),
+        };
+
+        let response = self
+            .http
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+            .send()
+            .await?;
+
+        retry::check_status(response)
+    }
+}
+
+impl ChatResponse {
+    /// Returns the tokens used by the request, if this chunk reports them
+    fn usage(&self) -> Option<TokenUsage> {
+        Some(TokenUsage {
+            prompt_tokens: self.prompt_eval_count?,
+            completion_tokens: self.eval_count?,
+        })
+    }
+}
+
+/// Parses a single line of an Ollama response
+fn parse_chunk(line: &[u8]) -> Result<ChatResponse, ChatError> {
+    let chunk: ChatResponse =
+        serde_json::from_slice(line).map_err(|e| ChatError::Request(e.to_string()))?;
+
+    match chunk.error {
+        Some(error) => Err(ChatError::Request(error)),
+        None => Ok(chunk),
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OllamaProvider {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        let body = self.post_chat(request, false).await?.bytes().await?;
+        let chunk = parse_chunk(&body)?;
+        let usage = chunk.usage();
+        let message = chunk
+            .message
+            .ok_or_else(|| ChatError::Request("Ollama returned no message".to_string()))?;
+
+        Ok(Completion {
+            message: ChatCompletionResponseMessage {
+                role: message.role,
+                content: Some(message.content),
+                function_call: None,
+            },
+            usage,
+            finish_reason: chunk.done_reason,
+        })
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        let mut body = self.post_chat(request, true).await?.bytes_stream();
+
+        // Ollama streams one JSON object per line, which may be split across
+        // or share chunks of the body.
+        let mut buffer = Vec::new();
+        let mut content = String::new();
+        let mut done = false;
+        let mut usage = None;
+        let mut finish_reason = None;
+
+        while let Some(bytes) = body.next().await {
+            buffer.extend_from_slice(&bytes?);
+
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+
+                let chunk = parse_chunk(&line)?;
+                usage = chunk.usage().or(usage);
+                finish_reason = chunk.done_reason.clone().or(finish_reason);
+                if let Some(message) = chunk.message {
+                    if !message.content.is_empty() {
+                        on_token(&message.content);
+                        content.push_str(&message.content);
+                    }
+                }
+                done |= chunk.done;
+            }
+        }
+
+        if !done {
+            return Err(ChatError::Request(
+                "Ollama ended the stream early".to_string(),
+            ));
+        }
+
+        Ok(Completion {
+            message: ChatCompletionResponseMessage {
+                role: Role::Assistant,
+                content: Some(content),
+                function_call: None,
+            },
+            usage,
+            finish_reason,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        let tags: TagsResponse = self
+            .http
+            .get(format!("{}/api/tags", self.base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(tags.models.into_iter().map(|model| model.name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ollama_parse_chunk() {
+        let chunk = parse_chunk(
+            br#"{"model":"llama3","message":{"role":"assistant","content":"Hi"},"done":false}"#,
+        )
+        .unwrap();
+        assert_eq!(None, chunk.usage());
+        let message = chunk.message.unwrap();
+
+        assert_eq!(Role::Assistant, message.role);
+        assert_eq!("Hi", message.content);
+        assert!(!chunk.done);
+
+        let chunk = parse_chunk(
+            br#"{"model":"llama3","done":true,"done_reason":"stop","prompt_eval_count":26,"eval_count":290}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(TokenUsage {
+                prompt_tokens: 26,
+                completion_tokens: 290
+            }),
+            chunk.usage()
+        );
+        assert_eq!(Some("stop"), chunk.done_reason.as_deref());
+
+        assert_eq!(
+            Err(ChatError::Request("model 'nope' not found".to_string())),
+            parse_chunk(br#"{"error":"model 'nope' not found"}"#).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_ollama_trims_base_url() {
+        let provider = OllamaProvider::new("http://localhost:11434/");
+
+        assert_eq!(DEFAULT_OLLAMA_URL, provider.get_base_url());
+    }
+}
@@ -0,0 +1,336 @@
+//! `LlmProvider` implementation for the OpenAI API client.
+
+use super::{Completion, CompletionRequest, LlmProvider, OnToken};
+use crate::chat_requests::{
+    first_choice, requeset_chat_model, request_chat_model_stream, MAX_RESPONSE_TOKENS,
+};
+use crate::embeddings::Embedder;
+use crate::images::{GeneratedImage, ImageOptions};
+use crate::moderation::ModerationResult;
+use crate::retry;
+use crate::speech::SpeechOptions;
+use crate::timeouts;
+use crate::tokens::{count_message_tokens, count_tokens, TOKENS_PER_REPLY};
+use crate::usage::TokenUsage;
+use crate::ChatError;
+use async_openai::{
+    config::Config,
+    types::{CreateChatCompletionResponse, CreateModerationRequestArgs},
+    Client,
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// An OpenAI client along with the HTTP client the requests async-openai can
+/// not make are sent with directly
+#[derive(Debug, Clone)]
+pub struct OpenAiProvider<C: Config> {
+    client: Client<C>,
+    http: reqwest::Client,
+}
+
+impl<C: Config> OpenAiProvider<C> {
+    /// Create a provider sending requests with `client`
+    pub fn new(client: Client<C>) -> OpenAiProvider<C> {
+        OpenAiProvider {
+            client,
+            http: timeouts::default_http_client(),
+        }
+    }
+
+    /// Sends every request with `http` instead, such as a client with
+    /// timeouts or a proxy
+    pub fn with_http_client(self, http: reqwest::Client) -> OpenAiProvider<C> {
+        OpenAiProvider {
+            client: self.client.with_http_client(http.clone()),
+            http,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Config + fmt::Debug + Send + Sync> Embedder for OpenAiProvider<C> {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChatError> {
+        self.client.embed(texts).await
+    }
+}
+
+/// Sends a request with images attached to its last message. The request is
+/// made directly, as async-openai only supports text content.
+async fn complete_with_images<C: Config>(
+    client: &Client<C>,
+    http: &reqwest::Client,
+    request: CompletionRequest,
+) -> Result<Completion, ChatError> {
+    let mut messages: Vec<Value> = request.messages.iter().map(|msg| json!(msg)).collect();
+
+    if let Some(last) = messages.last_mut() {
+        let mut parts = vec![json!({ "type": "text", "text": last["content"] })];
+        parts.extend(
+            request.images.iter().map(
+                |image| json!({ "type": "image_url", "image_url": { "url": image.data_url() } }),
+            ),
+        );
+        last["content"] = Value::Array(parts);
+    }
+
+    let mut body = json!({
+        "model": request.model,
+        "messages": messages,
+        "max_tokens": MAX_RESPONSE_TOKENS,
+    });
+    if !request.functions.is_empty() {
+        body["functions"] = json!(request.functions);
+    }
+    if let Some(temperature) = request.temperature {
+        body["temperature"] = json!(temperature);
+    }
+
+    let config = client.config();
+    let response = http
+        .post(config.url("/chat/completions"))
+        .headers(config.headers())
+        .query(&config.query())
+        .json(&body)
+        .send()
+        .await?;
+    let response: CreateChatCompletionResponse = retry::check_status(response)?.json().await?;
+    let (choice, usage) = first_choice(response)?;
+
+    Ok(Completion {
+        message: choice.message,
+        usage: usage.map(TokenUsage::from),
+        finish_reason: choice.finish_reason,
+    })
+}
+
+#[derive(Deserialize)]
+struct ImagesResponse {
+    data: Vec<ImageObject>,
+}
+
+#[derive(Deserialize)]
+struct ImageObject {
+    b64_json: String,
+    revised_prompt: Option<String>,
+}
+
+#[async_trait]
+impl<C: Config + fmt::Debug + Send + Sync> LlmProvider for OpenAiProvider<C> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        if !request.images.is_empty() {
+            return complete_with_images(&self.client, &self.http, request).await;
+        }
+
+        let (choice, usage) = requeset_chat_model(
+            &self.client,
+            request.messages,
+            Some(&request.model),
+            request.functions,
+            request.temperature,
+        )
+        .await?;
+
+        Ok(Completion {
+            message: choice.message,
+            usage: usage.map(TokenUsage::from),
+            finish_reason: choice.finish_reason,
+        })
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        // Requests with images are not streamed, so the response arrives whole
+        if !request.images.is_empty() {
+            let completion = complete_with_images(&self.client, &self.http, request).await?;
+            on_token(completion.message.content.as_deref().unwrap_or_default());
+            return Ok(completion);
+        }
+
+        // Streamed responses do not report their usage, so it is estimated
+        let prompt_tokens = request
+            .messages
+            .iter()
+            .map(count_message_tokens)
+            .sum::<usize>()
+            + TOKENS_PER_REPLY;
+
+        let model = Some(request.model.as_str());
+        let (message, finish_reason) = request_chat_model_stream(
+            &self.client,
+            request.messages,
+            model,
+            request.functions,
+            request.temperature,
+            on_token,
+        )
+        .await?;
+        let completion_tokens = message.content.as_deref().map_or(0, count_tokens);
+
+        Ok(Completion {
+            message,
+            usage: Some(TokenUsage {
+                prompt_tokens: prompt_tokens as u32,
+                completion_tokens: completion_tokens as u32,
+            }),
+            finish_reason,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        let models = self.client.models().list().await?;
+
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+
+    // Made directly, as async-openai does not support picking the model or
+    // quality of images
+    async fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> Result<GeneratedImage, ChatError> {
+        let mut body = json!({
+            "model": options.model,
+            "prompt": prompt,
+            "n": 1,
+            "size": options.size,
+            "response_format": "b64_json",
+        });
+        // Only DALL-E 3 and later take a quality
+        if options.model != "dall-e-2" {
+            body["quality"] = json!(options.quality);
+        }
+
+        let config = self.client.config();
+        let response = self
+            .http
+            .post(config.url("/images/generations"))
+            .headers(config.headers())
+            .query(&config.query())
+            .json(&body)
+            .send()
+            .await?;
+        let response: ImagesResponse = retry::check_status(response)?.json().await?;
+
+        let image = response
+            .data
+            .into_iter()
+            .next()
+            .ok_or(ChatError::EmptyResponse)?;
+
+        Ok(GeneratedImage {
+            base64: image.b64_json,
+            revised_prompt: image.revised_prompt,
+        })
+    }
+
+    async fn synthesize_speech(
+        &self,
+        text: &str,
+        options: &SpeechOptions,
+    ) -> Result<Vec<u8>, ChatError> {
+        let body = json!({
+            "model": options.model,
+            "input": text,
+            "voice": options.voice,
+            "response_format": "mp3",
+        });
+
+        let config = self.client.config();
+        let response = self
+            .http
+            .post(config.url("/audio/speech"))
+            .headers(config.headers())
+            .query(&config.query())
+            .json(&body)
+            .send()
+            .await?;
+        let audio = retry::check_status(response)?.bytes().await?;
+
+        Ok(audio.to_vec())
+    }
+
+    async fn moderate(&self, text: &str) -> Result<ModerationResult, ChatError> {
+        let request = CreateModerationRequestArgs::default()
+            .input(text.to_string())
+            .build()?;
+        let response = self.client.moderations().create(request).await?;
+        let result = response
+            .results
+            .into_iter()
+            .next()
+            .ok_or(ChatError::EmptyResponse)?;
+
+        // The categories are fields named after them, so they are read back
+        // out by name
+        let categories: BTreeMap<String, bool> = serde_json::to_value(result.categories)
+            .and_then(serde_json::from_value)
+            .unwrap_or_default();
+        let scores: BTreeMap<String, f32> = serde_json::to_value(result.category_scores)
+            .and_then(serde_json::from_value)
+            .unwrap_or_default();
+
+        Ok(ModerationResult {
+            flagged: result.flagged,
+            categories: categories
+                .into_iter()
+                .filter_map(|(name, flagged)| flagged.then_some(name))
+                .collect(),
+            scores,
+        })
+    }
+}
+
+/// A bare client sends the requests it can not make itself with the default
+/// HTTP client
+#[async_trait]
+impl<C: Config + fmt::Debug + Send + Sync> LlmProvider for Client<C> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        OpenAiProvider::new(self.clone()).complete(request).await
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        OpenAiProvider::new(self.clone())
+            .stream(request, on_token)
+            .await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        OpenAiProvider::new(self.clone()).list_models().await
+    }
+
+    async fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> Result<GeneratedImage, ChatError> {
+        OpenAiProvider::new(self.clone())
+            .generate_image(prompt, options)
+            .await
+    }
+
+    async fn synthesize_speech(
+        &self,
+        text: &str,
+        options: &SpeechOptions,
+    ) -> Result<Vec<u8>, ChatError> {
+        OpenAiProvider::new(self.clone())
+            .synthesize_speech(text, options)
+            .await
+    }
+
+    async fn moderate(&self, text: &str) -> Result<ModerationResult, ChatError> {
+        OpenAiProvider::new(self.clone()).moderate(text).await
+    }
+}
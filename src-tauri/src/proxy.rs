@@ -0,0 +1,137 @@
+//! Sending requests through an HTTP or SOCKS proxy.
+//!
+//! Each profile can name a proxy its provider's requests are sent through,
+//! along with hosts reached directly instead. A proxy asking for credentials
+//! is given the username saved with the profile and the password stored for
+//! it in the platform keyring.
+
+use crate::ChatError;
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+use serde::{Deserialize, Serialize};
+
+/// Schemes a proxy can be reached with
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+/// A proxy requests are sent through
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Address of the proxy, such as `http://proxy.corp:3128` or
+    /// `socks5://127.0.0.1:1080`
+    pub url: String,
+
+    /// Username given to the proxy, if it asks for credentials. The password
+    /// is kept in the keyring.
+    pub username: Option<String>,
+
+    /// Hosts, domains and IP ranges reached without the proxy, written like
+    /// the `NO_PROXY` environment variable, such as `localhost` or
+    /// `.corp.example.com`
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    /// Returns whether the proxy asks for credentials
+    pub fn has_auth(&self) -> bool {
+        self.username.as_deref().is_some_and(|x| !x.is_empty())
+    }
+
+    /// Returns the proxy, authenticating with the username and `password`
+    /// if it asks for credentials.
+    ///
+    /// Returns `ChatError::InvalidEndpoint` if the URL can not be used.
+    pub fn to_proxy(&self, password: Option<&str>) -> Result<Proxy, ChatError> {
+        let url = self.url.trim();
+        let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+        if !scheme.is_some_and(|x| PROXY_SCHEMES.contains(&x.to_lowercase().as_str())) {
+            return Err(ChatError::InvalidEndpoint(format!(
+                "\"{}\" is not an http, https or socks5 proxy",
+                url
+            )));
+        }
+
+        let mut proxy = Proxy::all(url).map_err(|e| ChatError::InvalidEndpoint(e.to_string()))?;
+        if let (true, Some(username)) = (self.has_auth(), &self.username) {
+            proxy = proxy.basic_auth(username, password.unwrap_or_default());
+        }
+
+        Ok(proxy.no_proxy(NoProxy::from_string(&self.no_proxy.join(","))))
+    }
+
+    /// Sends the requests of the client built by `builder` through the proxy
+    pub fn apply(
+        &self,
+        builder: ClientBuilder,
+        password: Option<&str>,
+    ) -> Result<ClientBuilder, ChatError> {
+        Ok(builder.proxy(self.to_proxy(password)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::ollama::OllamaProvider;
+    use crate::providers::LlmProvider;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_proxy_config_urls() {
+        let mut config = ProxyConfig {
+            url: String::from("ftp://proxy.corp"),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.to_proxy(None),
+            Err(ChatError::InvalidEndpoint(_))
+        ));
+
+        config.url = String::from("proxy.corp:3128");
+        assert!(config.to_proxy(None).is_err());
+
+        config.url = String::from(" http://proxy.corp:3128 ");
+        config.username = Some(String::new());
+        assert!(!config.has_auth());
+        assert!(config.to_proxy(None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            username: Some(String::from("ada")),
+            no_proxy: vec![],
+        };
+
+        // Answers a single request, returning what was asked of it
+        let proxy = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = vec![0; 4096];
+            let read = socket.read(&mut request).unwrap();
+            let body = r#"{"models":[{"name":"llama3"}]}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).unwrap();
+
+            String::from_utf8_lossy(&request[..read]).to_string()
+        });
+
+        let http = config
+            .apply(reqwest::Client::builder(), Some("secret"))
+            .unwrap()
+            .build()
+            .unwrap();
+        let provider = OllamaProvider::new("http://models.invalid").with_http_client(http);
+        assert_eq!(vec!["llama3"], provider.list_models().await.unwrap());
+
+        let request = proxy.join().unwrap();
+        assert!(request.starts_with("GET http://models.invalid/api/tags "));
+        // "ada:secret" in base64
+        assert!(request.contains("Basic YWRhOnNlY3JldA=="));
+    }
+}
@@ -0,0 +1,388 @@
+//! Keeping requests to each provider within its rate limits.
+//!
+//! Providers with a requests-per-minute or tokens-per-minute limit configured
+//! have their requests sent through a queue. A request waits in the queue,
+//! in the order it arrived, until sending it keeps the provider within its
+//! limits over the last minute. This keeps a burst of messages from being
+//! answered with a burst of rate limit errors. The number of requests waiting
+//! is reported to the store's listener. The limits are saved to their own
+//! file in the app config directory.
+
+use crate::events::{QueueDepthPayload, StoreEvent, StoreListener};
+use crate::images::{GeneratedImage, ImageOptions};
+use crate::moderation::ModerationResult;
+use crate::persistence::JsonConfig;
+use crate::providers::{Completion, CompletionRequest, LlmProvider, OnToken};
+use crate::speech::SpeechOptions;
+use crate::{chat_requests, tokens, ChatError};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Name of the file the rate limits are saved to inside the app config
+/// directory
+pub const RATE_LIMIT_FILE_NAME: &str = "rate_limits.json";
+
+/// The window the limits apply to
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How many requests and tokens may be sent to a provider each minute. A
+/// limit of None is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl RateLimit {
+    /// Returns whether this limit lets every request through
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.is_none() && self.tokens_per_minute.is_none()
+    }
+}
+
+/// The rate limits of every provider
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Limits of the default provider
+    pub default: RateLimit,
+
+    /// Limits of the providers registered by name, keyed by their name
+    pub providers: BTreeMap<String, RateLimit>,
+}
+
+impl JsonConfig for RateLimitConfig {}
+
+/// The requests sent to a provider within the last minute, oldest first,
+/// along with the tokens each may use
+#[derive(Debug, Default)]
+struct Window {
+    sent: VecDeque<(Instant, u32)>,
+}
+
+impl Window {
+    /// Forgets the requests sent a minute or more before `now`
+    fn expire(&mut self, now: Instant) {
+        while matches!(self.sent.front(), Some((at, _)) if now.duration_since(*at) >= WINDOW) {
+            self.sent.pop_front();
+        }
+    }
+
+    /// Returns how long from `now` a request using `tokens` has to wait to
+    /// keep within `limit`, or None if it can be sent right away. A request
+    /// using more tokens than the limit allows is sent once the window is
+    /// empty, as waiting longer would not help.
+    fn wait_time(&self, limit: &RateLimit, tokens: u32, now: Instant) -> Option<Duration> {
+        let mut until: Option<Instant> = None;
+
+        if let Some(rpm) = limit.requests_per_minute {
+            let rpm = rpm.max(1) as usize;
+            if self.sent.len() >= rpm {
+                until = Some(self.sent[self.sent.len() - rpm].0 + WINDOW);
+            }
+        }
+
+        if let Some(tpm) = limit.tokens_per_minute {
+            let mut used: u32 = self.sent.iter().map(|(_, x)| *x).sum();
+            // Waits for the oldest requests to leave the window until there
+            // is room
+            for (at, sent) in &self.sent {
+                if used.saturating_add(tokens) <= tpm {
+                    break;
+                }
+                used -= sent;
+                until = until.max(Some(*at + WINDOW));
+            }
+        }
+
+        until
+            .map(|x| x.saturating_duration_since(now))
+            .filter(|x| !x.is_zero())
+    }
+}
+
+/// Requests waiting to be sent to a single provider
+#[derive(Debug)]
+struct RequestQueue {
+    limit: RateLimit,
+    /// Only one request is let through at a time. Tokio's mutex is fair, so
+    /// requests are let through in the order they arrived.
+    window: tokio::sync::Mutex<Window>,
+    waiting: AtomicUsize,
+}
+
+/// Takes a request back out of the queue once it is let through, or given up
+/// on, and reports the new depth
+struct Waiting<'a> {
+    queue: &'a RequestQueue,
+    report: &'a (dyn Fn(usize) + Sync),
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let depth = self.queue.waiting.fetch_sub(1, Ordering::SeqCst) - 1;
+        (self.report)(depth);
+    }
+}
+
+impl RequestQueue {
+    fn new(limit: RateLimit) -> RequestQueue {
+        RequestQueue {
+            limit,
+            window: tokio::sync::Mutex::new(Window::default()),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits until a request using `tokens` can be sent, then records it as
+    /// sent. `report` is called with the number of requests waiting each
+    /// time it changes.
+    async fn acquire(&self, tokens: u32, report: &(dyn Fn(usize) + Sync)) {
+        let depth = self.waiting.fetch_add(1, Ordering::SeqCst) + 1;
+        report(depth);
+        let _waiting = Waiting {
+            queue: self,
+            report,
+        };
+
+        let mut window = self.window.lock().await;
+        loop {
+            let now = Instant::now();
+            window.expire(now);
+            match window.wait_time(&self.limit, tokens, now) {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => {
+                    window.sent.push_back((now, tokens));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The queues of the providers with rate limits
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    /// Keyed by provider name, None being the default provider
+    queues: HashMap<Option<String>, Arc<RequestQueue>>,
+}
+
+impl RateLimiter {
+    pub fn get_config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Replaces the limits with `config`. Providers whose limits did not
+    /// change keep their queue, along with the requests already sent.
+    pub fn set_config(&mut self, config: RateLimitConfig) {
+        let limits = std::iter::once((None, config.default)).chain(
+            config
+                .providers
+                .iter()
+                .map(|(name, limit)| (Some(name.clone()), *limit)),
+        );
+
+        let mut queues = HashMap::new();
+        for (name, limit) in limits.filter(|(_, x)| !x.is_unlimited()) {
+            let queue = match self.queues.remove(&name) {
+                Some(queue) if queue.limit == limit => queue,
+                _ => Arc::new(RequestQueue::new(limit)),
+            };
+            queues.insert(name, queue);
+        }
+
+        self.queues = queues;
+        self.config = config;
+    }
+
+    /// Returns `provider`, registered under `name`, with its requests sent
+    /// through its queue if it has rate limits. Changes to the depth of the
+    /// queue are reported to `listener`.
+    pub fn wrap(
+        &self,
+        name: Option<&str>,
+        provider: Arc<dyn LlmProvider>,
+        listener: Option<Arc<dyn StoreListener>>,
+    ) -> Arc<dyn LlmProvider> {
+        match self.queues.get(&name.map(String::from)) {
+            Some(queue) => Arc::new(RateLimited {
+                inner: provider,
+                name: name.map(String::from),
+                queue: queue.clone(),
+                listener,
+            }),
+            None => provider,
+        }
+    }
+}
+
+/// A provider whose requests wait in a queue to keep within its rate limits
+#[derive(Debug)]
+struct RateLimited {
+    inner: Arc<dyn LlmProvider>,
+    name: Option<String>,
+    queue: Arc<RequestQueue>,
+    listener: Option<Arc<dyn StoreListener>>,
+}
+
+impl RateLimited {
+    /// Waits for `request`'s turn to be sent
+    async fn acquire(&self, request: &CompletionRequest) {
+        // Providers count the most tokens a request may use against the
+        // limit, so the response's are counted in full
+        let tokens = request
+            .messages
+            .iter()
+            .map(tokens::count_message_tokens)
+            .sum::<usize>()
+            + tokens::TOKENS_PER_REPLY
+            + chat_requests::MAX_RESPONSE_TOKENS as usize;
+
+        let report = |depth| {
+            if let Some(listener) = &self.listener {
+                listener.on_event(&StoreEvent::QueueDepth(QueueDepthPayload {
+                    provider: self.name.clone(),
+                    depth,
+                }));
+            }
+        };
+
+        self.queue
+            .acquire(u32::try_from(tokens).unwrap_or(u32::MAX), &report)
+            .await;
+    }
+}
+
+#[async_trait]
+impl LlmProvider for RateLimited {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        self.acquire(&request).await;
+        self.inner.complete(request).await
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        self.acquire(&request).await;
+        self.inner.stream(request, on_token).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        self.inner.list_models().await
+    }
+
+    async fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> Result<GeneratedImage, ChatError> {
+        self.inner.generate_image(prompt, options).await
+    }
+
+    async fn synthesize_speech(
+        &self,
+        text: &str,
+        options: &SpeechOptions,
+    ) -> Result<Vec<u8>, ChatError> {
+        self.inner.synthesize_speech(text, options).await
+    }
+
+    async fn moderate(&self, text: &str) -> Result<ModerationResult, ChatError> {
+        self.inner.moderate(text).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+    use std::sync::Mutex;
+
+    /// Records the queue depths it is told about
+    #[derive(Debug, Clone, Default)]
+    struct DepthRecorder(Arc<Mutex<Vec<usize>>>);
+
+    impl StoreListener for DepthRecorder {
+        fn on_event(&self, event: &StoreEvent) {
+            if let StoreEvent::QueueDepth(payload) = event {
+                self.0.lock().unwrap().push(payload.depth);
+            }
+        }
+    }
+
+    #[test]
+    fn test_window_wait_time() {
+        let start = Instant::now();
+        let limit = RateLimit {
+            requests_per_minute: Some(2),
+            tokens_per_minute: Some(100),
+        };
+        let mut window = Window::default();
+        assert_eq!(None, window.wait_time(&limit, 500, start));
+
+        window.sent.push_back((start, 30));
+        assert_eq!(None, window.wait_time(&limit, 70, start));
+        assert_eq!(Some(WINDOW), window.wait_time(&limit, 71, start));
+
+        let later = start + Duration::from_secs(10);
+        window.sent.push_back((later, 10));
+        assert_eq!(
+            Some(Duration::from_secs(50)),
+            window.wait_time(&limit, 1, later)
+        );
+
+        // Both requests have to leave the window to make room
+        window.sent.pop_front();
+        window.sent.push_front((start, 80));
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            window.wait_time(&limit, 95, later)
+        );
+
+        window.expire(start + WINDOW);
+        assert_eq!(1, window.sent.len());
+    }
+
+    #[tokio::test]
+    async fn test_store_queues_rate_limited_requests() {
+        let recorder = DepthRecorder::default();
+        let mut store = Store::new(MockProvider::canned("Hi"));
+        store.set_listener(recorder.clone());
+        store.set_rate_limit_config(RateLimitConfig {
+            default: RateLimit {
+                requests_per_minute: Some(1),
+                tokens_per_minute: None,
+            },
+            ..Default::default()
+        });
+        let id = store.add_empty_session(String::from("Limited"), "greeter");
+        let cancel = CancellationToken::new();
+
+        let request = store.prepare_message(id, String::from("One")).unwrap();
+        let completed = request.complete(&cancel).await.unwrap();
+        store.commit(completed).unwrap();
+        assert_eq!(vec![1, 0], std::mem::take(&mut *recorder.0.lock().unwrap()));
+
+        // The next request waits for the minute to pass, until it is given up
+        let request = store.prepare_message(id, String::from("Two")).unwrap();
+        let waited = tokio::time::timeout(Duration::from_millis(50), request.complete(&cancel));
+        assert!(waited.await.is_err());
+        assert_eq!(vec![1, 0], std::mem::take(&mut *recorder.0.lock().unwrap()));
+
+        // Lifting the limit lets requests straight through
+        store.set_rate_limit_config(RateLimitConfig::default());
+        let request = store.prepare_message(id, String::from("Three")).unwrap();
+        request.complete(&cancel).await.unwrap();
+        assert!(recorder.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+//! Retrying chat requests that fail for reasons that may go away.
+//!
+//! Rate limiting and server errors surface as `ChatError::Transient`, and
+//! unreachable servers as `ChatError::Network`. Those requests are sent again
+//! after a jittered, exponentially growing delay, or after however long the
+//! server asked to wait.
+
+use crate::cancellation::CancellationToken;
+use crate::ChatError;
+use async_openai::{config::Config, Client};
+use backoff::ExponentialBackoff;
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use std::future::Future;
+use std::time::Duration;
+
+/// How often and how patiently failed requests are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most times a request is sent, including the first. Requests are
+    /// sent at least once even if this is 0.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry. Each retry after that waits twice as
+    /// long as the one before.
+    pub base_delay: Duration,
+
+    /// The longest delay between two attempts, unless the server asks for
+    /// more
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy sending every request only once
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Returns how long to wait after attempt number `attempt` failed. The
+    /// delay is at least `retry_after`, if the server sent one.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self.base_delay.saturating_mul(factor).min(self.max_delay);
+
+        // Jitter keeps clients that failed together from retrying together
+        let backoff = backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+
+        match retry_after {
+            Some(retry_after) => backoff.max(retry_after),
+            None => backoff,
+        }
+    }
+}
+
+/// Calls `request` until it succeeds, fails with an error that is not
+/// transient, or `policy` runs out of attempts. Returns the result along with
+/// the number of attempts made.
+///
+/// Returns `ChatError::Cancelled` if `cancel` is cancelled while a request is
+/// running or between attempts.
+pub async fn retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    cancel: &CancellationToken,
+    mut request: F,
+) -> Result<(T, u32), ChatError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ChatError>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        let result = tokio::select! {
+            result = request() => result,
+            _ = cancel.cancelled() => return Err(ChatError::Cancelled),
+        };
+
+        match result {
+            Ok(value) => return Ok((value, attempt)),
+            Err(e) if e.is_transient() && attempt < policy.max_attempts => {
+                tokio::select! {
+                    _ = tokio::time::sleep(policy.delay(attempt, e.retry_after())) => {}
+                    _ = cancel.cancelled() => return Err(ChatError::Cancelled),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Turns off the backoff `client` retries rate limited and failed requests
+/// with on its own, so they are only retried by `retry` under a
+/// `RetryPolicy`
+pub fn without_backoff<C: Config>(client: Client<C>) -> Client<C> {
+    client.with_backoff(ExponentialBackoff {
+        max_elapsed_time: Some(Duration::ZERO),
+        ..Default::default()
+    })
+}
+
+/// Returns true if a response with `status` may succeed when sent again
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Checks the status of `response`, turning rate limiting and server errors
+/// into `ChatError::Transient` with the delay from its `Retry-After` header.
+pub fn check_status(response: Response) -> Result<Response, ChatError> {
+    let status = response.status();
+
+    if !is_transient_status(status) {
+        return Ok(response.error_for_status()?);
+    }
+
+    // Only the delay-seconds form of the header is supported
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+
+    Err(ChatError::Transient(status.to_string(), retry_after))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails with a transient error `failures` times, then answers
+    fn flaky(failures: u32) -> MockProvider {
+        let provider = MockProvider::canned("Finally");
+        for _ in 0..failures {
+            provider.push_error(ChatError::Transient(String::from("503"), None));
+        }
+        provider
+    }
+
+    fn quick_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+
+        let first = policy.delay(1, None);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+
+        let capped = policy.delay(4, None);
+        assert!(capped >= Duration::from_millis(150) && capped <= Duration::from_millis(300));
+
+        let asked = policy.delay(1, Some(Duration::from_secs(2)));
+        assert_eq!(Duration::from_secs(2), asked);
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let mut store = Store::new(flaky(2));
+        store.set_retry_policy(quick_policy(3));
+        let id = store.add_empty_session(String::from("Flaky"), "flaky");
+
+        let response = store
+            .send_message(id, String::from("Hello?"), &CancellationToken::new())
+            .await
+            .unwrap();
+
+        assert_eq!("Finally", response.get_content());
+        assert_eq!(Some(3), response.get_metadata().map(|x| x.attempts));
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let mut store = Store::new(flaky(5));
+        store.set_retry_policy(quick_policy(2));
+        let id = store.add_empty_session(String::from("Flaky"), "flaky");
+
+        let response = store
+            .send_message(id, String::from("Hello?"), &CancellationToken::new())
+            .await;
+
+        assert_eq!(
+            Err(ChatError::Transient(String::from("503"), None)),
+            response.map(|_| ())
+        );
+        assert_eq!(0, store.get_session(id).unwrap().message_count());
+    }
+
+    #[tokio::test]
+    async fn test_retry_skips_other_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<((), u32), ChatError> =
+            retry(&quick_policy(3), &CancellationToken::new(), || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(ChatError::Request(String::from("Invalid model")))
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,95 @@
+//! The roles of the messages in a session.
+//!
+//! Messages carry a `ChatRole` owned by this crate rather than the role type
+//! of the OpenAI client, so the shape the frontend sees does not change when
+//! the client does. Roles are converted to and from the client's when
+//! requests are built and responses stored.
+
+use crate::ChatError;
+use async_openai::types::Role;
+use serde::{Deserialize, Serialize};
+
+/// Who a message is from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    /// Instructions given to the chat model ahead of the conversation
+    System,
+    User,
+    Assistant,
+    /// The result of a tool the chat model called. Saved by older versions
+    /// as "function".
+    #[serde(alias = "function")]
+    Tool,
+}
+
+impl From<Role> for ChatRole {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::System => ChatRole::System,
+            Role::User => ChatRole::User,
+            Role::Assistant => ChatRole::Assistant,
+            Role::Function => ChatRole::Tool,
+        }
+    }
+}
+
+impl From<ChatRole> for Role {
+    fn from(role: ChatRole) -> Self {
+        match role {
+            ChatRole::System => Role::System,
+            ChatRole::User => Role::User,
+            ChatRole::Assistant => Role::Assistant,
+            ChatRole::Tool => Role::Function,
+        }
+    }
+}
+
+/// Checks that `roles` are those a session can start with, a User message
+/// or a System message then a User message. Returns
+/// `ChatError::InvalidOpeningRoles` otherwise.
+pub fn validate_opening(roles: &[ChatRole]) -> Result<(), ChatError> {
+    match roles {
+        [ChatRole::User] | [ChatRole::System, ChatRole::User] => Ok(()),
+        _ => Err(ChatError::InvalidOpeningRoles(roles.to_vec())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_opening() {
+        assert!(validate_opening(&[ChatRole::User]).is_ok());
+        assert!(validate_opening(&[ChatRole::System, ChatRole::User]).is_ok());
+
+        for roles in [
+            vec![],
+            vec![ChatRole::Assistant],
+            vec![ChatRole::System],
+            vec![ChatRole::User, ChatRole::User],
+            vec![ChatRole::User, ChatRole::System],
+        ] {
+            assert_eq!(
+                Some(ChatError::InvalidOpeningRoles(roles.clone())),
+                validate_opening(&roles).err()
+            );
+        }
+        assert_eq!(
+            "Sessions start with a User message, or a System message then a User message, not system, assistant",
+            ChatError::InvalidOpeningRoles(vec![ChatRole::System, ChatRole::Assistant]).to_string()
+        );
+    }
+
+    #[test]
+    fn test_chat_role_conversions() {
+        for role in [Role::System, Role::User, Role::Assistant, Role::Function] {
+            assert_eq!(role, Role::from(ChatRole::from(role.clone())));
+        }
+
+        assert_eq!("\"tool\"", serde_json::to_string(&ChatRole::Tool).unwrap());
+        let old: ChatRole = serde_json::from_str("\"function\"").unwrap();
+        assert_eq!(ChatRole::Tool, old);
+    }
+}
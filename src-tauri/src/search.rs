@@ -0,0 +1,284 @@
+//! Ranked full-text search over session titles and message contents.
+
+use crate::ids::{MessageId, SessionId};
+use crate::{escape_html, ChatSession};
+use serde::Serialize;
+
+/// Characters of context kept on each side of the first match in a snippet
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Score multiplier for matches in a session's title
+const TITLE_WEIGHT: usize = 3;
+
+/// A session title or message matching a search
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub session_id: SessionId,
+    /// Id of the matching message, or None if the session's title matched
+    pub message_id: Option<MessageId>,
+    /// HTML escaped excerpt around the first match, with every match wrapped
+    /// in `<mark>` tags
+    pub snippet: String,
+    /// Higher scores are better matches
+    pub score: usize,
+}
+
+/// Returns the length in bytes of the start of `haystack` matching `needle`,
+/// ignoring case, or None if it does not start with `needle`
+fn starts_with_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    let mut haystack_chars = haystack.char_indices();
+    let mut needle_chars = needle.chars().flat_map(char::to_lowercase);
+
+    let mut expected = needle_chars.next();
+    for (idx, c) in haystack_chars.by_ref() {
+        for lower in c.to_lowercase() {
+            match expected {
+                Some(e) if e == lower => expected = needle_chars.next(),
+                _ => return None,
+            }
+        }
+
+        if expected.is_none() {
+            return Some(idx + c.len_utf8());
+        }
+    }
+
+    None
+}
+
+/// Returns the byte ranges in `text` matching any of `terms`, ignoring case.
+/// Ranges are sorted and do not overlap.
+fn find_matches(text: &str, terms: &[String]) -> Vec<(usize, usize)> {
+    let mut matches = Vec::new();
+    let mut idx = 0;
+
+    while idx < text.len() {
+        let rest = &text[idx..];
+        let longest = terms
+            .iter()
+            .filter_map(|term| starts_with_ignore_case(rest, term))
+            .max();
+
+        match longest {
+            Some(len) => {
+                matches.push((idx, idx + len));
+                idx += len;
+            }
+            None => idx += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+
+    matches
+}
+
+/// Returns the byte index `chars` characters before `idx` in `text`
+fn back_chars(text: &str, idx: usize, chars: usize) -> usize {
+    text[..idx]
+        .char_indices()
+        .rev()
+        .take(chars)
+        .last()
+        .map_or(idx, |(i, _)| i)
+}
+
+/// Returns the byte index `chars` characters after `idx` in `text`
+fn forward_chars(text: &str, idx: usize, chars: usize) -> usize {
+    text[idx..]
+        .char_indices()
+        .nth(chars)
+        .map_or(text.len(), |(i, _)| idx + i)
+}
+
+/// Builds the snippet of `text` around the first of `matches`
+fn snippet(text: &str, matches: &[(usize, usize)]) -> String {
+    let (first_start, first_end) = matches[0];
+    let start = back_chars(text, first_start, SNIPPET_CONTEXT);
+    let end = forward_chars(text, first_end, SNIPPET_CONTEXT);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+
+    let mut offset = start;
+    for &(match_start, match_end) in matches.iter().filter(|(s, e)| *s >= start && *e <= end) {
+        snippet.push_str(&escape_html(&text[offset..match_start]));
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape_html(&text[match_start..match_end]));
+        snippet.push_str("</mark>");
+        offset = match_end;
+    }
+    snippet.push_str(&escape_html(&text[offset..end]));
+
+    if end < text.len() {
+        snippet.push('…');
+    }
+
+    snippet
+}
+
+/// Returns the matches in `text` if it contains every one of `terms`
+fn match_all(text: &str, terms: &[String]) -> Option<Vec<(usize, usize)>> {
+    let matches = find_matches(text, terms);
+
+    let all_found = terms.iter().all(|term| {
+        matches
+            .iter()
+            .any(|(start, end)| text[*start..*end].to_lowercase() == *term)
+    });
+
+    (all_found && !matches.is_empty()).then_some(matches)
+}
+
+/// Builds the result of a match for `query`, found by a backend's full-text
+/// index, in the title of `session` or in its message with id `message_id`.
+/// Returns None if the message no longer exists.
+pub fn result_of(
+    session: &ChatSession,
+    message_id: Option<MessageId>,
+    query: &str,
+    score: usize,
+) -> Option<SearchResult> {
+    let text = match message_id {
+        Some(id) => session.messages.values().find(|x| x.id == id)?.plain_text(),
+        None => session.title.clone(),
+    };
+    let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    let snippet = match match_all(&text, &terms) {
+        Some(matches) => snippet(&text, &matches),
+        // The index also matches words ignoring their accents
+        None => {
+            let end = forward_chars(&text, 0, SNIPPET_CONTEXT * 2);
+            let ellipsis = if end < text.len() { "…" } else { "" };
+            format!("{}{}", escape_html(&text[..end]), ellipsis)
+        }
+    };
+
+    Some(SearchResult {
+        session_id: session.id,
+        message_id,
+        snippet,
+        score,
+    })
+}
+
+/// Searches the titles and message contents of `sessions` for `query`,
+/// ignoring case. Every whitespace separated word of the query must appear
+/// for a title or message to match. Results are sorted from best to worst
+/// match, with newer sessions first among equal scores.
+pub fn search<'a>(
+    sessions: impl IntoIterator<Item = &'a ChatSession>,
+    query: &str,
+) -> Vec<SearchResult> {
+    let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    if terms.is_empty() {
+        return Vec::new();
+    }
+
+    let mut results = Vec::new();
+
+    for session in sessions {
+        if let Some(matches) = match_all(&session.title, &terms) {
+            results.push((
+                session.last_activity(),
+                SearchResult {
+                    session_id: session.id,
+                    message_id: None,
+                    snippet: snippet(&session.title, &matches),
+                    score: matches.len() * TITLE_WEIGHT,
+                },
+            ));
+        }
+
+        for msg in session.messages.values() {
+            let text = msg.plain_text();
+            if let Some(matches) = match_all(&text, &terms) {
+                results.push((
+                    msg.created_at,
+                    SearchResult {
+                        session_id: session.id,
+                        message_id: Some(msg.id),
+                        snippet: snippet(&text, &matches),
+                        score: matches.len(),
+                    },
+                ));
+            }
+        }
+    }
+
+    results.sort_by(|(a_time, a), (b_time, b)| b.score.cmp(&a.score).then(b_time.cmp(a_time)));
+
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatRole;
+
+    #[test]
+    fn test_find_matches() {
+        let terms = vec![String::from("rust"), String::from("ß")];
+
+        assert_eq!(vec![(0, 4), (9, 13)], find_matches("Rust and RUST", &terms));
+        assert_eq!(vec![(2, 4)], find_matches("Maß", &terms));
+        assert!(find_matches("Trust", &[String::from("trusty")]).is_empty());
+    }
+
+    #[test]
+    fn test_snippet() {
+        let text = format!("{}needle <b>{}", "a".repeat(50), "z".repeat(50));
+        let matches = find_matches(&text, &[String::from("needle")]);
+
+        assert_eq!(
+            format!(
+                "…{}<mark>needle</mark> &lt;b&gt;{}…",
+                "a".repeat(40),
+                "z".repeat(36)
+            ),
+            snippet(&text, &matches)
+        );
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let mut rust = ChatSession::new(String::from("Rust questions"), "gpt-3.5-turbo");
+        rust.add_message_batch_without_api(vec![
+            (ChatRole::User, String::from("How do I learn rust?")),
+            (
+                ChatRole::Assistant,
+                String::from("Read the rust book, then write rust"),
+            ),
+        ]);
+        let mut other = ChatSession::new(String::from("Other"), "gpt-3.5-turbo");
+        other.add_message_batch_without_api(vec![(
+            ChatRole::User,
+            String::from("Rust never sleeps"),
+        )]);
+
+        rust.messages.values_mut().for_each(|x| x.created_at = 100);
+        other.messages[0].created_at = 50;
+        let (rust_id, other_id) = (rust.get_id(), other.get_id());
+        let rust_ids: Vec<MessageId> = rust.get_messages().iter().map(|x| x.get_id()).collect();
+        let other_ids: Vec<MessageId> = other.get_messages().iter().map(|x| x.get_id()).collect();
+
+        let results = search(&[rust, other], "RUST");
+        let found: Vec<(SessionId, Option<MessageId>, usize)> = results
+            .iter()
+            .map(|x| (x.session_id, x.message_id, x.score))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (rust_id, None, 3),
+                (rust_id, Some(rust_ids[1]), 2),
+                (rust_id, Some(rust_ids[0]), 1),
+                (other_id, Some(other_ids[0]), 1)
+            ],
+            found
+        );
+        assert_eq!("<mark>Rust</mark> questions", results[0].snippet);
+
+        assert!(search(&[], "rust").is_empty());
+    }
+}
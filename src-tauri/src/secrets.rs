@@ -0,0 +1,195 @@
+//! Storing API keys in the platform keyring.
+//!
+//! When no OpenAI key has been stored, clients fall back to the
+//! `OPENAI_API_KEY` environment variable picked up by async-openai. The web
+//! search, Azure, Anthropic and endpoint API keys have no such fallback.
+
+use crate::{retry, timeouts, ChatError};
+use async_openai::{config::OpenAIConfig, Client};
+use keyring::Entry;
+
+/// Service name the key is stored under in the keyring
+const KEYRING_SERVICE: &str = "chat-overlay";
+
+/// Account name the OpenAI key is stored under in the keyring
+const OPENAI_KEY_ACCOUNT: &str = "openai-api-key";
+
+/// Account name the web search API key is stored under in the keyring
+const SEARCH_KEY_ACCOUNT: &str = "web-search-api-key";
+
+/// Account name the Azure OpenAI key is stored under in the keyring
+const AZURE_KEY_ACCOUNT: &str = "azure-openai-api-key";
+
+/// Account name the Anthropic API key is stored under in the keyring
+const ANTHROPIC_KEY_ACCOUNT: &str = "anthropic-api-key";
+
+/// Prefix of the account names OpenAI-compatible endpoint keys are stored
+/// under in the keyring, followed by the endpoint's name
+const ENDPOINT_KEY_ACCOUNT_PREFIX: &str = "endpoint-api-key-";
+
+/// Prefix of the account names the keys of profiles are stored under in the
+/// keyring, followed by the profile's name
+const PROFILE_KEY_ACCOUNT_PREFIX: &str = "profile-api-key-";
+
+/// Prefix of the account names the proxy passwords of profiles are stored
+/// under in the keyring, followed by the profile's name
+const PROFILE_PROXY_ACCOUNT_PREFIX: &str = "profile-proxy-password-";
+
+fn get_secret(account: &str) -> Result<Option<String>, ChatError> {
+    match Entry::new(KEYRING_SERVICE, account)?.get_password() {
+        Ok(key) => Ok(Some(key)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn set_secret(account: &str, key: &str) -> Result<(), ChatError> {
+    Ok(Entry::new(KEYRING_SERVICE, account)?.set_password(key)?)
+}
+
+fn clear_secret(account: &str) -> Result<(), ChatError> {
+    match Entry::new(KEYRING_SERVICE, account)?.delete_password() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the stored OpenAI API key, or None if no key has been stored
+pub fn get_api_key() -> Result<Option<String>, ChatError> {
+    get_secret(OPENAI_KEY_ACCOUNT)
+}
+
+/// Stores `key` as the OpenAI API key, replacing any stored key
+pub fn set_api_key(key: &str) -> Result<(), ChatError> {
+    set_secret(OPENAI_KEY_ACCOUNT, key)
+}
+
+/// Removes the stored OpenAI API key. Does nothing if no key is stored.
+pub fn clear_api_key() -> Result<(), ChatError> {
+    clear_secret(OPENAI_KEY_ACCOUNT)
+}
+
+/// Returns the stored web search API key, or None if no key has been stored
+pub fn get_search_api_key() -> Result<Option<String>, ChatError> {
+    get_secret(SEARCH_KEY_ACCOUNT)
+}
+
+/// Stores `key` as the web search API key, replacing any stored key
+pub fn set_search_api_key(key: &str) -> Result<(), ChatError> {
+    set_secret(SEARCH_KEY_ACCOUNT, key)
+}
+
+/// Removes the stored web search API key. Does nothing if no key is stored.
+pub fn clear_search_api_key() -> Result<(), ChatError> {
+    clear_secret(SEARCH_KEY_ACCOUNT)
+}
+
+/// Returns the stored Azure OpenAI key, or None if no key has been stored
+pub fn get_azure_api_key() -> Result<Option<String>, ChatError> {
+    get_secret(AZURE_KEY_ACCOUNT)
+}
+
+/// Stores `key` as the Azure OpenAI key, replacing any stored key
+pub fn set_azure_api_key(key: &str) -> Result<(), ChatError> {
+    set_secret(AZURE_KEY_ACCOUNT, key)
+}
+
+/// Removes the stored Azure OpenAI key. Does nothing if no key is stored.
+pub fn clear_azure_api_key() -> Result<(), ChatError> {
+    clear_secret(AZURE_KEY_ACCOUNT)
+}
+
+/// Returns the stored Anthropic API key, or None if no key has been stored
+pub fn get_anthropic_api_key() -> Result<Option<String>, ChatError> {
+    get_secret(ANTHROPIC_KEY_ACCOUNT)
+}
+
+/// Stores `key` as the Anthropic API key, replacing any stored key
+pub fn set_anthropic_api_key(key: &str) -> Result<(), ChatError> {
+    set_secret(ANTHROPIC_KEY_ACCOUNT, key)
+}
+
+/// Removes the stored Anthropic API key. Does nothing if no key is stored.
+pub fn clear_anthropic_api_key() -> Result<(), ChatError> {
+    clear_secret(ANTHROPIC_KEY_ACCOUNT)
+}
+
+/// Returns the key stored for the endpoint named `name`, or None if no key
+/// has been stored
+pub fn get_endpoint_api_key(name: &str) -> Result<Option<String>, ChatError> {
+    get_secret(&format!("{}{}", ENDPOINT_KEY_ACCOUNT_PREFIX, name))
+}
+
+/// Stores `key` as the key of the endpoint named `name`, replacing any
+/// stored key
+pub fn set_endpoint_api_key(name: &str, key: &str) -> Result<(), ChatError> {
+    set_secret(&format!("{}{}", ENDPOINT_KEY_ACCOUNT_PREFIX, name), key)
+}
+
+/// Removes the key stored for the endpoint named `name`. Does nothing if no
+/// key is stored.
+pub fn clear_endpoint_api_key(name: &str) -> Result<(), ChatError> {
+    clear_secret(&format!("{}{}", ENDPOINT_KEY_ACCOUNT_PREFIX, name))
+}
+
+/// Returns the key stored for the profile named `name`, or None if no key
+/// has been stored
+pub fn get_profile_api_key(name: &str) -> Result<Option<String>, ChatError> {
+    get_secret(&format!("{}{}", PROFILE_KEY_ACCOUNT_PREFIX, name))
+}
+
+/// Stores `key` as the key of the profile named `name`, replacing any stored
+/// key
+pub fn set_profile_api_key(name: &str, key: &str) -> Result<(), ChatError> {
+    set_secret(&format!("{}{}", PROFILE_KEY_ACCOUNT_PREFIX, name), key)
+}
+
+/// Removes the key stored for the profile named `name`. Does nothing if no
+/// key is stored.
+pub fn clear_profile_api_key(name: &str) -> Result<(), ChatError> {
+    clear_secret(&format!("{}{}", PROFILE_KEY_ACCOUNT_PREFIX, name))
+}
+
+/// Returns the proxy password stored for the profile named `name`, or None
+/// if no password has been stored
+pub fn get_profile_proxy_password(name: &str) -> Result<Option<String>, ChatError> {
+    get_secret(&format!("{}{}", PROFILE_PROXY_ACCOUNT_PREFIX, name))
+}
+
+/// Stores `password` as the proxy password of the profile named `name`,
+/// replacing any stored password
+pub fn set_profile_proxy_password(name: &str, password: &str) -> Result<(), ChatError> {
+    set_secret(
+        &format!("{}{}", PROFILE_PROXY_ACCOUNT_PREFIX, name),
+        password,
+    )
+}
+
+/// Removes the proxy password stored for the profile named `name`. Does
+/// nothing if no password is stored.
+pub fn clear_profile_proxy_password(name: &str) -> Result<(), ChatError> {
+    clear_secret(&format!("{}{}", PROFILE_PROXY_ACCOUNT_PREFIX, name))
+}
+
+/// Create an OpenAI client using `key`, or the `OPENAI_API_KEY` environment
+/// variable if None.
+pub fn openai_client(key: Option<&str>) -> Client<OpenAIConfig> {
+    let client = match key {
+        Some(key) => Client::with_config(OpenAIConfig::new().with_api_key(key)),
+        None => Client::new(),
+    };
+
+    retry::without_backoff(client.with_http_client(timeouts::default_http_client()))
+}
+
+/// Create an OpenAI client using the stored key, falling back to the
+/// `OPENAI_API_KEY` environment variable if no key is stored or the keyring
+/// can not be read.
+pub fn stored_openai_client() -> Client<OpenAIConfig> {
+    let key = get_api_key().unwrap_or_else(|e| {
+        eprintln!("Could not read the API key: {}", e);
+        None
+    });
+
+    openai_client(key.as_deref())
+}
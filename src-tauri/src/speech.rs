@@ -0,0 +1,216 @@
+//! Reading the chat model's responses aloud.
+//!
+//! A message's text is sent to the provider's speech endpoint and the audio
+//! cached inside the app data directory, so each message is only synthesized
+//! once for a given voice. With `read_aloud` on, every new response is read
+//! as soon as it is committed. The speech settings are saved to their own
+//! file in the app config directory.
+
+use crate::cancellation::CancellationToken;
+use crate::ids::{MessageId, SessionId};
+use crate::persistence::{write_atomically, JsonConfig};
+use crate::providers::LlmProvider;
+use crate::retry::{self, RetryPolicy};
+use crate::ChatError;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Name of the file the speech settings are saved to inside the app config
+/// directory
+pub const SPEECH_FILE_NAME: &str = "speech.json";
+
+/// Name of the directory synthesized audio is cached in inside the app data
+/// directory
+pub const SPEECH_CACHE_DIR_NAME: &str = "speech";
+
+/// Model used to synthesize speech unless another one is picked
+pub const DEFAULT_SPEECH_MODEL: &str = "tts-1";
+
+/// Voice used unless another one is picked
+pub const DEFAULT_VOICE: &str = "alloy";
+
+/// How text is turned into speech
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeechOptions {
+    pub model: String,
+    pub voice: String,
+}
+
+impl Default for SpeechOptions {
+    fn default() -> Self {
+        SpeechOptions {
+            model: DEFAULT_SPEECH_MODEL.to_string(),
+            voice: DEFAULT_VOICE.to_string(),
+        }
+    }
+}
+
+/// The speech settings
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeechConfig {
+    /// Whether every new response is read aloud
+    pub read_aloud: bool,
+
+    #[serde(flatten)]
+    pub options: SpeechOptions,
+}
+
+impl JsonConfig for SpeechConfig {}
+
+/// The Tauri managed state holding the speech settings
+#[derive(Debug)]
+pub struct SpeechState {
+    config: Mutex<SpeechConfig>,
+    path: PathBuf,
+}
+
+impl SpeechState {
+    /// Loads the speech settings saved inside `app_config_dir`
+    pub fn in_app_config_dir(app_config_dir: &Path) -> Result<SpeechState, ChatError> {
+        let path = app_config_dir.join(SPEECH_FILE_NAME);
+
+        Ok(SpeechState {
+            config: Mutex::new(SpeechConfig::load(&path)?),
+            path,
+        })
+    }
+
+    /// Returns a copy of the current speech settings
+    pub fn get_config(&self) -> SpeechConfig {
+        self.lock().clone()
+    }
+
+    /// Replaces the speech settings with `config` and saves them
+    pub fn set_config(&self, config: SpeechConfig) -> Result<(), ChatError> {
+        let mut current = self.lock();
+
+        config.save(&self.path)?;
+        *current = config;
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SpeechConfig> {
+        // The config is only ever replaced whole, so a poisoned lock is still usable
+        self.config.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A request to read a message aloud, prepared by the store
+#[derive(Debug)]
+pub struct SpeechRequest {
+    pub(crate) provider: Arc<dyn LlmProvider>,
+    pub(crate) text: String,
+    pub(crate) options: SpeechOptions,
+    /// Name of the file the audio is cached in
+    pub(crate) file_name: String,
+    pub(crate) retry_policy: RetryPolicy,
+}
+
+impl SpeechRequest {
+    /// Returns the path of the message's audio inside `dir`, synthesizing it
+    /// first if it is not cached yet. Returns `ChatError::Cancelled` if
+    /// `cancel` is cancelled before the audio arrives.
+    pub async fn synthesize(
+        self,
+        dir: &Path,
+        cancel: &CancellationToken,
+    ) -> Result<PathBuf, ChatError> {
+        let path = dir.join(&self.file_name);
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        let (audio, _) = retry::retry(&self.retry_policy, cancel, || {
+            self.provider.synthesize_speech(&self.text, &self.options)
+        })
+        .await?;
+
+        fs::create_dir_all(dir).map_err(|e| ChatError::Persistence(e.to_string()))?;
+        write_atomically(&path, &audio)?;
+
+        Ok(path)
+    }
+}
+
+/// Returns the name of the file the audio of `text`, from the message with id
+/// `message_id` in the session with id `session_id`, is cached in. Editing or
+/// regenerating the message, or changing the voice, gives it a new file.
+pub(crate) fn cache_file_name(
+    session_id: SessionId,
+    message_id: MessageId,
+    text: &str,
+    options: &SpeechOptions,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    options.hash(&mut hasher);
+
+    format!("{}-{}-{:x}.mp3", session_id, message_id, hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+    use async_openai::types::{ChatCompletionRequestMessage, Role};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_speech_is_cached_per_message() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-speech-{}", nanos));
+        let cancel = CancellationToken::new();
+
+        let provider = MockProvider::canned("Hi");
+        let mut store = Store::new(provider.clone());
+        let msg = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(String::from("Hello")),
+            name: None,
+            function_call: None,
+        };
+        let id = store
+            .add_session(vec![msg], String::from("Speech"), "speaker")
+            .await
+            .unwrap();
+        let reply = store.get_session(id).unwrap().get_messages()[1].get_id();
+
+        for _ in 0..2 {
+            let request = store
+                .prepare_speech(id, reply, SpeechOptions::default())
+                .unwrap();
+            let path = request.synthesize(&dir, &cancel).await.unwrap();
+            assert_eq!("Hi", fs::read_to_string(path).unwrap());
+        }
+        assert_eq!(1, provider.get_spoken().len());
+
+        let options = SpeechOptions {
+            voice: String::from("nova"),
+            ..Default::default()
+        };
+        let request = store.prepare_speech(id, reply, options).unwrap();
+        request.synthesize(&dir, &cancel).await.unwrap();
+        assert_eq!(2, provider.get_spoken().len());
+
+        let missing = MessageId::generate();
+        assert_eq!(
+            Some(ChatError::MessageNotFound(missing)),
+            store
+                .prepare_speech(id, missing, SpeechOptions::default())
+                .err()
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+//! Saving the store to an SQLite database, for stores too large to rewrite as
+//! a JSON file after every change.
+//!
+//! Each session is a row holding it as JSON, so a save only writes the
+//! sessions that changed, and the prompts, trash and memories are rows of
+//! their own. Titles and messages are kept in an FTS5 index, which
+//! `Store::search_page` queries a page at a time instead of searching every
+//! message. The schema version of the saved data is the database's
+//! `user_version`, and older data is migrated like store files are.
+//!
+//! The backend is picked with `storage = "sqlite"` in `config.toml`, taking
+//! effect on the next start. The JSON store is copied into a new database the
+//! first time it is opened, and left as it is. Databases are not encrypted,
+//! so an encrypted store keeps being saved as JSON, and automatic backups only
+//! copy the JSON store.
+
+use crate::journal::{JournalState, Record};
+use crate::persistence::{
+    migrate, JsonFileBackend, SearchHit, StorageBackend, StorageKind, StoreSnapshot, SCHEMA_VERSION,
+};
+use crate::{ChatError, ChatSession};
+use rusqlite::{params, Connection, Transaction};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Name of the database the store is saved to inside the app data directory
+pub const SQLITE_FILE_NAME: &str = "store.sqlite3";
+
+/// Tables of a new database. Title rows of the search index have no message
+/// id.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sessions (
+        id TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS sessions_position ON sessions (position);
+    CREATE TABLE IF NOT EXISTS library (
+        name TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5 (
+        session_id UNINDEXED,
+        message_id UNINDEXED,
+        text,
+        tokenize = 'unicode61 remove_diacritics 2'
+    );
+";
+
+/// Saves snapshots to an SQLite database, indexing them for full-text search
+#[derive(Debug)]
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+    path: PathBuf,
+    /// What the database holds, or None if unknown, in which case the next
+    /// save rewrites it whole
+    state: Mutex<Option<JournalState>>,
+}
+
+impl SqliteBackend {
+    /// Opens the database at `path`, creating it if needed
+    pub fn open(path: impl Into<PathBuf>) -> Result<SqliteBackend, ChatError> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| ChatError::Persistence(e.to_string()))?;
+        }
+
+        let connection = Connection::open(&path).map_err(db_error)?;
+        connection.execute_batch(SCHEMA).map_err(db_error)?;
+
+        Ok(SqliteBackend {
+            connection: Mutex::new(connection),
+            path,
+            state: Mutex::new(None),
+        })
+    }
+
+    /// Opens `SQLITE_FILE_NAME` inside `app_data_dir`, copying the JSON store
+    /// saved there into it if nothing has been saved to it yet. Returns
+    /// `ChatError::Encryption` if the JSON store is encrypted.
+    pub fn in_app_data_dir(app_data_dir: &Path) -> Result<SqliteBackend, ChatError> {
+        let json = JsonFileBackend::in_app_data_dir(app_data_dir);
+        if json.is_encrypted()? {
+            return Err(ChatError::Encryption(String::from(
+                "An encrypted store can not be saved to SQLite",
+            )));
+        }
+
+        let backend = SqliteBackend::open(app_data_dir.join(SQLITE_FILE_NAME))?;
+        if backend.schema_version()? == 0 {
+            if let Some(snapshot) = json.load()? {
+                backend.save(&snapshot)?;
+            }
+        }
+
+        Ok(backend)
+    }
+
+    /// Returns the path of the database
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the schema version of the saved data, or 0 if nothing has been
+    /// saved yet
+    fn schema_version(&self) -> Result<u32, ChatError> {
+        self.lock_connection()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(db_error)
+    }
+
+    /// Returns the ids of the sessions and messages whose text has every
+    /// word of `query`, best match first
+    fn search_index(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, ChatError> {
+        let query = fts_query(query);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let connection = self.lock_connection();
+        let mut statement = connection
+            .prepare_cached(
+                "SELECT session_id, message_id FROM search_index WHERE search_index MATCH ?1
+                 ORDER BY rank LIMIT ?2 OFFSET ?3",
+            )
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map(params![query, limit as i64, offset as i64], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })
+            .map_err(db_error)?;
+
+        let mut hits = vec![];
+        for row in rows {
+            let (session_id, message_id) = row.map_err(db_error)?;
+            let session_id = session_id.parse().map_err(invalid_id)?;
+            let message_id = message_id
+                .map(|x| x.parse())
+                .transpose()
+                .map_err(invalid_id)?;
+            hits.push((session_id, message_id));
+        }
+
+        Ok(hits)
+    }
+
+    fn lock_connection(&self) -> MutexGuard<'_, Connection> {
+        // Writes happen in transactions, so a poisoned connection is still
+        // consistent
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, Option<JournalState>> {
+        // A poisoned state is discarded, so the next save rewrites the
+        // database whole
+        self.state.lock().unwrap_or_else(|e| {
+            let mut state = e.into_inner();
+            *state = None;
+            state
+        })
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    fn save(&self, snapshot: &StoreSnapshot) -> Result<(), ChatError> {
+        let mut state = self.lock_state();
+        let mut connection = self.lock_connection();
+        let transaction = connection.transaction().map_err(db_error)?;
+
+        let mut saved = match state.take() {
+            Some(saved) => saved,
+            None => {
+                transaction
+                    .execute_batch(
+                        "DELETE FROM sessions; DELETE FROM library; DELETE FROM search_index;",
+                    )
+                    .map_err(db_error)?;
+                JournalState::default()
+            }
+        };
+        for record in saved.changes(snapshot) {
+            write_record(&transaction, record)?;
+        }
+        transaction
+            .pragma_update(None, "user_version", SCHEMA_VERSION)
+            .map_err(db_error)?;
+        transaction.commit().map_err(db_error)?;
+        *state = Some(saved);
+
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<StoreSnapshot>, ChatError> {
+        let mut state = self.lock_state();
+        *state = None;
+        let version = self.schema_version()?;
+        if version == 0 {
+            return Ok(None);
+        }
+
+        let connection = self.lock_connection();
+        let mut data = Map::new();
+        let mut statement = connection
+            .prepare("SELECT data FROM sessions ORDER BY position")
+            .map_err(db_error)?;
+        let sessions = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(db_error)?
+            .map(|x| x.map_err(db_error).and_then(|x| parse(&x)))
+            .collect::<Result<Vec<Value>, ChatError>>()?;
+        data.insert(String::from("sessions"), Value::Array(sessions));
+
+        let mut statement = connection
+            .prepare("SELECT name, data FROM library")
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(db_error)?;
+        for row in rows {
+            let (name, value) = row.map_err(db_error)?;
+            data.insert(name, parse(&value)?);
+        }
+
+        let snapshot = migrate(serde_json::json!({
+            "schema_version": version,
+            "data": data,
+        }))?;
+        // Migrated data is rewritten whole on the next save
+        if version == SCHEMA_VERSION {
+            *state = Some(JournalState::of(&snapshot));
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    fn kind(&self) -> StorageKind {
+        StorageKind::Sqlite
+    }
+
+    fn search(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Option<Result<Vec<SearchHit>, ChatError>> {
+        Some(self.search_index(query, offset, limit))
+    }
+}
+
+/// Writes the change in `record` to the database
+fn write_record(transaction: &Transaction, record: Record) -> Result<(), ChatError> {
+    match record {
+        Record::Session { session } => {
+            let data = serde_json::to_string(&session)
+                .map_err(|e| ChatError::Persistence(e.to_string()))?;
+            // New sessions are given their position by the order record
+            // that follows
+            transaction
+                .execute(
+                    "INSERT INTO sessions (id, position, data) VALUES (?1, 0, ?2)
+                     ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                    params![session.get_id().to_string(), data],
+                )
+                .map_err(db_error)?;
+            index_session(transaction, &session)
+        }
+        Record::Order { ids } => {
+            let ids: Vec<String> = ids.iter().map(ToString::to_string).collect();
+            let saved: Vec<String> = transaction
+                .prepare("SELECT id FROM sessions")
+                .and_then(|mut x| x.query_map([], |row| row.get(0))?.collect())
+                .map_err(db_error)?;
+            for id in saved.iter().filter(|x| !ids.contains(x)) {
+                transaction
+                    .execute("DELETE FROM sessions WHERE id = ?1", [id])
+                    .and_then(|_| {
+                        transaction.execute("DELETE FROM search_index WHERE session_id = ?1", [id])
+                    })
+                    .map_err(db_error)?;
+            }
+
+            let mut statement = transaction
+                .prepare_cached("UPDATE sessions SET position = ?2 WHERE id = ?1")
+                .map_err(db_error)?;
+            for (position, id) in ids.iter().enumerate() {
+                statement
+                    .execute(params![id, position as i64])
+                    .map_err(db_error)?;
+            }
+
+            Ok(())
+        }
+        Record::Library {
+            prompts,
+            trash,
+            memories,
+        } => {
+            let rows = [
+                ("prompts", serde_json::to_string(&prompts)),
+                ("trash", serde_json::to_string(&trash)),
+                ("memories", serde_json::to_string(&memories)),
+            ];
+            for (name, data) in rows {
+                let data = data.map_err(|e| ChatError::Persistence(e.to_string()))?;
+                transaction
+                    .execute(
+                        "INSERT INTO library (name, data) VALUES (?1, ?2)
+                         ON CONFLICT (name) DO UPDATE SET data = excluded.data",
+                        params![name, data],
+                    )
+                    .map_err(db_error)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Replaces the rows of `session` in the search index with its title and
+/// the text of its messages
+fn index_session(transaction: &Transaction, session: &ChatSession) -> Result<(), ChatError> {
+    let id = session.get_id().to_string();
+    transaction
+        .execute("DELETE FROM search_index WHERE session_id = ?1", [&id])
+        .map_err(db_error)?;
+
+    let mut statement = transaction
+        .prepare_cached(
+            "INSERT INTO search_index (session_id, message_id, text) VALUES (?1, ?2, ?3)",
+        )
+        .map_err(db_error)?;
+    statement
+        .execute(params![id, None::<String>, session.title])
+        .map_err(db_error)?;
+    for message in session.messages.values() {
+        statement
+            .execute(params![id, message.id.to_string(), message.plain_text()])
+            .map_err(db_error)?;
+    }
+
+    Ok(())
+}
+
+/// Quotes each word of `query` as a prefix, so a match has words starting
+/// with every one of them and no word is read as FTS5 syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|x| format!("\"{}\"*", x.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse(data: &str) -> Result<Value, ChatError> {
+    serde_json::from_str(data).map_err(|e| ChatError::Persistence(e.to_string()))
+}
+
+fn db_error(e: rusqlite::Error) -> ChatError {
+    ChatError::Persistence(e.to_string())
+}
+
+fn invalid_id(e: uuid::Error) -> ChatError {
+    ChatError::Persistence(format!("The search index has an invalid id: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::SessionId;
+    use crate::providers::mock::MockProvider;
+    use crate::{ChatRole, Store};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("chat-overlay-{}-{}", name, nanos))
+    }
+
+    #[test]
+    fn test_sqlite_backend_saves_and_searches() {
+        let dir = temp_dir("sqlite");
+        let provider = || MockProvider::default();
+        let backend = SqliteBackend::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+        assert!(backend.load().unwrap().is_none());
+
+        let mut store = Store::load(provider(), backend).unwrap();
+        let rust = store.add_empty_session(String::from("Rust questions"), "gpt-3.5-turbo");
+        let cooking = store.add_empty_session(String::from("Cooking"), "gpt-3.5-turbo");
+        let deleted = store.add_empty_session(String::from("Deleted"), "gpt-3.5-turbo");
+        store
+            .session_mut(cooking)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("How long do I boil an egg?")),
+                (
+                    ChatRole::Assistant,
+                    String::from("Boil it for seven minutes."),
+                ),
+            ]);
+        store.save().unwrap();
+        store.delete_session(deleted).unwrap();
+        store.empty_trash();
+
+        let reloaded = Store::load(
+            provider(),
+            SqliteBackend::open(dir.join(SQLITE_FILE_NAME)).unwrap(),
+        )
+        .unwrap();
+        let ids: Vec<SessionId> = reloaded
+            .get_all_sessions()
+            .iter()
+            .map(|x| x.get_id())
+            .collect();
+        assert_eq!(vec![rust, cooking], ids);
+        assert_eq!(
+            2,
+            reloaded.get_session(cooking).unwrap().get_messages().len()
+        );
+
+        let results = reloaded.search_page("boil", 0, 10);
+        assert_eq!(2, results.len());
+        assert!(results.iter().all(|x| x.session_id == cooking));
+        assert!(results[0].snippet.contains("<mark>"));
+        assert_eq!(1, reloaded.search_page("boil", 1, 10).len());
+        assert_eq!(rust, reloaded.search_page("QUEST", 0, 10)[0].session_id);
+        assert!(reloaded.search_page("deleted", 0, 10).is_empty());
+        assert!(reloaded.search_page("\"AND", 0, 10).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_backend_imports_json_store() {
+        let dir = temp_dir("sqlite-import");
+        let session = ChatSession::new(String::from("Imported"), "gpt-3.5-turbo");
+        JsonFileBackend::in_app_data_dir(&dir)
+            .save(&StoreSnapshot {
+                sessions: vec![session.clone()],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let backend = SqliteBackend::in_app_data_dir(&dir).unwrap();
+        let loaded = backend.load().unwrap().unwrap();
+        assert_eq!(session.get_id(), loaded.sessions[0].get_id());
+        assert_eq!(StorageKind::Sqlite, backend.kind());
+
+        // The database is only filled from the JSON store once
+        drop(backend);
+        JsonFileBackend::in_app_data_dir(&dir)
+            .save(&StoreSnapshot::default())
+            .unwrap();
+        let reopened = SqliteBackend::in_app_data_dir(&dir).unwrap();
+        assert_eq!(1, reopened.load().unwrap().unwrap().sessions.len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
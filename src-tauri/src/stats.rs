@@ -0,0 +1,190 @@
+//! Statistics about sessions, for the usage dashboard.
+//!
+//! Statistics are worked out from the messages of a session when asked for,
+//! so they are never out of date. A store's statistics add up those of all
+//! its sessions.
+
+use crate::{ChatRole, ChatSession};
+use serde::Serialize;
+
+/// The number of messages with each role
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RoleCounts {
+    pub system: usize,
+    pub user: usize,
+    pub assistant: usize,
+    pub tool: usize,
+}
+
+impl RoleCounts {
+    /// Counts another message with `role`
+    fn add(&mut self, role: ChatRole) {
+        match role {
+            ChatRole::System => self.system += 1,
+            ChatRole::User => self.user += 1,
+            ChatRole::Assistant => self.assistant += 1,
+            ChatRole::Tool => self.tool += 1,
+        }
+    }
+
+    /// Returns the number of messages counted
+    pub fn total(&self) -> usize {
+        self.system + self.user + self.assistant + self.tool
+    }
+}
+
+/// Statistics about the messages of one or more sessions
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SessionStats {
+    pub messages: RoleCounts,
+    /// Tokens used by every request that produced a response
+    pub total_tokens: u64,
+    /// Cost in USD of those requests, leaving out models whose price is
+    /// unknown
+    pub total_cost: f64,
+    /// How long responses took to arrive on average, in milliseconds, or
+    /// None if no response recorded it
+    pub average_latency_ms: Option<u64>,
+    /// The number of responses the average latency is taken over. Responses
+    /// saved by older versions did not record their latency.
+    pub timed_responses: usize,
+    /// Unix timestamp of the oldest message, or of the session's creation if
+    /// it has no messages. None for a store without sessions.
+    pub first_activity: Option<u64>,
+    /// Unix timestamp of the newest change. None for a store without
+    /// sessions.
+    pub last_activity: Option<u64>,
+    /// Latency of the timed responses added up, kept to average them exactly
+    /// when statistics are merged
+    #[serde(skip)]
+    total_latency_ms: u64,
+}
+
+/// Statistics about every session in a store
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AggregateStats {
+    pub sessions: usize,
+    pub archived_sessions: usize,
+    /// The statistics of all sessions added up
+    pub totals: SessionStats,
+}
+
+impl SessionStats {
+    /// Works out the statistics of `session`
+    pub fn of(session: &ChatSession) -> SessionStats {
+        let mut stats = SessionStats {
+            total_tokens: session.total_usage().total_tokens() as u64,
+            total_cost: session.total_cost(),
+            first_activity: Some(session.get_created_at()),
+            last_activity: Some(session.last_activity()),
+            ..SessionStats::default()
+        };
+
+        for message in session.get_messages() {
+            stats.messages.add(message.get_role());
+            stats.first_activity = stats.first_activity.min(Some(message.get_created_at()));
+
+            let latency = message.get_metadata().map_or(0, |x| x.latency_ms);
+            if latency > 0 {
+                stats.total_latency_ms += latency;
+                stats.timed_responses += 1;
+            }
+        }
+        stats.average_latency_ms = stats.average_latency();
+
+        stats
+    }
+
+    fn average_latency(&self) -> Option<u64> {
+        (self.timed_responses > 0).then(|| self.total_latency_ms / self.timed_responses as u64)
+    }
+
+    /// Adds the statistics of another session to these
+    fn merge(&mut self, other: &SessionStats) {
+        self.messages.system += other.messages.system;
+        self.messages.user += other.messages.user;
+        self.messages.assistant += other.messages.assistant;
+        self.messages.tool += other.messages.tool;
+        self.total_tokens += other.total_tokens;
+        self.total_cost += other.total_cost;
+        self.timed_responses += other.timed_responses;
+        self.total_latency_ms += other.total_latency_ms;
+        self.average_latency_ms = self.average_latency();
+
+        self.first_activity = match (self.first_activity, other.first_activity) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.last_activity = self.last_activity.max(other.last_activity);
+    }
+}
+
+/// Adds up the statistics of `sessions`
+pub(crate) fn aggregate<'a>(sessions: impl Iterator<Item = &'a ChatSession>) -> AggregateStats {
+    let mut stats = AggregateStats::default();
+    for session in sessions {
+        stats.sessions += 1;
+        if session.is_archived() {
+            stats.archived_sessions += 1;
+        }
+        stats.totals.merge(&SessionStats::of(session));
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_session_and_aggregate_stats() {
+        let provider = MockProvider::canned("Hello there");
+        provider.set_latency(Duration::from_millis(5));
+        let mut store = Store::new(provider);
+        assert_eq!(AggregateStats::default(), store.aggregate_stats());
+
+        let cancel = CancellationToken::new();
+        let first = store.add_empty_session(String::from("First"), "gpt-3.5-turbo");
+        let second = store.add_empty_session(String::from("Second"), "llama3");
+        for id in [first, first, second] {
+            store
+                .send_message(id, String::from("Hi"), &cancel)
+                .await
+                .unwrap();
+        }
+
+        let session = store.get_session(first).unwrap();
+        let stats = session.stats();
+        assert_eq!(2, stats.messages.user);
+        assert_eq!(2, stats.messages.assistant);
+        assert_eq!(4, stats.messages.total());
+        assert_eq!(
+            session.total_usage().total_tokens() as u64,
+            stats.total_tokens
+        );
+        assert!(stats.total_cost > 0.0);
+        assert_eq!(2, stats.timed_responses);
+        assert!(stats.average_latency_ms.unwrap() >= 5);
+        assert!(stats.first_activity <= stats.last_activity);
+
+        let empty = store.add_empty_session(String::from("Empty"), "gpt-3.5-turbo");
+        let stats = store.get_session(empty).unwrap().stats();
+        assert_eq!(None, stats.average_latency_ms);
+        assert_eq!(0, stats.messages.total());
+
+        let aggregate = store.aggregate_stats();
+        assert_eq!(3, aggregate.sessions);
+        assert_eq!(6, aggregate.totals.messages.total());
+        assert_eq!(3, aggregate.totals.timed_responses);
+        // Only the first session's model has a known price
+        assert_eq!(
+            store.get_session(first).unwrap().total_cost(),
+            aggregate.totals.total_cost
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Summarizing the older messages of long sessions.
+//!
+//! Once the messages of a session take up most of its chat model's context
+//! window, the messages before the newest few are summarized by a cheaper
+//! model. The summary is kept on the session as a System message and sent in
+//! place of the messages it covers, which stay in the session for display.
+//! Later summaries build on the previous one, so it rolls forward as the
+//! session grows.
+
+use crate::cancellation::CancellationToken;
+use crate::ids::{MessageId, SessionId};
+use crate::providers::{CompletionRequest, LlmProvider};
+use crate::retry::{self, RetryPolicy};
+use crate::tokens::{self, count_tokens};
+use crate::usage;
+use crate::{role_name, ChatError, ChatSession, Message};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Model summaries are written with for sessions on the default provider.
+/// Sessions on other providers are summarized by their own model.
+pub const SUMMARY_MODEL: &str = "gpt-3.5-turbo";
+
+/// How full, in percent, a session's context window may get before its
+/// older messages are summarized
+pub const SUMMARIZE_AT_PERCENT: usize = 75;
+
+/// How many of the newest messages are always sent as they are
+pub const KEPT_MESSAGES: usize = 4;
+
+/// Heads the summary sent in place of the messages it covers
+pub(crate) const SUMMARY_HEADING: &str = "Summary of the earlier conversation:";
+
+/// Instructions sent with the messages to summarize
+const SUMMARY_PROMPT: &str = "Summarize the conversation below in a few short paragraphs. \
+Keep every name, decision, fact and open question needed to carry on the conversation.";
+
+/// A summary of the older messages of a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    /// The summary, as a System message sent before the messages it does not
+    /// cover
+    pub message: Message,
+
+    /// Id of the newest message the summary covers
+    pub through: MessageId,
+}
+
+/// A request to summarize the older messages of a session, prepared by the
+/// store
+#[derive(Debug)]
+pub struct SummaryRequest {
+    pub(crate) session_id: SessionId,
+    pub(crate) provider: Arc<dyn LlmProvider>,
+    pub(crate) request: CompletionRequest,
+    pub(crate) through: MessageId,
+    pub(crate) retry_policy: RetryPolicy,
+}
+
+/// A summary waiting to be committed with `Store::commit_summary`
+#[derive(Debug)]
+pub struct CompletedSummary {
+    pub(crate) session_id: SessionId,
+    pub(crate) through: MessageId,
+    pub(crate) text: String,
+    /// Cost in USD of the request, if the model's price is known
+    pub(crate) cost: Option<f64>,
+}
+
+impl SummaryRequest {
+    /// Returns the id of the session being summarized
+    pub fn get_session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    /// Waits for the summary, retrying transient failures as the store's
+    /// retry policy allows. Returns `ChatError::Cancelled` if `cancel` is
+    /// cancelled before it arrives.
+    pub async fn complete(self, cancel: &CancellationToken) -> Result<CompletedSummary, ChatError> {
+        let (completion, _) = retry::retry(&self.retry_policy, cancel, || {
+            self.provider.complete(self.request.clone())
+        })
+        .await?;
+        let cost = completion
+            .usage
+            .and_then(|usage| usage::cost(&self.request.model, &usage));
+
+        let text = completion
+            .message
+            .content
+            .filter(|x| !x.trim().is_empty())
+            .ok_or(ChatError::EmptyResponse)?;
+
+        Ok(CompletedSummary {
+            session_id: self.session_id,
+            through: self.through,
+            text,
+            cost,
+        })
+    }
+}
+
+/// Returns whether `messages` take up more than `SUMMARIZE_AT_PERCENT` of
+/// the room `model`'s context window leaves for them
+pub(crate) fn needs_summary(messages: &[ChatCompletionRequestMessage], model: &str) -> bool {
+    let budget = tokens::context_window(model)
+        .saturating_sub(crate::chat_requests::MAX_RESPONSE_TOKENS as usize);
+    let total: usize = messages.iter().map(tokens::count_message_tokens).sum();
+
+    total * 100 > budget * SUMMARIZE_AT_PERCENT
+}
+
+/// Builds the request summarizing the messages of `session` not yet covered
+/// by its summary, except the newest `KEPT_MESSAGES`, with `model`. Returns
+/// the request along with the id of the newest message it covers, or None
+/// if there is nothing new to summarize.
+///
+/// Only as many messages as fill half of `model`'s context window are
+/// summarized at once. The rest are left for the next summary.
+pub(crate) fn summary_request(
+    session: &ChatSession,
+    model: &str,
+) -> Option<(CompletionRequest, MessageId)> {
+    let previous = session.summary.as_ref();
+    let start = previous
+        .and_then(|x| session.messages.get_index_of(&x.through))
+        .map_or(0, |x| x + 1);
+    let end = session.messages.len().saturating_sub(KEPT_MESSAGES);
+    if start >= end {
+        return None;
+    }
+
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("{}\n\n", previous.message.plain_text()));
+    }
+
+    let budget = tokens::context_window(model) / 2;
+    let mut through = None;
+    for (id, message) in session.messages[start..end].iter() {
+        let line = format!("{}: {}\n\n", role_name(message.role), message.plain_text());
+        if through.is_some() && count_tokens(&transcript) + count_tokens(&line) > budget {
+            break;
+        }
+        transcript.push_str(&line);
+        through = Some(*id);
+    }
+
+    let request = CompletionRequest {
+        model: model.to_string(),
+        messages: vec![
+            ChatCompletionRequestMessage {
+                role: Role::System,
+                content: Some(SUMMARY_PROMPT.to_string()),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionRequestMessage {
+                role: Role::User,
+                content: Some(transcript),
+                name: None,
+                function_call: None,
+            },
+        ],
+        images: vec![],
+        functions: vec![],
+        temperature: None,
+    };
+
+    through.map(|through| (request, through))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::{ChatRole, Store};
+
+    #[tokio::test]
+    async fn test_store_summarize_session() {
+        let mut store = Store::new(MockProvider::canned("They talked about rust."));
+        let id = store.add_empty_session(String::from("Long"), "gpt-3.5-turbo");
+        let long = "rust ".repeat(2000);
+        let batch = (0..8)
+            .map(|x| match x % 2 {
+                0 => (ChatRole::User, format!("{} {}", x, long)),
+                _ => (ChatRole::Assistant, format!("{} {}", x, long)),
+            })
+            .collect();
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(batch);
+        let ids: Vec<MessageId> = store
+            .get_session(id)
+            .unwrap()
+            .get_messages()
+            .iter()
+            .map(|x| x.get_id())
+            .collect();
+
+        let request = store.prepare_summary(id).unwrap().unwrap();
+        assert_eq!(SUMMARY_MODEL, request.request.model);
+        let completed = request.complete(&CancellationToken::new()).await.unwrap();
+        store.commit_summary(completed).unwrap();
+
+        // The summary takes the place of the messages it covers, which are
+        // still kept for display
+        let session = store.get_session(id).unwrap();
+        let summary = session.get_summary().unwrap();
+        assert_eq!(ChatRole::System, summary.message.get_role());
+        assert!(ids[..ids.len() - KEPT_MESSAGES].contains(&summary.through));
+        assert_eq!(8, session.message_count());
+
+        let request = store.prepare_message(id, String::from("Go on")).unwrap();
+        let sent = &request.request.messages;
+        assert!(sent[0]
+            .content
+            .as_deref()
+            .unwrap()
+            .contains("They talked about rust."));
+        assert_eq!(Some("Go on"), sent.last().unwrap().content.as_deref());
+
+        // A session that still fits is left alone
+        let short = store.add_empty_session(String::from("Short"), "gpt-3.5-turbo");
+        assert!(store.prepare_summary(short).unwrap().is_none());
+    }
+}
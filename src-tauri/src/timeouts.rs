@@ -0,0 +1,211 @@
+//! Giving up on providers that take too long.
+//!
+//! Each profile has a connect timeout, applied by the HTTP client its
+//! provider sends requests with, and a read timeout, applied to every
+//! response. A non-streamed response must arrive in full within the read
+//! timeout, while a streamed response must start, and keep sending pieces,
+//! without a gap longer than it. Requests timing out fail with
+//! `ChatError::Timeout`, so a hung provider can not keep a request running
+//! forever. Embedding requests for semantic search are timed the same way.
+//! Providers not set up from a profile use the default timeouts.
+
+use crate::embeddings::Embedder;
+use crate::images::{GeneratedImage, ImageOptions};
+use crate::moderation::ModerationResult;
+use crate::providers::{Completion, CompletionRequest, LlmProvider, OnToken};
+use crate::speech::SpeechOptions;
+use crate::ChatError;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long a provider may take to connect and to respond
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Seconds a connection to the provider may take to open
+    pub connect_secs: u64,
+
+    /// Seconds the provider may take to respond, or to send the next piece of
+    /// a streamed response
+    pub read_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect_secs: 10,
+            read_secs: 120,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect_secs)
+    }
+
+    pub fn read(&self) -> Duration {
+        Duration::from_secs(self.read_secs)
+    }
+
+    /// Returns `provider` with the read timeout applied to its responses
+    pub fn wrap<P>(&self, provider: P) -> Timed<P> {
+        Timed::new(provider, self.read())
+    }
+}
+
+/// Returns the HTTP client requests not set up from a profile are sent with,
+/// applying the default connect timeout
+pub fn default_http_client() -> reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+    CLIENT
+        .get_or_init(|| {
+            reqwest::Client::builder()
+                .connect_timeout(Timeouts::default().connect())
+                .build()
+                .unwrap_or_default()
+        })
+        .clone()
+}
+
+/// A provider whose responses must arrive within a read timeout
+#[derive(Debug)]
+pub struct Timed<P> {
+    inner: P,
+    read: Duration,
+}
+
+impl<P> Timed<P> {
+    /// Wraps `inner`, failing its responses with `ChatError::Timeout` if they
+    /// take longer than `read`
+    pub fn new(inner: P, read: Duration) -> Timed<P> {
+        Timed { inner, read }
+    }
+
+    fn timed_out(&self) -> ChatError {
+        ChatError::Timeout(format!("no response after {:?}", self.read))
+    }
+
+    /// Waits for `response`, failing if it takes longer than the read timeout
+    async fn within<T>(
+        &self,
+        response: impl Future<Output = Result<T, ChatError>>,
+    ) -> Result<T, ChatError> {
+        tokio::time::timeout(self.read, response)
+            .await
+            .map_err(|_| self.timed_out())?
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for Timed<P> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion, ChatError> {
+        self.within(self.inner.complete(request)).await
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+        on_token: &mut OnToken<'_>,
+    ) -> Result<Completion, ChatError> {
+        // The timeout restarts with every piece received
+        let last_piece = Mutex::new(Instant::now());
+        let mut on_piece = |token: &str| {
+            *last_piece.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+            on_token(token);
+        };
+        let response = self.inner.stream(request, &mut on_piece);
+        tokio::pin!(response);
+
+        loop {
+            let deadline = *last_piece.lock().unwrap_or_else(|e| e.into_inner()) + self.read;
+            tokio::select! {
+                result = &mut response => return result,
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    let last = *last_piece.lock().unwrap_or_else(|e| e.into_inner());
+                    if last.elapsed() >= self.read {
+                        return Err(self.timed_out());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ChatError> {
+        self.within(self.inner.list_models()).await
+    }
+
+    async fn generate_image(
+        &self,
+        prompt: &str,
+        options: &ImageOptions,
+    ) -> Result<GeneratedImage, ChatError> {
+        self.within(self.inner.generate_image(prompt, options))
+            .await
+    }
+
+    async fn synthesize_speech(
+        &self,
+        text: &str,
+        options: &SpeechOptions,
+    ) -> Result<Vec<u8>, ChatError> {
+        self.within(self.inner.synthesize_speech(text, options))
+            .await
+    }
+
+    async fn moderate(&self, text: &str) -> Result<ModerationResult, ChatError> {
+        self.within(self.inner.moderate(text)).await
+    }
+}
+
+#[async_trait]
+impl<E: Embedder> Embedder for Timed<E> {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChatError> {
+        self.within(self.inner.embed(texts)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::providers::mock::MockProvider;
+    use crate::retry::RetryPolicy;
+    use crate::Store;
+
+    #[tokio::test]
+    async fn test_store_read_timeout() {
+        let provider = MockProvider::canned("Hello");
+        let script = provider.clone();
+        let mut store = Store::new(Timed::new(provider, Duration::from_millis(50)));
+        store.set_retry_policy(RetryPolicy::never());
+        let id = store.add_empty_session(String::from("Slow"), "mock");
+        let cancel = CancellationToken::new();
+
+        script.set_latency(Duration::from_millis(10));
+        let message = store
+            .send_message(id, String::from("Hi"), &cancel)
+            .await
+            .unwrap();
+        assert_eq!("Hello", message.get_content());
+
+        script.set_latency(Duration::from_secs(60));
+        let error = store
+            .send_message(id, String::from("Still there?"), &cancel)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ChatError::Timeout(_)));
+        assert!(!error.is_offline());
+
+        let error = store
+            .send_message_streaming(id, String::from("Hello?"), |_| {}, &cancel)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ChatError::Timeout(_)));
+        assert_eq!(2, store.get_session(id).unwrap().message_count());
+    }
+}
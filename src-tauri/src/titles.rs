@@ -0,0 +1,220 @@
+//! Retitling sessions after what they are about now.
+//!
+//! A session keeps the title it was given when it started, which can become
+//! misleading once the conversation drifts to another topic. Its newest
+//! messages, along with the summary of older ones if it has one, can be sent
+//! to a chat model asking for a short title that replaces the old one.
+
+use crate::cancellation::CancellationToken;
+use crate::ids::SessionId;
+use crate::providers::{CompletionRequest, LlmProvider};
+use crate::retry::{self, RetryPolicy};
+use crate::tokens::{self, count_tokens};
+use crate::usage;
+use crate::{role_name, ChatError, ChatSession};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use std::sync::Arc;
+
+/// Model titles are written with for sessions on the default provider.
+/// Sessions on other providers are titled by their own model.
+pub const TITLE_MODEL: &str = "gpt-3.5-turbo";
+
+/// The most characters a title may have. Longer titles are cut at a word.
+pub const MAX_TITLE_CHARS: usize = 60;
+
+/// Instructions sent with the conversation to title
+const TITLE_PROMPT: &str = "Write a title of at most six words for the conversation below. \
+It should say what the conversation is about now. Reply with the title only, without quotes.";
+
+/// A request for a new title of a session, prepared by the store
+#[derive(Debug)]
+pub struct TitleRequest {
+    pub(crate) session_id: SessionId,
+    pub(crate) provider: Arc<dyn LlmProvider>,
+    pub(crate) request: CompletionRequest,
+    pub(crate) retry_policy: RetryPolicy,
+}
+
+/// A title waiting to be committed with `Store::commit_title`
+#[derive(Debug)]
+pub struct CompletedTitle {
+    pub(crate) session_id: SessionId,
+    pub(crate) title: String,
+    /// Cost in USD of the request, if the model's price is known
+    pub(crate) cost: Option<f64>,
+}
+
+impl TitleRequest {
+    /// Returns the id of the session being titled
+    pub fn get_session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    /// Waits for the title, retrying transient failures as the store's retry
+    /// policy allows. Returns `ChatError::EmptyResponse` if the chat model
+    /// answers without a title, and `ChatError::Cancelled` if `cancel` is
+    /// cancelled before it arrives.
+    pub async fn complete(self, cancel: &CancellationToken) -> Result<CompletedTitle, ChatError> {
+        let (completion, _) = retry::retry(&self.retry_policy, cancel, || {
+            self.provider.complete(self.request.clone())
+        })
+        .await?;
+        let cost = completion
+            .usage
+            .and_then(|usage| usage::cost(&self.request.model, &usage));
+
+        let title = completion
+            .message
+            .content
+            .as_deref()
+            .map(clean_title)
+            .filter(|x| !x.is_empty())
+            .ok_or(ChatError::EmptyResponse)?;
+
+        Ok(CompletedTitle {
+            session_id: self.session_id,
+            title,
+            cost,
+        })
+    }
+}
+
+/// Turns the chat model's answer into a title, keeping its first line
+/// without the quotes, label or full stop models like to add
+pub(crate) fn clean_title(answer: &str) -> String {
+    let line = answer
+        .lines()
+        .map(str::trim)
+        .find(|x| !x.is_empty())
+        .unwrap_or_default();
+    let line = line.strip_prefix("Title:").unwrap_or(line);
+    let title = line
+        .trim()
+        .trim_matches(|c: char| matches!(c, '"' | '\'' | '*' | '#' | '`'))
+        .trim_end_matches('.')
+        .trim();
+
+    if title.chars().count() <= MAX_TITLE_CHARS {
+        return title.to_string();
+    }
+
+    let cut: String = title.chars().take(MAX_TITLE_CHARS).collect();
+    match cut.rsplit_once(' ') {
+        Some((words, _)) if !words.is_empty() => words.trim_end().to_string(),
+        _ => cut,
+    }
+}
+
+/// Builds the request for a new title of `session` with `model`, or returns
+/// None if the session has no messages to title it after.
+///
+/// The newest messages filling a quarter of `model`'s context window are
+/// sent, after the summary of the older messages if any are left out.
+pub(crate) fn title_request(session: &ChatSession, model: &str) -> Option<CompletionRequest> {
+    let budget = tokens::context_window(model) / 4;
+    let mut lines = vec![];
+    let mut used = 0;
+    for message in session.messages.values().rev() {
+        let line = format!("{}: {}\n\n", role_name(message.role), message.plain_text());
+        let cost = count_tokens(&line);
+        if !lines.is_empty() && used + cost > budget {
+            break;
+        }
+        used += cost;
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        return None;
+    }
+
+    // The summary stands in for the older messages left out
+    let mut transcript = String::new();
+    match &session.summary {
+        Some(summary) if lines.len() < session.messages.len() => {
+            transcript.push_str(&format!("{}\n\n", summary.message.plain_text()));
+        }
+        _ => {}
+    }
+    transcript.extend(lines.into_iter().rev());
+
+    Some(CompletionRequest {
+        model: model.to_string(),
+        messages: vec![
+            ChatCompletionRequestMessage {
+                role: Role::System,
+                content: Some(TITLE_PROMPT.to_string()),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionRequestMessage {
+                role: Role::User,
+                content: Some(transcript),
+                name: None,
+                function_call: None,
+            },
+        ],
+        images: vec![],
+        functions: vec![],
+        temperature: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::{ChatRole, Store};
+
+    #[test]
+    fn test_clean_title() {
+        assert_eq!("Learning Rust", clean_title("\"Learning Rust.\"\n"));
+        assert_eq!("Trip to Accra", clean_title("\n Title: **Trip to Accra**"));
+        assert_eq!("", clean_title("  "));
+
+        let long = "word ".repeat(20);
+        let title = clean_title(&long);
+        assert!(title.chars().count() <= MAX_TITLE_CHARS);
+        assert!(title.ends_with("word"));
+    }
+
+    #[tokio::test]
+    async fn test_store_regenerate_title() {
+        let provider = MockProvider::canned("\"Borrowing in Rust\"");
+        let script = provider.clone();
+        let mut store = Store::new(provider);
+        let id = store.add_empty_session(String::from("Hello"), "gpt-3.5-turbo");
+        assert!(store.prepare_title(id).unwrap().is_none());
+
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("Hi")),
+                (ChatRole::Assistant, String::from("Hello")),
+                (ChatRole::User, String::from("How does borrowing work?")),
+            ]);
+        let request = store.prepare_title(id).unwrap().unwrap();
+        assert_eq!(id, request.get_session_id());
+        let completed = request.complete(&CancellationToken::new()).await.unwrap();
+        store.commit_title(completed).unwrap();
+        assert_eq!(
+            "Borrowing in Rust",
+            store.get_session(id).unwrap().get_title()
+        );
+
+        // The whole conversation is sent, oldest first
+        let sent = script.get_requests()[0].messages[1]
+            .content
+            .clone()
+            .unwrap();
+        assert!(sent.starts_with("user: Hi"));
+        assert!(sent.contains("user: How does borrowing work?"));
+
+        script.push_text("  ");
+        let request = store.prepare_title(id).unwrap().unwrap();
+        assert_eq!(
+            Some(ChatError::EmptyResponse),
+            request.complete(&CancellationToken::new()).await.err()
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Counting the tokens of messages and trimming conversations to fit in a chat
+//! model's context window.
+//!
+//! Counts use the cl100k_base encoding of the OpenAI chat models. They are
+//! estimates for models from other providers.
+
+use crate::chat_requests::MAX_RESPONSE_TOKENS;
+use crate::role_name;
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use tiktoken_rs::{cl100k_base_singleton, model::get_context_size};
+
+/// Tokens the chat format adds around every message
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Tokens every reply from the chat model is primed with
+pub const TOKENS_PER_REPLY: usize = 3;
+
+/// Returns the number of tokens in `text`
+pub fn count_tokens(text: &str) -> usize {
+    cl100k_base_singleton()
+        .lock()
+        .encode_with_special_tokens(text)
+        .len()
+}
+
+/// Returns the number of tokens `msg` takes up in a request, including the
+/// tokens the chat format adds around it.
+pub fn count_message_tokens(msg: &ChatCompletionRequestMessage) -> usize {
+    let role = count_tokens(role_name(msg.role.clone().into()));
+    let name = msg.name.as_deref().map_or(0, count_tokens);
+    let content = msg.content.as_deref().map_or(0, count_tokens);
+
+    TOKENS_PER_MESSAGE + role + name + content
+}
+
+/// Returns the size of `model`'s context window in tokens. Unknown models are
+/// assumed to have a window of 4096 tokens.
+pub fn context_window(model: &str) -> usize {
+    get_context_size(model)
+}
+
+/// Drops the oldest messages from `messages` until they fit in `model`'s
+/// context window, leaving room for the response.
+///
+/// System messages and the newest message are always kept, so the result may
+/// still exceed the window if those alone do.
+pub fn trim_to_context(
+    messages: Vec<ChatCompletionRequestMessage>,
+    model: &str,
+) -> Vec<ChatCompletionRequestMessage> {
+    let budget = context_window(model).saturating_sub(MAX_RESPONSE_TOKENS as usize);
+    let counts: Vec<usize> = messages.iter().map(count_message_tokens).collect();
+    let mut total = TOKENS_PER_REPLY + counts.iter().sum::<usize>();
+
+    let last = messages.len().saturating_sub(1);
+    let mut dropped = vec![false; messages.len()];
+
+    for (idx, msg) in messages.iter().enumerate().take(last) {
+        if total <= budget {
+            break;
+        }
+        if msg.role != Role::System {
+            dropped[idx] = true;
+            total -= counts[idx];
+        }
+    }
+
+    messages
+        .into_iter()
+        .zip(dropped)
+        .filter(|(_, dropped)| !dropped)
+        .map(|(msg, _)| msg)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: Role, content: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestMessage {
+            role,
+            content: Some(content.to_string()),
+            name: None,
+            function_call: None,
+        }
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        assert_eq!(0, count_tokens(""));
+        assert_eq!(2, count_tokens("Hello world"));
+
+        // "user" and "Hi" are a token each
+        assert_eq!(6, count_message_tokens(&msg(Role::User, "Hi")));
+    }
+
+    #[test]
+    fn test_trim_to_context() {
+        let long = "word ".repeat(2000);
+        let messages = vec![
+            msg(Role::System, "Be brief"),
+            msg(Role::User, &long),
+            msg(Role::Assistant, &long),
+            msg(Role::User, "Short question"),
+        ];
+
+        // Everything fits in a large window
+        let trimmed = trim_to_context(messages.clone(), "gpt-4-32k");
+        assert_eq!(messages, trimmed);
+
+        // The first long message no longer fits in a 4096 token window
+        let trimmed = trim_to_context(messages.clone(), "unknown-model");
+        assert_eq!(
+            vec![Role::System, Role::Assistant, Role::User],
+            trimmed.iter().map(|m| m.role.clone()).collect::<Vec<_>>()
+        );
+
+        // The system and newest messages are kept even if they do not fit
+        let messages = vec![msg(Role::System, &long), msg(Role::User, &long)];
+        let trimmed = trim_to_context(messages.clone(), "davinci");
+        assert_eq!(messages, trimmed);
+    }
+}
@@ -0,0 +1,230 @@
+//! A tool reading text files, so the chat model can answer questions about
+//! them.
+//!
+//! Only files inside the directories the user approved in the
+//! `FileReaderConfig` can be read, and only up to a size limit. Symbolic
+//! links and `..` are resolved before a path is checked, so neither can be
+//! used to reach outside the approved directories.
+
+use super::{Tool, ToolOutput};
+use crate::persistence::JsonConfig;
+use crate::{ChatError, Store};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file the file reader config is saved to inside the app config
+/// directory
+pub const FILE_READER_FILE_NAME: &str = "file_reader.json";
+
+/// Name the chat model calls the file reader tool by
+pub const FILE_READER_TOOL: &str = "read_file";
+
+/// Largest file read unless configured otherwise, in bytes
+pub const DEFAULT_MAX_BYTES: u64 = 100_000;
+
+/// Which files the chat model can read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileReaderConfig {
+    /// Directories the chat model can read files in, including their
+    /// subdirectories. The tool is not offered if empty.
+    pub allowed_dirs: Vec<PathBuf>,
+
+    /// Largest file the chat model can read, in bytes
+    pub max_bytes: u64,
+}
+
+impl Default for FileReaderConfig {
+    fn default() -> Self {
+        FileReaderConfig {
+            allowed_dirs: vec![],
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl JsonConfig for FileReaderConfig {}
+
+/// Reads text files inside the approved directories for the chat model
+#[derive(Debug)]
+pub struct FileReaderTool {
+    /// The approved directories, with their links resolved
+    allowed_dirs: Vec<PathBuf>,
+    max_bytes: u64,
+    description: String,
+}
+
+impl FileReaderTool {
+    /// Create a tool reading files allowed by `config`. Approved directories
+    /// that do not exist are left out.
+    pub fn new(config: &FileReaderConfig) -> FileReaderTool {
+        let allowed_dirs: Vec<PathBuf> = config
+            .allowed_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .collect();
+
+        let dirs = allowed_dirs
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        let description = format!(
+            "Reads a text file of at most {} bytes. Only files inside these directories can be read: {}",
+            config.max_bytes, dirs
+        );
+
+        FileReaderTool {
+            allowed_dirs,
+            max_bytes: config.max_bytes,
+            description,
+        }
+    }
+
+    /// Returns `path` with its links resolved if it is inside an approved
+    /// directory
+    fn resolve(&self, path: &Path) -> Result<PathBuf, ChatError> {
+        let resolved = path
+            .canonicalize()
+            .map_err(|e| ChatError::FileRead(format!("{}: {}", path.display(), e)))?;
+
+        if self
+            .allowed_dirs
+            .iter()
+            .any(|dir| resolved.starts_with(dir))
+        {
+            Ok(resolved)
+        } else {
+            Err(ChatError::FileNotAllowed(path.display().to_string()))
+        }
+    }
+
+    /// Reads the text file at `path`, returning it along with its resolved
+    /// path
+    fn read(&self, path: &Path) -> Result<(PathBuf, String), ChatError> {
+        let path = self.resolve(path)?;
+        let read_error =
+            |e: std::io::Error| ChatError::FileRead(format!("{}: {}", path.display(), e));
+
+        let metadata = fs::metadata(&path).map_err(read_error)?;
+        if !metadata.is_file() {
+            return Err(ChatError::FileRead(format!(
+                "{} is not a file",
+                path.display()
+            )));
+        }
+        if metadata.len() > self.max_bytes {
+            return Err(ChatError::FileRead(format!(
+                "{} is {} bytes, more than the limit of {}",
+                path.display(),
+                metadata.len(),
+                self.max_bytes
+            )));
+        }
+
+        let contents = fs::read(&path).map_err(read_error)?;
+        let text = String::from_utf8(contents)
+            .map_err(|_| ChatError::FileRead(format!("{} is not a text file", path.display())))?;
+
+        Ok((path, text))
+    }
+}
+
+/// Offers the file reader tool in requests sent through `store` if `config`
+/// approves any directories, or stops offering it otherwise
+pub fn configure(store: &mut Store, config: &FileReaderConfig) {
+    if config.allowed_dirs.is_empty() {
+        store.unregister_tool(FILE_READER_TOOL);
+    } else {
+        store.register_tool(FileReaderTool::new(config));
+    }
+}
+
+#[async_trait]
+impl Tool for FileReaderTool {
+    fn name(&self) -> &str {
+        FILE_READER_TOOL
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Absolute path of the file to read"
+                }
+            },
+            "required": ["path"]
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<ToolOutput, ChatError> {
+        let path = arguments["path"]
+            .as_str()
+            .ok_or_else(|| ChatError::FileRead(String::from("No path was given")))?;
+
+        let (path, content) = self.read(Path::new(path))?;
+
+        Ok(ToolOutput {
+            content,
+            accessed_files: vec![path],
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_read_allowed_files_only() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let root = std::env::temp_dir().join(format!("chat-overlay-files-{}", nanos));
+        let allowed = root.join("allowed");
+        fs::create_dir_all(&allowed).unwrap();
+        fs::write(allowed.join("notes.txt"), "Buy milk").unwrap();
+        fs::write(allowed.join("big.txt"), "x".repeat(20)).unwrap();
+        fs::write(root.join("secret.txt"), "hunter2").unwrap();
+
+        let tool = FileReaderTool::new(&FileReaderConfig {
+            allowed_dirs: vec![allowed.clone()],
+            max_bytes: 10,
+        });
+        let read = |path: PathBuf| tool.execute(json!({ "path": path }));
+
+        let output = read(allowed.join("notes.txt")).await.unwrap();
+        assert_eq!("Buy milk", output.content);
+        assert_eq!(
+            vec![allowed.join("notes.txt").canonicalize().unwrap()],
+            output.accessed_files
+        );
+
+        assert!(matches!(
+            read(allowed.join("../secret.txt")).await,
+            Err(ChatError::FileNotAllowed(_))
+        ));
+        assert!(matches!(
+            read(allowed.join("big.txt")).await,
+            Err(ChatError::FileRead(_))
+        ));
+        assert!(matches!(
+            read(allowed.clone()).await,
+            Err(ChatError::FileRead(_))
+        ));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+//! Tools the chat model can call while answering.
+//!
+//! Tools registered with the store are offered to the chat model as functions
+//! with every request. When the model calls one, the tool is run and its
+//! result sent back, until the model answers without calling a tool. Every
+//! call and result is recorded as a message in the session.
+
+use crate::cancellation::CancellationToken;
+use crate::content::{self, MessageContent, Source};
+use crate::moderation::ModerationResult;
+use crate::providers::{Completion, CompletionRequest};
+use crate::role::ChatRole;
+use crate::usage::{self, TokenUsage};
+use crate::ChatError;
+use async_openai::types::{ChatCompletionFunctions, FunctionCall};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod file_reader;
+pub mod web_search;
+
+/// The most tool calls answered for a single request. Past it, the chat
+/// model's response is kept as its answer.
+pub const MAX_TOOL_ROUNDS: u32 = 8;
+
+/// A function the chat model can call
+#[async_trait]
+pub trait Tool: fmt::Debug + Send + Sync {
+    /// Returns the name the chat model calls this tool by. Names are 1 to 64
+    /// letters, digits, underscores and dashes.
+    fn name(&self) -> &str;
+
+    /// Returns what this tool does, so the chat model knows when to call it
+    fn description(&self) -> &str;
+
+    /// Returns the JSON schema of the arguments this tool takes
+    fn parameters(&self) -> Value;
+
+    /// Runs this tool with `arguments`, returning its result for the chat
+    /// model
+    async fn execute(&self, arguments: Value) -> Result<ToolOutput, ChatError>;
+}
+
+/// What a tool returns to the chat model
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ToolOutput {
+    /// The result, as text for the chat model
+    pub content: String,
+    /// Where the result came from, kept with it so answers can be cited
+    pub sources: Vec<Source>,
+    /// Files on disk read to produce the result
+    pub accessed_files: Vec<PathBuf>,
+}
+
+impl From<String> for ToolOutput {
+    fn from(content: String) -> Self {
+        ToolOutput {
+            content,
+            ..Default::default()
+        }
+    }
+}
+
+/// The tools offered to the chat model, by name
+#[derive(Debug, Clone, Default)]
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    /// Adds `tool`, replacing any tool with the same name
+    pub fn register<T: Tool + 'static>(&mut self, tool: T) {
+        self.tools.insert(tool.name().to_string(), Arc::new(tool));
+    }
+
+    /// Removes the tool named `name`. Returns false if there was none.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.tools.remove(name).is_some()
+    }
+
+    /// Returns the names of every tool, sorted
+    pub fn get_names(&self) -> Vec<&str> {
+        self.tools.keys().map(String::as_str).collect()
+    }
+
+    /// Returns true if no tools are registered
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Returns the function definitions sent to the chat model
+    pub fn definitions(&self) -> Vec<ChatCompletionFunctions> {
+        self.tools
+            .values()
+            .map(|tool| ChatCompletionFunctions {
+                name: tool.name().to_string(),
+                description: Some(tool.description().to_string()),
+                parameters: Some(tool.parameters()),
+            })
+            .collect()
+    }
+
+    /// Runs the tool `call` names with its arguments. Failures, including
+    /// calls to unknown tools and arguments that are not JSON, are returned
+    /// as the result so the chat model can correct itself.
+    pub async fn call(&self, call: &FunctionCall) -> ToolOutput {
+        let Some(tool) = self.tools.get(&call.name) else {
+            return format!("Error: there is no tool named {}", call.name).into();
+        };
+
+        // Tools without parameters may be called with no arguments at all
+        let arguments = if call.arguments.trim().is_empty() {
+            Ok(Value::Object(Default::default()))
+        } else {
+            serde_json::from_str(&call.arguments)
+        };
+
+        match arguments {
+            Ok(arguments) => match tool.execute(arguments).await {
+                Ok(output) => output,
+                Err(e) => format!("Error: {}", e).into(),
+            },
+            Err(e) => format!("Error: the arguments are not valid JSON: {}", e).into(),
+        }
+    }
+}
+
+/// Answers the tool calls in the chat model's responses to a single request,
+/// keeping track of what they cost
+#[derive(Debug)]
+pub(crate) struct ToolLoop<'a> {
+    tools: &'a ToolRegistry,
+    /// The tool calls and their results, in the order they were sent
+    messages: Vec<(ChatRole, Vec<MessageContent>)>,
+    accessed_files: Vec<PathBuf>,
+    usage: Option<TokenUsage>,
+    attempts: u32,
+    rounds: u32,
+    /// When the loop was started, before the first request was sent
+    started: Instant,
+}
+
+/// The outcome of a request once the chat model stopped calling tools
+#[derive(Debug)]
+pub(crate) struct ToolOutcome {
+    /// The chat model's answer, with the tokens used by every round
+    pub completion: Completion,
+    /// The tool calls and their results that came before the answer
+    pub messages: Vec<(ChatRole, Vec<MessageContent>)>,
+    /// Files on disk the tools read
+    pub accessed_files: Vec<PathBuf>,
+    /// How many requests were sent, counting retries
+    pub attempts: u32,
+    /// The chat model the requests were sent to
+    pub model: String,
+    /// How long the answer took to arrive, counting every retry and tool
+    /// call before it
+    pub latency: Duration,
+    /// The moderation endpoint's verdict on the User message the request
+    /// sent, if it was checked
+    pub moderation: Option<ModerationResult>,
+}
+
+impl<'a> ToolLoop<'a> {
+    pub fn new(tools: &'a ToolRegistry) -> ToolLoop<'a> {
+        ToolLoop {
+            tools,
+            messages: vec![],
+            accessed_files: vec![],
+            usage: None,
+            attempts: 0,
+            rounds: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Handles a response to `request` that took `attempts` to arrive.
+    ///
+    /// If the response calls a tool, the tool is run and both the call and
+    /// its result are added to `request` to be sent again, returning None.
+    /// Otherwise the response is the chat model's answer and is returned.
+    pub async fn step(
+        &mut self,
+        request: &mut CompletionRequest,
+        completion: Completion,
+        attempts: u32,
+        cancel: &CancellationToken,
+    ) -> Result<Option<ToolOutcome>, ChatError> {
+        self.attempts += attempts;
+        self.usage = usage::add_usage(self.usage, completion.usage);
+
+        let call = match &completion.message.function_call {
+            Some(call) if !self.tools.is_empty() && self.rounds < MAX_TOOL_ROUNDS => call.clone(),
+            _ => return Ok(Some(self.finish(completion, &request.model))),
+        };
+        self.rounds += 1;
+
+        let output = tokio::select! {
+            output = self.tools.call(&call) => output,
+            _ = cancel.cancelled() => return Err(ChatError::Cancelled),
+        };
+
+        let call_parts = content::from_response(&completion.message);
+        self.accessed_files.extend(output.accessed_files);
+        let result_parts = vec![MessageContent::ToolResult {
+            name: call.name,
+            content: output.content,
+            sources: output.sources,
+        }];
+
+        request.messages.push(content::to_request_message(
+            ChatRole::Assistant,
+            &call_parts,
+        ));
+        request
+            .messages
+            .push(content::to_request_message(ChatRole::Tool, &result_parts));
+        // Images belong to the last message, which is now the tool's result.
+        // The chat model already saw them when it made the call.
+        request.images.clear();
+
+        self.messages.push((ChatRole::Assistant, call_parts));
+        self.messages.push((ChatRole::Tool, result_parts));
+
+        Ok(None)
+    }
+
+    fn finish(&mut self, completion: Completion, model: &str) -> ToolOutcome {
+        ToolOutcome {
+            completion: Completion {
+                usage: self.usage,
+                ..completion
+            },
+            messages: std::mem::take(&mut self.messages),
+            accessed_files: std::mem::take(&mut self.accessed_files),
+            attempts: self.attempts,
+            model: model.to_string(),
+            latency: self.started.elapsed(),
+            moderation: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::{MockProvider, MockResponse};
+    use crate::Store;
+    use serde_json::json;
+
+    /// Adds two numbers
+    #[derive(Debug)]
+    struct AddTool;
+
+    #[async_trait]
+    impl Tool for AddTool {
+        fn name(&self) -> &str {
+            "add"
+        }
+
+        fn description(&self) -> &str {
+            "Adds two numbers"
+        }
+
+        fn parameters(&self) -> Value {
+            json!({
+                "type": "object",
+                "properties": {
+                    "a": { "type": "number" },
+                    "b": { "type": "number" }
+                },
+                "required": ["a", "b"]
+            })
+        }
+
+        async fn execute(&self, arguments: Value) -> Result<ToolOutput, ChatError> {
+            match (arguments["a"].as_f64(), arguments["b"].as_f64()) {
+                (Some(a), Some(b)) => Ok((a + b).to_string().into()),
+                _ => Err(ChatError::Request(String::from("a and b must be numbers"))),
+            }
+        }
+    }
+
+    fn call(name: &str, arguments: &str) -> FunctionCall {
+        FunctionCall {
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry_calls() {
+        let mut tools = ToolRegistry::default();
+        tools.register(AddTool);
+
+        assert_eq!(vec!["add"], tools.get_names());
+        assert_eq!("add", tools.definitions()[0].name);
+
+        let sum = tools.call(&call("add", r#"{"a": 1, "b": 2}"#)).await;
+        assert_eq!("3", sum.content);
+        for (name, arguments) in [("add", r#"{"a": "one"}"#), ("add", "{"), ("nope", "{}")] {
+            let output = tools.call(&call(name, arguments)).await;
+            assert!(output.content.starts_with("Error"));
+        }
+
+        assert!(tools.unregister("add"));
+        assert!(tools.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_tool_loop() {
+        // Calls `add`, then answers with its result
+        let provider = MockProvider::default();
+        provider
+            .push(MockResponse::FunctionCall {
+                name: String::from("add"),
+                arguments: String::from(r#"{"a": 2, "b": 3}"#),
+            })
+            .push_text("5");
+        provider.set_usage(TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 1,
+        });
+        let mut store = Store::new(provider.clone());
+        store.register_tool(AddTool);
+        let id = store.add_empty_session(String::from("Maths"), "adder");
+
+        let answer = store
+            .send_message(id, String::from("2 + 3?"), &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!("5", answer.plain_text());
+        assert_eq!(
+            Some(TokenUsage {
+                prompt_tokens: 20,
+                completion_tokens: 2,
+            }),
+            answer.get_usage()
+        );
+
+        let roles = store.get_session(id).unwrap().message_role_sequence();
+        assert_eq!(
+            vec![
+                ChatRole::User,
+                ChatRole::Assistant,
+                ChatRole::Tool,
+                ChatRole::Assistant
+            ],
+            roles
+        );
+
+        let messages = store.get_session(id).unwrap().get_messages();
+        assert!(matches!(
+            messages[1].get_parts(),
+            [MessageContent::ToolCall { name, .. }] if name == "add"
+        ));
+        assert_eq!("5", messages[2].plain_text());
+
+        // Both requests offered the tool, and the second carried its result
+        let requests = provider.get_requests();
+        assert_eq!(2, requests.len());
+        assert!(requests.iter().all(|x| x.functions.len() == 1));
+        let last = requests[1].messages.last().unwrap();
+        assert_eq!(Some(String::from("5")), last.content);
+    }
+}
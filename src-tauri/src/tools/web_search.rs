@@ -0,0 +1,293 @@
+//! A tool searching the web, so the chat model can answer questions about
+//! current events.
+//!
+//! Searches go to the API picked in the `WebSearchConfig`: the Brave Search
+//! API, which needs a key stored with `secrets::set_search_api_key`, or a
+//! self-hosted SearXNG instance. The URL of every result is kept with the
+//! tool's result so answers can cite them.
+
+use super::{Tool, ToolOutput};
+use crate::content::Source;
+use crate::persistence::JsonConfig;
+use crate::retry;
+use crate::secrets;
+use crate::{ChatError, Store};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Name of the file the web search config is saved to inside the app config
+/// directory
+pub const WEB_SEARCH_FILE_NAME: &str = "web_search.json";
+
+/// How many results are sent to the chat model unless configured otherwise
+pub const DEFAULT_MAX_RESULTS: usize = 5;
+
+/// Name the chat model calls the web search tool by
+pub const WEB_SEARCH_TOOL: &str = "web_search";
+
+/// Endpoint of the Brave Search API
+const BRAVE_SEARCH_URL: &str = "https://api.search.brave.com/res/v1/web/search";
+
+/// The search API queries are sent to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SearchApi {
+    /// The Brave Search API, sent the stored search API key
+    Brave,
+
+    /// A SearXNG instance at `base_url` with its JSON output format enabled
+    Searxng { base_url: String },
+}
+
+/// Whether, and how, the chat model can search the web
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSearchConfig {
+    /// Whether the chat model is offered the web search tool
+    pub enabled: bool,
+
+    /// The search API queries are sent to
+    pub api: SearchApi,
+
+    /// The most results sent to the chat model per search
+    pub max_results: usize,
+}
+
+impl Default for WebSearchConfig {
+    fn default() -> Self {
+        WebSearchConfig {
+            enabled: false,
+            api: SearchApi::Brave,
+            max_results: DEFAULT_MAX_RESULTS,
+        }
+    }
+}
+
+impl JsonConfig for WebSearchConfig {}
+
+/// A single search result. Brave calls the snippet a description, SearXNG
+/// calls it the content.
+#[derive(Debug, Deserialize)]
+struct SearchResult {
+    title: String,
+    url: String,
+    #[serde(default, alias = "description")]
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct BraveResponse {
+    web: Option<BraveResults>,
+}
+
+#[derive(Deserialize)]
+struct BraveResults {
+    results: Vec<SearchResult>,
+}
+
+#[derive(Deserialize)]
+struct SearxngResponse {
+    results: Vec<SearchResult>,
+}
+
+/// Searches the web for the chat model
+pub struct WebSearchTool {
+    api: SearchApi,
+    api_key: Option<String>,
+    max_results: usize,
+    http: reqwest::Client,
+}
+
+impl fmt::Debug for WebSearchTool {
+    // Leaves out the API key
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSearchTool")
+            .field("api", &self.api)
+            .field("max_results", &self.max_results)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WebSearchTool {
+    /// Create a tool searching with the API in `config`. `api_key` is only
+    /// used by APIs that need one.
+    pub fn new(config: &WebSearchConfig, api_key: Option<String>) -> WebSearchTool {
+        WebSearchTool {
+            api: config.api.clone(),
+            api_key,
+            max_results: config.max_results.max(1),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Returns the results for `query`, best first
+    async fn search(&self, query: &str) -> Result<Vec<SearchResult>, ChatError> {
+        let mut results = match &self.api {
+            SearchApi::Brave => {
+                let api_key = self.api_key.as_deref().ok_or(ChatError::MissingApiKey)?;
+                let response = self
+                    .http
+                    .get(BRAVE_SEARCH_URL)
+                    .query(&[("q", query), ("count", &self.max_results.to_string())])
+                    .header("Accept", "application/json")
+                    .header("X-Subscription-Token", api_key)
+                    .send()
+                    .await?;
+                let response: BraveResponse = retry::check_status(response)?.json().await?;
+
+                response.web.map(|x| x.results).unwrap_or_default()
+            }
+            SearchApi::Searxng { base_url } => {
+                let response = self
+                    .http
+                    .get(format!("{}/search", base_url.trim_end_matches('/')))
+                    .query(&[("q", query), ("format", "json")])
+                    .send()
+                    .await?;
+                let response: SearxngResponse = retry::check_status(response)?.json().await?;
+
+                response.results
+            }
+        };
+
+        results.truncate(self.max_results);
+        Ok(results)
+    }
+}
+
+/// Offers the web search tool in requests sent through `store` if `config`
+/// enables it, or stops offering it otherwise. APIs needing a key are sent
+/// the one stored in the keyring.
+pub fn configure(store: &mut Store, config: &WebSearchConfig) -> Result<(), ChatError> {
+    if !config.enabled {
+        store.unregister_tool(WEB_SEARCH_TOOL);
+        return Ok(());
+    }
+
+    let api_key = match config.api {
+        SearchApi::Brave => secrets::get_search_api_key()?,
+        SearchApi::Searxng { .. } => None,
+    };
+    store.register_tool(WebSearchTool::new(config, api_key));
+
+    Ok(())
+}
+
+/// Lists `results` for the chat model, numbered so answers can refer to them
+fn format_results(query: &str, results: Vec<SearchResult>) -> ToolOutput {
+    if results.is_empty() {
+        return format!("No results were found for \"{}\"", query).into();
+    }
+
+    let content = results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            format!(
+                "[{}] {}\n{}\n{}",
+                i + 1,
+                result.title,
+                result.url,
+                result.content.trim()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    let sources = results
+        .into_iter()
+        .map(|result| Source {
+            title: result.title,
+            url: result.url,
+        })
+        .collect();
+
+    ToolOutput {
+        content,
+        sources,
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl Tool for WebSearchTool {
+    fn name(&self) -> &str {
+        WEB_SEARCH_TOOL
+    }
+
+    fn description(&self) -> &str {
+        "Searches the web. Use it for current events, or anything that may have changed recently. Cite the results you use by their URL."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "type": "string",
+                    "description": "What to search for"
+                }
+            },
+            "required": ["query"]
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<ToolOutput, ChatError> {
+        let query = arguments["query"]
+            .as_str()
+            .filter(|x| !x.trim().is_empty())
+            .ok_or_else(|| ChatError::Request(String::from("A query is needed to search")))?;
+
+        let results = self.search(query).await?;
+
+        Ok(format_results(query, results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_search_results() {
+        let brave: BraveResponse = serde_json::from_str(
+            r#"{"web": {"results": [{"title": "Rust", "url": "https://rust-lang.org", "description": "A language"}]}}"#,
+        )
+        .unwrap();
+        let searxng: SearxngResponse = serde_json::from_str(
+            r#"{"results": [{"title": "Rust", "url": "https://rust-lang.org", "content": "A language", "engine": "ddg"}]}"#,
+        )
+        .unwrap();
+
+        for results in [brave.web.unwrap().results, searxng.results] {
+            let output = format_results("rust", results);
+            assert_eq!(
+                "[1] Rust\nhttps://rust-lang.org\nA language",
+                output.content
+            );
+            assert_eq!(
+                vec![Source {
+                    title: String::from("Rust"),
+                    url: String::from("https://rust-lang.org"),
+                }],
+                output.sources
+            );
+        }
+
+        let output = format_results("nothing", vec![]);
+        assert!(output.sources.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_brave_needs_api_key() {
+        let tool = WebSearchTool::new(&WebSearchConfig::default(), None);
+
+        assert_eq!(
+            Err(ChatError::MissingApiKey),
+            tool.execute(json!({ "query": "news" })).await
+        );
+        assert!(tool.execute(json!({})).await.is_err());
+    }
+}
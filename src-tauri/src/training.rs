@@ -0,0 +1,120 @@
+//! Exporting sessions as a dataset for fine-tuning chat models.
+//!
+//! Each session becomes one line of OpenAI's chat fine-tuning format, a JSON
+//! object holding the session's messages in order. The session's system
+//! prompt comes first if it has one. Only the system, user and assistant
+//! messages are kept, with their active variant, and messages after the last
+//! response are left out since there is nothing to learn from them.
+
+use crate::{role_name, ChatRole, ChatSession};
+use serde::Serialize;
+
+/// A message of a fine-tuning example
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrainingMessage {
+    pub role: &'static str,
+    pub content: String,
+}
+
+/// One conversation to fine-tune on, written as a line of JSONL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrainingExample {
+    pub messages: Vec<TrainingMessage>,
+}
+
+impl TrainingExample {
+    /// Builds the example of `session`, or returns None if it has no response
+    /// to learn from
+    pub fn of(session: &ChatSession) -> Option<TrainingExample> {
+        let mut messages: Vec<TrainingMessage> = session
+            .get_system_prompt()
+            .map(|x| TrainingMessage {
+                role: role_name(ChatRole::System),
+                content: x.to_string(),
+            })
+            .into_iter()
+            .collect();
+
+        for message in session.get_messages() {
+            let role = message.get_role();
+            let content = message.plain_text();
+            if role == ChatRole::Tool || content.trim().is_empty() {
+                continue;
+            }
+
+            messages.push(TrainingMessage {
+                role: role_name(role),
+                content,
+            });
+        }
+
+        let last_response = messages
+            .iter()
+            .rposition(|x| x.role == role_name(ChatRole::Assistant))?;
+        messages.truncate(last_response + 1);
+
+        Some(TrainingExample { messages })
+    }
+}
+
+/// Renders `examples` as JSONL, one example per line
+pub fn to_jsonl(examples: &[TrainingExample]) -> String {
+    examples
+        .iter()
+        .filter_map(|x| serde_json::to_string(x).ok())
+        .map(|x| x + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::Store;
+
+    #[test]
+    fn test_store_export_training_jsonl() {
+        let mut store = Store::new(MockProvider::default());
+        let id = store.add_empty_session(String::from("Greetings"), "gpt-3.5-turbo");
+        store
+            .set_system_prompt(id, Some(String::from("Be brief.")))
+            .unwrap();
+        store
+            .session_mut(id)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("Hi")),
+                (ChatRole::Tool, String::from("12:00")),
+                (ChatRole::Assistant, String::from("Hello \"there\"")),
+                (ChatRole::User, String::from("Unanswered")),
+            ]);
+        let unanswered = store.add_empty_session(String::from("Empty"), "gpt-3.5-turbo");
+        store
+            .session_mut(unanswered)
+            .unwrap()
+            .add_message_batch_without_api(vec![(ChatRole::User, String::from("Hi"))]);
+
+        let path = std::env::temp_dir().join(format!("chat-overlay-training-{}.jsonl", id));
+        assert_eq!(
+            1,
+            store
+                .export_training_jsonl(&[id, unanswered], &path)
+                .unwrap()
+        );
+        assert_eq!(
+            concat!(
+                r#"{"messages":[{"role":"system","content":"Be brief."},"#,
+                r#"{"role":"user","content":"Hi"},"#,
+                r#"{"role":"assistant","content":"Hello \"there\""}]}"#,
+                "\n"
+            ),
+            std::fs::read_to_string(&path).unwrap()
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let missing = crate::ids::SessionId::generate();
+        assert!(store
+            .export_training_jsonl(&[missing], &std::env::temp_dir())
+            .is_err());
+    }
+}
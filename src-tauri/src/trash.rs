@@ -0,0 +1,245 @@
+//! Deleted sessions and messages, kept for a while so they can be restored.
+//!
+//! Deleting a session or message moves it to the store's trash along with
+//! when, and where, it was deleted. The trash is saved with the store.
+//! Items are purged for good once they have been in the trash longer than
+//! the retention period, which is saved to its own file in the app config
+//! directory.
+
+use crate::ids::{MessageId, SessionId};
+use crate::persistence::JsonConfig;
+use crate::{ChatSession, Message};
+use serde::{Deserialize, Serialize};
+
+/// Name of the file the trash config is saved to inside the app config
+/// directory
+pub const TRASH_FILE_NAME: &str = "trash.json";
+
+/// How many days deleted items are kept unless configured otherwise
+pub const DEFAULT_RETENTION_DAYS: u64 = 30;
+
+/// How long deleted items are kept before they are purged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrashConfig {
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig {
+            retention_days: DEFAULT_RETENTION_DAYS,
+        }
+    }
+}
+
+impl JsonConfig for TrashConfig {}
+
+impl TrashConfig {
+    /// Returns the retention period in seconds
+    pub fn retention_secs(&self) -> u64 {
+        self.retention_days.saturating_mul(24 * 60 * 60)
+    }
+}
+
+/// A deleted session or message. `position` is where it was in the list it
+/// was deleted from, so it can be put back in the same place.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrashedItem {
+    Session {
+        session: ChatSession,
+        position: usize,
+        deleted_at: u64,
+    },
+    Message {
+        session_id: SessionId,
+        message: Message,
+        position: usize,
+        deleted_at: u64,
+    },
+}
+
+impl TrashedItem {
+    /// Returns the unix timestamp when this item was deleted
+    pub fn deleted_at(&self) -> u64 {
+        match self {
+            TrashedItem::Session { deleted_at, .. } | TrashedItem::Message { deleted_at, .. } => {
+                *deleted_at
+            }
+        }
+    }
+}
+
+/// The deleted sessions and messages of a store, oldest first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Trash {
+    items: Vec<TrashedItem>,
+}
+
+impl Trash {
+    /// Returns the items in the trash, oldest first
+    pub fn get_items(&self) -> &[TrashedItem] {
+        &self.items
+    }
+
+    pub(crate) fn push(&mut self, item: TrashedItem) {
+        self.items.push(item);
+    }
+
+    /// Removes the session with matching id from the trash, returning it and
+    /// its position
+    pub(crate) fn take_session(&mut self, session_id: SessionId) -> Option<(ChatSession, usize)> {
+        let index = self.items.iter().position(
+            |x| matches!(x, TrashedItem::Session { session, .. } if session.get_id() == session_id),
+        )?;
+
+        match self.items.remove(index) {
+            TrashedItem::Session {
+                session, position, ..
+            } => Some((session, position)),
+            TrashedItem::Message { .. } => unreachable!(),
+        }
+    }
+
+    /// Returns whether the message with id `message_id`, deleted from the
+    /// session with id `session_id`, is in the trash
+    pub(crate) fn has_message(&self, session_id: SessionId, message_id: MessageId) -> bool {
+        self.message_index(session_id, message_id).is_some()
+    }
+
+    /// Removes the message with id `message_id`, deleted from the session
+    /// with id `session_id`, from the trash, returning it and its position
+    pub(crate) fn take_message(
+        &mut self,
+        session_id: SessionId,
+        message_id: MessageId,
+    ) -> Option<(Message, usize)> {
+        let index = self.message_index(session_id, message_id)?;
+
+        match self.items.remove(index) {
+            TrashedItem::Message {
+                message, position, ..
+            } => Some((message, position)),
+            TrashedItem::Session { .. } => unreachable!(),
+        }
+    }
+
+    fn message_index(&self, session_id: SessionId, message_id: MessageId) -> Option<usize> {
+        self.items.iter().position(|x| {
+            matches!(x, TrashedItem::Message { session_id: id, message, .. }
+                if *id == session_id && message.get_id() == message_id)
+        })
+    }
+
+    /// Purges the items deleted more than `retention_secs` seconds before
+    /// `now`, returning how many were purged
+    pub fn purge(&mut self, retention_secs: u64, now: u64) -> usize {
+        let before = self.items.len();
+        self.items
+            .retain(|x| now.saturating_sub(x.deleted_at()) <= retention_secs);
+
+        before - self.items.len()
+    }
+
+    /// Purges the items deleted from, or being, a session whose id matches
+    /// `predicate`, returning how many were purged
+    pub(crate) fn purge_sessions(&mut self, predicate: impl Fn(SessionId) -> bool) -> usize {
+        let before = self.items.len();
+        self.items.retain(|x| match x {
+            TrashedItem::Session { session, .. } => !predicate(session.get_id()),
+            TrashedItem::Message { session_id, .. } => !predicate(*session_id),
+        });
+
+        before - self.items.len()
+    }
+
+    /// Purges every item, returning how many were purged
+    pub fn clear(&mut self) -> usize {
+        std::mem::take(&mut self.items).len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::MockProvider;
+    use crate::{ChatError, ChatRole, Store};
+
+    const MODEL: &str = "gpt-3.5-turbo";
+
+    #[test]
+    fn test_store_restore_from_trash() {
+        let mut store = Store::new(MockProvider::default());
+        let first = store.add_empty_session(String::from("First"), MODEL);
+        let second = store.add_empty_session(String::from("Second"), MODEL);
+        store
+            .session_mut(first)
+            .unwrap()
+            .add_message_batch_without_api(vec![
+                (ChatRole::User, String::from("One")),
+                (ChatRole::Assistant, String::from("Two")),
+                (ChatRole::User, String::from("Three")),
+            ]);
+        let two = store.get_session(first).unwrap().get_messages()[1].get_id();
+
+        let deleted = store.delete_message(first, two).unwrap().unwrap();
+        assert_eq!("Two", deleted.get_content());
+        assert_eq!(2, store.get_session(first).unwrap().message_count());
+
+        store.restore_message(first, two).unwrap();
+        let contents: Vec<String> = store
+            .get_session(first)
+            .unwrap()
+            .get_messages()
+            .iter()
+            .map(|x| x.get_content())
+            .collect();
+        assert_eq!(vec!["One", "Two", "Three"], contents);
+        assert_eq!(
+            Some(ChatError::MessageNotFound(two)),
+            store.restore_message(first, two).err()
+        );
+
+        store.delete_session(first).unwrap();
+        assert_eq!(1, store.get_trash().len());
+        store.restore_session(first).unwrap();
+        assert_eq!(first, store.get_all_sessions()[0].get_id());
+        assert_eq!(second, store.get_all_sessions()[1].get_id());
+        assert!(store.get_trash().is_empty());
+        assert_eq!(
+            Some(ChatError::SessionNotFound(first)),
+            store.restore_session(first).err()
+        );
+
+        // Messages can not be restored into a session that is gone
+        store
+            .session_mut(second)
+            .unwrap()
+            .add_message_batch_without_api(vec![(ChatRole::User, String::from("Hello"))]);
+        let hello = store.get_session(second).unwrap().get_messages()[0].get_id();
+        store.delete_message(second, hello).unwrap();
+        store.delete_session(second);
+        assert_eq!(
+            Some(ChatError::SessionNotFound(second)),
+            store.restore_message(second, hello).err()
+        );
+        assert_eq!(2, store.empty_trash());
+    }
+
+    #[test]
+    fn test_trash_purge() {
+        let mut store = Store::new(MockProvider::default());
+        let id = store.add_empty_session(String::from("Old"), MODEL);
+        store.delete_session(id);
+
+        let mut trash = store.trash.clone();
+        let deleted_at = trash.get_items()[0].deleted_at();
+        assert_eq!(0, trash.purge(60, deleted_at + 60));
+        assert_eq!(1, trash.purge(60, deleted_at + 61));
+        assert!(trash.get_items().is_empty());
+
+        store.set_trash_retention(TrashConfig::default().retention_secs());
+        assert_eq!(1, store.get_trash().len());
+    }
+}
@@ -0,0 +1,199 @@
+//! The system tray icon and its quick actions.
+//!
+//! The tray menu starts a new chat, shows or hides the overlay, pauses the
+//! quick-ask hotkey and quits the app. Clicking the icon itself toggles the
+//! overlay. Whether the overlay is visible is tracked here, however it is
+//! shown or hidden, and every change is emitted to the webview as
+//! `OVERLAY_VISIBILITY_EVENT`. Pausing or resuming the hotkey is emitted as
+//! `HOTKEY_PAUSED_EVENT`.
+
+use crate::events::{
+    HotkeyPausedPayload, OverlayVisibilityPayload, HOTKEY_PAUSED_EVENT, OVERLAY_VISIBILITY_EVENT,
+};
+use crate::hotkey::{self, HotkeyState, InputSession, OVERLAY_WINDOW};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tauri::{
+    AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu,
+    SystemTrayMenuItem,
+};
+
+/// Id of the menu item starting a new chat
+pub const NEW_CHAT_ITEM: &str = "new_chat";
+
+/// Id of the menu item showing or hiding the overlay
+pub const TOGGLE_OVERLAY_ITEM: &str = "toggle_overlay";
+
+/// Id of the menu item pausing or resuming the quick-ask hotkey
+pub const PAUSE_HOTKEY_ITEM: &str = "pause_hotkey";
+
+/// Id of the menu item quitting the app
+pub const QUIT_ITEM: &str = "quit";
+
+/// Whether the overlay is visible and the hotkey paused, as shown in the tray
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct OverlayStatus {
+    pub visible: bool,
+    pub hotkey_paused: bool,
+}
+
+/// The Tauri managed state tracking whether the overlay is visible
+#[derive(Debug, Default)]
+pub struct OverlayState {
+    visible: AtomicBool,
+}
+
+impl OverlayState {
+    /// Starts tracking an overlay that is `visible`
+    pub fn new(visible: bool) -> OverlayState {
+        OverlayState {
+            visible: AtomicBool::new(visible),
+        }
+    }
+
+    /// Returns whether the overlay is visible
+    pub fn is_visible(&self) -> bool {
+        self.visible.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns the tray icon with its menu of quick actions
+pub fn system_tray() -> SystemTray {
+    let menu = SystemTrayMenu::new()
+        .add_item(CustomMenuItem::new(NEW_CHAT_ITEM, "New chat"))
+        .add_item(CustomMenuItem::new(TOGGLE_OVERLAY_ITEM, "Hide overlay"))
+        .add_item(CustomMenuItem::new(PAUSE_HOTKEY_ITEM, "Pause hotkey"))
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new(QUIT_ITEM, "Quit"));
+
+    SystemTray::new().with_menu(menu)
+}
+
+/// Carries out the quick action picked from the tray
+pub fn handle_event(app: &AppHandle, event: SystemTrayEvent) {
+    match event {
+        SystemTrayEvent::LeftClick { .. } => toggle_overlay(app),
+        SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
+            NEW_CHAT_ITEM => {
+                show_overlay(app);
+                hotkey::focus_input(app, InputSession::New);
+            }
+            TOGGLE_OVERLAY_ITEM => toggle_overlay(app),
+            PAUSE_HOTKEY_ITEM => toggle_hotkey(app),
+            QUIT_ITEM => app.exit(0),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+/// Returns whether the overlay is visible and the hotkey paused
+pub fn status(app: &AppHandle) -> OverlayStatus {
+    OverlayStatus {
+        visible: app
+            .try_state::<OverlayState>()
+            .is_some_and(|x| x.is_visible()),
+        hotkey_paused: app
+            .try_state::<HotkeyState>()
+            .is_some_and(|x| x.is_paused()),
+    }
+}
+
+/// Shows and focuses the overlay window
+pub fn show_overlay(app: &AppHandle) {
+    let Some(window) = app.get_window(OVERLAY_WINDOW) else {
+        eprintln!("No overlay window to show");
+        return;
+    };
+
+    match window.show().and_then(|_| window.set_focus()) {
+        Ok(()) => set_visible(app, true),
+        Err(e) => eprintln!("Could not show the overlay: {}", e),
+    }
+}
+
+/// Hides the overlay window, discarding the ephemeral sessions started in it
+pub fn hide_overlay(app: &AppHandle) {
+    let Some(window) = app.get_window(OVERLAY_WINDOW) else {
+        eprintln!("No overlay window to hide");
+        return;
+    };
+
+    match window.hide() {
+        Ok(()) => {
+            set_visible(app, false);
+            hotkey::discard_scratch_sessions(app);
+        }
+        Err(e) => eprintln!("Could not hide the overlay: {}", e),
+    }
+}
+
+/// Hides the overlay if it is visible, otherwise shows it
+pub fn toggle_overlay(app: &AppHandle) {
+    match status(app).visible {
+        true => hide_overlay(app),
+        false => show_overlay(app),
+    }
+}
+
+/// Records that the overlay became `visible`, relabelling the tray item that
+/// toggles it and telling the webview
+fn set_visible(app: &AppHandle, visible: bool) {
+    if let Some(state) = app.try_state::<OverlayState>() {
+        state.visible.store(visible, Ordering::SeqCst);
+    }
+
+    let title = if visible {
+        "Hide overlay"
+    } else {
+        "Show overlay"
+    };
+    if let Err(e) = app
+        .tray_handle()
+        .get_item(TOGGLE_OVERLAY_ITEM)
+        .set_title(title)
+    {
+        eprintln!("Could not update the tray: {}", e);
+    }
+    if let Err(e) = app.emit_all(
+        OVERLAY_VISIBILITY_EVENT,
+        OverlayVisibilityPayload { visible },
+    ) {
+        eprintln!("Could not emit the overlay visibility: {}", e);
+    }
+}
+
+/// Pauses the quick-ask hotkey if it is registered, otherwise resumes it
+fn toggle_hotkey(app: &AppHandle) {
+    let Some(hotkey) = app.try_state::<HotkeyState>() else {
+        return;
+    };
+    if let Err(e) = set_hotkey_paused(app, &hotkey, !hotkey.is_paused()) {
+        eprintln!("Could not pause the hotkey: {}", e);
+    }
+}
+
+/// Pauses the quick-ask hotkey, or resumes it if not `paused`, relabelling
+/// the tray item that toggles it and telling the webview
+pub fn set_hotkey_paused(
+    app: &AppHandle,
+    hotkey: &HotkeyState,
+    paused: bool,
+) -> Result<(), String> {
+    hotkey.set_paused(app, paused)?;
+
+    let title = if paused {
+        "Resume hotkey"
+    } else {
+        "Pause hotkey"
+    };
+    if let Err(e) = app
+        .tray_handle()
+        .get_item(PAUSE_HOTKEY_ITEM)
+        .set_title(title)
+    {
+        eprintln!("Could not update the tray: {}", e);
+    }
+    app.emit_all(HOTKEY_PAUSED_EVENT, HotkeyPausedPayload { paused })
+        .map_err(|e| e.to_string())
+}
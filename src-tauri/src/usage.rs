@@ -0,0 +1,247 @@
+//! Token usage of chat requests and what it costs.
+//!
+//! Usage can also be broken down by day and chat model, and written as CSV
+//! for expensing or budgeting.
+
+use serde::{Deserialize, Serialize};
+
+/// The tokens used by a single chat request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    /// Tokens in the messages sent to the chat model
+    pub prompt_tokens: u32,
+    /// Tokens in the chat model's response
+    pub completion_tokens: u32,
+}
+
+/// Price of a chat model in USD per 1000 tokens
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Prices of the OpenAI chat models, keyed by model name prefix. More specific
+/// prefixes come first.
+const PRICES: &[(&str, ModelPrice)] = &[
+    (
+        "gpt-4-32k",
+        ModelPrice {
+            prompt: 0.06,
+            completion: 0.12,
+        },
+    ),
+    (
+        "gpt-4",
+        ModelPrice {
+            prompt: 0.03,
+            completion: 0.06,
+        },
+    ),
+    (
+        "gpt-3.5-turbo-16k",
+        ModelPrice {
+            prompt: 0.003,
+            completion: 0.004,
+        },
+    ),
+    (
+        "gpt-3.5-turbo",
+        ModelPrice {
+            prompt: 0.0015,
+            completion: 0.002,
+        },
+    ),
+];
+
+/// Token usage and cost of all the messages sent to a single chat model
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ModelUsage {
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Cost in USD, or 0 if the model's price is unknown
+    pub cost: f64,
+}
+
+/// Token usage and cost of every session in a store
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageReport {
+    /// Usage per chat model, sorted by model name
+    pub models: Vec<ModelUsage>,
+    /// Cost in USD across all models
+    pub total_cost: f64,
+}
+
+/// Days whose usage is reported, as unix timestamps in seconds. Either end
+/// can be left open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DateRange {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+impl DateRange {
+    /// Returns whether `timestamp` falls within this range, ends included
+    pub fn contains(&self, timestamp: u64) -> bool {
+        self.start.is_none_or(|x| timestamp >= x) && self.end.is_none_or(|x| timestamp <= x)
+    }
+}
+
+/// Token usage and cost of the messages sent to a single chat model on a
+/// single day
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DailyUsage {
+    /// The day, in UTC, written as `YYYY-MM-DD`
+    pub date: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Cost in USD, or 0 if the model's price is unknown
+    pub cost: f64,
+}
+
+impl TokenUsage {
+    /// Returns the total number of tokens used
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Adds up the tokens used by two requests, either of which may be unknown
+pub fn add_usage(total: Option<TokenUsage>, usage: Option<TokenUsage>) -> Option<TokenUsage> {
+    match (total, usage) {
+        (Some(total), Some(usage)) => Some(TokenUsage {
+            prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+            completion_tokens: total.completion_tokens + usage.completion_tokens,
+        }),
+        (total, usage) => total.or(usage),
+    }
+}
+
+impl From<async_openai::types::Usage> for TokenUsage {
+    fn from(usage: async_openai::types::Usage) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+/// Returns the price of `model`, or None if it is unknown
+pub fn model_price(model: &str) -> Option<ModelPrice> {
+    PRICES
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, price)| *price)
+}
+
+/// Returns the cost in USD of `usage` with `model`, or None if the model's
+/// price is unknown
+pub fn cost(model: &str, usage: &TokenUsage) -> Option<f64> {
+    model_price(model).map(|price| {
+        (usage.prompt_tokens as f64 * price.prompt
+            + usage.completion_tokens as f64 * price.completion)
+            / 1000.0
+    })
+}
+
+/// Returns the UTC day of `timestamp`, written as `YYYY-MM-DD`
+pub(crate) fn day_of(timestamp: u64) -> String {
+    chrono::NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+        .map(|dt| dt.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/// Writes `rows` as CSV, with a header row
+pub fn to_csv(rows: &[DailyUsage]) -> String {
+    let mut csv =
+        String::from("date,model,prompt_tokens,completion_tokens,total_tokens,cost_usd\n");
+
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{:.6}\n",
+            row.date,
+            csv_field(&row.model),
+            row.prompt_tokens,
+            row.completion_tokens,
+            row.prompt_tokens + row.completion_tokens,
+            row.cost
+        ));
+    }
+
+    csv
+}
+
+/// Quotes `field` if it holds characters CSV gives a meaning to
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_cost(expected: f64, model: &str, usage: &TokenUsage) {
+        let cost = cost(model, usage).unwrap();
+        assert!((expected - cost).abs() < 1e-9, "{} != {}", expected, cost);
+    }
+
+    #[test]
+    fn test_usage_cost() {
+        let usage = TokenUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+        };
+
+        assert_eq!(1500, usage.total_tokens());
+        assert_cost(0.0025, "gpt-3.5-turbo", &usage);
+        assert_cost(0.0025, "gpt-3.5-turbo-0613", &usage);
+        assert_cost(0.005, "gpt-3.5-turbo-16k", &usage);
+        assert_cost(0.06, "gpt-4", &usage);
+        assert_cost(0.12, "gpt-4-32k-0613", &usage);
+        assert_eq!(None, cost("llama3", &usage));
+    }
+
+    #[test]
+    fn test_usage_csv() {
+        let range = DateRange {
+            start: Some(100),
+            end: None,
+        };
+        assert!(!range.contains(99));
+        assert!(range.contains(100) && range.contains(u64::MAX));
+        assert_eq!("2023-11-14", day_of(1_700_000_000));
+
+        let rows = vec![
+            DailyUsage {
+                date: String::from("2023-11-14"),
+                model: String::from("gpt-4"),
+                prompt_tokens: 1000,
+                completion_tokens: 500,
+                cost: 0.06,
+            },
+            DailyUsage {
+                date: String::from("2023-11-15"),
+                model: String::from("my \"fast\", model"),
+                ..DailyUsage::default()
+            },
+        ];
+        let csv = to_csv(&rows);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            "date,model,prompt_tokens,completion_tokens,total_tokens,cost_usd",
+            lines[0]
+        );
+        assert_eq!("2023-11-14,gpt-4,1000,500,1500,0.060000", lines[1]);
+        assert_eq!(
+            "2023-11-15,\"my \"\"fast\"\", model\",0,0,0,0.000000",
+            lines[2]
+        );
+    }
+}
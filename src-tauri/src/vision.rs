@@ -0,0 +1,155 @@
+//! Asking vision-capable chat models about what is on screen.
+//!
+//! A region of the screen picked by the user is captured to a PNG inside the
+//! app data directory, then attached to a User message as base64 so the chat
+//! model can answer questions about it.
+
+use crate::ChatError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use xcap::image::{imageops, ImageFormat};
+use xcap::Monitor;
+
+/// Model used for questions about images when the session's model can not
+/// see them
+pub const VISION_MODEL: &str = "gpt-4o";
+
+/// Name of the directory screenshots are saved to inside the app data
+/// directory
+pub const SCREENSHOTS_DIR_NAME: &str = "screenshots";
+
+/// Prefixes of the models known to accept images
+const VISION_MODEL_PREFIXES: [&str; 7] = [
+    "gpt-4o",
+    "gpt-4-turbo",
+    "gpt-4-vision",
+    "llava",
+    "bakllava",
+    "llama3.2-vision",
+    "moondream",
+];
+
+/// A rectangle of the screen, in physical pixels from the top left corner of
+/// the primary monitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScreenRegion {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An image attached to a request, encoded for sending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageData {
+    /// The image's media type, such as `image/png`
+    pub mime: String,
+    /// The image's bytes, base64 encoded
+    pub base64: String,
+}
+
+impl ImageData {
+    /// Reads and encodes the image at `path`. The media type is guessed from
+    /// the file extension.
+    pub fn load(path: &Path) -> Result<ImageData, ChatError> {
+        let bytes = fs::read(path).map_err(|e| ChatError::Image(e.to_string()))?;
+
+        Ok(ImageData {
+            mime: mime_type(path).to_string(),
+            base64: STANDARD.encode(bytes),
+        })
+    }
+
+    /// Returns this image as a `data:` URL
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime, self.base64)
+    }
+}
+
+/// Returns the media type of the image at `path` based on its extension,
+/// assuming PNG if it is not recognised
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(str::to_lowercase);
+
+    match extension.as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "image/png",
+    }
+}
+
+/// Returns true if `model` is known to accept images
+pub fn is_vision_model(model: &str) -> bool {
+    VISION_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+}
+
+/// Captures `region` of the screen, saving it as a PNG inside `dir`. Returns
+/// the path of the saved image.
+///
+/// The region is clipped to the monitor its top left corner is on.
+pub fn capture_region(region: ScreenRegion, dir: &Path) -> Result<PathBuf, ChatError> {
+    if region.width == 0 || region.height == 0 {
+        return Err(ChatError::Image("The region is empty".to_string()));
+    }
+
+    let monitor =
+        Monitor::from_point(region.x, region.y).map_err(|e| ChatError::Image(e.to_string()))?;
+    let screen = monitor
+        .capture_image()
+        .map_err(|e| ChatError::Image(e.to_string()))?;
+
+    let x = (region.x - monitor.x()).max(0) as u32;
+    let y = (region.y - monitor.y()).max(0) as u32;
+    let width = region.width.min(screen.width().saturating_sub(x));
+    let height = region.height.min(screen.height().saturating_sub(y));
+    let image = imageops::crop_imm(&screen, x, y, width, height).to_image();
+
+    fs::create_dir_all(dir).map_err(|e| ChatError::Image(e.to_string()))?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis());
+    let path = dir.join(format!("screenshot-{}.png", millis));
+
+    image
+        .save_with_format(&path, ImageFormat::Png)
+        .map_err(|e| ChatError::Image(e.to_string()))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_data() {
+        let path = std::env::temp_dir().join("chat-overlay-vision-test.JPG");
+        fs::write(&path, b"not really a jpeg").unwrap();
+
+        let image = ImageData::load(&path).unwrap();
+        assert_eq!("image/jpeg", image.mime);
+        assert_eq!(
+            "data:image/jpeg;base64,bm90IHJlYWxseSBhIGpwZWc=",
+            image.data_url()
+        );
+
+        fs::remove_file(path).unwrap();
+        assert!(ImageData::load(Path::new("/no/such/image.png")).is_err());
+    }
+
+    #[test]
+    fn test_is_vision_model() {
+        assert!(is_vision_model("gpt-4o-mini"));
+        assert!(is_vision_model("llava:13b"));
+        assert!(!is_vision_model("gpt-3.5-turbo"));
+    }
+}
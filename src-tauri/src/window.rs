@@ -0,0 +1,485 @@
+//! Placing and styling the overlay window.
+//!
+//! The overlay can stay on top of other windows, be drawn see-through, let
+//! clicks pass through to the windows under it, and be snapped to a corner of
+//! the monitor it is on. Where it was left, and how large it was, is
+//! remembered for each monitor, so it reopens in the same place on the monitor
+//! it was last on. When the hotkey brings it up on another monitor, it goes
+//! back to where it was left there. The settings are saved to their own file
+//! in the app config directory.
+//!
+//! The overlay hides itself when another window takes focus, unless the user
+//! has pinned it or lets clicks pass through it.
+//!
+//! Tauri can not change the opacity of a window itself, so it is emitted to
+//! the webview as `WINDOW_OPACITY_EVENT` to be applied there.
+
+use crate::events::{WindowOpacityPayload, WINDOW_OPACITY_EVENT};
+use crate::hotkey::OVERLAY_WINDOW;
+use crate::persistence::JsonConfig;
+use crate::tray;
+use crate::ChatError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, Monitor, PhysicalPosition, PhysicalSize, Window};
+
+/// Name of the file the window settings are saved to inside the app config
+/// directory
+pub const WINDOW_FILE_NAME: &str = "window.json";
+
+/// Gap left between a snapped overlay and the edges of its monitor, in
+/// logical pixels
+pub const SNAP_MARGIN: f64 = 16.0;
+
+/// The least opacity the overlay can be given, so it never disappears
+pub const MIN_OPACITY: f32 = 0.2;
+
+/// Key of monitors the OS does not name
+const UNNAMED_MONITOR: &str = "unnamed";
+
+/// A corner of the screen the overlay can be snapped to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Where the overlay was left on a monitor, in physical pixels from the
+/// monitor's top left corner, and how large it was
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Placement {
+    pub x: i32,
+    pub y: i32,
+    /// Size of the overlay in physical pixels. None for placements saved by
+    /// older versions, which leave the overlay at its current size.
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+/// How the overlay window is placed and drawn
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowSettings {
+    /// Whether the overlay stays above other windows
+    pub always_on_top: bool,
+
+    /// Opacity of the overlay, from `MIN_OPACITY` to 1
+    pub opacity: f32,
+
+    /// Whether clicks pass through the overlay to the windows under it
+    pub click_through: bool,
+
+    /// Where the overlay was left on each monitor, keyed by monitor name
+    pub placements: BTreeMap<String, Placement>,
+
+    /// Name of the monitor the overlay was last on
+    pub last_monitor: Option<String>,
+
+    /// Whether the hotkey brings the overlay up on the monitor the user is
+    /// working on, rather than where it was left
+    pub follow_active_monitor: bool,
+
+    /// Whether the overlay stays visible when another window takes focus
+    pub pinned: bool,
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        WindowSettings {
+            always_on_top: false,
+            opacity: 1.0,
+            click_through: false,
+            placements: BTreeMap::new(),
+            last_monitor: None,
+            follow_active_monitor: true,
+            pinned: false,
+        }
+    }
+}
+
+impl JsonConfig for WindowSettings {}
+
+impl WindowSettings {
+    /// Returns whether the overlay hides when another window takes focus. A
+    /// click-through overlay is left visible, since clicking past it is what
+    /// it is for.
+    pub fn auto_hides(&self) -> bool {
+        !self.pinned && !self.click_through
+    }
+}
+
+/// Returns the top left corner of a window of `window` size snapped to
+/// `corner` of a monitor at `origin` of `monitor` size, `margin` pixels from
+/// its edges
+pub fn corner_position(
+    corner: Corner,
+    origin: (i32, i32),
+    monitor: (u32, u32),
+    window: (u32, u32),
+    margin: i32,
+) -> (i32, i32) {
+    let left = origin.0 + margin;
+    let top = origin.1 + margin;
+    let right = origin.0 + monitor.0 as i32 - window.0 as i32 - margin;
+    let bottom = origin.1 + monitor.1 as i32 - window.1 as i32 - margin;
+
+    match corner {
+        Corner::TopLeft => (left, top),
+        Corner::TopRight => (right.max(left), top),
+        Corner::BottomLeft => (left, bottom.max(top)),
+        Corner::BottomRight => (right.max(left), bottom.max(top)),
+    }
+}
+
+/// Returns the top left corner and size of a window of `window` size placed
+/// on a monitor at `origin` of `monitor` size. The window goes where
+/// `placement` says, at the size it saved, or in the middle of the monitor
+/// without a placement. Either way it is kept within the monitor.
+pub fn placement_geometry(
+    placement: Option<&Placement>,
+    origin: (i32, i32),
+    monitor: (u32, u32),
+    window: (u32, u32),
+) -> ((i32, i32), (u32, u32)) {
+    let width = placement.and_then(|x| x.width).unwrap_or(window.0);
+    let height = placement.and_then(|x| x.height).unwrap_or(window.1);
+    let size = (width.min(monitor.0), height.min(monitor.1));
+    let free = ((monitor.0 - size.0) as i32, (monitor.1 - size.1) as i32);
+
+    let (x, y) = match placement {
+        Some(placement) => (placement.x.clamp(0, free.0), placement.y.clamp(0, free.1)),
+        None => (free.0 / 2, free.1 / 2),
+    };
+
+    ((origin.0 + x, origin.1 + y), size)
+}
+
+/// Returns the key `monitor` is remembered by
+fn monitor_key(monitor: &Monitor) -> String {
+    monitor
+        .name()
+        .cloned()
+        .unwrap_or_else(|| UNNAMED_MONITOR.to_string())
+}
+
+/// The Tauri managed state holding the window settings
+#[derive(Debug)]
+pub struct WindowState {
+    settings: Mutex<WindowSettings>,
+    path: PathBuf,
+}
+
+impl WindowState {
+    /// Loads the settings saved inside `app_config_dir`
+    pub fn in_app_config_dir(app_config_dir: &Path) -> Result<WindowState, ChatError> {
+        let path = app_config_dir.join(WINDOW_FILE_NAME);
+
+        Ok(WindowState {
+            settings: Mutex::new(WindowSettings::load(&path)?),
+            path,
+        })
+    }
+
+    /// Returns a copy of the settings in effect
+    pub fn get_settings(&self) -> WindowSettings {
+        self.lock().clone()
+    }
+
+    /// Applies the saved settings to the overlay, moving it back to where it
+    /// was left on the monitor it was last on, if that monitor is still
+    /// connected
+    pub fn apply(&self, app: &AppHandle) -> Result<(), String> {
+        let window = overlay(app)?;
+        let settings = self.get_settings();
+
+        window
+            .set_always_on_top(settings.always_on_top)
+            .and_then(|_| window.set_ignore_cursor_events(settings.click_through))
+            .map_err(|e| e.to_string())?;
+        emit_opacity(app, settings.opacity);
+
+        let monitors = window.available_monitors().map_err(|e| e.to_string())?;
+        let last = settings.last_monitor.as_deref();
+        let Some(monitor) = monitors
+            .iter()
+            .find(|x| Some(monitor_key(x).as_str()) == last)
+        else {
+            return Ok(());
+        };
+        if settings.placements.contains_key(&monitor_key(monitor)) {
+            self.place_on(&window, monitor)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the overlay onto `monitor`, where and at the size it was left
+    /// there, or to its middle if it was never on it. Does nothing if the
+    /// overlay is already on `monitor`.
+    pub fn move_to_monitor(&self, app: &AppHandle, monitor: &Monitor) -> Result<(), String> {
+        let window = overlay(app)?;
+        let current = window.current_monitor().map_err(|e| e.to_string())?;
+        if current.is_some_and(|x| x.position() == monitor.position()) {
+            return Ok(());
+        }
+
+        self.place_on(&window, monitor)?;
+        self.remember_geometry(&window)
+    }
+
+    /// Places `window` on `monitor` as its saved placement there says
+    fn place_on(&self, window: &Window, monitor: &Monitor) -> Result<(), String> {
+        let settings = self.get_settings();
+        let placement = settings.placements.get(&monitor_key(monitor));
+        let size = window.outer_size().map_err(|e| e.to_string())?;
+        let origin = monitor.position();
+        let ((x, y), (width, height)) = placement_geometry(
+            placement,
+            (origin.x, origin.y),
+            (monitor.size().width, monitor.size().height),
+            (size.width, size.height),
+        );
+
+        if (width, height) != (size.width, size.height) {
+            window
+                .set_size(PhysicalSize::new(width, height))
+                .map_err(|e| e.to_string())?;
+        }
+        window
+            .set_position(PhysicalPosition::new(x, y))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Keeps the overlay visible when another window takes focus, or lets it
+    /// hide again if not `pinned`
+    pub fn set_pinned(&self, pinned: bool) -> Result<(), String> {
+        self.update(|x| x.pinned = pinned)
+    }
+
+    /// Hides the overlay after it lost focus, unless it is pinned
+    pub fn hide_on_blur(&self, app: &AppHandle) {
+        if self.lock().auto_hides() && tray::status(app).visible {
+            tray::hide_overlay(app);
+        }
+    }
+
+    /// Has the hotkey bring the overlay up on the monitor the user is working
+    /// on, or leave it where it was if not `follow`
+    pub fn set_follow_active_monitor(&self, follow: bool) -> Result<(), String> {
+        self.update(|x| x.follow_active_monitor = follow)
+    }
+
+    /// Keeps the overlay above other windows, or lets them cover it if not
+    /// `always_on_top`
+    pub fn set_always_on_top(&self, app: &AppHandle, always_on_top: bool) -> Result<(), String> {
+        overlay(app)?
+            .set_always_on_top(always_on_top)
+            .map_err(|e| e.to_string())?;
+
+        self.update(|x| x.always_on_top = always_on_top)
+    }
+
+    /// Draws the overlay with `opacity`, kept between `MIN_OPACITY` and 1
+    pub fn set_opacity(&self, app: &AppHandle, opacity: f32) -> Result<(), String> {
+        let opacity = opacity.clamp(MIN_OPACITY, 1.0);
+        emit_opacity(app, opacity);
+
+        self.update(|x| x.opacity = opacity)
+    }
+
+    /// Lets clicks pass through the overlay to the windows under it, or has
+    /// the overlay take them again if not `click_through`
+    pub fn set_click_through(&self, app: &AppHandle, click_through: bool) -> Result<(), String> {
+        overlay(app)?
+            .set_ignore_cursor_events(click_through)
+            .map_err(|e| e.to_string())?;
+
+        self.update(|x| x.click_through = click_through)
+    }
+
+    /// Moves the overlay to `corner` of the monitor it is on, remembering it
+    /// there
+    pub fn snap_to_corner(&self, app: &AppHandle, corner: Corner) -> Result<(), String> {
+        let window = overlay(app)?;
+        let monitor = window
+            .current_monitor()
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| String::from("The overlay is not on any monitor"))?;
+        let size = window.outer_size().map_err(|e| e.to_string())?;
+        let origin = monitor.position();
+        let (x, y) = corner_position(
+            corner,
+            (origin.x, origin.y),
+            (monitor.size().width, monitor.size().height),
+            (size.width, size.height),
+            (SNAP_MARGIN * monitor.scale_factor()).round() as i32,
+        );
+
+        window
+            .set_position(PhysicalPosition::new(x, y))
+            .map_err(|e| e.to_string())?;
+        self.remember_geometry(&window)
+    }
+
+    /// Records where `window` now is on the monitor it is on, and its size
+    pub fn remember_geometry(&self, window: &Window) -> Result<(), String> {
+        let Some(monitor) = window.current_monitor().map_err(|e| e.to_string())? else {
+            return Ok(());
+        };
+        let position = window.outer_position().map_err(|e| e.to_string())?;
+        let size = window.outer_size().map_err(|e| e.to_string())?;
+        let origin = monitor.position();
+        let placement = Placement {
+            x: position.x - origin.x,
+            y: position.y - origin.y,
+            width: Some(size.width),
+            height: Some(size.height),
+        };
+        let key = monitor_key(&monitor);
+
+        self.update(|x| {
+            x.placements.insert(key.clone(), placement);
+            x.last_monitor = Some(key);
+        })
+    }
+
+    /// Changes the settings with `change`, then saves them
+    fn update(&self, change: impl FnOnce(&mut WindowSettings)) -> Result<(), String> {
+        let mut settings = self.lock();
+        change(&mut settings);
+
+        settings.save(&self.path).map_err(|e| e.to_string())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WindowSettings> {
+        // The settings are only changed in single steps, so a poisoned lock is
+        // still usable
+        self.settings.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returns the overlay window of `app`
+fn overlay(app: &AppHandle) -> Result<Window, String> {
+    app.get_window(OVERLAY_WINDOW)
+        .ok_or_else(|| String::from("There is no overlay window"))
+}
+
+/// Tells the webview to draw the overlay with `opacity`
+fn emit_opacity(app: &AppHandle, opacity: f32) {
+    if let Err(e) = app.emit_all(WINDOW_OPACITY_EVENT, WindowOpacityPayload { opacity }) {
+        eprintln!("Could not emit the overlay opacity: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_corner_position() {
+        let origin = (1920, 0);
+        let monitor = (1280, 1024);
+        let window = (400, 600);
+
+        assert_eq!(
+            (1936, 16),
+            corner_position(Corner::TopLeft, origin, monitor, window, 16)
+        );
+        assert_eq!(
+            (2784, 16),
+            corner_position(Corner::TopRight, origin, monitor, window, 16)
+        );
+        assert_eq!(
+            (1936, 408),
+            corner_position(Corner::BottomLeft, origin, monitor, window, 16)
+        );
+
+        // A window larger than the monitor stays on it from the top left
+        assert_eq!(
+            (1936, 16),
+            corner_position(Corner::BottomRight, origin, monitor, (2000, 2000), 16)
+        );
+    }
+
+    #[test]
+    fn test_placement_geometry() {
+        let origin = (-1280, 0);
+        let monitor = (1280, 1024);
+
+        // Without a placement the window is centred at its current size
+        assert_eq!(
+            ((-1080, 212), (880, 600)),
+            placement_geometry(None, origin, monitor, (880, 600))
+        );
+
+        let placement = Placement {
+            x: 100,
+            y: 50,
+            width: Some(400),
+            height: Some(700),
+        };
+        assert_eq!(
+            ((-1180, 50), (400, 700)),
+            placement_geometry(Some(&placement), origin, monitor, (880, 600))
+        );
+
+        // A placement saved on a larger monitor is kept within this one
+        let placement = Placement {
+            x: 2000,
+            y: 900,
+            width: Some(1600),
+            height: None,
+        };
+        assert_eq!(
+            ((-1280, 424), (1280, 600)),
+            placement_geometry(Some(&placement), origin, monitor, (880, 600))
+        );
+    }
+
+    #[test]
+    fn test_window_settings_round_trip() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("chat-overlay-window-{}", nanos));
+        let path = dir.join(WINDOW_FILE_NAME);
+
+        assert_eq!(
+            WindowSettings::default(),
+            WindowSettings::load(&path).unwrap()
+        );
+
+        let mut settings = WindowSettings {
+            always_on_top: true,
+            opacity: 0.8,
+            ..Default::default()
+        };
+        settings.placements.insert(
+            String::from("DELL U2720Q"),
+            Placement {
+                x: 40,
+                y: 80,
+                width: Some(420),
+                height: None,
+            },
+        );
+        settings.last_monitor = Some(String::from("DELL U2720Q"));
+        assert!(settings.auto_hides());
+        settings.pinned = true;
+        assert!(!settings.auto_hides());
+        settings.save(&path).unwrap();
+        assert_eq!(settings, WindowSettings::load(&path).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}